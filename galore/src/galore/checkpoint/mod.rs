@@ -0,0 +1,393 @@
+use ndarray::{Array, Array2, ArrayD, Dimension, Ix2};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufWriter, Read, Write};
+use std::path::Path;
+use std::str::FromStr;
+
+use self::storage::{Storage, StorageWriter};
+
+mod native;
+mod npz;
+#[cfg(feature = "object-store")]
+pub mod object_store;
+mod safetensors;
+pub mod storage;
+
+#[derive(Debug)]
+pub enum CheckpointError {
+    Io(io::Error),
+    Format(String),
+    Unsupported(String),
+    Missing(String),
+}
+
+impl fmt::Display for CheckpointError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CheckpointError::Io(e) => write!(f, "checkpoint I/O error: {}", e),
+            CheckpointError::Format(msg) => write!(f, "malformed checkpoint: {}", msg),
+            CheckpointError::Unsupported(msg) => write!(f, "unsupported checkpoint content: {}", msg),
+            CheckpointError::Missing(name) => write!(f, "checkpoint entry not found: {}", name),
+        }
+    }
+}
+
+impl std::error::Error for CheckpointError {}
+
+impl From<io::Error> for CheckpointError {
+    fn from(e: io::Error) -> Self {
+        CheckpointError::Io(e)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CheckpointFormat {
+    // The crate's own format (.galore): tensors plus string metadata.
+    Native,
+    // safetensors; metadata goes in the `__metadata__` header entry.
+    SafeTensors,
+    // numpy .npz (uncompressed); has no place for metadata.
+    Npz,
+}
+
+impl CheckpointFormat {
+    pub fn from_path(path: &Path) -> Option<Self> {
+        path.extension().and_then(|ext| ext.to_str()).and_then(Self::parse)
+    }
+
+    pub fn parse(name: &str) -> Option<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "galore" | "native" => Some(CheckpointFormat::Native),
+            "safetensors" => Some(CheckpointFormat::SafeTensors),
+            "npz" => Some(CheckpointFormat::Npz),
+            _ => None,
+        }
+    }
+
+    pub fn supports_metadata(self) -> bool {
+        !matches!(self, CheckpointFormat::Npz)
+    }
+}
+
+// Named f32 tensors plus string metadata. Optimizer and projection state are stored as
+// ordinary tensors under "optimizer." / "galore." prefixes, with scalars in the metadata.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Checkpoint {
+    tensors: BTreeMap<String, ArrayD<f32>>,
+    metadata: BTreeMap<String, String>,
+}
+
+impl Checkpoint {
+    pub fn new() -> Self {
+        Checkpoint::default()
+    }
+
+    pub fn insert_tensor<D: Dimension>(&mut self, name: impl Into<String>, tensor: Array<f32, D>) {
+        self.tensors.insert(name.into(), tensor.into_dyn());
+    }
+
+    pub fn tensor(&self, name: &str) -> Option<&ArrayD<f32>> {
+        self.tensors.get(name)
+    }
+
+    pub fn matrix(&self, name: &str) -> Result<Array2<f32>, CheckpointError> {
+        let tensor = self.tensor(name).ok_or_else(|| CheckpointError::Missing(name.to_string()))?;
+        tensor
+            .clone()
+            .into_dimensionality::<Ix2>()
+            .map_err(|_| CheckpointError::Format(format!("{} is not a matrix", name)))
+    }
+
+    pub fn remove_tensor(&mut self, name: &str) -> Option<ArrayD<f32>> {
+        self.tensors.remove(name)
+    }
+
+    pub fn tensors(&self) -> impl Iterator<Item = (&str, &ArrayD<f32>)> {
+        self.tensors.iter().map(|(k, v)| (k.as_str(), v))
+    }
+
+    pub fn set_metadata(&mut self, key: impl Into<String>, value: impl ToString) {
+        self.metadata.insert(key.into(), value.to_string());
+    }
+
+    pub fn metadata(&self, key: &str) -> Option<&str> {
+        self.metadata.get(key).map(|v| v.as_str())
+    }
+
+    pub fn metadata_entries(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.metadata.iter().map(|(k, v)| (k.as_str(), v.as_str()))
+    }
+
+    pub fn parse_metadata<T: FromStr>(&self, key: &str) -> Result<T, CheckpointError> {
+        let value = self.metadata(key).ok_or_else(|| CheckpointError::Missing(key.to_string()))?;
+        value
+            .parse()
+            .map_err(|_| CheckpointError::Format(format!("bad value for {}: {}", key, value)))
+    }
+
+    pub fn clear_metadata(&mut self) {
+        self.metadata.clear();
+    }
+
+    pub fn write<W: Write>(&self, writer: &mut W, format: CheckpointFormat) -> Result<(), CheckpointError> {
+        match format {
+            CheckpointFormat::Native => native::write(self, writer),
+            CheckpointFormat::SafeTensors => safetensors::write(self, writer),
+            CheckpointFormat::Npz => npz::write(self, writer),
+        }
+    }
+
+    pub fn read<R: Read>(reader: &mut R, format: CheckpointFormat) -> Result<Self, CheckpointError> {
+        let mut bytes = Vec::new();
+        reader.read_to_end(&mut bytes)?;
+        match format {
+            CheckpointFormat::Native => native::read(&bytes),
+            CheckpointFormat::SafeTensors => safetensors::read(&bytes),
+            CheckpointFormat::Npz => npz::read(&bytes),
+        }
+    }
+
+    pub fn save(&self, path: impl AsRef<Path>, format: CheckpointFormat) -> Result<(), CheckpointError> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        self.write(&mut writer, format)?;
+        writer.flush()?;
+        Ok(())
+    }
+
+    pub fn load(path: impl AsRef<Path>, format: CheckpointFormat) -> Result<Self, CheckpointError> {
+        let mut file = File::open(path)?;
+        Self::read(&mut file, format)
+    }
+
+    // Uploads under `key`; the object appears only once it is complete.
+    pub fn save_to(&self, storage: &dyn Storage, key: &str, format: CheckpointFormat) -> Result<(), CheckpointError> {
+        let mut writer = BufWriter::new(StorageWriter::new(storage, key)?);
+        self.write(&mut writer, format)?;
+        writer.into_inner().map_err(|e| e.into_error())?.finish()?;
+        Ok(())
+    }
+
+    pub fn load_from(storage: &dyn Storage, key: &str, format: CheckpointFormat) -> Result<Self, CheckpointError> {
+        Self::read(&mut storage.get(key)?.as_slice(), format)
+    }
+}
+
+// Little-endian cursor shared by the format readers.
+struct ByteReader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> ByteReader<'a> {
+    fn new(buf: &'a [u8]) -> Self {
+        ByteReader { buf, pos: 0 }
+    }
+
+    fn seek(&mut self, pos: usize) {
+        self.pos = pos;
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8], CheckpointError> {
+        let end = self
+            .pos
+            .checked_add(len)
+            .filter(|&end| end <= self.buf.len())
+            .ok_or_else(|| CheckpointError::Format("unexpected end of data".to_string()))?;
+        let bytes = &self.buf[self.pos..end];
+        self.pos = end;
+        Ok(bytes)
+    }
+
+    fn remaining(&self) -> usize {
+        self.buf.len().saturating_sub(self.pos)
+    }
+
+    fn u16(&mut self) -> Result<u16, CheckpointError> {
+        Ok(u16::from_le_bytes(self.take(2)?.try_into().unwrap()))
+    }
+
+    fn u32(&mut self) -> Result<u32, CheckpointError> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> Result<u64, CheckpointError> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+}
+
+// Elements in a tensor of `shape`, checked against the `available` bytes of data before
+// anything is allocated: shapes come from the file and may be corrupt or hostile.
+fn element_count(shape: &[usize], element_bytes: usize, available: usize) -> Result<usize, CheckpointError> {
+    let len = shape
+        .iter()
+        .try_fold(1usize, |len, &dim| len.checked_mul(dim))
+        .ok_or_else(|| CheckpointError::Format(format!("shape {:?} overflows", shape)))?;
+    match len.checked_mul(element_bytes) {
+        Some(bytes) if bytes <= available => Ok(len),
+        _ => Err(CheckpointError::Format(format!("shape {:?} needs more data than the {} bytes present", shape, available))),
+    }
+}
+
+fn f32s_from_le(bytes: &[u8]) -> Vec<f32> {
+    bytes.chunks_exact(4).map(|c| f32::from_le_bytes(c.try_into().unwrap())).collect()
+}
+
+fn f64s_from_le(bytes: &[u8]) -> Vec<f32> {
+    bytes.chunks_exact(8).map(|c| f64::from_le_bytes(c.try_into().unwrap()) as f32).collect()
+}
+
+fn write_f32s<W: Write>(writer: &mut W, tensor: &ArrayD<f32>) -> io::Result<()> {
+    for &x in tensor.iter() {
+        writer.write_all(&x.to_le_bytes())?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ndarray::{arr0, Array1};
+
+    const FORMATS: [CheckpointFormat; 3] = [CheckpointFormat::Native, CheckpointFormat::SafeTensors, CheckpointFormat::Npz];
+
+    fn sample() -> Checkpoint {
+        let mut checkpoint = Checkpoint::new();
+        checkpoint.insert_tensor("layer0.weight", Array2::from_shape_fn((2, 3), |(i, j)| i as f32 - 0.5 * j as f32));
+        checkpoint.insert_tensor("layer0.bias", Array1::from(vec![1.5f32, -2.25, f32::MAX]));
+        checkpoint.insert_tensor("step", arr0(7.0f32));
+        checkpoint.insert_tensor("empty", Array2::<f32>::zeros((0, 4)));
+        checkpoint.set_metadata("galore.step", 12);
+        checkpoint.set_metadata("note \"quoted\"\n", "tab\tand \u{e9}\u{1F600}");
+        checkpoint
+    }
+
+    fn encode(checkpoint: &Checkpoint, format: CheckpointFormat) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        checkpoint.write(&mut bytes, format).unwrap();
+        bytes
+    }
+
+    fn decode(bytes: &[u8], format: CheckpointFormat) -> Result<Checkpoint, CheckpointError> {
+        Checkpoint::read(&mut &bytes[..], format)
+    }
+
+    fn replace(bytes: &mut [u8], from: &[u8], to: &[u8]) {
+        assert_eq!(from.len(), to.len());
+        let at = bytes.windows(from.len()).position(|w| w == from).expect("pattern present");
+        bytes[at..at + to.len()].copy_from_slice(to);
+    }
+
+    fn assert_format_error(result: Result<Checkpoint, CheckpointError>) {
+        match result {
+            Err(CheckpointError::Format(_)) | Err(CheckpointError::Unsupported(_)) => {}
+            other => panic!("expected a format error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn round_trips_every_format() {
+        let checkpoint = sample();
+        for format in FORMATS {
+            let restored = decode(&encode(&checkpoint, format), format).unwrap();
+            if format.supports_metadata() {
+                assert_eq!(restored, checkpoint, "{:?}", format);
+            } else {
+                let mut tensors_only = checkpoint.clone();
+                tensors_only.clear_metadata();
+                assert_eq!(restored, tensors_only, "{:?}", format);
+            }
+        }
+    }
+
+    #[test]
+    fn rejects_every_truncation() {
+        let checkpoint = sample();
+        for format in FORMATS {
+            let bytes = encode(&checkpoint, format);
+            for len in 0..bytes.len() {
+                assert!(decode(&bytes[..len], format).is_err(), "{:?} accepted {} of {} bytes", format, len, bytes.len());
+            }
+        }
+    }
+
+    #[test]
+    fn native_rejects_bad_magic_and_oversized_shapes() {
+        let mut single = Checkpoint::new();
+        single.insert_tensor("w", Array2::<f32>::ones((2, 3)));
+        let bytes = encode(&single, CheckpointFormat::Native);
+
+        let mut bad_magic = bytes.clone();
+        bad_magic[0] = b'X';
+        assert_format_error(decode(&bad_magic, CheckpointFormat::Native));
+
+        // magic, version, no metadata, one tensor, name "w", rank 2, then the dims.
+        let first_dim = 8 + 4 + 8 + 8 + 8 + 1 + 4;
+        for dim in [u64::MAX, 1 << 40, 3] {
+            let mut corrupt = bytes.clone();
+            corrupt[first_dim..first_dim + 8].copy_from_slice(&dim.to_le_bytes());
+            assert_format_error(decode(&corrupt, CheckpointFormat::Native));
+        }
+    }
+
+    fn safetensors(header: &str, data: &[u8]) -> Vec<u8> {
+        let mut bytes = (header.len() as u64).to_le_bytes().to_vec();
+        bytes.extend_from_slice(header.as_bytes());
+        bytes.extend_from_slice(data);
+        bytes
+    }
+
+    #[test]
+    fn safetensors_rejects_corrupt_headers() {
+        let data = [0u8; 24];
+        let cases = [
+            r#"{"w":{"dtype":"F32","shape":[2,3],"data_offsets":[0,24]}"#,
+            r#"{"w":{"dtype":"F32","shape":[3,3],"data_offsets":[0,24]}}"#,
+            r#"{"w":{"dtype":"F32","shape":[2,3],"data_offsets":[0,48]}}"#,
+            r#"{"w":{"dtype":"F32","shape":[4294967296,4294967296],"data_offsets":[0,24]}}"#,
+            r#"{"w":{"dtype":"I8","shape":[24],"data_offsets":[0,24]}}"#,
+            r#"{"w":{"dtype":"F32","shape":[-1],"data_offsets":[0,24]}}"#,
+            r#"{"w":{"dtype":"F32","data_offsets":[0,24]}}"#,
+            r#"["w"]"#,
+            r#"{"w":"\ud800"#,
+        ];
+        for header in cases {
+            assert_format_error(decode(&safetensors(header, &data), CheckpointFormat::SafeTensors));
+        }
+
+        let deep = format!("{{\"w\":{}{}}}", "[".repeat(100_000), "]".repeat(100_000));
+        assert_format_error(decode(&safetensors(&deep, &data), CheckpointFormat::SafeTensors));
+
+        let mut huge_header = safetensors("{}", &data);
+        huge_header[..8].copy_from_slice(&u64::MAX.to_le_bytes());
+        assert_format_error(decode(&huge_header, CheckpointFormat::SafeTensors));
+
+        let valid = r#"{"w":{"dtype":"F32","shape":[2,3],"data_offsets":[0,24]}}"#;
+        assert_eq!(decode(&safetensors(valid, &data), CheckpointFormat::SafeTensors).unwrap().matrix("w").unwrap(), Array2::zeros((2, 3)));
+    }
+
+    #[test]
+    fn npz_rejects_compressed_entries_and_oversized_shapes() {
+        let mut single = Checkpoint::new();
+        single.insert_tensor("w", Array2::<f32>::ones((2, 3)));
+        let bytes = encode(&single, CheckpointFormat::Npz);
+
+        let mut compressed = bytes.clone();
+        let central = compressed.windows(4).position(|w| w == [0x50, 0x4b, 0x01, 0x02]).unwrap();
+        compressed[central + 10] = 8; // deflate
+        assert_format_error(decode(&compressed, CheckpointFormat::Npz));
+
+        for shape in [&b"(9, 3)"[..], b"(9,99)", b"(x, 3)"] {
+            let mut corrupt = bytes.clone();
+            replace(&mut corrupt, b"(2, 3)", shape);
+            assert_format_error(decode(&corrupt, CheckpointFormat::Npz));
+        }
+
+        let mut not_npy = bytes.clone();
+        replace(&mut not_npy, b"\x93NUMPY", b"\x93NUMPX");
+        assert_format_error(decode(&not_npy, CheckpointFormat::Npz));
+
+        assert_format_error(decode(b"PK\x03\x04 not really a zip", CheckpointFormat::Npz));
+    }
+}
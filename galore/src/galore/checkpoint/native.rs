@@ -0,0 +1,81 @@
+use ndarray::{ArrayD, IxDyn};
+use std::io::Write;
+
+use super::{element_count, f32s_from_le, write_f32s, ByteReader, Checkpoint, CheckpointError};
+
+// Layout: magic, version, metadata entries, then tensors as name, rank, dims and
+// row-major little-endian f32 data. Strings and counts are u64-length prefixed.
+const MAGIC: &[u8; 8] = b"GALORECK";
+const VERSION: u32 = 1;
+
+pub(super) fn write<W: Write>(checkpoint: &Checkpoint, writer: &mut W) -> Result<(), CheckpointError> {
+    writer.write_all(MAGIC)?;
+    writer.write_all(&VERSION.to_le_bytes())?;
+
+    writer.write_all(&(checkpoint.metadata.len() as u64).to_le_bytes())?;
+    for (key, value) in &checkpoint.metadata {
+        write_str(writer, key)?;
+        write_str(writer, value)?;
+    }
+
+    writer.write_all(&(checkpoint.tensors.len() as u64).to_le_bytes())?;
+    for (name, tensor) in &checkpoint.tensors {
+        write_str(writer, name)?;
+        writer.write_all(&(tensor.ndim() as u32).to_le_bytes())?;
+        for &dim in tensor.shape() {
+            writer.write_all(&(dim as u64).to_le_bytes())?;
+        }
+        write_f32s(writer, tensor)?;
+    }
+    Ok(())
+}
+
+pub(super) fn read(bytes: &[u8]) -> Result<Checkpoint, CheckpointError> {
+    let mut reader = ByteReader::new(bytes);
+    if reader.take(MAGIC.len())? != MAGIC {
+        return Err(CheckpointError::Format("not a galore checkpoint".to_string()));
+    }
+    let version = reader.u32()?;
+    if version != VERSION {
+        return Err(CheckpointError::Unsupported(format!("checkpoint version {}", version)));
+    }
+
+    let mut checkpoint = Checkpoint::new();
+    for _ in 0..reader.u64()? {
+        let key = read_str(&mut reader)?;
+        let value = read_str(&mut reader)?;
+        checkpoint.metadata.insert(key, value);
+    }
+
+    for _ in 0..reader.u64()? {
+        let name = read_str(&mut reader)?;
+        let ndim = reader.u32()? as usize;
+        let shape = (0..ndim)
+            .map(|_| reader.u64().map(|d| usize::try_from(d).unwrap_or(usize::MAX)))
+            .collect::<Result<Vec<_>, _>>()?;
+        let len = element_count(&shape, 4, reader.remaining()).map_err(|e| prefixed(&name, e))?;
+        let data = f32s_from_le(reader.take(len * 4)?);
+        let tensor = ArrayD::from_shape_vec(IxDyn(&shape), data)
+            .map_err(|e| CheckpointError::Format(format!("{}: {}", name, e)))?;
+        checkpoint.tensors.insert(name, tensor);
+    }
+    Ok(checkpoint)
+}
+
+fn prefixed(name: &str, error: CheckpointError) -> CheckpointError {
+    match error {
+        CheckpointError::Format(msg) => CheckpointError::Format(format!("{}: {}", name, msg)),
+        other => other,
+    }
+}
+
+fn write_str<W: Write>(writer: &mut W, s: &str) -> Result<(), CheckpointError> {
+    writer.write_all(&(s.len() as u64).to_le_bytes())?;
+    writer.write_all(s.as_bytes())?;
+    Ok(())
+}
+
+fn read_str(reader: &mut ByteReader) -> Result<String, CheckpointError> {
+    let len = reader.u64()? as usize;
+    String::from_utf8(reader.take(len)?.to_vec()).map_err(|_| CheckpointError::Format("invalid UTF-8 string".to_string()))
+}
@@ -0,0 +1,234 @@
+use ndarray::{ArrayD, IxDyn, ShapeBuilder};
+use std::io::Write;
+
+use super::{element_count, f32s_from_le, f64s_from_le, write_f32s, ByteReader, Checkpoint, CheckpointError};
+
+// An .npz is a zip archive of .npy files, one per tensor. Entries are written uncompressed
+// (as np.savez does); deflated archives from np.savez_compressed are rejected.
+
+const LOCAL_HEADER: u32 = 0x0403_4b50;
+const CENTRAL_HEADER: u32 = 0x0201_4b50;
+const END_OF_CENTRAL_DIR: u32 = 0x0605_4b50;
+// 1980-01-01, the earliest date a zip entry can carry.
+const DOS_DATE: u16 = 0x21;
+
+pub(super) fn write<W: Write>(checkpoint: &Checkpoint, writer: &mut W) -> Result<(), CheckpointError> {
+    let mut central = Vec::new();
+    let mut offset: u64 = 0;
+    let count = checkpoint.tensors.len();
+    if count > u16::MAX as usize {
+        return Err(CheckpointError::Unsupported(format!("{} tensors exceed the zip entry limit", count)));
+    }
+
+    for (name, tensor) in &checkpoint.tensors {
+        let mut npy = npy_header(tensor.shape());
+        write_f32s(&mut npy, tensor)?;
+        let file_name = format!("{}.npy", name);
+        let crc = crc32(&npy);
+        let size = u32::try_from(npy.len())
+            .map_err(|_| CheckpointError::Unsupported(format!("{} is larger than 4 GiB", name)))?;
+        let local_offset = u32::try_from(offset)
+            .map_err(|_| CheckpointError::Unsupported("archive larger than 4 GiB".to_string()))?;
+
+        let mut local = Vec::with_capacity(30 + file_name.len());
+        local.extend_from_slice(&LOCAL_HEADER.to_le_bytes());
+        local.extend_from_slice(&20u16.to_le_bytes()); // version needed
+        local.extend_from_slice(&0u16.to_le_bytes()); // flags
+        local.extend_from_slice(&0u16.to_le_bytes()); // stored
+        local.extend_from_slice(&0u16.to_le_bytes()); // time
+        local.extend_from_slice(&DOS_DATE.to_le_bytes());
+        local.extend_from_slice(&crc.to_le_bytes());
+        local.extend_from_slice(&size.to_le_bytes());
+        local.extend_from_slice(&size.to_le_bytes());
+        local.extend_from_slice(&(file_name.len() as u16).to_le_bytes());
+        local.extend_from_slice(&0u16.to_le_bytes()); // extra length
+        local.extend_from_slice(file_name.as_bytes());
+        writer.write_all(&local)?;
+        writer.write_all(&npy)?;
+
+        central.extend_from_slice(&CENTRAL_HEADER.to_le_bytes());
+        central.extend_from_slice(&20u16.to_le_bytes()); // version made by
+        central.extend_from_slice(&20u16.to_le_bytes()); // version needed
+        central.extend_from_slice(&0u16.to_le_bytes());
+        central.extend_from_slice(&0u16.to_le_bytes());
+        central.extend_from_slice(&0u16.to_le_bytes());
+        central.extend_from_slice(&DOS_DATE.to_le_bytes());
+        central.extend_from_slice(&crc.to_le_bytes());
+        central.extend_from_slice(&size.to_le_bytes());
+        central.extend_from_slice(&size.to_le_bytes());
+        central.extend_from_slice(&(file_name.len() as u16).to_le_bytes());
+        central.extend_from_slice(&[0u8; 12]); // extra, comment, disk, internal and external attributes
+        central.extend_from_slice(&local_offset.to_le_bytes());
+        central.extend_from_slice(file_name.as_bytes());
+
+        offset += (local.len() + npy.len()) as u64;
+    }
+
+    let central_offset = u32::try_from(offset)
+        .map_err(|_| CheckpointError::Unsupported("archive larger than 4 GiB".to_string()))?;
+    writer.write_all(&central)?;
+    let mut end = Vec::with_capacity(22);
+    end.extend_from_slice(&END_OF_CENTRAL_DIR.to_le_bytes());
+    end.extend_from_slice(&[0u8; 4]); // disk numbers
+    end.extend_from_slice(&(count as u16).to_le_bytes());
+    end.extend_from_slice(&(count as u16).to_le_bytes());
+    end.extend_from_slice(&(central.len() as u32).to_le_bytes());
+    end.extend_from_slice(&central_offset.to_le_bytes());
+    end.extend_from_slice(&0u16.to_le_bytes()); // comment length
+    writer.write_all(&end)?;
+    Ok(())
+}
+
+pub(super) fn read(bytes: &[u8]) -> Result<Checkpoint, CheckpointError> {
+    let end = find_end_of_central_dir(bytes)?;
+    let mut reader = ByteReader::new(bytes);
+    reader.seek(end + 10);
+    let count = reader.u16()?;
+    reader.u32()?; // central directory size
+    let central_offset = reader.u32()? as usize;
+
+    let mut checkpoint = Checkpoint::new();
+    reader.seek(central_offset);
+    for _ in 0..count {
+        if reader.u32()? != CENTRAL_HEADER {
+            return Err(CheckpointError::Format("bad zip central directory".to_string()));
+        }
+        reader.take(6)?; // versions, flags
+        let method = reader.u16()?;
+        reader.take(8)?; // time, date, crc
+        let compressed_size = reader.u32()? as usize;
+        reader.u32()?; // uncompressed size
+        let name_len = reader.u16()? as usize;
+        let extra_len = reader.u16()? as usize;
+        let comment_len = reader.u16()? as usize;
+        reader.take(8)?; // disk, attributes
+        let local_offset = reader.u32()? as usize;
+        let file_name = String::from_utf8_lossy(reader.take(name_len)?).into_owned();
+        reader.take(extra_len + comment_len)?;
+
+        if method != 0 {
+            return Err(CheckpointError::Unsupported(format!("{} is compressed (np.savez_compressed)", file_name)));
+        }
+
+        let mut local = ByteReader::new(bytes);
+        local.seek(local_offset);
+        if local.u32()? != LOCAL_HEADER {
+            return Err(CheckpointError::Format(format!("{}: bad local header", file_name)));
+        }
+        local.take(22)?;
+        let local_name_len = local.u16()? as usize;
+        let local_extra_len = local.u16()? as usize;
+        local.take(local_name_len + local_extra_len)?;
+        let npy = local.take(compressed_size)?;
+
+        let name = file_name.strip_suffix(".npy").unwrap_or(&file_name).to_string();
+        let tensor = parse_npy(npy).map_err(|e| match e {
+            CheckpointError::Format(msg) => CheckpointError::Format(format!("{}: {}", name, msg)),
+            CheckpointError::Unsupported(msg) => CheckpointError::Unsupported(format!("{}: {}", name, msg)),
+            other => other,
+        })?;
+        checkpoint.tensors.insert(name, tensor);
+    }
+    Ok(checkpoint)
+}
+
+fn find_end_of_central_dir(bytes: &[u8]) -> Result<usize, CheckpointError> {
+    let signature = END_OF_CENTRAL_DIR.to_le_bytes();
+    (0..bytes.len().saturating_sub(21))
+        .rev()
+        .find(|&i| bytes[i..i + 4] == signature)
+        .ok_or_else(|| CheckpointError::Format("not a zip archive".to_string()))
+}
+
+fn npy_header(shape: &[usize]) -> Vec<u8> {
+    let dims = match shape {
+        [single] => format!("{},", single),
+        _ => shape.iter().map(|d| d.to_string()).collect::<Vec<_>>().join(", "),
+    };
+    let mut header = format!("{{'descr': '<f4', 'fortran_order': False, 'shape': ({}), }}", dims);
+    // Magic (6) + version (2) + length (2) + header must be a multiple of 64, ending in '\n'.
+    while (10 + header.len() + 1) % 64 != 0 {
+        header.push(' ');
+    }
+    header.push('\n');
+
+    let mut out = Vec::with_capacity(10 + header.len());
+    out.extend_from_slice(b"\x93NUMPY");
+    out.extend_from_slice(&[1, 0]);
+    out.extend_from_slice(&(header.len() as u16).to_le_bytes());
+    out.extend_from_slice(header.as_bytes());
+    out
+}
+
+fn parse_npy(bytes: &[u8]) -> Result<ArrayD<f32>, CheckpointError> {
+    let mut reader = ByteReader::new(bytes);
+    if reader.take(6)? != b"\x93NUMPY" {
+        return Err(CheckpointError::Format("not an .npy file".to_string()));
+    }
+    let major = reader.take(2)?[0];
+    let header_len = if major == 1 { reader.u16()? as usize } else { reader.u32()? as usize };
+    let header = String::from_utf8_lossy(reader.take(header_len)?).into_owned();
+    let data = &bytes[reader.pos..];
+
+    let descr = header_value(&header, "descr").ok_or_else(|| CheckpointError::Format("missing descr".to_string()))?;
+    let fortran_order = header_value(&header, "fortran_order").is_some_and(|v| v.starts_with("True"));
+    let shape_text = header_value(&header, "shape").ok_or_else(|| CheckpointError::Format("missing shape".to_string()))?;
+    let shape = shape_text
+        .trim_start_matches('(')
+        .split(')')
+        .next()
+        .unwrap_or("")
+        .split(',')
+        .map(|d| d.trim())
+        .filter(|d| !d.is_empty())
+        .map(|d| d.parse::<usize>().map_err(|_| CheckpointError::Format(format!("bad shape {}", shape_text))))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let descr = descr
+        .strip_prefix(|c| c == '\'' || c == '"')
+        .and_then(|rest| rest.split(['\'', '"']).next())
+        .unwrap_or(descr);
+    let element_bytes = match descr {
+        "<f4" | "|f4" => 4,
+        "<f8" | "|f8" => 8,
+        other => return Err(CheckpointError::Unsupported(format!("dtype {}", other))),
+    };
+    let len = element_count(&shape, element_bytes, data.len())?;
+    let data = &data[..len * element_bytes];
+    let values = if element_bytes == 4 { f32s_from_le(data) } else { f64s_from_le(data) };
+
+    let tensor = if fortran_order {
+        ArrayD::from_shape_vec(IxDyn(&shape).f(), values).map(|a| a.as_standard_layout().into_owned())
+    } else {
+        ArrayD::from_shape_vec(IxDyn(&shape), values)
+    };
+    tensor.map_err(|e| CheckpointError::Format(e.to_string()))
+}
+
+// Returns the text after `'key':` in a numpy header dict.
+fn header_value<'h>(header: &'h str, key: &str) -> Option<&'h str> {
+    let pattern = format!("'{}':", key);
+    header.find(&pattern).map(|i| header[i + pattern.len()..].trim_start())
+}
+
+const CRC_TABLE: [u32; 256] = {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 != 0 { 0xEDB8_8320 ^ (crc >> 1) } else { crc >> 1 };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+};
+
+fn crc32(bytes: &[u8]) -> u32 {
+    !bytes
+        .iter()
+        .fold(!0u32, |crc, &b| CRC_TABLE[((crc ^ b as u32) & 0xFF) as usize] ^ (crc >> 8))
+}
@@ -0,0 +1,345 @@
+use ndarray::{ArrayD, IxDyn};
+use std::io::Write;
+
+use super::{element_count, f32s_from_le, f64s_from_le, write_f32s, ByteReader, Checkpoint, CheckpointError};
+
+// https://github.com/huggingface/safetensors: u64 header length, JSON header, raw data.
+// Tensors are written as F32; F64 tensors are narrowed to f32 on read.
+
+pub(super) fn write<W: Write>(checkpoint: &Checkpoint, writer: &mut W) -> Result<(), CheckpointError> {
+    let mut header = String::from("{");
+    let mut entries = Vec::new();
+    if !checkpoint.metadata.is_empty() {
+        let fields: Vec<String> = checkpoint
+            .metadata
+            .iter()
+            .map(|(k, v)| format!("{}:{}", json_string(k), json_string(v)))
+            .collect();
+        entries.push(format!("\"__metadata__\":{{{}}}", fields.join(",")));
+    }
+    let mut offset = 0;
+    for (name, tensor) in &checkpoint.tensors {
+        let len = tensor.len() * 4;
+        let shape: Vec<String> = tensor.shape().iter().map(|d| d.to_string()).collect();
+        entries.push(format!(
+            "{}:{{\"dtype\":\"F32\",\"shape\":[{}],\"data_offsets\":[{},{}]}}",
+            json_string(name),
+            shape.join(","),
+            offset,
+            offset + len
+        ));
+        offset += len;
+    }
+    header.push_str(&entries.join(","));
+    header.push('}');
+    // The data section is expected to start 8-byte aligned.
+    while (8 + header.len()) % 8 != 0 {
+        header.push(' ');
+    }
+
+    writer.write_all(&(header.len() as u64).to_le_bytes())?;
+    writer.write_all(header.as_bytes())?;
+    for tensor in checkpoint.tensors.values() {
+        write_f32s(writer, tensor)?;
+    }
+    Ok(())
+}
+
+pub(super) fn read(bytes: &[u8]) -> Result<Checkpoint, CheckpointError> {
+    let mut reader = ByteReader::new(bytes);
+    let header_len = reader.u64()? as usize;
+    let header = std::str::from_utf8(reader.take(header_len)?)
+        .map_err(|_| CheckpointError::Format("safetensors header is not UTF-8".to_string()))?;
+    let data = &bytes[8 + header_len..];
+
+    let entries = match Json::parse(header)? {
+        Json::Object(entries) => entries,
+        _ => return Err(CheckpointError::Format("safetensors header is not an object".to_string())),
+    };
+
+    let mut checkpoint = Checkpoint::new();
+    for (name, entry) in entries {
+        if name == "__metadata__" {
+            for (key, value) in entry.as_object()? {
+                checkpoint.metadata.insert(key.clone(), value.as_str()?.to_string());
+            }
+            continue;
+        }
+
+        let dtype = entry.field("dtype")?.as_str()?;
+        let shape = entry
+            .field("shape")?
+            .as_array()?
+            .iter()
+            .map(|d| d.as_usize())
+            .collect::<Result<Vec<_>, _>>()?;
+        let offsets = entry.field("data_offsets")?.as_array()?;
+        let (begin, end) = match offsets {
+            [begin, end] => (begin.as_usize()?, end.as_usize()?),
+            _ => return Err(CheckpointError::Format(format!("{}: bad data_offsets", name))),
+        };
+        let raw = data
+            .get(begin..end)
+            .ok_or_else(|| CheckpointError::Format(format!("{}: data out of range", name)))?;
+
+        let element_bytes = match dtype {
+            "F32" => 4,
+            "F64" => 8,
+            other => return Err(CheckpointError::Unsupported(format!("{}: dtype {}", name, other))),
+        };
+        let len = element_count(&shape, element_bytes, raw.len())?;
+        if len * element_bytes != raw.len() {
+            return Err(CheckpointError::Format(format!("{}: {} bytes of data for shape {:?}", name, raw.len(), shape)));
+        }
+        let values = if element_bytes == 4 { f32s_from_le(raw) } else { f64s_from_le(raw) };
+        let tensor = ArrayD::from_shape_vec(IxDyn(&shape), values)
+            .map_err(|e| CheckpointError::Format(format!("{}: {}", name, e)))?;
+        checkpoint.tensors.insert(name, tensor);
+    }
+    Ok(checkpoint)
+}
+
+fn json_string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+// Just enough JSON for safetensors headers.
+#[derive(Debug)]
+enum Json {
+    Null,
+    // Only read through Debug, in error messages.
+    Bool(#[allow(dead_code)] bool),
+    Number(f64),
+    String(String),
+    Array(Vec<Json>),
+    Object(Vec<(String, Json)>),
+}
+
+impl Json {
+    fn parse(text: &str) -> Result<Json, CheckpointError> {
+        let mut parser = JsonParser { chars: text.chars().collect(), pos: 0, depth: 0 };
+        let value = parser.value()?;
+        parser.skip_whitespace();
+        if parser.pos != parser.chars.len() {
+            return Err(parser.error("trailing characters"));
+        }
+        Ok(value)
+    }
+
+    fn field(&self, key: &str) -> Result<&Json, CheckpointError> {
+        self.as_object()?
+            .iter()
+            .find(|(k, _)| k == key)
+            .map(|(_, v)| v)
+            .ok_or_else(|| CheckpointError::Format(format!("missing field {}", key)))
+    }
+
+    fn as_object(&self) -> Result<&[(String, Json)], CheckpointError> {
+        match self {
+            Json::Object(entries) => Ok(entries),
+            other => Err(CheckpointError::Format(format!("expected object, found {:?}", other))),
+        }
+    }
+
+    fn as_array(&self) -> Result<&[Json], CheckpointError> {
+        match self {
+            Json::Array(items) => Ok(items),
+            other => Err(CheckpointError::Format(format!("expected array, found {:?}", other))),
+        }
+    }
+
+    fn as_str(&self) -> Result<&str, CheckpointError> {
+        match self {
+            Json::String(s) => Ok(s),
+            other => Err(CheckpointError::Format(format!("expected string, found {:?}", other))),
+        }
+    }
+
+    fn as_usize(&self) -> Result<usize, CheckpointError> {
+        match self {
+            Json::Number(n) if *n >= 0.0 && n.fract() == 0.0 => Ok(*n as usize),
+            other => Err(CheckpointError::Format(format!("expected integer, found {:?}", other))),
+        }
+    }
+}
+
+// Nesting beyond this is rejected rather than recursed into; real headers use three levels.
+const MAX_DEPTH: usize = 64;
+
+struct JsonParser {
+    chars: Vec<char>,
+    pos: usize,
+    depth: usize,
+}
+
+impl JsonParser {
+    fn error(&self, msg: &str) -> CheckpointError {
+        CheckpointError::Format(format!("JSON header: {} at offset {}", msg, self.pos))
+    }
+
+    fn skip_whitespace(&mut self) {
+        while self.chars.get(self.pos).is_some_and(|c| c.is_whitespace()) {
+            self.pos += 1;
+        }
+    }
+
+    fn peek(&mut self) -> Option<char> {
+        self.skip_whitespace();
+        self.chars.get(self.pos).copied()
+    }
+
+    fn expect(&mut self, c: char) -> Result<(), CheckpointError> {
+        if self.peek() == Some(c) {
+            self.pos += 1;
+            Ok(())
+        } else {
+            Err(self.error(&format!("expected '{}'", c)))
+        }
+    }
+
+    fn literal(&mut self, word: &str, value: Json) -> Result<Json, CheckpointError> {
+        let end = self.pos + word.len();
+        if end <= self.chars.len() && self.chars[self.pos..end].iter().copied().eq(word.chars()) {
+            self.pos = end;
+            Ok(value)
+        } else {
+            Err(self.error("invalid literal"))
+        }
+    }
+
+    fn value(&mut self) -> Result<Json, CheckpointError> {
+        if self.depth >= MAX_DEPTH {
+            return Err(self.error("nested too deeply"));
+        }
+        self.depth += 1;
+        let value = self.value_inner();
+        self.depth -= 1;
+        value
+    }
+
+    fn value_inner(&mut self) -> Result<Json, CheckpointError> {
+        match self.peek() {
+            Some('{') => self.object(),
+            Some('[') => self.array(),
+            Some('"') => self.string().map(Json::String),
+            Some('t') => self.literal("true", Json::Bool(true)),
+            Some('f') => self.literal("false", Json::Bool(false)),
+            Some('n') => self.literal("null", Json::Null),
+            Some(c) if c == '-' || c.is_ascii_digit() => self.number(),
+            _ => Err(self.error("unexpected character")),
+        }
+    }
+
+    fn object(&mut self) -> Result<Json, CheckpointError> {
+        self.expect('{')?;
+        let mut entries = Vec::new();
+        if self.peek() == Some('}') {
+            self.pos += 1;
+            return Ok(Json::Object(entries));
+        }
+        loop {
+            if self.peek() != Some('"') {
+                return Err(self.error("expected key"));
+            }
+            let key = self.string()?;
+            self.expect(':')?;
+            entries.push((key, self.value()?));
+            match self.peek() {
+                Some(',') => self.pos += 1,
+                Some('}') => {
+                    self.pos += 1;
+                    return Ok(Json::Object(entries));
+                }
+                _ => return Err(self.error("expected ',' or '}'")),
+            }
+        }
+    }
+
+    fn array(&mut self) -> Result<Json, CheckpointError> {
+        self.expect('[')?;
+        let mut items = Vec::new();
+        if self.peek() == Some(']') {
+            self.pos += 1;
+            return Ok(Json::Array(items));
+        }
+        loop {
+            items.push(self.value()?);
+            match self.peek() {
+                Some(',') => self.pos += 1,
+                Some(']') => {
+                    self.pos += 1;
+                    return Ok(Json::Array(items));
+                }
+                _ => return Err(self.error("expected ',' or ']'")),
+            }
+        }
+    }
+
+    fn string(&mut self) -> Result<String, CheckpointError> {
+        self.expect('"')?;
+        let mut out = String::new();
+        loop {
+            let c = *self.chars.get(self.pos).ok_or_else(|| self.error("unterminated string"))?;
+            self.pos += 1;
+            match c {
+                '"' => return Ok(out),
+                '\\' => {
+                    let escaped = *self.chars.get(self.pos).ok_or_else(|| self.error("unterminated escape"))?;
+                    self.pos += 1;
+                    match escaped {
+                        '"' | '\\' | '/' => out.push(escaped),
+                        'b' => out.push('\u{8}'),
+                        'f' => out.push('\u{c}'),
+                        'n' => out.push('\n'),
+                        'r' => out.push('\r'),
+                        't' => out.push('\t'),
+                        'u' => {
+                            let mut code = self.hex4()?;
+                            if (0xD800..0xDC00).contains(&code) && self.chars.get(self.pos..self.pos + 2) == Some(&['\\', 'u']) {
+                                self.pos += 2;
+                                let low = self.hex4()?;
+                                code = 0x10000 + ((code - 0xD800) << 10) + (low.wrapping_sub(0xDC00) & 0x3FF);
+                            }
+                            out.push(char::from_u32(code).unwrap_or('\u{FFFD}'));
+                        }
+                        _ => return Err(self.error("invalid escape")),
+                    }
+                }
+                c => out.push(c),
+            }
+        }
+    }
+
+    fn hex4(&mut self) -> Result<u32, CheckpointError> {
+        let digits: String = self.chars.get(self.pos..self.pos + 4).ok_or_else(|| self.error("short \\u escape"))?.iter().collect();
+        self.pos += 4;
+        u32::from_str_radix(&digits, 16).map_err(|_| self.error("invalid \\u escape"))
+    }
+
+    fn number(&mut self) -> Result<Json, CheckpointError> {
+        let start = self.pos;
+        while self
+            .chars
+            .get(self.pos)
+            .is_some_and(|c| c.is_ascii_digit() || matches!(c, '-' | '+' | '.' | 'e' | 'E'))
+        {
+            self.pos += 1;
+        }
+        let text: String = self.chars[start..self.pos].iter().collect();
+        text.parse().map(Json::Number).map_err(|_| self.error("invalid number"))
+    }
+}
@@ -0,0 +1,438 @@
+use std::fs::{self, File};
+use std::io::{self, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::Duration;
+
+// Every part but the last is at least this large (S3 needs 5 MiB).
+pub const DEFAULT_PART_BYTES: usize = 8 << 20;
+
+// Where checkpoints live, addressed by '/'-separated keys. Writes go through an `Upload`
+// that receives the object in parts and only makes it visible on `complete`, so a reader
+// never sees a half-written checkpoint; this is the shape of an object-store multipart
+// upload, which `object_store::ObjectStorage` (the "object-store" feature) maps onto
+// directly.
+pub trait Storage: Send + Sync {
+    fn get(&self, key: &str) -> io::Result<Vec<u8>>;
+
+    fn exists(&self, key: &str) -> io::Result<bool>;
+
+    fn delete(&self, key: &str) -> io::Result<()>;
+
+    fn begin_upload(&self, key: &str) -> io::Result<Box<dyn Upload>>;
+
+    // The size `StorageWriter` buffers before sending a part.
+    fn part_bytes(&self) -> usize {
+        DEFAULT_PART_BYTES
+    }
+}
+
+pub trait Upload: Send {
+    // Parts are numbered from 0 in the order they are written.
+    fn write_part(&mut self, part: usize, data: &[u8]) -> io::Result<()>;
+
+    // Makes the object visible. A failed completion leaves the upload open, so it can be
+    // tried again or aborted.
+    fn complete(&mut self) -> io::Result<()>;
+
+    // Discards the parts written so far; the previous object, if any, is untouched.
+    fn abort(self: Box<Self>) -> io::Result<()>;
+}
+
+// Files under `root`. Uploads go to "<key>.partial" and are renamed over the key on
+// completion, so a process killed mid-save keeps the previous checkpoint.
+#[derive(Clone, Debug)]
+pub struct LocalStorage {
+    root: PathBuf,
+}
+
+impl LocalStorage {
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        LocalStorage { root: root.into() }
+    }
+
+    pub fn path(&self, key: &str) -> PathBuf {
+        self.root.join(key)
+    }
+}
+
+impl Storage for LocalStorage {
+    fn get(&self, key: &str) -> io::Result<Vec<u8>> {
+        fs::read(self.path(key))
+    }
+
+    fn exists(&self, key: &str) -> io::Result<bool> {
+        self.path(key).try_exists()
+    }
+
+    fn delete(&self, key: &str) -> io::Result<()> {
+        match fs::remove_file(self.path(key)) {
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+            other => other,
+        }
+    }
+
+    fn begin_upload(&self, key: &str) -> io::Result<Box<dyn Upload>> {
+        let path = self.path(key);
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let partial = partial_path(&path);
+        let file = File::create(&partial)?;
+        Ok(Box::new(LocalUpload { path, partial, file, next_part: 0, end: 0 }))
+    }
+}
+
+struct LocalUpload {
+    path: PathBuf,
+    partial: PathBuf,
+    file: File,
+    next_part: usize,
+    // Length of the parts written so far.
+    end: u64,
+}
+
+impl Upload for LocalUpload {
+    fn write_part(&mut self, part: usize, data: &[u8]) -> io::Result<()> {
+        if part != self.next_part {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("expected part {}, got {}", self.next_part, part)));
+        }
+        // A retry after a failed write starts the part over.
+        self.file.seek(SeekFrom::Start(self.end))?;
+        self.file.write_all(data)?;
+        self.end += data.len() as u64;
+        self.next_part += 1;
+        Ok(())
+    }
+
+    fn complete(&mut self) -> io::Result<()> {
+        self.file.set_len(self.end)?;
+        self.file.sync_all()?;
+        fs::rename(&self.partial, &self.path)
+    }
+
+    fn abort(self: Box<Self>) -> io::Result<()> {
+        fs::remove_file(&self.partial)
+    }
+}
+
+// Retries the operations of another backend on transient errors (timeouts, dropped
+// connections, interrupted calls), waiting `backoff`, then twice as long, and so on, up to
+// `attempts` tries in all. Upload parts are retried individually, so a failure late in a
+// large checkpoint does not resend what already arrived, and so is the completion, so a
+// transient error at the end does not throw the uploaded parts away.
+pub struct Retrying<S: Storage> {
+    inner: S,
+    attempts: usize,
+    backoff: Duration,
+}
+
+impl<S: Storage> Retrying<S> {
+    pub fn new(inner: S) -> Self {
+        Retrying { inner, attempts: 5, backoff: Duration::from_millis(200) }
+    }
+
+    pub fn with_attempts(mut self, attempts: usize) -> Self {
+        assert!(attempts > 0, "need at least one attempt");
+        self.attempts = attempts;
+        self
+    }
+
+    pub fn with_backoff(mut self, backoff: Duration) -> Self {
+        self.backoff = backoff;
+        self
+    }
+
+    pub fn inner(&self) -> &S {
+        &self.inner
+    }
+}
+
+fn retry<T>(attempts: usize, backoff: Duration, mut op: impl FnMut() -> io::Result<T>) -> io::Result<T> {
+    let mut wait = backoff;
+    for _ in 1..attempts {
+        match op() {
+            Err(e) if is_transient(&e) => {
+                thread::sleep(wait);
+                wait *= 2;
+            }
+            result => return result,
+        }
+    }
+    op()
+}
+
+fn is_transient(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        io::ErrorKind::Interrupted
+            | io::ErrorKind::TimedOut
+            | io::ErrorKind::WouldBlock
+            | io::ErrorKind::ConnectionReset
+            | io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::ConnectionRefused
+            | io::ErrorKind::NotConnected
+            | io::ErrorKind::BrokenPipe
+    )
+}
+
+impl<S: Storage> Storage for Retrying<S> {
+    fn get(&self, key: &str) -> io::Result<Vec<u8>> {
+        retry(self.attempts, self.backoff, || self.inner.get(key))
+    }
+
+    fn exists(&self, key: &str) -> io::Result<bool> {
+        retry(self.attempts, self.backoff, || self.inner.exists(key))
+    }
+
+    fn delete(&self, key: &str) -> io::Result<()> {
+        retry(self.attempts, self.backoff, || self.inner.delete(key))
+    }
+
+    fn begin_upload(&self, key: &str) -> io::Result<Box<dyn Upload>> {
+        let inner = retry(self.attempts, self.backoff, || self.inner.begin_upload(key))?;
+        Ok(Box::new(RetryingUpload { inner, attempts: self.attempts, backoff: self.backoff }))
+    }
+
+    fn part_bytes(&self) -> usize {
+        self.inner.part_bytes()
+    }
+}
+
+struct RetryingUpload {
+    inner: Box<dyn Upload>,
+    attempts: usize,
+    backoff: Duration,
+}
+
+impl Upload for RetryingUpload {
+    fn write_part(&mut self, part: usize, data: &[u8]) -> io::Result<()> {
+        retry(self.attempts, self.backoff, || self.inner.write_part(part, data))
+    }
+
+    fn complete(&mut self) -> io::Result<()> {
+        retry(self.attempts, self.backoff, || self.inner.complete())
+    }
+
+    fn abort(self: Box<Self>) -> io::Result<()> {
+        self.inner.abort()
+    }
+}
+
+// Buffers writes into parts of `Storage::part_bytes` and uploads each as it fills. Call
+// `finish` to send the last part and complete the upload; dropping the writer unfinished,
+// or a `finish` that fails, aborts it.
+pub struct StorageWriter {
+    upload: Option<Box<dyn Upload>>,
+    buffer: Vec<u8>,
+    part_bytes: usize,
+    next_part: usize,
+}
+
+impl StorageWriter {
+    pub fn new(storage: &dyn Storage, key: &str) -> io::Result<Self> {
+        let part_bytes = storage.part_bytes().max(1);
+        Ok(StorageWriter { upload: Some(storage.begin_upload(key)?), buffer: Vec::with_capacity(part_bytes), part_bytes, next_part: 0 })
+    }
+
+    fn send_part(&mut self) -> io::Result<()> {
+        let upload = self.upload.as_mut().expect("upload already finished");
+        upload.write_part(self.next_part, &self.buffer)?;
+        self.next_part += 1;
+        self.buffer.clear();
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> io::Result<()> {
+        if !self.buffer.is_empty() || self.next_part == 0 {
+            self.send_part()?;
+        }
+        self.upload.as_mut().expect("upload already finished").complete()?;
+        self.upload = None;
+        Ok(())
+    }
+}
+
+impl Write for StorageWriter {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        let taken = data.len().min(self.part_bytes - self.buffer.len());
+        self.buffer.extend_from_slice(&data[..taken]);
+        if self.buffer.len() == self.part_bytes {
+            self.send_part()?;
+        }
+        Ok(taken)
+    }
+
+    // Parts are only sent once full; `finish` sends the rest.
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for StorageWriter {
+    fn drop(&mut self) {
+        if let Some(upload) = self.upload.take() {
+            // Best effort: an unfinished partial object is harmless.
+            let _ = upload.abort();
+        }
+    }
+}
+
+fn partial_path(path: &Path) -> PathBuf {
+    let mut name = path.file_name().map(|n| n.to_os_string()).unwrap_or_default();
+    name.push(".partial");
+    path.with_file_name(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::VecDeque;
+    use std::sync::{Arc, Mutex};
+
+    // Fails each call with the next queued error kind, if any, and counts the calls.
+    #[derive(Clone, Default)]
+    struct Flaky {
+        failures: Arc<Mutex<VecDeque<io::ErrorKind>>>,
+        calls: Arc<Mutex<Vec<String>>>,
+    }
+
+    impl Flaky {
+        fn failing(kinds: &[io::ErrorKind]) -> Self {
+            let flaky = Flaky::default();
+            flaky.failures.lock().unwrap().extend(kinds);
+            flaky
+        }
+
+        fn call(&self, name: String) -> io::Result<()> {
+            self.calls.lock().unwrap().push(name);
+            match self.failures.lock().unwrap().pop_front() {
+                Some(kind) => Err(io::Error::new(kind, "injected")),
+                None => Ok(()),
+            }
+        }
+
+        fn calls(&self) -> Vec<String> {
+            self.calls.lock().unwrap().clone()
+        }
+    }
+
+    impl Storage for Flaky {
+        fn get(&self, key: &str) -> io::Result<Vec<u8>> {
+            self.call(format!("get {}", key)).map(|_| key.as_bytes().to_vec())
+        }
+
+        fn exists(&self, key: &str) -> io::Result<bool> {
+            self.call(format!("exists {}", key)).map(|_| true)
+        }
+
+        fn delete(&self, key: &str) -> io::Result<()> {
+            self.call(format!("delete {}", key))
+        }
+
+        fn begin_upload(&self, key: &str) -> io::Result<Box<dyn Upload>> {
+            self.call(format!("begin {}", key))?;
+            Ok(Box::new(self.clone()))
+        }
+
+        // Small, so a few bytes through a `StorageWriter` make a part.
+        fn part_bytes(&self) -> usize {
+            4
+        }
+    }
+
+    impl Upload for Flaky {
+        fn write_part(&mut self, part: usize, _data: &[u8]) -> io::Result<()> {
+            self.call(format!("part {}", part))
+        }
+
+        fn complete(&mut self) -> io::Result<()> {
+            self.call("complete".to_string())
+        }
+
+        fn abort(self: Box<Self>) -> io::Result<()> {
+            self.call("abort".to_string())
+        }
+    }
+
+    fn retrying(inner: Flaky, attempts: usize) -> Retrying<Flaky> {
+        Retrying::new(inner).with_attempts(attempts).with_backoff(Duration::ZERO)
+    }
+
+    #[test]
+    fn retries_transient_errors_up_to_the_attempt_limit() {
+        let flaky = Flaky::failing(&[io::ErrorKind::TimedOut, io::ErrorKind::ConnectionReset]);
+        assert_eq!(retrying(flaky.clone(), 3).get("a").unwrap(), b"a");
+        assert_eq!(flaky.calls().len(), 3);
+
+        let flaky = Flaky::failing(&[io::ErrorKind::Interrupted; 4]);
+        let error = retrying(flaky.clone(), 3).delete("a").unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::Interrupted);
+        assert_eq!(flaky.calls().len(), 3);
+    }
+
+    #[test]
+    fn permanent_errors_are_not_retried() {
+        for kind in [io::ErrorKind::NotFound, io::ErrorKind::PermissionDenied, io::ErrorKind::InvalidData] {
+            let flaky = Flaky::failing(&[kind]);
+            assert_eq!(retrying(flaky.clone(), 5).exists("a").unwrap_err().kind(), kind);
+            assert_eq!(flaky.calls(), ["exists a"]);
+        }
+    }
+
+    #[test]
+    fn upload_parts_are_retried_individually() {
+        let flaky = Flaky::default();
+        let storage = retrying(flaky.clone(), 3);
+        let mut upload = storage.begin_upload("a").unwrap();
+        upload.write_part(0, b"x").unwrap();
+        flaky.failures.lock().unwrap().extend([io::ErrorKind::BrokenPipe, io::ErrorKind::TimedOut]);
+        upload.write_part(1, b"y").unwrap();
+        upload.complete().unwrap();
+        assert_eq!(flaky.calls(), ["begin a", "part 0", "part 1", "part 1", "part 1", "complete"]);
+    }
+
+    #[test]
+    fn completion_is_retried_before_the_upload_is_aborted() {
+        let flaky = Flaky::default();
+        let mut writer = StorageWriter::new(&retrying(flaky.clone(), 3), "a").unwrap();
+        writer.write_all(b"abcd").unwrap();
+        flaky.failures.lock().unwrap().extend([io::ErrorKind::Interrupted, io::ErrorKind::TimedOut]);
+        writer.finish().unwrap();
+        assert_eq!(flaky.calls(), ["begin a", "part 0", "complete", "complete", "complete"]);
+
+        // Only once the attempts run out does the writer give up and abort.
+        let flaky = Flaky::default();
+        let mut writer = StorageWriter::new(&retrying(flaky.clone(), 3), "a").unwrap();
+        writer.write_all(b"abcd").unwrap();
+        flaky.failures.lock().unwrap().extend([io::ErrorKind::Interrupted; 3]);
+        assert_eq!(writer.finish().unwrap_err().kind(), io::ErrorKind::Interrupted);
+        assert_eq!(flaky.calls(), ["begin a", "part 0", "complete", "complete", "complete", "abort"]);
+    }
+
+    #[test]
+    fn local_uploads_replace_the_object_only_on_completion() {
+        let storage = LocalStorage::new(std::env::temp_dir().join(format!("galore-storage-{}", std::process::id())));
+        let mut upload = storage.begin_upload("ckpt/a").unwrap();
+        assert_eq!(upload.write_part(1, b"old").unwrap_err().kind(), io::ErrorKind::InvalidInput);
+        upload.write_part(0, b"old").unwrap();
+        upload.complete().unwrap();
+        assert_eq!(storage.get("ckpt/a").unwrap(), b"old");
+
+        // An unfinished writer aborts on drop and leaves the previous object in place.
+        let mut writer = StorageWriter::new(&storage, "ckpt/a").unwrap();
+        writer.write_all(b"new").unwrap();
+        assert!(storage.exists("ckpt/a.partial").unwrap());
+        drop(writer);
+        assert!(!storage.exists("ckpt/a.partial").unwrap());
+        assert_eq!(storage.get("ckpt/a").unwrap(), b"old");
+
+        let mut writer = StorageWriter::new(&storage, "ckpt/a").unwrap();
+        writer.write_all(b"new").unwrap();
+        writer.finish().unwrap();
+        assert_eq!(storage.get("ckpt/a").unwrap(), b"new");
+
+        storage.delete("ckpt/a").unwrap();
+        assert!(!storage.exists("ckpt/a").unwrap());
+    }
+}
@@ -0,0 +1,157 @@
+use std::fmt;
+
+use super::matrix_ops::{GaLoreOptimizer, GaLoreProjection, OneSidedRule};
+use super::optimizer::Optimizer;
+use super::pipeline::{ParamGroup, PipelineError, Scale};
+
+// The reference implementation's (galore_torch) per-group GaLore arguments, with its defaults.
+#[derive(Clone, Debug, PartialEq)]
+pub struct GaLoreArgs {
+    pub rank: usize,
+    pub update_proj_gap: usize,
+    pub scale: f32,
+    // "std", "reverse_std", "left", "right" or "full".
+    pub proj_type: String,
+}
+
+impl Default for GaLoreArgs {
+    fn default() -> Self {
+        GaLoreArgs { rank: 128, update_proj_gap: 200, scale: 1.0, proj_type: "std".to_string() }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum CompatError {
+    // Named by its `GaLoreArgs` field.
+    NotPositive(&'static str),
+    UnknownProjType(String),
+    // Building the pipeline failed.
+    Pipeline(PipelineError),
+}
+
+impl fmt::Display for CompatError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CompatError::NotPositive(field) => write!(f, "{} must be positive", field),
+            CompatError::UnknownProjType(proj_type) => write!(f, "unknown proj_type {:?}", proj_type),
+            CompatError::Pipeline(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for CompatError {}
+
+impl From<PipelineError> for CompatError {
+    fn from(e: PipelineError) -> Self {
+        CompatError::Pipeline(e)
+    }
+}
+
+// GaLore configured to take the same steps as the reference implementation applied to the
+// parameters in `targets` (its GaLore param group); the rest are optimized unprojected and
+// unscaled, as the reference's plain param groups are. Matches it in:
+//   - sidedness: one-sided by `proj_type` (`OneSidedRule`), two-sided only for "full";
+//   - refresh timing: the reference checks `step % update_proj_gap == 0` with its step count
+//     before incrementing it, so it refreshes on this crate's steps 1, gap + 1, 2 gap + 1, ...;
+//   - plain SVD bases, replaced on refresh (no EMA), with the optimizer state carried over;
+//   - `scale` applied to the back-projected update of projected parameters only.
+// Not covered: the base optimizer's own arithmetic. `Adam` adds epsilon to sqrt(v_hat), while
+// the reference AdamW adds it to sqrt(v) and folds the bias corrections into the step size,
+// so early steps differ slightly when v is of the order of epsilon^2.
+pub fn pytorch_galore<O: Optimizer>(base_optimizer: O, targets: ParamGroup, args: &GaLoreArgs) -> Result<GaLoreOptimizer<O>, CompatError> {
+    if args.rank == 0 {
+        return Err(CompatError::NotPositive("rank"));
+    }
+    if args.update_proj_gap == 0 {
+        return Err(CompatError::NotPositive("update_proj_gap"));
+    }
+    let rule = match args.proj_type.as_str() {
+        "std" => Some(OneSidedRule::Std),
+        "reverse_std" => Some(OneSidedRule::ReverseStd),
+        "left" => Some(OneSidedRule::Left),
+        "right" => Some(OneSidedRule::Right),
+        "full" => None,
+        other => return Err(CompatError::UnknownProjType(other.to_string())),
+    };
+    let mut projection = GaLoreProjection::new(args.rank, args.update_proj_gap, 0.0)
+        .with_targets(targets.clone())
+        .with_refresh_phase(1);
+    if let Some(rule) = rule {
+        projection = projection.with_one_sided(rule);
+    }
+    let optimizer = GaLoreOptimizer::with_projection(base_optimizer, projection)
+        .with_transform_after("project_back", Box::new(Scale::new(args.scale).with_group(targets)))?;
+    Ok(optimizer)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::galore::optimizer::Adam;
+    use crate::galore::subspace::Subspace;
+    use crate::galore::testing::{adam, gradient};
+    use ndarray::s;
+
+    fn args(proj_type: &str) -> GaLoreArgs {
+        GaLoreArgs { rank: 4, update_proj_gap: 3, proj_type: proj_type.to_string(), ..GaLoreArgs::default() }
+    }
+
+    fn subspaces(optimizer: &GaLoreOptimizer<Adam>) -> Vec<Option<Subspace>> {
+        optimizer.projection().export_subspaces()
+    }
+
+    #[test]
+    fn refreshes_land_on_the_reference_steps() {
+        let mut optimizer = pytorch_galore(adam(), ParamGroup::All, &args("std")).unwrap();
+        let mut refreshed = Vec::new();
+        let mut previous = None;
+        for step in 1..=8 {
+            optimizer.step(vec![gradient(step).view()]).unwrap();
+            let current = subspaces(&optimizer);
+            if previous.as_ref() != Some(&current) {
+                refreshed.push(step);
+            }
+            previous = Some(current);
+        }
+        assert_eq!(refreshed, [1, 4, 7]);
+    }
+
+    #[test]
+    fn proj_type_picks_the_reference_side() {
+        // (wide, tall, square) weights: whether each keeps P (true) or Q (false).
+        let sides = [
+            ("std", [Some(true), Some(false), Some(false)]),
+            ("reverse_std", [Some(false), Some(true), Some(true)]),
+            ("left", [Some(true), Some(true), Some(true)]),
+            ("right", [Some(false), Some(false), Some(false)]),
+            ("full", [None, None, None]),
+        ];
+        let g = gradient(0);
+        let grads = [g.clone(), g.t().to_owned(), g.slice(s![.., ..24]).to_owned()];
+        for (proj_type, expected) in sides {
+            let mut optimizer = pytorch_galore(adam(), ParamGroup::All, &args(proj_type)).unwrap();
+            optimizer.step(grads.iter().map(|g| g.view())).unwrap();
+            let actual: Vec<Option<bool>> = subspaces(&optimizer)
+                .into_iter()
+                .map(|subspace| match subspace.unwrap() {
+                    Subspace { p: Some(_), q: Some(_) } => None,
+                    Subspace { p, .. } => Some(p.is_some()),
+                })
+                .collect();
+            assert_eq!(actual, expected, "{}", proj_type);
+        }
+    }
+
+    #[test]
+    fn invalid_arguments_are_rejected() {
+        let invalid = [
+            (GaLoreArgs { proj_type: "diagonal".to_string(), ..args("std") }, CompatError::UnknownProjType("diagonal".to_string())),
+            (GaLoreArgs { rank: 0, ..args("std") }, CompatError::NotPositive("rank")),
+            (GaLoreArgs { update_proj_gap: 0, ..args("std") }, CompatError::NotPositive("update_proj_gap")),
+        ];
+        for (args, expected) in &invalid {
+            let refused = pytorch_galore(adam(), ParamGroup::All, args).err();
+            assert_eq!(refused.as_ref(), Some(expected), "{:?}", args);
+        }
+    }
+}
@@ -0,0 +1,316 @@
+use ndarray::{s, Array1, Array2, Axis};
+use ndarray_linalg::error::LinalgError;
+use ndarray_linalg::{Eigh, JobSvd, QR, SVDDC, SVD, UPLO};
+use ndarray_rand::rand_distr::StandardNormal;
+use ndarray_rand::RandomExt;
+use rand::rngs::StdRng;
+use rand::SeedableRng;
+
+use super::matrix_ops::GradInput;
+
+// Leading singular triplets of a gradient: `u` is m x k, `v` is n x k (columns, not V^T) and
+// `sigma` has at least k entries, largest first. Only the requested sides need be present.
+#[derive(Clone, Debug)]
+pub struct Decomposition {
+    pub u: Option<Array2<f32>>,
+    pub sigma: Array1<f32>,
+    pub v: Option<Array2<f32>>,
+}
+
+// How `GaLoreProjection` finds a gradient's dominant subspace. Implement this to plug in a
+// GPU or approximate decomposition. `seed` is fixed per (step, parameter) so randomized
+// methods are reproducible. Errors are reported as fallback events, not panics.
+pub trait Decomposer: Send + Sync {
+    fn name(&self) -> &str;
+
+    fn decompose(&self, grad: &GradInput, rank: usize, need_u: bool, need_v: bool, seed: u64) -> Result<Decomposition, String>;
+}
+
+// Full SVD (LAPACK gesdd), as in the reference implementation. Sparse gradients go through
+// a randomized sketch instead so they are never densified.
+#[derive(Clone, Debug, Default)]
+pub struct ExactSvd {
+    sparse: RandomizedSvd,
+}
+
+impl ExactSvd {
+    pub fn new() -> Self {
+        ExactSvd::default()
+    }
+
+    pub fn with_sparse_sketch(mut self, oversample: usize, power_iters: usize) -> Self {
+        self.sparse = RandomizedSvd::new(oversample, power_iters);
+        self
+    }
+}
+
+impl Decomposer for ExactSvd {
+    fn name(&self) -> &str {
+        "exact_svd"
+    }
+
+    fn decompose(&self, grad: &GradInput, rank: usize, need_u: bool, need_v: bool, seed: u64) -> Result<Decomposition, String> {
+        let grad = match grad {
+            GradInput::Dense(grad) => grad,
+            GradInput::Sparse(_) => return self.sparse.decompose(grad, rank, need_u, need_v, seed),
+        };
+        let (u, sigma, vt) = grad.svd(need_u, need_v).map_err(|e| e.to_string())?;
+        Ok(Decomposition {
+            u: u.map(|u| u.slice(s![.., ..rank]).to_owned()),
+            sigma,
+            v: vt.map(|vt| vt.slice(s![..rank, ..]).t().to_owned()),
+        })
+    }
+}
+
+// Randomized SVD (Halko et al.): a Gaussian sketch finds the range, `power_iters` rounds
+// sharpen it, and only the small (rank + oversample) x n core is decomposed exactly. Uses
+// products with the gradient only, so sparse gradients stay sparse.
+#[derive(Clone, Debug)]
+pub struct RandomizedSvd {
+    oversample: usize,
+    power_iters: usize,
+}
+
+impl RandomizedSvd {
+    pub fn new(oversample: usize, power_iters: usize) -> Self {
+        RandomizedSvd { oversample, power_iters }
+    }
+}
+
+impl Default for RandomizedSvd {
+    fn default() -> Self {
+        RandomizedSvd::new(8, 2)
+    }
+}
+
+impl Decomposer for RandomizedSvd {
+    fn name(&self) -> &str {
+        "randomized_svd"
+    }
+
+    fn decompose(&self, grad: &GradInput, rank: usize, need_u: bool, need_v: bool, seed: u64) -> Result<Decomposition, String> {
+        let (m, n) = grad.dim();
+        let k = (rank + self.oversample).min(m).min(n);
+        let mut rng = StdRng::seed_from_u64(seed);
+
+        let range = range_finder(grad, k, self.power_iters, &mut rng).map_err(|e| e.to_string())?;
+        // B = Q^T G, computed as (G^T Q)^T.
+        let core = grad.t_dot(&range.view()).reversed_axes();
+        let (u_core, sigma, vt) = core.svddc(JobSvd::Some).map_err(|e| e.to_string())?;
+        let (u_core, vt) = (u_core.ok_or("SVD returned no U")?, vt.ok_or("SVD returned no V^T")?);
+
+        Ok(Decomposition {
+            u: need_u.then(|| range.dot(&u_core.slice(s![.., ..rank]))),
+            sigma,
+            v: need_v.then(|| vt.slice(s![..rank, ..]).t().to_owned()),
+        })
+    }
+}
+
+// Eigendecomposition of the smaller Gram matrix, G G^T (m x m) or G^T G (n x n), with the
+// other side recovered as G^T U / sigma or G V / sigma. Much cheaper than an SVD for very
+// rectangular gradients, at the cost of squaring the condition number. Directions with
+// (near-)zero singular values come back as zero columns on the recovered side. Sparse
+// gradients are densified.
+#[derive(Clone, Debug, Default)]
+pub struct CovarianceEigh;
+
+impl Decomposer for CovarianceEigh {
+    fn name(&self) -> &str {
+        "covariance_eigh"
+    }
+
+    fn decompose(&self, grad: &GradInput, rank: usize, need_u: bool, need_v: bool, _seed: u64) -> Result<Decomposition, String> {
+        let dense = grad.to_dense();
+        let (m, n) = dense.dim();
+        let left = m <= n;
+        let gram = if left { dense.dot(&dense.t()) } else { dense.t().dot(&dense) };
+        let (eigvals, eigvecs) = gram.eigh(UPLO::Lower).map_err(|e| e.to_string())?;
+
+        // eigh sorts ascending.
+        let order: Vec<usize> = (0..eigvals.len()).rev().collect();
+        let sigma: Array1<f32> = order.iter().map(|&i| eigvals[i].max(0.0).sqrt()).collect();
+        let basis = eigvecs.select(Axis(1), &order[..rank]);
+        let recover = |mut other: Array2<f32>| {
+            for (mut column, &s) in other.axis_iter_mut(Axis(1)).zip(sigma.iter()) {
+                if s > f32::EPSILON * sigma[0] {
+                    column /= s;
+                } else {
+                    column.fill(0.0);
+                }
+            }
+            other
+        };
+
+        let (u, v) = if left {
+            let v = need_v.then(|| recover(dense.t().dot(&basis)));
+            (need_u.then_some(basis), v)
+        } else {
+            let u = need_u.then(|| recover(dense.dot(&basis)));
+            (u, need_v.then_some(basis))
+        };
+        Ok(Decomposition { u, sigma, v })
+    }
+}
+
+// The orthonormalized sketch itself, with no SVD of the core: the basis spans (approximately)
+// the dominant subspace but its columns are not individual singular vectors, and `sigma`
+// holds the norms of G^T q_i (or G q_i) rather than true singular values. The cheapest
+// option when only the subspace matters.
+#[derive(Clone, Debug)]
+pub struct SketchQr {
+    power_iters: usize,
+}
+
+impl SketchQr {
+    pub fn new(power_iters: usize) -> Self {
+        SketchQr { power_iters }
+    }
+}
+
+impl Default for SketchQr {
+    fn default() -> Self {
+        SketchQr::new(1)
+    }
+}
+
+impl Decomposer for SketchQr {
+    fn name(&self) -> &str {
+        "sketch_qr"
+    }
+
+    fn decompose(&self, grad: &GradInput, rank: usize, need_u: bool, need_v: bool, seed: u64) -> Result<Decomposition, String> {
+        let mut rng = StdRng::seed_from_u64(seed);
+        let linalg = |e: LinalgError| e.to_string();
+        let column_norms = |m: &Array2<f32>| m.map_axis(Axis(0), |c| c.dot(&c).sqrt());
+
+        if need_u {
+            let u = range_finder(grad, rank, self.power_iters, &mut rng).map_err(linalg)?;
+            let projected = grad.t_dot(&u.view());
+            let sigma = column_norms(&projected);
+            let v = if need_v { Some(orthonormalize(projected).map_err(linalg)?) } else { None };
+            Ok(Decomposition { u: Some(u), sigma, v })
+        } else {
+            let (m, _) = grad.dim();
+            let omega = Array2::<f32>::random_using((m, rank), StandardNormal, &mut rng);
+            let mut v = orthonormalize(grad.t_dot(&omega.view())).map_err(linalg)?;
+            for _ in 0..self.power_iters {
+                let back = orthonormalize(grad.dot(&v.view())).map_err(linalg)?;
+                v = orthonormalize(grad.t_dot(&back.view())).map_err(linalg)?;
+            }
+            let sigma = column_norms(&grad.dot(&v.view()));
+            Ok(Decomposition { u: None, sigma, v: Some(v) })
+        }
+    }
+}
+
+// Orthonormal m x k basis for the range of G from a Gaussian sketch plus power iterations.
+fn range_finder(grad: &GradInput, k: usize, power_iters: usize, rng: &mut StdRng) -> Result<Array2<f32>, LinalgError> {
+    let (_, n) = grad.dim();
+    let omega = Array2::<f32>::random_using((n, k), StandardNormal, rng);
+    let mut range = orthonormalize(grad.dot(&omega.view()))?;
+    for _ in 0..power_iters {
+        let back = orthonormalize(grad.t_dot(&range.view()))?;
+        range = orthonormalize(grad.dot(&back.view()))?;
+    }
+    Ok(range)
+}
+
+pub(crate) fn orthonormalize(matrix: Array2<f32>) -> Result<Array2<f32>, LinalgError> {
+    let cols = matrix.ncols();
+    let (q, _) = matrix.qr()?;
+    Ok(q.slice_axis(Axis(1), (..cols).into()).to_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::galore::sparse::CsrMatrix;
+    use ndarray::{Array, ArrayView2};
+
+    const RANK: usize = 3;
+    const SIGMA: [f32; RANK] = [9.0, 4.0, 2.0];
+
+    // Orthonormal columns by modified Gram-Schmidt, independent of the LAPACK QR under test.
+    fn orthonormal(rows: usize, cols: usize, seed: f32) -> Array2<f32> {
+        let mut basis = Array::from_shape_fn((rows, cols), |(i, j)| ((i * j) as f32 * seed + (i + j * j) as f32).sin());
+        for j in 0..cols {
+            for k in 0..j {
+                let previous = basis.column(k).to_owned();
+                let overlap = basis.column(j).dot(&previous);
+                basis.column_mut(j).scaled_add(-overlap, &previous);
+            }
+            let norm = basis.column(j).dot(&basis.column(j)).sqrt();
+            basis.column_mut(j).mapv_inplace(|x| x / norm);
+        }
+        basis
+    }
+
+    // U diag(SIGMA) V^T with known singular subspaces, plus the subspaces.
+    fn low_rank(rows: usize, cols: usize) -> (Array2<f32>, Array2<f32>, Array2<f32>) {
+        let u = orthonormal(rows, RANK, 0.73);
+        let v = orthonormal(cols, RANK, 0.29);
+        let scaled = &v * &Array::from_vec(SIGMA.to_vec());
+        (u.dot(&scaled.t()), u, v)
+    }
+
+    // Largest distance from a column of `basis` to the span of the orthonormal `truth`.
+    fn subspace_error(basis: &ArrayView2<f32>, truth: &Array2<f32>) -> f32 {
+        let residual = basis - &truth.dot(&truth.t().dot(basis));
+        residual.columns().into_iter().map(|c| c.dot(&c).sqrt()).fold(0.0, f32::max)
+    }
+
+    fn check(decomposer: &dyn Decomposer, rows: usize, cols: usize, singular_values: bool) {
+        let (grad, u, v) = low_rank(rows, cols);
+        let found = decomposer.decompose(&GradInput::Dense(grad.view()), RANK, true, true, 7).unwrap();
+        let (found_u, found_v) = (found.u.unwrap(), found.v.unwrap());
+        assert_eq!(found_u.dim(), (rows, RANK));
+        assert_eq!(found_v.dim(), (cols, RANK));
+        for (basis, truth) in [(&found_u, &u), (&found_v, &v)] {
+            let gram = basis.t().dot(basis);
+            assert!((gram - Array2::<f32>::eye(RANK)).iter().all(|x| x.abs() < 1e-4), "{}: not orthonormal", decomposer.name());
+            let error = subspace_error(&basis.view(), truth);
+            assert!(error < 1e-3, "{} {}x{}: subspace error {}", decomposer.name(), rows, cols, error);
+        }
+        if singular_values {
+            for (found, expected) in found.sigma.iter().zip(SIGMA) {
+                assert!((found - expected).abs() < 1e-3 * expected, "{}: sigma {} vs {}", decomposer.name(), found, expected);
+            }
+        }
+    }
+
+    #[test]
+    fn exact_svd_recovers_a_low_rank_subspace() {
+        check(&ExactSvd::new(), 30, 20, true);
+        check(&ExactSvd::new(), 12, 40, true);
+    }
+
+    #[test]
+    fn randomized_svd_recovers_a_low_rank_subspace() {
+        check(&RandomizedSvd::default(), 30, 20, true);
+        check(&RandomizedSvd::new(2, 0), 12, 40, true);
+        // Sparse input, which `ExactSvd` also hands to the sketch.
+        let (grad, u, _) = low_rank(25, 18);
+        let sparse = CsrMatrix::from_dense(&grad.view());
+        let found = ExactSvd::new().decompose(&GradInput::Sparse(&sparse), RANK, true, false, 3).unwrap();
+        assert!(subspace_error(&found.u.unwrap().view(), &u) < 1e-3);
+    }
+
+    #[test]
+    fn covariance_eigh_recovers_a_low_rank_subspace() {
+        check(&CovarianceEigh, 30, 20, true);
+        check(&CovarianceEigh, 12, 40, true);
+    }
+
+    #[test]
+    fn sketch_qr_recovers_a_low_rank_subspace() {
+        // The basis is not aligned with the singular vectors, so sigma is not checked.
+        check(&SketchQr::default(), 30, 20, false);
+        check(&SketchQr::new(0), 12, 40, false);
+        let (grad, _, v) = low_rank(16, 22);
+        let found = SketchQr::default().decompose(&GradInput::Dense(grad.view()), RANK, false, true, 5).unwrap();
+        assert!(found.u.is_none());
+        assert!(subspace_error(&found.v.unwrap().view(), &v) < 1e-3);
+    }
+}
@@ -0,0 +1,661 @@
+use ndarray::{Array2, ArrayView2, CowArray};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver, SyncSender, TryRecvError};
+use std::sync::{Arc, Barrier, Mutex};
+use std::thread;
+
+use super::matrix_ops::{GaLoreOptimizer, GaLoreProjection};
+use super::optimizer::Optimizer;
+use super::pipeline::{Grad, GradTransform, PipelineError, TransformContext};
+use super::registry::TensorRegistry;
+use super::subspace::Subspace;
+
+const BYTES_PER_ELEMENT: usize = std::mem::size_of::<f32>();
+
+// The worker side of asynchronous training: full-size gradients of the parameters at the
+// weights the worker currently holds. `step` counts the worker's own calls.
+pub trait GradientTask: Send + Sync {
+    fn gradients(&self, weights: &[Array2<f32>], worker: usize, step: usize) -> Vec<Array2<f32>>;
+}
+
+enum WorkerMessage {
+    // Projected with the subspaces of `version`; unprojected parameters are sent in full.
+    Projected { version: usize, weights_version: usize, grads: Vec<Array2<f32>> },
+    Full { weights_version: usize, grads: Vec<Array2<f32>> },
+}
+
+enum ServerMessage {
+    Delta { version: usize, deltas: Arc<Vec<Array2<f32>>> },
+    Subspaces { version: usize, subspaces: Arc<Vec<Option<Subspace>>> },
+    NeedFull,
+    Stop,
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct AsyncReport {
+    pub updates: usize,
+    pub refreshes: usize,
+    // Projected gradients computed against subspaces replaced before they arrived.
+    pub discarded: usize,
+    // Gradients dropped for exceeding the staleness limit.
+    pub too_stale: usize,
+    // Server updates applied between a worker reading the weights and its gradient
+    // arriving, maximum and mean over applied gradients.
+    pub max_staleness: usize,
+    pub mean_staleness: f32,
+    // Gradient traffic from workers, and what the same messages would have cost unprojected.
+    pub sent_bytes: usize,
+    pub full_bytes: usize,
+}
+
+// Asynchronous parameter-server training. Workers compute gradients on their copy of the
+// weights, project them with the server's current subspaces and send the rank-sized
+// result; the server applies each gradient as it arrives (no barrier) and broadcasts the
+// resulting weight deltas. Every `refresh_every` updates the server asks for one full
+// gradient, refreshes the projections from it and broadcasts the new subspaces. Projected
+// gradients computed with superseded subspaces are dropped, since they live in a different
+// basis.
+//
+// The server's inbox holds at most one gradient per worker; a worker that would exceed it
+// blocks in send until the server catches up, so a slow server throttles the workers
+// instead of queueing their gradients without bound. Queueing alone does not bound how
+// stale a slow worker's gradient gets while faster workers keep the server busy, so
+// gradients more than `max_staleness` updates old are dropped on arrival.
+//
+// Runs in-process: workers are threads and messages go over channels, so the byte counts
+// in the report are what a network transport would carry. Deltas are sent at full size.
+pub struct ParameterServer<O: Optimizer> {
+    optimizer: GaLoreOptimizer<O>,
+    params: Vec<Array2<f32>>,
+    refresh_every: usize,
+    max_staleness: Option<usize>,
+}
+
+impl<O: Optimizer> ParameterServer<O> {
+    pub fn new(optimizer: GaLoreOptimizer<O>, params: Vec<Array2<f32>>) -> Self {
+        ParameterServer { optimizer, params, refresh_every: 200, max_staleness: None }
+    }
+
+    pub fn with_refresh_every(mut self, refresh_every: usize) -> Self {
+        assert!(refresh_every > 0, "refresh interval must be positive");
+        self.refresh_every = refresh_every;
+        self
+    }
+
+    // Drop gradients computed more than `max_staleness` updates before they would be applied.
+    // Full gradients requested for a refresh are still used for the refresh.
+    pub fn with_max_staleness(mut self, max_staleness: usize) -> Self {
+        self.max_staleness = Some(max_staleness);
+        self
+    }
+
+    pub fn params(&self) -> &[Array2<f32>] {
+        &self.params
+    }
+
+    pub fn optimizer(&self) -> &GaLoreOptimizer<O> {
+        &self.optimizer
+    }
+
+    pub fn into_params(self) -> Vec<Array2<f32>> {
+        self.params
+    }
+
+    // Trains with `workers` worker threads until `updates` gradients have been applied.
+    // Stops the workers and fails if the optimizer refuses an update.
+    pub fn run(&mut self, task: Arc<dyn GradientTask>, workers: usize, updates: usize) -> Result<AsyncReport, PipelineError> {
+        assert!(workers > 0, "need at least one worker");
+        let (to_server, inbox) = mpsc::sync_channel(workers);
+        let mut outboxes = Vec::with_capacity(workers);
+        let mut handles = Vec::with_capacity(workers);
+        for worker in 0..workers {
+            let (to_worker, worker_inbox) = mpsc::channel();
+            outboxes.push(to_worker);
+            let (task, to_server, weights) = (Arc::clone(&task), to_server.clone(), self.params.clone());
+            handles.push(thread::spawn(move || run_worker(worker, task, weights, worker_inbox, to_server)));
+        }
+        drop(to_server);
+
+        let mut report = AsyncReport::default();
+        let mut weights_version = 0;
+        let mut subspace_version = 0;
+        let mut staleness_sum = 0;
+        let mut refused = None;
+        let broadcast = |message: &dyn Fn() -> ServerMessage| {
+            for outbox in &outboxes {
+                // A worker that already exited has nothing left to receive.
+                let _ = outbox.send(message());
+            }
+        };
+
+        while report.updates < updates {
+            let Ok(message) = inbox.recv() else {
+                break;
+            };
+            let (worker_weights, projected) = match message {
+                WorkerMessage::Projected { version, weights_version: seen, grads } => {
+                    report.sent_bytes += bytes(&grads);
+                    report.full_bytes += self.params.iter().map(|p| p.len() * BYTES_PER_ELEMENT).sum::<usize>();
+                    if version != subspace_version {
+                        report.discarded += 1;
+                        continue;
+                    }
+                    (seen, grads)
+                }
+                WorkerMessage::Full { weights_version: seen, grads } => {
+                    report.sent_bytes += bytes(&grads);
+                    report.full_bytes += bytes(&grads);
+                    let views: Vec<ArrayView2<f32>> = grads.iter().map(|g| g.view()).collect();
+                    if subspace_version == 0 || report.updates >= report.refreshes * self.refresh_every {
+                        self.optimizer.refresh_projection(views.clone());
+                        subspace_version += 1;
+                        report.refreshes += 1;
+                        let subspaces = Arc::new(self.optimizer.projection().export_subspaces());
+                        broadcast(&|| ServerMessage::Subspaces { version: subspace_version, subspaces: Arc::clone(&subspaces) });
+                    }
+                    (seen, self.optimizer.projection().project_current(&views))
+                }
+            };
+
+            let staleness = weights_version - worker_weights;
+            if self.max_staleness.is_some_and(|max| staleness > max) {
+                report.too_stale += 1;
+                continue;
+            }
+            staleness_sum += staleness;
+            report.max_staleness = report.max_staleness.max(staleness);
+            let views: Vec<ArrayView2<f32>> = projected.iter().map(|g| g.view()).collect();
+            let deltas = match self.optimizer.apply_projected_updates(&mut self.params, views) {
+                Ok(deltas) => Arc::new(deltas),
+                Err(e) => {
+                    refused = Some(e);
+                    break;
+                }
+            };
+            weights_version += 1;
+            report.updates += 1;
+            broadcast(&|| ServerMessage::Delta { version: weights_version, deltas: Arc::clone(&deltas) });
+            if report.updates % self.refresh_every == 0 {
+                broadcast(&|| ServerMessage::NeedFull);
+            }
+        }
+
+        broadcast(&|| ServerMessage::Stop);
+        drop(inbox);
+        for handle in handles {
+            if let Err(panic) = handle.join() {
+                std::panic::resume_unwind(panic);
+            }
+        }
+        if let Some(e) = refused {
+            return Err(e);
+        }
+        report.mean_staleness = staleness_sum as f32 / report.updates.max(1) as f32;
+        Ok(report)
+    }
+}
+
+fn run_worker(
+    worker: usize,
+    task: Arc<dyn GradientTask>,
+    mut weights: Vec<Array2<f32>>,
+    inbox: Receiver<ServerMessage>,
+    to_server: SyncSender<WorkerMessage>,
+) {
+    let mut weights_version = 0;
+    let mut subspaces: Option<(usize, Arc<Vec<Option<Subspace>>>)> = None;
+    let mut need_full = true;
+    for step in 0.. {
+        loop {
+            match inbox.try_recv() {
+                Ok(ServerMessage::Delta { version, deltas }) => {
+                    for (weight, delta) in weights.iter_mut().zip(deltas.iter()) {
+                        *weight += delta;
+                    }
+                    weights_version = version;
+                }
+                Ok(ServerMessage::Subspaces { version, subspaces: new }) => subspaces = Some((version, new)),
+                Ok(ServerMessage::NeedFull) => need_full = true,
+                Ok(ServerMessage::Stop) | Err(TryRecvError::Disconnected) => return,
+                Err(TryRecvError::Empty) => break,
+            }
+        }
+
+        let grads = task.gradients(&weights, worker, step);
+        let message = match &subspaces {
+            Some((version, subspaces)) if !need_full => {
+                let grads = grads
+                    .iter()
+                    .zip(subspaces.iter())
+                    .map(|(grad, subspace)| match subspace {
+                        Some(subspace) => subspace.project(&grad.view()),
+                        None => grad.clone(),
+                    })
+                    .collect();
+                WorkerMessage::Projected { version: *version, weights_version, grads }
+            }
+            _ => {
+                need_full = false;
+                WorkerMessage::Full { weights_version, grads }
+            }
+        };
+        if to_server.send(message).is_err() {
+            return;
+        }
+    }
+}
+
+// Moves the gradient out (copying only if it is borrowed), leaving an empty placeholder.
+fn take(grad: &mut Grad) -> Array2<f32> {
+    std::mem::replace(grad, CowArray::from(Array2::zeros((0, 0)))).into_owned()
+}
+
+fn bytes(tensors: &[Array2<f32>]) -> usize {
+    tensors.iter().map(|t| t.len() * BYTES_PER_ELEMENT).sum()
+}
+
+// Collective operations across the data-parallel replicas of one run. Every replica must
+// make the same calls in the same order.
+pub trait Collective: Send + Sync {
+    fn world_size(&self) -> usize;
+
+    fn rank(&self) -> usize;
+
+    // Replaces each tensor with its mean over the replicas.
+    fn all_reduce_mean(&self, tensors: &mut [Array2<f32>]);
+
+    // Replaces `tensors` with the root's.
+    fn broadcast(&self, tensors: &mut Vec<Array2<f32>>, root: usize);
+
+    // Payload bytes this replica has contributed so far.
+    fn bytes_sent(&self) -> usize;
+}
+
+struct Exchange {
+    slots: Mutex<Vec<Vec<Array2<f32>>>>,
+    barrier: Barrier,
+}
+
+// In-process collective for replicas running as threads of one process.
+pub struct LocalCollective {
+    rank: usize,
+    exchange: Arc<Exchange>,
+    sent: AtomicUsize,
+}
+
+impl LocalCollective {
+    // One handle per replica; hand each to its own thread.
+    pub fn group(world_size: usize) -> Vec<LocalCollective> {
+        assert!(world_size > 0, "need at least one replica");
+        let exchange = Arc::new(Exchange { slots: Mutex::new(vec![Vec::new(); world_size]), barrier: Barrier::new(world_size) });
+        (0..world_size)
+            .map(|rank| LocalCollective { rank, exchange: Arc::clone(&exchange), sent: AtomicUsize::new(0) })
+            .collect()
+    }
+}
+
+impl Collective for LocalCollective {
+    fn world_size(&self) -> usize {
+        self.exchange.slots.lock().unwrap().len()
+    }
+
+    fn rank(&self) -> usize {
+        self.rank
+    }
+
+    fn all_reduce_mean(&self, tensors: &mut [Array2<f32>]) {
+        self.exchange.slots.lock().unwrap()[self.rank] = tensors.to_vec();
+        self.exchange.barrier.wait();
+        {
+            let slots = self.exchange.slots.lock().unwrap();
+            let n = slots.len() as f32;
+            for (i, tensor) in tensors.iter_mut().enumerate() {
+                tensor.fill(0.0);
+                for slot in slots.iter() {
+                    tensor.scaled_add(1.0 / n, &slot[i]);
+                }
+            }
+        }
+        // Nobody may overwrite its slot for the next call before everyone has read this one.
+        self.exchange.barrier.wait();
+        self.sent.fetch_add(bytes(tensors), Ordering::Relaxed);
+    }
+
+    fn broadcast(&self, tensors: &mut Vec<Array2<f32>>, root: usize) {
+        if self.rank == root {
+            self.exchange.slots.lock().unwrap()[root] = tensors.clone();
+            self.sent.fetch_add(bytes(tensors), Ordering::Relaxed);
+        }
+        self.exchange.barrier.wait();
+        if self.rank != root {
+            *tensors = self.exchange.slots.lock().unwrap()[root].clone();
+        }
+        self.exchange.barrier.wait();
+    }
+
+    fn bytes_sent(&self) -> usize {
+        self.sent.load(Ordering::Relaxed)
+    }
+}
+
+// What data-parallel replicas average each step. `Full` all-reduces the m x n gradients
+// before projection. `Compressed` all-reduces the projected r x n (or m x r, r x r)
+// gradients instead, which cuts traffic by the same factor GaLore cuts optimizer memory.
+//
+// Averaging projected gradients is only meaningful if every replica projects with the
+// same P/Q. Replicas refreshing from their own local gradients would not, so the full
+// gradients of the parameters whose refresh is due are all-reduced first and every replica
+// decomposes the same matrices; the other parameters still go projected. Every
+// `sync_every` steps (0 disables it) rank 0's subspaces are broadcast as well and adopted
+// wherever a replica's differ, which catches decompositions that are not bit-for-bit
+// reproducible across replicas (randomized SVD seeds, thread-count dependent reductions).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AllReduceMode {
+    Full,
+    Compressed { sync_every: usize },
+}
+
+// Which gradients the first stage reduced at full size, the bytes that took, and what an
+// uncompressed all-reduce of the whole step would send.
+#[derive(Default)]
+struct ReduceState {
+    reduced_full: Vec<bool>,
+    sent_bytes: usize,
+    full_bytes: usize,
+}
+
+// The stage before "project". Before the first step it also checks that every replica
+// registered the same tensors (names and shapes, in order; or no registry at all), since the
+// reductions pair tensors up by position. Every replica reaches the same verdict, so on a
+// mismatch all of them fail their step with `PipelineError::Check` instead of some
+// waiting forever in the next reduction. Records "all_reduce/bytes" and "all_reduce/full_bytes" (what
+// an uncompressed all-reduce of the same step would send) and, on subspace syncs,
+// "all_reduce/diverged", the number of parameters whose subspaces differed from rank 0's.
+pub struct GradAllReduce {
+    collective: Arc<dyn Collective>,
+    mode: AllReduceMode,
+    state: Arc<Mutex<ReduceState>>,
+    checked_registry: bool,
+}
+
+// The stage after "project" in compressed mode.
+pub struct ProjectedAllReduce {
+    collective: Arc<dyn Collective>,
+    state: Arc<Mutex<ReduceState>>,
+}
+
+// The stages for `mode`: the first goes before "project", the second (compressed mode
+// only) right after it.
+pub fn all_reduce_stages(collective: Arc<dyn Collective>, mode: AllReduceMode) -> (GradAllReduce, Option<ProjectedAllReduce>) {
+    let state = Arc::new(Mutex::new(ReduceState::default()));
+    let projected = match mode {
+        AllReduceMode::Full => None,
+        AllReduceMode::Compressed { .. } => {
+            Some(ProjectedAllReduce { collective: Arc::clone(&collective), state: Arc::clone(&state) })
+        }
+    };
+    (GradAllReduce { collective, mode, state, checked_registry: false }, projected)
+}
+
+impl GradAllReduce {
+    // Compares the registry fingerprint with rank 0's, sent as four exact 16-bit pieces (all
+    // zero without a registry), then averages the per-replica mismatch flags so that every
+    // replica learns how many differ.
+    fn check_registry(&self, registry: Option<&TensorRegistry>) -> Result<(), PipelineError> {
+        let fingerprint = registry.map_or(0, TensorRegistry::fingerprint);
+        let pieces = Array2::from_shape_fn((1, 4), |(_, k)| ((fingerprint >> (16 * k)) & 0xFFFF) as f32);
+        let mut root = vec![pieces.clone()];
+        self.collective.broadcast(&mut root, 0);
+        let mut mismatched = [Array2::from_elem((1, 1), if root[0] == pieces { 0.0 } else { 1.0 })];
+        self.collective.all_reduce_mean(&mut mismatched);
+        let differing = (mismatched[0][[0, 0]] * self.collective.world_size() as f32).round() as usize;
+        if differing == 0 {
+            return Ok(());
+        }
+        Err(PipelineError::Check {
+            stage: self.name().to_string(),
+            reason: format!(
+                "{} of {} replicas registered different tensors than replica 0; gradients would be averaged with the wrong tensors",
+                differing,
+                self.collective.world_size()
+            ),
+        })
+    }
+
+    // Adopts rank 0's subspaces. Each subspace travels as a (P, Q) pair with 0 x 0
+    // placeholders for absent factors.
+    fn sync_subspaces(&self, galore: &mut GaLoreProjection) -> usize {
+        let local = galore.export_subspaces();
+        let empty = || Array2::zeros((0, 0));
+        let mut tensors: Vec<Array2<f32>> = local
+            .iter()
+            .flat_map(|subspace| match subspace {
+                Some(Subspace { p, q }) => [p.clone().unwrap_or_else(empty), q.clone().unwrap_or_else(empty)],
+                None => [empty(), empty()],
+            })
+            .collect();
+        self.collective.broadcast(&mut tensors, 0);
+        let present = |m: &Array2<f32>| (!m.is_empty()).then(|| m.clone());
+        let root: Vec<Option<Subspace>> = tensors
+            .chunks(2)
+            .map(|pair| match (present(&pair[0]), present(&pair[1])) {
+                (None, None) => None,
+                (p, q) => Some(Subspace { p, q }),
+            })
+            .collect();
+        let diverged = root.iter().zip(local.iter()).filter(|(a, b)| a != b).count() + root.len().abs_diff(local.len());
+        if diverged > 0 {
+            // Rank 0 exported these from its own projections, so they are well-formed.
+            galore.replace_subspaces(root).expect("rank 0 broadcast malformed subspaces");
+        }
+        diverged
+    }
+}
+
+impl GradTransform for GradAllReduce {
+    fn name(&self) -> &str {
+        "all_reduce"
+    }
+
+    fn check(&mut self, galore: &GaLoreProjection, _optimizer: &dyn Optimizer) -> Result<(), PipelineError> {
+        if self.checked_registry {
+            return Ok(());
+        }
+        self.check_registry(galore.registry())?;
+        self.checked_registry = true;
+        Ok(())
+    }
+
+    fn apply<'a>(&mut self, grads: Vec<Grad<'a>>, ctx: &mut TransformContext) -> Vec<Grad<'a>> {
+        let full_bytes = grads.iter().map(|g| g.len() * BYTES_PER_ELEMENT).sum();
+        let reduce_full = match self.mode {
+            AllReduceMode::Full => vec![true; grads.len()],
+            AllReduceMode::Compressed { .. } => ctx.galore.refresh_pending_params(grads.len()),
+        };
+
+        let mut grads = grads;
+        let due: Vec<usize> = (0..grads.len()).filter(|&i| reduce_full[i]).collect();
+        let mut sent_bytes = 0;
+        if !due.is_empty() {
+            let mut full: Vec<Array2<f32>> = due.iter().map(|&i| take(&mut grads[i])).collect();
+            self.collective.all_reduce_mean(&mut full);
+            sent_bytes = bytes(&full);
+            for (&i, grad) in due.iter().zip(full) {
+                grads[i] = CowArray::from(grad);
+            }
+        }
+        let all_full = due.len() == grads.len();
+        *self.state.lock().unwrap() = ReduceState { reduced_full: reduce_full, sent_bytes, full_bytes };
+
+        if all_full {
+            let metrics = ctx.galore.metrics_mut();
+            metrics.record(format!("{}/bytes", self.name()), ctx.step, sent_bytes as f32);
+            metrics.record(format!("{}/full_bytes", self.name()), ctx.step, full_bytes as f32);
+            return grads;
+        }
+        if let AllReduceMode::Compressed { sync_every } = self.mode {
+            if sync_every > 0 && ctx.step.is_multiple_of(sync_every) {
+                let diverged = self.sync_subspaces(ctx.galore);
+                ctx.galore.metrics_mut().record(format!("{}/diverged", self.name()), ctx.step, diverged as f32);
+            }
+        }
+        grads
+    }
+}
+
+impl GradTransform for ProjectedAllReduce {
+    fn name(&self) -> &str {
+        "all_reduce_projected"
+    }
+
+    fn apply<'a>(&mut self, grads: Vec<Grad<'a>>, ctx: &mut TransformContext) -> Vec<Grad<'a>> {
+        let state = self.state.lock().unwrap();
+        let projected: Vec<usize> = (0..grads.len()).filter(|&i| !state.reduced_full.get(i).copied().unwrap_or(false)).collect();
+        if projected.is_empty() {
+            return grads;
+        }
+        let mut grads = grads;
+        let mut reduced: Vec<Array2<f32>> = projected.iter().map(|&i| take(&mut grads[i])).collect();
+        self.collective.all_reduce_mean(&mut reduced);
+        let sent_bytes = state.sent_bytes + bytes(&reduced);
+        for (&i, grad) in projected.iter().zip(reduced) {
+            grads[i] = CowArray::from(grad);
+        }
+        let metrics = ctx.galore.metrics_mut();
+        metrics.record("all_reduce/bytes", ctx.step, sent_bytes as f32);
+        metrics.record("all_reduce/full_bytes", ctx.step, state.full_bytes as f32);
+        grads
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::galore::optimizer::Adam;
+    use std::time::Duration;
+
+    // Gradient of |w - target|^2 / 2, with worker 0 much slower than the others.
+    struct Quadratic {
+        target: Array2<f32>,
+        slow: Duration,
+        fast: Duration,
+    }
+
+    impl GradientTask for Quadratic {
+        fn gradients(&self, weights: &[Array2<f32>], worker: usize, _step: usize) -> Vec<Array2<f32>> {
+            thread::sleep(if worker == 0 { self.slow } else { self.fast });
+            vec![&weights[0] - &self.target]
+        }
+    }
+
+    fn server() -> ParameterServer<Adam> {
+        let optimizer = GaLoreOptimizer::new(Adam::new(1e-2, 0.9, 0.999, 1e-8), 2, 200, 0.0);
+        ParameterServer::new(optimizer, vec![Array2::zeros((6, 8))]).with_refresh_every(25)
+    }
+
+    fn task(slow_ms: u64) -> Arc<dyn GradientTask> {
+        let target = Array2::from_shape_fn((6, 8), |(i, j)| ((i * 8 + j) as f32 * 0.3).sin());
+        Arc::new(Quadratic { target, slow: Duration::from_millis(slow_ms), fast: Duration::from_millis(1) })
+    }
+
+    // A replica's final parameters and its "all_reduce/bytes" and "all_reduce/full_bytes".
+    type ReplicaRun = (Vec<Array2<f32>>, Vec<(usize, f32)>, Vec<(usize, f32)>);
+
+    // Two replicas with different gradients training the same parameters for `steps` steps.
+    fn data_parallel(mode: AllReduceMode, steps: usize) -> Vec<ReplicaRun> {
+        let shapes = [(6, 10), (8, 5), (7, 7)];
+        let handles: Vec<_> = LocalCollective::group(2)
+            .into_iter()
+            .map(|collective| {
+                thread::spawn(move || {
+                    let rank = collective.rank();
+                    let projection = GaLoreProjection::new(2, 3, 0.0).with_staggered_refresh(true);
+                    let mut optimizer = GaLoreOptimizer::with_projection(Adam::new(1e-2, 0.9, 0.999, 1e-8), projection)
+                        .with_all_reduce(Arc::new(collective), mode);
+                    let mut params: Vec<Array2<f32>> = shapes.iter().map(|&shape| Array2::zeros(shape)).collect();
+                    for step in 0..steps {
+                        let grads: Vec<Array2<f32>> = shapes
+                            .iter()
+                            .enumerate()
+                            .map(|(k, &shape)| {
+                                Array2::from_shape_fn(shape, |(i, j)| (((i + 1) * (j + 2) * (k + 1) + 5 * rank + step) as f32 * 0.37).sin())
+                            })
+                            .collect();
+                        optimizer.apply_updates(&mut params, grads.iter().map(|g| g.view()).collect()).unwrap();
+                    }
+                    let series = |name: &str| optimizer.metrics().series(name).unwrap_or_default().to_vec();
+                    (params, series("all_reduce/bytes"), series("all_reduce/full_bytes"))
+                })
+            })
+            .collect();
+        handles.into_iter().map(|h| h.join().unwrap()).collect()
+    }
+
+    #[test]
+    fn compressed_mode_reduces_only_due_parameters_in_full() {
+        let steps = 9;
+        let compressed = data_parallel(AllReduceMode::Compressed { sync_every: 0 }, steps);
+        let full = data_parallel(AllReduceMode::Full, steps);
+        // Projection is linear, so averaging projected gradients matches averaging full ones.
+        for ((params, _, _), (expected, _, _)) in compressed.iter().zip(&full) {
+            for (p, e) in params.iter().zip(expected) {
+                assert!(p.abs_diff_eq(e, 1e-4));
+            }
+        }
+        assert_eq!(compressed[0].0, compressed[1].0);
+
+        // With staggered refresh some parameter is due nearly every step; only the first
+        // step, with no projections yet, should send everything at full size.
+        let (_, sent, full_bytes) = &compressed[0];
+        assert_eq!(sent.len(), steps);
+        assert_eq!(sent[0].1, full_bytes[0].1);
+        for (&(step, sent), &(_, full)) in sent.iter().zip(full_bytes).skip(1) {
+            assert!(sent < full * 0.8, "step {}: sent {} of {} bytes", step, sent, full);
+        }
+    }
+
+    #[test]
+    fn mismatched_registries_fail_every_replica() {
+        let handles: Vec<_> = LocalCollective::group(2)
+            .into_iter()
+            .map(|collective| {
+                thread::spawn(move || {
+                    let name = if collective.rank() == 0 { "w" } else { "v" };
+                    let registry = TensorRegistry::from_shapes([(name, (6, 8))]).unwrap();
+                    let projection = GaLoreProjection::new(2, 3, 0.0).with_registry(Arc::new(registry));
+                    let mut optimizer = GaLoreOptimizer::with_projection(Adam::new(1e-2, 0.9, 0.999, 1e-8), projection)
+                        .with_all_reduce(Arc::new(collective), AllReduceMode::Full);
+                    let mut params = vec![Array2::<f32>::zeros((6, 8))];
+                    let grads = [Array2::<f32>::ones((6, 8))];
+                    let result = optimizer.apply_updates(&mut params, grads.iter().map(|g| g.view()).collect());
+                    (result, params)
+                })
+            })
+            .collect();
+        for handle in handles {
+            let (result, params) = handle.join().unwrap();
+            assert!(matches!(result, Err(PipelineError::Check { ref stage, .. }) if stage == "all_reduce"), "{:?}", result);
+            assert_eq!(params[0], Array2::<f32>::zeros((6, 8)));
+        }
+    }
+
+    #[test]
+    fn applies_the_requested_number_of_updates() {
+        let mut server = server();
+        let report = server.run(task(0), 3, 60).unwrap();
+        assert_eq!(report.updates, 60);
+        assert!(report.refreshes >= 2, "{:?}", report);
+        assert_eq!(report.too_stale, 0);
+        assert!(report.sent_bytes < report.full_bytes);
+    }
+
+    #[test]
+    fn drops_gradients_beyond_the_staleness_limit() {
+        let mut server = server().with_max_staleness(2);
+        let report = server.run(task(10), 3, 80).unwrap();
+        assert_eq!(report.updates, 80);
+        assert!(report.max_staleness <= 2, "{:?}", report);
+        // The slow worker's gradients are many updates old by the time they arrive.
+        assert!(report.too_stale > 0, "{:?}", report);
+    }
+}
@@ -0,0 +1,156 @@
+use ndarray::{Array2, ArrayD};
+
+use super::checkpoint::{Checkpoint, CheckpointError};
+
+// Optimizer and projection state saved alongside the weights; averaging it is meaningless,
+// so soups carry model weights only.
+const STATE_PREFIXES: [&str; 2] = ["galore.", "optimizer."];
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum SoupWeighting {
+    Uniform,
+    // One metric per checkpoint. Weights are proportional to the metric, or to its inverse
+    // when lower is better (e.g. validation loss), so all metrics must be positive.
+    Metric { metrics: Vec<f64>, higher_is_better: bool },
+}
+
+impl SoupWeighting {
+    pub fn weights(&self, count: usize) -> Result<Vec<f32>, CheckpointError> {
+        let raw: Vec<f64> = match self {
+            SoupWeighting::Uniform => vec![1.0; count],
+            SoupWeighting::Metric { metrics, higher_is_better } => {
+                if metrics.len() != count {
+                    return Err(CheckpointError::Format(format!(
+                        "{} metrics for {} checkpoints",
+                        metrics.len(),
+                        count
+                    )));
+                }
+                if let Some(bad) = metrics.iter().find(|m| !(m.is_finite() && **m > 0.0)) {
+                    return Err(CheckpointError::Format(format!("metric weights must be positive, got {}", bad)));
+                }
+                metrics.iter().map(|&m| if *higher_is_better { m } else { 1.0 / m }).collect()
+            }
+        };
+        let total: f64 = raw.iter().sum();
+        Ok(raw.iter().map(|w| (w / total) as f32).collect())
+    }
+}
+
+// Weighted average of the model weights in `checkpoints` ("model soup"). Every checkpoint
+// must hold the same weight tensors with the same shapes. The soup records its member
+// count and weights in the metadata.
+pub fn average(checkpoints: &[Checkpoint], weighting: &SoupWeighting) -> Result<Checkpoint, CheckpointError> {
+    let first = checkpoints
+        .first()
+        .ok_or_else(|| CheckpointError::Format("no checkpoints to average".to_string()))?;
+    let weights = weighting.weights(checkpoints.len())?;
+
+    let mut soup = Checkpoint::new();
+    for (name, tensor) in first.tensors().filter(|(name, _)| !is_state(name)) {
+        let mut sum = ArrayD::<f32>::zeros(tensor.raw_dim());
+        for (i, (checkpoint, &weight)) in checkpoints.iter().zip(weights.iter()).enumerate() {
+            let member = checkpoint
+                .tensor(name)
+                .ok_or_else(|| CheckpointError::Missing(format!("{} in checkpoint {}", name, i)))?;
+            if member.shape() != tensor.shape() {
+                return Err(CheckpointError::Format(format!(
+                    "{}: shape {:?} in checkpoint {} does not match {:?}",
+                    name,
+                    member.shape(),
+                    i,
+                    tensor.shape()
+                )));
+            }
+            sum.scaled_add(weight, member);
+        }
+        soup.insert_tensor(name, sum);
+    }
+
+    for (i, checkpoint) in checkpoints.iter().enumerate().skip(1) {
+        if let Some((name, _)) = checkpoint.tensors().find(|(name, _)| !is_state(name) && first.tensor(name).is_none()) {
+            return Err(CheckpointError::Format(format!("{} only present in checkpoint {}", name, i)));
+        }
+    }
+
+    soup.set_metadata("ensemble.members", checkpoints.len());
+    let weights: Vec<String> = weights.iter().map(|w| w.to_string()).collect();
+    soup.set_metadata("ensemble.weights", weights.join(","));
+    Ok(soup)
+}
+
+// Evaluates each named candidate (e.g. "soup", "ema", "last") with the same metric so the
+// merged model can be compared against its members.
+pub fn compare<F>(candidates: &[(&str, &Checkpoint)], evaluate: F) -> Vec<(String, Result<f64, String>)>
+where
+    F: Fn(&Checkpoint) -> Result<f64, String>,
+{
+    candidates
+        .iter()
+        .map(|(name, checkpoint)| (name.to_string(), evaluate(checkpoint)))
+        .collect()
+}
+
+// Scores `checkpoint` in-process: the tensors `names` are loaded as matrices, in the order
+// `score` expects them, and handed to it. Pass it to `compare` as
+// `|c| evaluate(c, &names, &score)`.
+pub fn evaluate<F>(checkpoint: &Checkpoint, names: &[String], score: F) -> Result<f64, String>
+where
+    F: Fn(&[Array2<f32>]) -> Result<f64, String>,
+{
+    let weights = names
+        .iter()
+        .map(|name| checkpoint.matrix(name).map_err(|e| format!("{}: {}", name, e)))
+        .collect::<Result<Vec<_>, _>>()?;
+    score(&weights)
+}
+
+fn is_state(name: &str) -> bool {
+    STATE_PREFIXES.iter().any(|prefix| name.starts_with(prefix))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The "loss" of a model is the mean of its first weight.
+    fn mean(weights: &[Array2<f32>]) -> Result<f64, String> {
+        Ok(weights[0].mean().unwrap() as f64)
+    }
+
+    fn checkpoint(value: f32) -> Checkpoint {
+        let mut checkpoint = Checkpoint::new();
+        checkpoint.insert_tensor("model.w", ArrayD::from_elem(vec![2, 3], value));
+        checkpoint.insert_tensor("optimizer.m", ArrayD::from_elem(vec![2, 3], 100.0));
+        checkpoint
+    }
+
+    #[test]
+    fn metric_weighting_favours_the_better_checkpoint() {
+        let lower = SoupWeighting::Metric { metrics: vec![1.0, 3.0], higher_is_better: false };
+        assert_eq!(lower.weights(2).unwrap(), vec![0.75, 0.25]);
+        let higher = SoupWeighting::Metric { metrics: vec![1.0, 3.0], higher_is_better: true };
+        assert_eq!(higher.weights(2).unwrap(), vec![0.25, 0.75]);
+        assert!(lower.weights(3).is_err());
+        assert!(SoupWeighting::Metric { metrics: vec![0.0], higher_is_better: true }.weights(1).is_err());
+    }
+
+    #[test]
+    fn soup_is_scored_in_process_with_its_members() {
+        let members = [checkpoint(1.0), checkpoint(3.0)];
+        let soup = average(&members, &SoupWeighting::Uniform).unwrap();
+        assert!(soup.tensor("optimizer.m").is_none());
+        assert_eq!(soup.metadata("ensemble.members"), Some("2"));
+
+        let names = ["model.w".to_string()];
+        let candidates = [("soup", &soup), ("first", &members[0]), ("last", &members[1])];
+        let scores = compare(&candidates, |c| evaluate(c, &names, mean));
+        let expected = [("soup", 2.0), ("first", 1.0), ("last", 3.0)];
+        for ((name, score), (expected_name, value)) in scores.iter().zip(expected) {
+            assert_eq!((name.as_str(), score.as_ref().ok()), (expected_name, Some(&value)));
+        }
+
+        let missing = evaluate(&soup, &["model.v".to_string()], mean).unwrap_err();
+        assert!(missing.starts_with("model.v:"), "{}", missing);
+    }
+}
@@ -0,0 +1,274 @@
+use ndarray::Array2;
+use std::collections::{BTreeMap, VecDeque};
+use std::sync::mpsc::{self, Receiver};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use super::metrics::Metrics;
+
+// A validation set evaluated batch by batch against a set of weights. Each batch returns
+// named values (e.g. "loss", "accuracy") that are averaged over the batches of one run.
+pub trait EvalTask: Send + Sync {
+    fn num_batches(&self) -> usize;
+
+    fn evaluate(&self, weights: &[Array2<f32>], batch: usize) -> Vec<(String, f32)>;
+}
+
+// Runs every batch of `task` on `weights` on the calling thread and averages each value.
+pub fn evaluate_all(task: &dyn EvalTask, weights: &[Array2<f32>]) -> BTreeMap<String, f32> {
+    run_batches(task, weights, 0..task.num_batches())
+}
+
+fn run_batches(task: &dyn EvalTask, weights: &[Array2<f32>], batches: impl ExactSizeIterator<Item = usize>) -> BTreeMap<String, f32> {
+    let n = batches.len().max(1) as f32;
+    let mut sums: BTreeMap<String, f32> = BTreeMap::new();
+    for batch in batches {
+        for (name, value) in task.evaluate(weights, batch) {
+            *sums.entry(name).or_insert(0.0) += value;
+        }
+    }
+    sums.into_iter().map(|(name, sum)| (name, sum / n)).collect()
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct EvalReport {
+    pub step: usize,
+    pub batches: usize,
+    pub metrics: BTreeMap<String, f32>,
+    pub duration: Duration,
+}
+
+impl EvalReport {
+    // Records each value as "eval/<name>" at the step the snapshot was taken.
+    pub fn record(&self, metrics: &mut Metrics) {
+        for (name, &value) in &self.metrics {
+            metrics.record(format!("eval/{}", name), self.step, value);
+        }
+    }
+}
+
+struct Running {
+    step: usize,
+    receiver: Receiver<EvalReport>,
+    handle: JoinHandle<()>,
+}
+
+// Online evaluation that does not block training: every `every` steps the weights are
+// copied and up to `max_batches` validation batches run on a background thread while
+// training continues. Successive runs take consecutive windows of batches, so a limited
+// batch count still covers the whole set over time. If the previous run has not finished
+// when the next one is due, the new one is skipped rather than queued.
+pub struct StreamingEvaluator {
+    task: Arc<dyn EvalTask>,
+    every: usize,
+    max_batches: usize,
+    next_batch: usize,
+    running: Option<Running>,
+    completed: VecDeque<EvalReport>,
+    skipped: usize,
+}
+
+impl StreamingEvaluator {
+    pub fn new(task: Arc<dyn EvalTask>, every: usize) -> Self {
+        assert!(every > 0, "evaluation cadence must be positive");
+        let max_batches = task.num_batches();
+        StreamingEvaluator {
+            task,
+            every,
+            max_batches,
+            next_batch: 0,
+            running: None,
+            completed: VecDeque::new(),
+            skipped: 0,
+        }
+    }
+
+    pub fn with_max_batches(mut self, max_batches: usize) -> Self {
+        self.max_batches = max_batches;
+        self
+    }
+
+    // Starts a run on a copy of `weights` if one is due at `step`. Returns whether it did.
+    pub fn maybe_start(&mut self, step: usize, weights: &[Array2<f32>]) -> bool {
+        if !step.is_multiple_of(self.every) {
+            return false;
+        }
+        self.collect_finished();
+        if self.running.is_some() {
+            self.skipped += 1;
+            return false;
+        }
+        self.start(step, weights.to_vec());
+        true
+    }
+
+    fn start(&mut self, step: usize, snapshot: Vec<Array2<f32>>) {
+        let total = self.task.num_batches();
+        let count = self.max_batches.min(total);
+        let batches: Vec<usize> = (0..count).map(|k| (self.next_batch + k) % total.max(1)).collect();
+        self.next_batch = (self.next_batch + count) % total.max(1);
+
+        let task = Arc::clone(&self.task);
+        let (sender, receiver) = mpsc::channel();
+        let handle = thread::spawn(move || {
+            let start = Instant::now();
+            let metrics = run_batches(&*task, &snapshot, batches.iter().copied());
+            // The receiver may be gone if the evaluator was dropped; nothing to report then.
+            let _ = sender.send(EvalReport { step, batches: batches.len(), metrics, duration: start.elapsed() });
+        });
+        self.running = Some(Running { step, receiver, handle });
+    }
+
+    // The oldest report not yet returned, if any run has finished; never blocks.
+    pub fn poll(&mut self) -> Option<EvalReport> {
+        self.collect_finished();
+        self.completed.pop_front()
+    }
+
+    // Waits for the current run, e.g. at the end of training, and returns every report not
+    // yet polled.
+    pub fn finish(&mut self) -> Vec<EvalReport> {
+        if let Some(running) = self.running.take() {
+            self.join(running);
+        }
+        self.completed.drain(..).collect()
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.running.as_ref().is_some_and(|running| !running.handle.is_finished())
+    }
+
+    fn collect_finished(&mut self) {
+        if let Some(running) = self.running.take() {
+            if running.handle.is_finished() {
+                self.join(running);
+            } else {
+                self.running = Some(running);
+            }
+        }
+    }
+
+    // A panic in the evaluation thread is re-raised here, on the training thread.
+    fn join(&mut self, running: Running) {
+        let report = running.receiver.recv();
+        if let Err(panic) = running.handle.join() {
+            std::panic::resume_unwind(panic);
+        }
+        match report {
+            Ok(report) => self.completed.push_back(report),
+            Err(_) => panic!("evaluation at step {} finished without a report", running.step),
+        }
+    }
+
+    // Runs that were due while the previous one was still going.
+    pub fn skipped(&self) -> usize {
+        self.skipped
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    // Reports each batch index as "batch" and waits on `gate` first, so a test holding the
+    // gate keeps a run in flight.
+    struct CountingTask {
+        batches: usize,
+        gate: Mutex<()>,
+        seen: Mutex<Vec<usize>>,
+    }
+
+    impl CountingTask {
+        fn new(batches: usize) -> Arc<Self> {
+            Arc::new(CountingTask { batches, gate: Mutex::new(()), seen: Mutex::new(Vec::new()) })
+        }
+    }
+
+    impl EvalTask for CountingTask {
+        fn num_batches(&self) -> usize {
+            self.batches
+        }
+
+        fn evaluate(&self, _weights: &[Array2<f32>], batch: usize) -> Vec<(String, f32)> {
+            drop(self.gate.lock().unwrap());
+            self.seen.lock().unwrap().push(batch);
+            vec![("batch".to_string(), batch as f32)]
+        }
+    }
+
+    struct PanickingTask;
+
+    impl EvalTask for PanickingTask {
+        fn num_batches(&self) -> usize {
+            1
+        }
+
+        fn evaluate(&self, _weights: &[Array2<f32>], _batch: usize) -> Vec<(String, f32)> {
+            panic!("validation data is corrupt");
+        }
+    }
+
+    fn weights() -> Vec<Array2<f32>> {
+        vec![Array2::ones((2, 3))]
+    }
+
+    #[test]
+    fn evaluate_all_averages_every_batch() {
+        let task = CountingTask::new(4);
+        let metrics = evaluate_all(&*task, &weights());
+        assert_eq!(metrics.get("batch"), Some(&1.5));
+        assert_eq!(*task.seen.lock().unwrap(), [0, 1, 2, 3]);
+    }
+
+    #[test]
+    fn runs_start_on_the_cadence_and_take_consecutive_windows() {
+        let task = CountingTask::new(5);
+        let mut evaluator = StreamingEvaluator::new(task.clone(), 10).with_max_batches(3);
+        let mut reports = Vec::new();
+        for step in 0..35 {
+            let started = evaluator.maybe_start(step, &weights());
+            assert_eq!(started, step % 10 == 0, "step {}", step);
+            reports.extend(evaluator.finish());
+        }
+
+        assert_eq!(reports.iter().map(|r| r.step).collect::<Vec<_>>(), [0, 10, 20, 30]);
+        assert!(reports.iter().all(|r| r.batches == 3));
+        // Windows of three wrap around the five batches: 0 1 2, 3 4 0, 1 2 3, 4 0 1.
+        assert_eq!(*task.seen.lock().unwrap(), [0, 1, 2, 3, 4, 0, 1, 2, 3, 4, 0, 1]);
+        assert_eq!(reports[1].metrics.get("batch"), Some(&(7.0 / 3.0)));
+    }
+
+    #[test]
+    fn due_runs_are_skipped_while_one_is_in_flight_and_finish_drains_them() {
+        let task = CountingTask::new(2);
+        let mut evaluator = StreamingEvaluator::new(task.clone(), 5);
+        let gate = task.gate.lock().unwrap();
+        assert!(evaluator.maybe_start(0, &weights()));
+        assert!(!evaluator.maybe_start(5, &weights()));
+        assert!(!evaluator.maybe_start(10, &weights()));
+        assert_eq!(evaluator.skipped(), 2);
+        assert!(evaluator.is_running());
+        assert_eq!(evaluator.poll(), None);
+        drop(gate);
+
+        let reports = evaluator.finish();
+        assert_eq!(reports.len(), 1);
+        assert_eq!((reports[0].step, reports[0].batches), (0, 2));
+        assert!(!evaluator.is_running());
+        assert!(evaluator.finish().is_empty());
+
+        let mut metrics = Metrics::new();
+        reports[0].record(&mut metrics);
+        assert_eq!(metrics.latest("eval/batch"), Some(0.5));
+    }
+
+    #[test]
+    #[should_panic(expected = "validation data is corrupt")]
+    fn a_panicking_task_is_reraised_on_the_training_thread() {
+        let mut evaluator = StreamingEvaluator::new(Arc::new(PanickingTask), 1);
+        evaluator.maybe_start(0, &weights());
+        evaluator.finish();
+    }
+}
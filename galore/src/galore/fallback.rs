@@ -0,0 +1,89 @@
+use ndarray::{Array1, Array2};
+use std::collections::BTreeMap;
+use std::fmt;
+
+use super::metrics::Metrics;
+
+// Why a projection refresh was not used.
+#[derive(Clone, Debug, PartialEq)]
+pub enum FallbackReason {
+    DecompositionFailed(String),
+    // NaN or infinite singular values or vectors, usually from a NaN/inf gradient.
+    NonFinite,
+    // An all-zero gradient, whose singular vectors are arbitrary.
+    ZeroSpectrum,
+    // A structure hint that does not fit the gradient, e.g. heads that do not divide it.
+    BadHint(String),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FallbackAction {
+    KeptPrevious,
+    // There was no previous projection: a random orthonormal basis of the same shape.
+    RandomBasis,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct FallbackEvent {
+    pub step: usize,
+    pub param: usize,
+    pub reason: FallbackReason,
+    pub action: FallbackAction,
+}
+
+impl fmt::Display for FallbackEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let reason = match &self.reason {
+            FallbackReason::DecompositionFailed(e) => format!("decomposition failed ({})", e),
+            FallbackReason::NonFinite => "non-finite decomposition".to_string(),
+            FallbackReason::ZeroSpectrum => "zero gradient".to_string(),
+            FallbackReason::BadHint(e) => format!("bad structure hint ({})", e),
+        };
+        let action = match self.action {
+            FallbackAction::KeptPrevious => "kept the previous projection",
+            FallbackAction::RandomBasis => "used a random basis",
+        };
+        write!(f, "step {}, param {}: {}; {}", self.step, self.param, reason, action)
+    }
+}
+
+// Fallback events since the last clear, and per-parameter totals since the start.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct FallbackLog {
+    events: Vec<FallbackEvent>,
+    counts: BTreeMap<usize, usize>,
+}
+
+impl FallbackLog {
+    // Records `event` and its parameter's new total as "<label>/fallbacks" in `metrics`.
+    pub(crate) fn record(&mut self, event: FallbackEvent, label: &str, metrics: &mut Metrics) {
+        let count = self.counts.entry(event.param).or_insert(0);
+        *count += 1;
+        metrics.record(format!("{}/fallbacks", label), event.step, *count as f32);
+        self.events.push(event);
+    }
+
+    pub(crate) fn events(&self) -> &[FallbackEvent] {
+        &self.events
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.events.clear();
+    }
+
+    pub(crate) fn count(&self, param: usize) -> usize {
+        self.counts.get(&param).copied().unwrap_or(0)
+    }
+}
+
+// Rejects decompositions that cannot serve as a projection.
+pub(crate) fn check_decomposition(sigma: &Array1<f32>, bases: &[Option<&Array2<f32>>]) -> Result<(), FallbackReason> {
+    let finite = sigma.iter().all(|s| s.is_finite()) && bases.iter().flatten().all(|b| b.iter().all(|x| x.is_finite()));
+    if !finite {
+        return Err(FallbackReason::NonFinite);
+    }
+    if sigma.iter().all(|&s| s <= f32::MIN_POSITIVE) {
+        return Err(FallbackReason::ZeroSpectrum);
+    }
+    Ok(())
+}
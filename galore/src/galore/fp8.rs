@@ -0,0 +1,274 @@
+use ndarray::{Array2, ArrayView2};
+
+// 8-bit floating point in the two OCP formats: E4M3 (the "FN" variant: no infinities,
+// max 448) and E5M2 (IEEE-like, max 57344). Encoding rounds to nearest-even and saturates
+// to the largest finite value instead of overflowing.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Fp8Format {
+    E4M3,
+    E5M2,
+}
+
+impl Fp8Format {
+    fn exponent_bits(self) -> u32 {
+        match self {
+            Fp8Format::E4M3 => 4,
+            Fp8Format::E5M2 => 5,
+        }
+    }
+
+    fn mantissa_bits(self) -> u32 {
+        8 - 1 - self.exponent_bits()
+    }
+
+    fn bias(self) -> i32 {
+        (1 << (self.exponent_bits() - 1)) - 1
+    }
+
+    pub fn max(self) -> f32 {
+        match self {
+            Fp8Format::E4M3 => 448.0,
+            Fp8Format::E5M2 => 57344.0,
+        }
+    }
+
+    fn nan(self) -> u8 {
+        match self {
+            Fp8Format::E4M3 => 0x7F,
+            Fp8Format::E5M2 => 0x7E,
+        }
+    }
+
+    pub fn encode(self, x: f32) -> u8 {
+        if x.is_nan() {
+            return self.nan();
+        }
+        let sign = if x.is_sign_negative() { 0x80 } else { 0 };
+        let a = x.abs().min(self.max());
+        let mbits = self.mantissa_bits() as i32;
+        let min_exponent = 1 - self.bias();
+
+        // f32 subnormals are far below the fp8 range.
+        if a < f32::MIN_POSITIVE {
+            return sign;
+        }
+        let exponent = (((a.to_bits() >> 23) & 0xFF) as i32 - 127).max(min_exponent);
+        let quantum = 2f32.powi(exponent - mbits);
+        let q = round_half_even(a / quantum) * quantum;
+        if q == 0.0 {
+            return sign;
+        }
+
+        let q_exponent = ((q.to_bits() >> 23) & 0xFF) as i32 - 127;
+        let (exp_field, mantissa) = if q_exponent < min_exponent {
+            (0, (q / 2f32.powi(min_exponent - mbits)) as u32)
+        } else {
+            let mantissa = ((q / 2f32.powi(q_exponent) - 1.0) * (1 << mbits) as f32) as u32;
+            ((q_exponent + self.bias()) as u32, mantissa)
+        };
+        sign | ((exp_field << mbits) | mantissa) as u8
+    }
+
+    pub fn decode(self, byte: u8) -> f32 {
+        let sign = if byte & 0x80 != 0 { -1.0 } else { 1.0 };
+        let mbits = self.mantissa_bits();
+        let exp_field = ((byte & 0x7F) >> mbits) as i32;
+        let mantissa = (byte & ((1 << mbits) - 1)) as f32;
+        let max_field = (1 << self.exponent_bits()) - 1;
+
+        match self {
+            Fp8Format::E4M3 if exp_field == max_field && mantissa as u32 == (1 << mbits) - 1 => return f32::NAN,
+            Fp8Format::E5M2 if exp_field == max_field => {
+                return if mantissa == 0.0 { sign * f32::INFINITY } else { f32::NAN };
+            }
+            _ => {}
+        }
+        let scale = (1u32 << mbits) as f32;
+        if exp_field == 0 {
+            sign * mantissa / scale * 2f32.powi(1 - self.bias())
+        } else {
+            sign * (1.0 + mantissa / scale) * 2f32.powi(exp_field - self.bias())
+        }
+    }
+}
+
+fn round_half_even(x: f32) -> f32 {
+    let r = x.round();
+    if (x - x.trunc()).abs() == 0.5 && r % 2.0 != 0.0 {
+        r - x.signum()
+    } else {
+        r
+    }
+}
+
+// An f32 matrix stored as fp8 with one f32 scale per tile of `tile` consecutive elements
+// (row-major). Each tile is scaled so its largest magnitude maps to the format's max, which
+// keeps the relative error of a tile near the format's precision whatever its range.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Fp8Matrix {
+    dim: (usize, usize),
+    format: Fp8Format,
+    tile: usize,
+    data: Vec<u8>,
+    scales: Vec<f32>,
+}
+
+impl Fp8Matrix {
+    pub fn encode(matrix: &ArrayView2<f32>, format: Fp8Format, tile: usize) -> Self {
+        assert!(tile > 0, "tile size must be positive");
+        let values: Vec<f32> = matrix.iter().copied().collect();
+        let mut data = Vec::with_capacity(values.len());
+        let mut scales = Vec::with_capacity(values.len().div_ceil(tile));
+        for chunk in values.chunks(tile) {
+            let amax = chunk.iter().fold(0.0f32, |m, x| m.max(x.abs()));
+            let scale = if amax > 0.0 && amax.is_finite() { amax / format.max() } else { 1.0 };
+            data.extend(chunk.iter().map(|&x| format.encode(x / scale)));
+            scales.push(scale);
+        }
+        Fp8Matrix { dim: matrix.dim(), format, tile, data, scales }
+    }
+
+    pub fn decode(&self) -> Array2<f32> {
+        let values = self
+            .data
+            .chunks(self.tile)
+            .zip(self.scales.iter())
+            .flat_map(|(chunk, &scale)| chunk.iter().map(move |&b| self.format.decode(b) * scale))
+            .collect();
+        Array2::from_shape_vec(self.dim, values).expect("fp8 data matches its shape")
+    }
+
+    pub fn dim(&self) -> (usize, usize) {
+        self.dim
+    }
+
+    // Storage in bytes, scales included.
+    pub fn bytes(&self) -> usize {
+        self.data.len() + self.scales.len() * 4
+    }
+
+    // self += other, re-quantizing the sum. Each addition rounds again, so long
+    // accumulations drift more than a single encode.
+    pub fn add_assign(&mut self, other: &ArrayView2<f32>) {
+        assert_eq!(self.dim, other.dim(), "fp8 accumulation shape mismatch");
+        let sum = self.decode() + other;
+        *self = Fp8Matrix::encode(&sum.view(), self.format, self.tile);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ndarray::Array;
+
+    const FORMATS: [Fp8Format; 2] = [Fp8Format::E4M3, Fp8Format::E5M2];
+
+    // Smallest subnormal, which is also the quantum of the whole subnormal range.
+    fn min_subnormal(format: Fp8Format) -> f32 {
+        2f32.powi(1 - format.bias() - format.mantissa_bits() as i32)
+    }
+
+    #[test]
+    fn every_finite_byte_round_trips() {
+        for format in FORMATS {
+            for byte in 0..=255u8 {
+                let value = format.decode(byte);
+                if value.is_finite() {
+                    assert_eq!(format.encode(value), byte, "{:?} {:#04x} ({})", format, byte, value);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn saturates_to_the_largest_finite_value() {
+        for (format, max_byte) in [(Fp8Format::E4M3, 0x7E), (Fp8Format::E5M2, 0x7B)] {
+            assert_eq!(format.encode(format.max()), max_byte);
+            assert_eq!(format.decode(max_byte), format.max());
+            for beyond in [format.max() * 1.01, 1e30, f32::INFINITY] {
+                assert_eq!(format.encode(beyond), max_byte, "{:?} {}", format, beyond);
+                assert_eq!(format.encode(-beyond), max_byte | 0x80);
+            }
+        }
+        // Only E5M2 has infinities.
+        assert_eq!(Fp8Format::E5M2.decode(0x7C), f32::INFINITY);
+        assert_eq!(Fp8Format::E5M2.decode(0xFC), f32::NEG_INFINITY);
+    }
+
+    #[test]
+    fn nan_is_preserved() {
+        for format in FORMATS {
+            assert!(format.decode(format.encode(f32::NAN)).is_nan());
+            assert!(format.decode(format.encode(-f32::NAN)).is_nan());
+        }
+        assert!(Fp8Format::E4M3.decode(0xFF).is_nan());
+        assert!(Fp8Format::E5M2.decode(0x7F).is_nan());
+    }
+
+    #[test]
+    fn subnormal_boundaries() {
+        for format in FORMATS {
+            let tiny = min_subnormal(format);
+            let mantissa_max = (1u8 << format.mantissa_bits()) - 1;
+            assert_eq!(format.encode(tiny), 0x01);
+            assert_eq!(format.decode(0x01), tiny);
+            assert_eq!(format.encode(tiny * 0.75), 0x01);
+            // Halfway to the smallest subnormal rounds to (even) zero, keeping the sign.
+            assert_eq!(format.encode(tiny * 0.5), 0x00);
+            assert_eq!(format.encode(-tiny * 0.5), 0x80);
+            assert_eq!(format.encode(f32::from_bits(1)), 0x00);
+            // Largest subnormal, then the smallest normal one quantum above it.
+            assert_eq!(format.encode(tiny * mantissa_max as f32), mantissa_max);
+            let min_normal = tiny * (mantissa_max as f32 + 1.0);
+            assert_eq!(format.encode(min_normal), mantissa_max + 1);
+            assert_eq!(format.decode(mantissa_max + 1), min_normal);
+            // The tie between the two goes to the even code, the normal one.
+            assert_eq!(format.encode(tiny * (mantissa_max as f32 + 0.5)), mantissa_max + 1);
+        }
+    }
+
+    #[test]
+    fn ties_round_to_even() {
+        let e4m3 = Fp8Format::E4M3;
+        // Around 1.0 the E4M3 step is 1/8.
+        assert_eq!(e4m3.decode(e4m3.encode(1.0625)), 1.0);
+        assert_eq!(e4m3.decode(e4m3.encode(1.1875)), 1.25);
+        assert_eq!(e4m3.decode(e4m3.encode(1.9375)), 2.0);
+        assert_eq!(e4m3.decode(e4m3.encode(-1.0625)), -1.0);
+        // Just off a tie rounds to nearest.
+        assert_eq!(e4m3.decode(e4m3.encode(1.0626)), 1.125);
+        let e5m2 = Fp8Format::E5M2;
+        // Around 1.0 the E5M2 step is 1/4.
+        assert_eq!(e5m2.decode(e5m2.encode(1.125)), 1.0);
+        assert_eq!(e5m2.decode(e5m2.encode(1.375)), 1.5);
+    }
+
+    #[test]
+    fn matrix_round_trip_is_relative_to_each_tile() {
+        let matrix = Array::from_shape_fn((6, 50), |(i, j)| ((i * 50 + j) as f32 * 0.7).sin() * 10f32.powi(i as i32 - 3));
+        for format in FORMATS {
+            let encoded = Fp8Matrix::encode(&matrix.view(), format, 50);
+            assert_eq!(encoded.bytes(), 300 + 6 * 4);
+            let restored = encoded.decode();
+            // Half a step at the top of a tile: 2^-(mantissa bits + 1) of its maximum.
+            let step = 2f32.powi(-(format.mantissa_bits() as i32) - 1);
+            for (row, restored) in matrix.rows().into_iter().zip(restored.rows()) {
+                let amax = row.iter().fold(0.0f32, |m, x| m.max(x.abs()));
+                for (&x, &y) in row.iter().zip(restored.iter()) {
+                    assert!((x - y).abs() <= amax * step * 1.0001, "{:?}: {} restored as {}", format, x, y);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn all_zero_tiles_and_accumulation() {
+        let zeros = Array2::<f32>::zeros((2, 3));
+        let mut encoded = Fp8Matrix::encode(&zeros.view(), Fp8Format::E4M3, 4);
+        assert_eq!(encoded.decode(), zeros);
+        let ones = Array2::<f32>::ones((2, 3));
+        encoded.add_assign(&ones.view());
+        encoded.add_assign(&ones.view());
+        assert_eq!(encoded.decode(), ones * 2.0);
+    }
+}
@@ -0,0 +1,322 @@
+use ndarray::{Array1, Array2, Axis};
+use ndarray_rand::rand_distr::StandardNormal;
+use ndarray_rand::RandomExt;
+use rand::rngs::StdRng;
+use rand::SeedableRng;
+use std::fmt;
+
+use super::checkpoint::{Checkpoint, CheckpointError};
+use super::matrix_ops::{GaLoreOptimizer, GaLoreProjection, LayerRank, RankConfig};
+use super::optimizer::Adam;
+
+// Bumped when the layout of golden files changes.
+pub const GOLDEN_VERSION: u32 = 1;
+
+// One reference scenario: a single weight trained for `steps` steps with GaLore + Adam on
+// gradients with a geometrically decaying spectrum, so the leading subspace is well
+// separated and reproducible across BLAS backends.
+#[derive(Clone, Debug, PartialEq)]
+pub struct GoldenCase {
+    pub name: String,
+    pub rows: usize,
+    pub cols: usize,
+    pub rank: usize,
+    pub one_sided: bool,
+    pub update_freq: usize,
+    pub steps: usize,
+    pub seed: u64,
+}
+
+impl GoldenCase {
+    pub fn new(name: impl Into<String>, (rows, cols): (usize, usize), rank: usize) -> Self {
+        GoldenCase { name: name.into(), rows, cols, rank, one_sided: false, update_freq: 200, steps: 3, seed: 0 }
+    }
+
+    pub fn with_one_sided(mut self, one_sided: bool) -> Self {
+        self.one_sided = one_sided;
+        self
+    }
+
+    pub fn with_steps(mut self, steps: usize, update_freq: usize) -> Self {
+        self.steps = steps;
+        self.update_freq = update_freq;
+        self
+    }
+
+    pub fn with_seed(mut self, seed: u64) -> Self {
+        self.seed = seed;
+        self
+    }
+
+    // The case's input gradients.
+    pub fn gradients(&self) -> Vec<Array2<f32>> {
+        let mut rng = StdRng::seed_from_u64(self.seed);
+        let components = (self.rank + 4).min(self.rows.min(self.cols));
+        (0..self.steps)
+            .map(|_| {
+                let u = Array2::<f32>::random_using((self.rows, components), StandardNormal, &mut rng);
+                let v = Array2::<f32>::random_using((self.cols, components), StandardNormal, &mut rng);
+                let scale = Array1::from_iter((0..components).map(|k| 0.5f32.powi(k as i32)));
+                let noise = Array2::<f32>::random_using((self.rows, self.cols), StandardNormal, &mut rng);
+                (u * &scale.insert_axis(Axis(0))).dot(&v.t()) + noise * 1e-3
+            })
+            .collect()
+    }
+}
+
+// Covers two-sided and one-sided projections on tall, wide and square weights. "refresh"
+// recomputes its projection mid-run; Adam's moments carry over into the new subspace, so
+// its later updates also depend on the decomposition's sign convention, and a failure
+// confined to it on a new backend may be a sign flip rather than lost accuracy.
+pub fn default_cases() -> Vec<GoldenCase> {
+    vec![
+        GoldenCase::new("tall", (64, 32), 8).with_seed(1),
+        GoldenCase::new("wide_one_sided", (24, 80), 6).with_one_sided(true).with_seed(2),
+        GoldenCase::new("square", (40, 40), 4).with_seed(3),
+        GoldenCase::new("refresh", (48, 36), 6).with_steps(4, 2).with_seed(4),
+    ]
+}
+
+// Per-step outputs of a case, keyed "<step>.p", "<step>.q", "<step>.projected" and
+// "<step>.update".
+fn run_case(case: &GoldenCase, gradients: &[Array2<f32>]) -> Vec<(String, Array2<f32>)> {
+    let layer = LayerRank::LowRank { rank: case.rank, one_sided: case.one_sided };
+    let projection = GaLoreProjection::new(case.rank, case.update_freq, 0.0).with_rank_config(RankConfig::new(vec![layer]));
+    let mut optimizer = GaLoreOptimizer::with_projection(Adam::new(1e-3, 0.9, 0.999, 1e-8), projection);
+    let mut outputs = Vec::new();
+    for (t, gradient) in gradients.iter().enumerate() {
+        let update = optimizer.step(vec![gradient.view()]).unwrap().remove(0);
+        let projection = optimizer.projection();
+        if let Some(subspace) = projection.export_subspaces().remove(0) {
+            outputs.extend(subspace.p.map(|p| (format!("{}.p", t), p)));
+            outputs.extend(subspace.q.map(|q| (format!("{}.q", t), q)));
+        }
+        outputs.push((format!("{}.projected", t), projection.project_current(&[gradient.view()]).remove(0)));
+        outputs.push((format!("{}.update", t), update));
+    }
+    outputs
+}
+
+// Reference outputs for `cases`, together with their inputs and settings, so a later
+// `compare` replays exactly the same gradients even if the random number generator
+// changes. Needs a format with metadata (native or safetensors).
+pub fn generate(cases: &[GoldenCase]) -> Checkpoint {
+    let mut checkpoint = Checkpoint::new();
+    checkpoint.set_metadata("golden.version", GOLDEN_VERSION);
+    checkpoint.set_metadata("golden.crate_version", env!("CARGO_PKG_VERSION"));
+    checkpoint.set_metadata("golden.cases", cases.iter().map(|c| c.name.as_str()).collect::<Vec<_>>().join(","));
+    for case in cases {
+        let key = |field: &str| format!("{}.{}", case.name, field);
+        checkpoint.set_metadata(key("rank"), case.rank);
+        checkpoint.set_metadata(key("one_sided"), case.one_sided);
+        checkpoint.set_metadata(key("update_freq"), case.update_freq);
+        checkpoint.set_metadata(key("seed"), case.seed);
+        let gradients = case.gradients();
+        for (name, tensor) in run_case(case, &gradients) {
+            checkpoint.insert_tensor(key(&name), tensor);
+        }
+        for (t, gradient) in gradients.into_iter().enumerate() {
+            checkpoint.insert_tensor(key(&format!("{}.gradient", t)), gradient);
+        }
+    }
+    checkpoint
+}
+
+// Elementwise bound |actual - expected| <= abs + rel * |expected|.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Tolerance {
+    pub abs: f32,
+    pub rel: f32,
+}
+
+impl Default for Tolerance {
+    fn default() -> Self {
+        Tolerance { abs: 1e-4, rel: 1e-3 }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct GoldenDiff {
+    pub tensor: String,
+    pub max_abs: f32,
+    pub max_rel: f32,
+    pub passed: bool,
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct GoldenReport {
+    pub diffs: Vec<GoldenDiff>,
+    // Reference tensors the replay did not produce (or produced with another shape).
+    pub missing: Vec<String>,
+}
+
+impl GoldenReport {
+    pub fn passed(&self) -> bool {
+        self.missing.is_empty() && self.diffs.iter().all(|d| d.passed)
+    }
+
+    pub fn failures(&self) -> impl Iterator<Item = &GoldenDiff> {
+        self.diffs.iter().filter(|d| !d.passed)
+    }
+}
+
+impl fmt::Display for GoldenReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "{:<32} {:>12} {:>12}  result", "tensor", "max abs", "max rel")?;
+        for diff in &self.diffs {
+            let result = if diff.passed { "ok" } else { "FAIL" };
+            writeln!(f, "{:<32} {:>12.3e} {:>12.3e}  {}", diff.tensor, diff.max_abs, diff.max_rel, result)?;
+        }
+        for name in &self.missing {
+            writeln!(f, "{:<32} {:>12} {:>12}  MISSING", name, "-", "-")?;
+        }
+        let failed = self.failures().count() + self.missing.len();
+        write!(f, "{} of {} tensors within tolerance", self.diffs.len() + self.missing.len() - failed, self.diffs.len() + self.missing.len())
+    }
+}
+
+// Replays every case of a golden file from its stored gradients and compares the outputs.
+// Singular vectors are only defined up to sign, so the columns of P and Q are sign-aligned
+// with the reference first and the projected gradients flipped to match; the full-size
+// updates do not depend on the signs.
+pub fn compare(golden: &Checkpoint, tolerance: Tolerance) -> Result<GoldenReport, CheckpointError> {
+    let version: u32 = golden.parse_metadata("golden.version")?;
+    if version != GOLDEN_VERSION {
+        return Err(CheckpointError::Unsupported(format!("golden file version {}, expected {}", version, GOLDEN_VERSION)));
+    }
+    let names = golden.metadata("golden.cases").ok_or_else(|| CheckpointError::Missing("golden.cases".to_string()))?;
+    let mut report = GoldenReport::default();
+    for name in names.split(',').filter(|n| !n.is_empty()) {
+        let key = |field: &str| format!("{}.{}", name, field);
+        let mut gradients = Vec::new();
+        while let Some(gradient) = golden.tensor(&key(&format!("{}.gradient", gradients.len()))) {
+            let gradient = gradient.clone().into_dimensionality().map_err(|e| CheckpointError::Format(e.to_string()))?;
+            gradients.push(gradient);
+        }
+        let (rows, cols) = gradients.first().map(|g: &Array2<f32>| g.dim()).unwrap_or((0, 0));
+        let case = GoldenCase {
+            name: name.to_string(),
+            rows,
+            cols,
+            rank: golden.parse_metadata(&key("rank"))?,
+            one_sided: golden.parse_metadata(&key("one_sided"))?,
+            update_freq: golden.parse_metadata(&key("update_freq"))?,
+            steps: gradients.len(),
+            seed: golden.parse_metadata(&key("seed"))?,
+        };
+
+        let mut signs: (Option<Array1<f32>>, Option<Array1<f32>>) = (None, None);
+        for (output, actual) in run_case(&case, &gradients) {
+            let tensor = key(&output);
+            let expected = match golden.matrix(&tensor) {
+                Ok(expected) if expected.dim() == actual.dim() => expected,
+                _ => {
+                    report.missing.push(tensor);
+                    continue;
+                }
+            };
+            let actual = if output.ends_with(".p") {
+                let flips = column_signs(&expected, &actual);
+                let aligned = &actual * &flips.view().insert_axis(Axis(0));
+                signs.0 = Some(flips);
+                aligned
+            } else if output.ends_with(".q") {
+                let flips = column_signs(&expected, &actual);
+                let aligned = &actual * &flips.view().insert_axis(Axis(0));
+                signs.1 = Some(flips);
+                aligned
+            } else if output.ends_with(".projected") {
+                let mut aligned = actual;
+                if let Some(rows) = signs.0.take() {
+                    aligned *= &rows.insert_axis(Axis(1));
+                }
+                if let Some(cols) = signs.1.take() {
+                    aligned *= &cols.insert_axis(Axis(0));
+                }
+                aligned
+            } else {
+                actual
+            };
+            report.diffs.push(diff(tensor, &expected, &actual, tolerance));
+        }
+    }
+    Ok(report)
+}
+
+// +1/-1 per column so that each column of `actual` points the same way as in `expected`.
+fn column_signs(expected: &Array2<f32>, actual: &Array2<f32>) -> Array1<f32> {
+    let dots = (expected * actual).sum_axis(Axis(0));
+    dots.mapv(|d| if d < 0.0 { -1.0 } else { 1.0 })
+}
+
+fn diff(tensor: String, expected: &Array2<f32>, actual: &Array2<f32>, tolerance: Tolerance) -> GoldenDiff {
+    let mut max_abs = 0.0f32;
+    let mut max_rel = 0.0f32;
+    let mut passed = true;
+    for (&e, &a) in expected.iter().zip(actual.iter()) {
+        let error = (a - e).abs();
+        max_abs = max_abs.max(error);
+        if e != 0.0 {
+            max_rel = max_rel.max(error / e.abs());
+        }
+        // NaN errors fail the comparison.
+        passed &= error <= tolerance.abs + tolerance.rel * e.abs();
+    }
+    GoldenDiff { tensor, max_abs, max_rel, passed }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cases() -> Vec<GoldenCase> {
+        vec![
+            GoldenCase::new("two_sided", (12, 8), 2).with_steps(3, 2).with_seed(5),
+            GoldenCase::new("one_sided", (6, 10), 2).with_one_sided(true).with_seed(6),
+        ]
+    }
+
+    #[test]
+    fn a_fresh_replay_matches_its_golden_file() {
+        let golden = generate(&cases());
+        let report = compare(&golden, Tolerance::default()).unwrap();
+        assert!(report.passed(), "{}", report);
+        assert!(report.missing.is_empty());
+        // P, Q, projected and update for three steps, then P, projected and update for three.
+        assert_eq!(report.diffs.len(), 12 + 9);
+    }
+
+    #[test]
+    fn sign_flipped_bases_still_match() {
+        let mut golden = generate(&cases());
+        let mut p = golden.matrix("two_sided.0.p").unwrap();
+        let mut projected = golden.matrix("two_sided.0.projected").unwrap();
+        p.column_mut(1).mapv_inplace(|x| -x);
+        projected.row_mut(1).mapv_inplace(|x| -x);
+        golden.insert_tensor("two_sided.0.p", p);
+        golden.insert_tensor("two_sided.0.projected", projected);
+        assert!(compare(&golden, Tolerance::default()).unwrap().passed());
+    }
+
+    #[test]
+    fn perturbed_and_missing_tensors_are_reported_by_name() {
+        let mut golden = generate(&cases());
+        let update = golden.matrix("one_sided.1.update").unwrap() + 1e-2;
+        golden.insert_tensor("one_sided.1.update", update);
+        golden.remove_tensor("two_sided.2.q");
+
+        let report = compare(&golden, Tolerance::default()).unwrap();
+        assert!(!report.passed());
+        let failed: Vec<&str> = report.failures().map(|d| d.tensor.as_str()).collect();
+        assert_eq!(failed, ["one_sided.1.update"]);
+        assert_eq!(report.missing, ["two_sided.2.q"]);
+        assert!(report.to_string().contains("one_sided.1.update"));
+    }
+
+    #[test]
+    fn other_versions_are_rejected() {
+        let mut golden = generate(&cases());
+        golden.set_metadata("golden.version", GOLDEN_VERSION + 1);
+        assert!(matches!(compare(&golden, Tolerance::default()), Err(CheckpointError::Unsupported(_))));
+    }
+}
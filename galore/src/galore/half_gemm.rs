@@ -0,0 +1,227 @@
+use half::f16;
+use ndarray::{s, Array2, ArrayView1, ArrayView2, ArrayViewMut2};
+use rayon::prelude::*;
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock, Weak};
+
+// Mixed f16/f32 GEMM with f32 accumulation, for products with a projection factor P or Q.
+// The factors are converted to f16 once (see `HalfCache`) and the gradient or update stays
+// f32, so each product reads half the factor bytes of the f32 path and nothing is converted
+// per call. This is a portable cache-blocked kernel, not a tuned BLAS: it trades some
+// arithmetic throughput for factor bandwidth and memory, and is not expected to beat an
+// optimized sgemm when the factors fit in cache.
+
+// Output rows per parallel task at most, columns per block and inner-dimension panel.
+const MAX_ROWS: usize = 64;
+const COLS: usize = 256;
+const PANEL: usize = 256;
+
+pub trait Element: Copy + Send + Sync + 'static {
+    fn widen(self) -> f32;
+
+    // `row` as f32, using `scratch` only if it has to be converted or gathered.
+    fn widen_row<'a>(row: ArrayView1<'a, Self>, scratch: &'a mut [f32]) -> &'a [f32] {
+        for (dst, &x) in scratch.iter_mut().zip(row.iter()) {
+            *dst = x.widen();
+        }
+        &scratch[..row.len()]
+    }
+}
+
+impl Element for f32 {
+    fn widen(self) -> f32 {
+        self
+    }
+
+    fn widen_row<'a>(row: ArrayView1<'a, f32>, scratch: &'a mut [f32]) -> &'a [f32] {
+        match row.to_slice() {
+            Some(slice) => slice,
+            None => {
+                for (dst, &x) in scratch.iter_mut().zip(row.iter()) {
+                    *dst = x;
+                }
+                &scratch[..row.len()]
+            }
+        }
+    }
+}
+
+impl Element for f16 {
+    fn widen(self) -> f32 {
+        self.to_f32()
+    }
+}
+
+pub fn to_f16(matrix: &ArrayView2<f32>) -> Array2<f16> {
+    matrix.mapv(f16::from_f32)
+}
+
+// a * b.
+pub fn gemm<A: Element, B: Element>(a: &ArrayView2<A>, b: &ArrayView2<B>) -> Array2<f32> {
+    let mut out = Array2::zeros((a.nrows(), b.ncols()));
+    gemm_acc(a, b, &mut out.view_mut());
+    out
+}
+
+// out += a * b. Blocks of output rows run in parallel; within one, each panel of b is
+// widened once and reused for every row of the block.
+pub fn gemm_acc<A: Element, B: Element>(a: &ArrayView2<A>, b: &ArrayView2<B>, out: &mut ArrayViewMut2<f32>) {
+    let (m, k) = a.dim();
+    let (k2, n) = b.dim();
+    assert_eq!(k, k2, "inner dimensions differ");
+    assert_eq!(out.dim(), (m, n), "output shape differs");
+    if m == 0 || n == 0 || k == 0 {
+        return;
+    }
+    let Some(out_slice) = out.as_slice_mut() else {
+        *out += &gemm(a, b);
+        return;
+    };
+
+    let rows = m.div_ceil(2 * rayon::current_num_threads()).clamp(1, MAX_ROWS);
+    out_slice.par_chunks_mut(rows * n).enumerate().for_each(|(block, out_rows)| {
+        let i0 = block * rows;
+        let mut scratch = vec![0.0f32; PANEL * COLS.min(n)];
+        for j0 in (0..n).step_by(COLS) {
+            let nc = COLS.min(n - j0);
+            for p0 in (0..k).step_by(PANEL) {
+                let kc = PANEL.min(k - p0);
+                let panel = b.slice(s![p0..p0 + kc, j0..j0 + nc]);
+                let widened: Vec<&[f32]> = panel_rows(&panel, &mut scratch, nc);
+                for (di, out_row) in out_rows.chunks_mut(n).enumerate() {
+                    let out_row = &mut out_row[j0..j0 + nc];
+                    let a_row = a.slice(s![i0 + di, p0..p0 + kc]);
+                    for (&a_ip, b_row) in a_row.iter().zip(&widened) {
+                        let a_ip = a_ip.widen();
+                        if a_ip == 0.0 {
+                            continue;
+                        }
+                        for (acc, &b_pj) in out_row.iter_mut().zip(b_row.iter()) {
+                            *acc += a_ip * b_pj;
+                        }
+                    }
+                }
+            }
+        }
+    });
+}
+
+fn panel_rows<'a, B: Element>(panel: &'a ArrayView2<'a, B>, scratch: &'a mut [f32], width: usize) -> Vec<&'a [f32]> {
+    panel
+        .outer_iter()
+        .zip(scratch.chunks_mut(width))
+        .map(|(row, scratch)| B::widen_row(row, scratch))
+        .collect()
+}
+
+// f16 copies of projection factors, made on first use and dropped once the factor itself
+// is. Entries are keyed by the factor's address; the `Weak` held alongside keeps that
+// address from being reused by a later factor while the entry exists.
+#[derive(Default)]
+pub struct HalfCache {
+    entries: RwLock<HashMap<usize, CachedFactor>>,
+}
+
+type CachedFactor = (Weak<Array2<f32>>, Arc<Array2<f16>>);
+
+impl HalfCache {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn get(&self, factor: &Arc<Array2<f32>>) -> Arc<Array2<f16>> {
+        let key = Arc::as_ptr(factor) as usize;
+        if let Some((_, half)) = self.entries.read().expect("cache lock").get(&key) {
+            return half.clone();
+        }
+        let half = Arc::new(to_f16(&factor.view()));
+        let mut entries = self.entries.write().expect("cache lock");
+        entries.retain(|_, (weak, _)| weak.strong_count() > 0);
+        entries.entry(key).or_insert_with(|| (Arc::downgrade(factor), half)).1.clone()
+    }
+
+    // Bytes held by the f16 copies of live factors.
+    pub fn bytes(&self) -> usize {
+        self.entries
+            .read()
+            .expect("cache lock")
+            .values()
+            .filter(|(weak, _)| weak.strong_count() > 0)
+            .map(|(_, half)| half.len() * std::mem::size_of::<f16>())
+            .sum()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ndarray::Array;
+
+    fn reference(a: &ArrayView2<f32>, b: &ArrayView2<f32>) -> Array2<f32> {
+        Array::from_shape_fn((a.nrows(), b.ncols()), |(i, j)| {
+            a.row(i).iter().zip(b.column(j).iter()).map(|(&x, &y)| x as f64 * y as f64).sum::<f64>() as f32
+        })
+    }
+
+    fn matrix(rows: usize, cols: usize, seed: f32) -> Array2<f32> {
+        Array::from_shape_fn((rows, cols), |(i, j)| ((i * cols + j) as f32 * seed).sin())
+    }
+
+    fn max_error(x: &Array2<f32>, y: &Array2<f32>) -> f32 {
+        x.iter().zip(y.iter()).fold(0.0f32, |max, (a, b)| max.max((a - b).abs()))
+    }
+
+    #[test]
+    fn f32_operands_match_the_reference_across_block_edges() {
+        // Shapes straddle the row, column and panel block sizes.
+        let a = matrix(MAX_ROWS * 2 + 3, PANEL + 5, 0.31);
+        let b = matrix(PANEL + 5, COLS + 7, 0.17);
+        let expected = reference(&a.view(), &b.view());
+        assert!(max_error(&gemm(&a.view(), &b.view()), &expected) < 1e-3);
+        // Transposed (non-contiguous) operands.
+        let at = a.t().to_owned();
+        let bt = b.t().to_owned();
+        assert!(max_error(&gemm(&at.t(), &bt.t()), &expected) < 1e-3);
+    }
+
+    #[test]
+    fn f16_factor_stays_within_half_precision_of_the_f32_product() {
+        let grad = matrix(300, 90, 0.23);
+        let factor = matrix(90, 8, 0.41);
+        let expected = reference(&grad.view(), &factor.view());
+        let half = to_f16(&factor.view());
+        let got = gemm(&grad.view(), &half.view());
+        // Each factor entry carries a relative error of at most 2^-11 and |factor| <= 1.
+        let row_bound = grad.rows().into_iter().map(|r| r.iter().map(|x| x.abs()).sum::<f32>()).fold(0.0, f32::max);
+        assert!(max_error(&got, &expected) <= row_bound * 2.0f32.powi(-11) * 1.1 + 1e-5);
+
+        // Factor on the left, transposed, as in P^T G.
+        let other = matrix(90, 20, 0.13);
+        let got = gemm(&half.t(), &other.view());
+        let expected = reference(&factor.t(), &other.view());
+        let col_bound = other.columns().into_iter().map(|c| c.iter().map(|x| x.abs()).sum::<f32>()).fold(0.0, f32::max);
+        assert!(max_error(&got, &expected) <= col_bound * 2.0f32.powi(-11) * 1.1 + 1e-5);
+    }
+
+    #[test]
+    fn accumulates_into_the_output() {
+        let a = matrix(5, 4, 0.7);
+        let b = matrix(4, 3, 0.9);
+        let mut out = Array2::ones((5, 3));
+        gemm_acc(&a.view(), &b.view(), &mut out.view_mut());
+        assert!(max_error(&out, &(reference(&a.view(), &b.view()) + 1.0)) < 1e-5);
+    }
+
+    #[test]
+    fn cache_converts_each_factor_once_and_forgets_dropped_ones() {
+        let cache = HalfCache::new();
+        let factor = Arc::new(matrix(10, 4, 0.5));
+        let first = cache.get(&factor);
+        assert!(Arc::ptr_eq(&first, &cache.get(&factor)));
+        assert_eq!(cache.bytes(), 80);
+        drop(factor);
+        assert_eq!(cache.bytes(), 0);
+        let other = Arc::new(matrix(10, 4, 0.9));
+        assert_eq!(cache.get(&other)[[1, 1]], f16::from_f32(other[[1, 1]]));
+    }
+}
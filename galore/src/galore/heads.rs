@@ -0,0 +1,116 @@
+use ndarray::{s, Array2, ArrayView2};
+use ndarray_rand::rand_distr::StandardNormal;
+use ndarray_rand::RandomExt;
+use rand::rngs::StdRng;
+
+use super::decompose::orthonormalize;
+
+// The axis an attention weight's heads are laid out along: rows for the Q/K/V
+// projections, columns for the output projection.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HeadAxis {
+    Rows,
+    Cols,
+}
+
+// The length of one of `heads` heads along `axis` of an `m x n` weight, or why the heads
+// do not fit it.
+pub(crate) fn head_len(heads: usize, axis: HeadAxis, (m, n): (usize, usize)) -> Result<usize, String> {
+    let len = if axis == HeadAxis::Rows { m } else { n };
+    if heads == 0 || len % heads != 0 {
+        return Err(format!("{} heads do not divide the {:?} of a {}x{} weight", heads, axis, m, n));
+    }
+    Ok(len / heads)
+}
+
+// Head `h` of `grad`, `head_len` rows or columns wide.
+pub(crate) fn head_block<'a>(grad: &'a ArrayView2<f32>, axis: HeadAxis, head_len: usize, h: usize) -> ArrayView2<'a, f32> {
+    let range = h * head_len..(h + 1) * head_len;
+    match axis {
+        HeadAxis::Rows => grad.slice(s![range, ..]),
+        HeadAxis::Cols => grad.slice(s![.., range]),
+    }
+}
+
+// Per-head bases (each head_len x r) placed on the diagonal of one len x (heads * r)
+// basis: P^T G then stacks P_h^T G_h over the row blocks (G Q the G_h Q_h over column
+// blocks), which is exactly the per-head projection, and the rest of the pipeline sees an
+// ordinary projection of rank heads * r.
+pub(crate) fn block_diagonal(blocks: &[Array2<f32>]) -> Array2<f32> {
+    let (head_len, rank) = blocks[0].dim();
+    let mut basis = Array2::zeros((head_len * blocks.len(), rank * blocks.len()));
+    for (h, block) in blocks.iter().enumerate() {
+        basis.slice_mut(s![h * head_len..(h + 1) * head_len, h * rank..(h + 1) * rank]).assign(block);
+    }
+    basis
+}
+
+// A random orthonormal basis per head, in the layout of `block_diagonal`.
+pub(crate) fn random_head_basis(heads: usize, head_len: usize, rank: usize, rng: &mut StdRng) -> Array2<f32> {
+    let blocks: Vec<_> = (0..heads)
+        .map(|_| {
+            let gaussian = Array2::<f32>::random_using((head_len, rank), StandardNormal, rng);
+            orthonormalize(gaussian).expect("QR of a Gaussian matrix failed")
+        })
+        .collect();
+    block_diagonal(&blocks)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::galore::fallback::{FallbackAction, FallbackReason};
+    use crate::galore::matrix_ops::{GaLoreProjection, GradStructure, LayerRank, RankConfig};
+    use crate::galore::subspace::Subspace;
+    use crate::galore::testing::gradient;
+
+    fn projector(basis: &Array2<f32>) -> Array2<f32> {
+        basis.dot(&basis.t())
+    }
+
+    fn one_sided(rank: usize) -> GaLoreProjection {
+        GaLoreProjection::new(rank, 100, 0.0).with_rank_config(RankConfig::new(vec![LayerRank::LowRank { rank, one_sided: true }]))
+    }
+
+    #[test]
+    fn head_projection_is_block_diagonal_with_a_basis_per_head() {
+        let (heads, rank, head_len) = (3, 2, 8);
+        let mut galore = one_sided(rank).with_structure_hint(0, GradStructure::Heads { heads, axis: HeadAxis::Rows });
+        let g = gradient(0);
+        let projected = galore.project_gradient(vec![g.view()]).remove(0);
+
+        let Some(Subspace { p: Some(p), q: None }) = galore.export_subspaces().remove(0) else { panic!("left projection") };
+        assert_eq!(p.dim(), (heads * head_len, heads * rank));
+        assert!(p.t().dot(&p).abs_diff_eq(&Array2::eye(heads * rank), 1e-5));
+        for h in 0..heads {
+            let (rows, cols) = (h * head_len..(h + 1) * head_len, h * rank..(h + 1) * rank);
+            for other in (0..heads).filter(|&o| o != h) {
+                assert!(p.slice(s![rows.clone(), other * rank..(other + 1) * rank]).iter().all(|&x| x == 0.0));
+            }
+            // Each head's block is that head's own rank-r subspace.
+            let block = g.slice(s![rows.clone(), ..]);
+            let mut alone = one_sided(rank);
+            alone.project_gradient(vec![block]);
+            let Some(Subspace { p: Some(p_head), .. }) = alone.export_subspaces().remove(0) else { panic!("left projection") };
+            let own = p.slice(s![rows, cols.clone()]).to_owned();
+            assert!(projector(&own).abs_diff_eq(&projector(&p_head), 1e-4));
+            assert!(projected.slice(s![cols, ..]).abs_diff_eq(&own.t().dot(&block), 1e-5));
+        }
+    }
+
+    #[test]
+    fn heads_that_do_not_divide_the_weight_fall_back_instead_of_panicking() {
+        // 24 rows split neither into 5 heads nor into 0.
+        for heads in [5, 0] {
+            let mut galore = one_sided(2).with_structure_hint(0, GradStructure::Heads { heads, axis: HeadAxis::Rows });
+            assert_eq!(galore.check_shapes(&[(24, 40)]).len(), 1);
+            let projected = galore.project_gradient(vec![gradient(0).view()]).remove(0);
+            assert_eq!(projected.dim(), (2, 40));
+
+            let events = galore.fallback_events();
+            assert_eq!(events.len(), 1);
+            assert!(matches!(events[0].reason, FallbackReason::BadHint(_)));
+            assert_eq!(events[0].action, FallbackAction::RandomBasis);
+        }
+    }
+}
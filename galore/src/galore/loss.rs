@@ -0,0 +1,364 @@
+use ndarray::{Array1, Array2, ArrayView1, ArrayView2, Axis};
+
+use super::metrics::Metrics;
+use super::packing::PackedBatch;
+
+// What a loss is evaluated against. Rows of `outputs` are positions (or samples).
+#[derive(Clone, Copy, Debug)]
+pub enum Targets<'a> {
+    // Terms that depend on the outputs alone, e.g. z-loss.
+    None,
+    // One class index per row.
+    Classes(&'a [usize]),
+    // Same shape as the outputs.
+    Values(ArrayView2<'a, f32>),
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct LossBatch<'a> {
+    pub outputs: ArrayView2<'a, f32>,
+    pub targets: Targets<'a>,
+    // Per-row weights, 0 for rows that must not contribute (padding, document boundaries in
+    // a packed batch). Losses average over the total weight instead of the row count.
+    pub mask: Option<ArrayView1<'a, f32>>,
+}
+
+impl<'a> LossBatch<'a> {
+    pub fn new(outputs: ArrayView2<'a, f32>, targets: Targets<'a>) -> Self {
+        LossBatch { outputs, targets, mask: None }
+    }
+
+    pub fn with_mask(mut self, mask: ArrayView1<'a, f32>) -> Self {
+        assert_eq!(mask.len(), self.outputs.nrows(), "one mask entry per row");
+        assert!(mask.iter().all(|&w| w >= 0.0), "mask weights must be non-negative");
+        self.mask = Some(mask);
+        self
+    }
+
+    // The row weights and their sum. A fully masked batch reports a sum of 1 instead of 0:
+    // every weight is zero, so the loss and gradient come out zero rather than NaN.
+    fn row_weights(&self) -> (Array1<f32>, f32) {
+        let weights = match self.mask {
+            Some(mask) => mask.to_owned(),
+            None => Array1::ones(self.outputs.nrows()),
+        };
+        let total = weights.sum();
+        (weights, if total > 0.0 { total } else { 1.0 })
+    }
+}
+
+// Next-token class targets and loss mask of a `PackedBatch`, flattened row-major to match
+// (rows * seq_len) x vocab logits such as `TransformerModel::forward_packed`'s. Padding
+// and the last token of every document carry no weight, so a packed batch gives each
+// document the loss it would have alone.
+#[derive(Clone, Debug)]
+pub struct PackedTargets {
+    pub classes: Vec<usize>,
+    pub mask: Array1<f32>,
+}
+
+impl PackedTargets {
+    pub fn new(batch: &PackedBatch, pad_token: usize) -> Self {
+        PackedTargets { classes: batch.targets(pad_token).iter().copied().collect(), mask: batch.loss_mask() }
+    }
+
+    // A masked class batch for any `Loss` or `CompositeLoss`.
+    pub fn batch<'a>(&'a self, outputs: ArrayView2<'a, f32>) -> LossBatch<'a> {
+        LossBatch::new(outputs, Targets::Classes(&self.classes)).with_mask(self.mask.view())
+    }
+}
+
+// A scalar loss and its gradient with respect to the outputs.
+#[derive(Clone, Debug)]
+pub struct LossValue {
+    pub value: f32,
+    pub grad: Array2<f32>,
+}
+
+pub trait Loss: Send + Sync {
+    fn name(&self) -> &str;
+
+    fn compute(&self, batch: &LossBatch) -> LossValue;
+}
+
+// Mean over rows of -log softmax(outputs)[target].
+pub struct CrossEntropy;
+
+impl Loss for CrossEntropy {
+    fn name(&self) -> &str {
+        "cross_entropy"
+    }
+
+    fn compute(&self, batch: &LossBatch) -> LossValue {
+        let Targets::Classes(classes) = batch.targets else {
+            panic!("cross entropy needs class targets");
+        };
+        assert_eq!(classes.len(), batch.outputs.nrows(), "one class per row");
+        let (weights, total) = batch.row_weights();
+        let (lse, mut grad) = log_softmax_parts(&batch.outputs);
+
+        let mut value = 0.0;
+        for (i, &class) in classes.iter().enumerate() {
+            if weights[i] == 0.0 {
+                continue;
+            }
+            value += weights[i] * (lse[i] - batch.outputs[[i, class]]);
+            grad[[i, class]] -= 1.0;
+        }
+        let grad = grad * &(weights / total).insert_axis(Axis(1));
+        LossValue { value: value / total, grad }
+    }
+}
+
+// Auxiliary z-loss (PaLM): mean over rows of logsumexp(outputs)^2, keeping the softmax
+// normalizer near 1 so logits do not drift. Usually weighted around 1e-4.
+pub struct ZLoss;
+
+impl Loss for ZLoss {
+    fn name(&self) -> &str {
+        "z_loss"
+    }
+
+    fn compute(&self, batch: &LossBatch) -> LossValue {
+        let (weights, total) = batch.row_weights();
+        let (lse, softmax) = log_softmax_parts(&batch.outputs);
+        let value = lse.iter().zip(weights.iter()).map(|(z, w)| w * z * z).sum::<f32>() / total;
+        // d(z^2)/dx = 2 z softmax(x)
+        let grad = softmax * &(lse * &weights * (2.0 / total)).insert_axis(Axis(1));
+        LossValue { value, grad }
+    }
+}
+
+// Mean squared error over all elements.
+pub struct Mse;
+
+impl Loss for Mse {
+    fn name(&self) -> &str {
+        "mse"
+    }
+
+    fn compute(&self, batch: &LossBatch) -> LossValue {
+        let Targets::Values(targets) = batch.targets else {
+            panic!("mse needs value targets");
+        };
+        let (weights, total) = batch.row_weights();
+        let weights = weights.insert_axis(Axis(1));
+        let diff = &batch.outputs - &targets;
+        let n = total * batch.outputs.ncols().max(1) as f32;
+        let value = (&diff * &diff * &weights).sum() / n;
+        LossValue { value, grad: diff * &weights * (2.0 / n) }
+    }
+}
+
+// Per-row logsumexp and softmax, computed with the row max subtracted.
+fn log_softmax_parts(outputs: &ArrayView2<f32>) -> (Array1<f32>, Array2<f32>) {
+    let mut softmax = outputs.to_owned();
+    let mut lse = Array1::zeros(outputs.nrows());
+    for (mut row, lse) in softmax.rows_mut().into_iter().zip(lse.iter_mut()) {
+        let max = row.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+        row.mapv_inplace(|x| (x - max).exp());
+        let sum = row.sum();
+        row /= sum;
+        *lse = max + sum.ln();
+    }
+    (lse, softmax)
+}
+
+// Weight of a loss term as a function of the training step.
+pub enum WeightSchedule {
+    Constant(f32),
+    // Linear from `start` to `end` over `steps` steps, then held at `end`.
+    Linear { start: f32, end: f32, steps: usize },
+    Custom(Box<dyn Fn(usize) -> f32 + Send + Sync>),
+}
+
+impl WeightSchedule {
+    pub fn weight(&self, step: usize) -> f32 {
+        match self {
+            WeightSchedule::Constant(weight) => *weight,
+            WeightSchedule::Linear { start, end, steps } => {
+                let t = if *steps == 0 { 1.0 } else { (step as f32 / *steps as f32).min(1.0) };
+                start + (end - start) * t
+            }
+            WeightSchedule::Custom(f) => f(step),
+        }
+    }
+}
+
+// Value of one term of a `CompositeLoss` evaluation, before weighting; None if the term
+// was skipped at weight 0.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TermValue {
+    pub name: String,
+    pub weight: f32,
+    pub value: Option<f32>,
+}
+
+#[derive(Clone, Debug)]
+pub struct CompositeValue {
+    pub value: f32,
+    pub grad: Array2<f32>,
+    pub terms: Vec<TermValue>,
+}
+
+impl CompositeValue {
+    // Records "loss" plus "loss/<term>" (unweighted, evaluated terms only) and
+    // "loss/<term>/weight".
+    pub fn record(&self, metrics: &mut Metrics, step: usize) {
+        metrics.record("loss", step, self.value);
+        for term in &self.terms {
+            if let Some(value) = term.value {
+                metrics.record(format!("loss/{}", term.name), step, value);
+            }
+            metrics.record(format!("loss/{}/weight", term.name), step, term.weight);
+        }
+    }
+}
+
+// Weighted sum of loss terms, e.g. LM cross entropy plus a small z-loss:
+//
+//     CompositeLoss::new()
+//         .with_term(Box::new(CrossEntropy), 1.0)
+//         .with_term(Box::new(ZLoss), 1e-4)
+//
+// The gradient is the same weighted sum of the term gradients. Terms with weight 0 at a
+// step are not evaluated.
+#[derive(Default)]
+pub struct CompositeLoss {
+    terms: Vec<(Box<dyn Loss>, WeightSchedule)>,
+}
+
+impl CompositeLoss {
+    pub fn new() -> Self {
+        CompositeLoss::default()
+    }
+
+    pub fn with_term(self, loss: Box<dyn Loss>, weight: f32) -> Self {
+        self.with_scheduled_term(loss, WeightSchedule::Constant(weight))
+    }
+
+    pub fn with_scheduled_term(mut self, loss: Box<dyn Loss>, schedule: WeightSchedule) -> Self {
+        self.terms.push((loss, schedule));
+        self
+    }
+
+    pub fn compute(&self, batch: &LossBatch, step: usize) -> CompositeValue {
+        let mut value = 0.0;
+        let mut grad = Array2::zeros(batch.outputs.dim());
+        let mut terms = Vec::with_capacity(self.terms.len());
+        for (loss, schedule) in &self.terms {
+            let weight = schedule.weight(step);
+            if weight == 0.0 {
+                terms.push(TermValue { name: loss.name().to_string(), weight, value: None });
+                continue;
+            }
+            let term = loss.compute(batch);
+            value += weight * term.value;
+            grad.scaled_add(weight, &term.grad);
+            terms.push(TermValue { name: loss.name().to_string(), weight, value: Some(term.value) });
+        }
+        CompositeValue { value, grad, terms }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ndarray::{arr1, arr2};
+
+    fn logits() -> Array2<f32> {
+        Array2::from_shape_fn((3, 5), |(i, j)| ((i * 5 + j) as f32 * 0.7).sin() * 2.0)
+    }
+
+    #[test]
+    fn composite_gradient_is_the_weighted_sum_of_its_terms() {
+        let outputs = logits();
+        let classes = [1, 4, 0];
+        let batch = LossBatch::new(outputs.view(), Targets::Classes(&classes));
+        let composite = CompositeLoss::new().with_term(Box::new(CrossEntropy), 1.0).with_term(Box::new(ZLoss), 0.25);
+        let total = composite.compute(&batch, 0);
+
+        let (ce, z) = (CrossEntropy.compute(&batch), ZLoss.compute(&batch));
+        assert!((total.value - (ce.value + 0.25 * z.value)).abs() < 1e-6);
+        assert!(total.grad.abs_diff_eq(&(&ce.grad + &(&z.grad * 0.25)), 1e-6));
+        let values: Vec<Option<f32>> = total.terms.iter().map(|t| t.value).collect();
+        assert_eq!(values, [Some(ce.value), Some(z.value)]);
+    }
+
+    #[test]
+    fn linear_schedules_interpolate_then_hold() {
+        let schedule = WeightSchedule::Linear { start: 1.0, end: 0.0, steps: 4 };
+        let weights: Vec<f32> = (0..7).map(|step| schedule.weight(step)).collect();
+        assert_eq!(weights, [1.0, 0.75, 0.5, 0.25, 0.0, 0.0, 0.0]);
+        assert_eq!(WeightSchedule::Linear { start: 2.0, end: 3.0, steps: 0 }.weight(0), 3.0);
+    }
+
+    #[test]
+    fn skipped_terms_record_their_weight_but_no_value() {
+        let outputs = logits();
+        let classes = [0, 0, 0];
+        let batch = LossBatch::new(outputs.view(), Targets::Classes(&classes));
+        let composite = CompositeLoss::new()
+            .with_term(Box::new(CrossEntropy), 1.0)
+            .with_scheduled_term(Box::new(ZLoss), WeightSchedule::Linear { start: 0.0, end: 1.0, steps: 10 });
+
+        let mut metrics = Metrics::new();
+        let first = composite.compute(&batch, 0);
+        assert_eq!(first.terms[1], TermValue { name: "z_loss".to_string(), weight: 0.0, value: None });
+        assert!((first.value - CrossEntropy.compute(&batch).value).abs() < 1e-6);
+        first.record(&mut metrics, 0);
+        composite.compute(&batch, 5).record(&mut metrics, 5);
+
+        let steps = |name: &str| metrics.series(name).map(|s| s.iter().map(|&(step, _)| step).collect::<Vec<_>>());
+        assert_eq!(steps("loss/z_loss"), Some(vec![5]));
+        assert_eq!(steps("loss/z_loss/weight"), Some(vec![0, 5]));
+        assert_eq!(steps("loss/cross_entropy"), Some(vec![0, 5]));
+    }
+
+    fn evaluate<'a>(loss: &dyn Loss, outputs: &'a Array2<f32>, targets: Targets<'a>, mask: Option<&'a Array1<f32>>) -> LossValue {
+        let batch = LossBatch::new(outputs.view(), targets);
+        match mask {
+            Some(mask) => loss.compute(&batch.with_mask(mask.view())),
+            None => loss.compute(&batch),
+        }
+    }
+
+    #[test]
+    fn fractional_masks_average_over_their_total_weight() {
+        let outputs = arr2(&[[1.0, 2.0, 0.5], [0.3, -1.0, 2.0], [4.0, 0.0, 1.0]]);
+        let values = arr2(&[[0.0, 1.0, 0.0], [1.0, 1.0, 1.0], [2.0, 0.0, 0.0]]);
+        let classes = [1, 2, 0];
+        let cases: [(&dyn Loss, Targets); 3] =
+            [(&CrossEntropy, Targets::Classes(&classes)), (&ZLoss, Targets::None), (&Mse, Targets::Values(values.view()))];
+        let (uniform, fractional) = (arr1(&[0.1, 0.1, 0.1]), arr1(&[0.0, 0.25, 0.0]));
+        for (loss, targets) in cases {
+            let unmasked = evaluate(loss, &outputs, targets, None);
+            // Uniform weights of any scale are a plain mean.
+            let scaled = evaluate(loss, &outputs, targets, Some(&uniform));
+            assert!((scaled.value - unmasked.value).abs() < 1e-5, "{}: {} vs {}", loss.name(), scaled.value, unmasked.value);
+            assert!(scaled.grad.abs_diff_eq(&unmasked.grad, 1e-5));
+
+            // A single row with a fractional weight is that row's loss.
+            let single = evaluate(loss, &outputs, targets, Some(&fractional));
+            let row = outputs.slice(ndarray::s![1..2, ..]).to_owned();
+            let row_targets = match targets {
+                Targets::Classes(classes) => Targets::Classes(&classes[1..2]),
+                Targets::Values(values) => Targets::Values(values.reborrow().slice_move(ndarray::s![1..2, ..])),
+                Targets::None => Targets::None,
+            };
+            let alone = evaluate(loss, &row, row_targets, None);
+            assert!((single.value - alone.value).abs() < 1e-5, "{}: {} vs {}", loss.name(), single.value, alone.value);
+            assert!(single.grad.row(1).abs_diff_eq(&alone.grad.row(0), 1e-5));
+            assert!(single.grad.row(0).iter().chain(single.grad.row(2).iter()).all(|&g| g == 0.0));
+        }
+    }
+
+    #[test]
+    fn fully_masked_batch_gives_zero_loss() {
+        let outputs = arr2(&[[1.0, 2.0], [0.5, -1.0]]);
+        let mask = arr1(&[0.0, 0.0]);
+        let value = evaluate(&CrossEntropy, &outputs, Targets::Classes(&[0, 1]), Some(&mask));
+        assert_eq!(value.value, 0.0);
+        assert!(value.grad.iter().all(|&g| g == 0.0));
+    }
+}
@@ -0,0 +1,129 @@
+use half::bf16;
+use ndarray::{Array2, ArrayView2};
+
+use super::neural_network::NeuralNetwork;
+
+// f32 master copies of the trainable tensors behind bf16 working weights. Updates are
+// added to the masters and the working copies re-cast from them, so updates smaller than
+// bf16's spacing (8 bits of mantissa) accumulate instead of rounding away.
+//
+// This simulates bf16 training numerics; it does not save memory. The network computes in
+// f32 and is loaded with the widened working weights before each forward, so it sees
+// exactly the values a bf16 model would, but it still holds its own f32 weights: with
+// masters and working copies that is 10 bytes per parameter instead of 4.
+pub struct MasterWeights {
+    names: Vec<String>,
+    master: Vec<Array2<f32>>,
+    working: Vec<Array2<bf16>>,
+}
+
+impl MasterWeights {
+    // Takes the network's current weights as the masters and rounds the network to bf16.
+    pub fn from_network(network: &mut NeuralNetwork) -> Self {
+        let (names, master): (Vec<String>, Vec<Array2<f32>>) =
+            network.parameters_mut().into_iter().map(|(name, param)| (name, param.to_owned())).unzip();
+        let working = master.iter().map(|m| to_bf16(&m.view())).collect();
+        let weights = MasterWeights { names, master, working };
+        weights.load_into(network);
+        weights
+    }
+
+    pub fn names(&self) -> &[String] {
+        &self.names
+    }
+
+    pub fn master(&self) -> &[Array2<f32>] {
+        &self.master
+    }
+
+    pub fn working(&self) -> &[Array2<bf16>] {
+        &self.working
+    }
+
+    // Cast-on-update: adds `updates` (in `parameters_mut` order) to the masters and
+    // refreshes the working copies.
+    pub fn apply_updates(&mut self, updates: &[Array2<f32>]) {
+        assert_eq!(updates.len(), self.master.len(), "one update per parameter");
+        for ((master, working), update) in self.master.iter_mut().zip(self.working.iter_mut()).zip(updates) {
+            *master += update;
+            *working = to_bf16(&master.view());
+        }
+    }
+
+    // Runs `update` on the masters, e.g. an optimizer's `apply_updates`. It reports whether
+    // it changed them; if so the working copies are refreshed.
+    pub fn update_masters(&mut self, update: impl FnOnce(&mut [Array2<f32>]) -> bool) -> bool {
+        let changed = update(&mut self.master);
+        if changed {
+            for (master, working) in self.master.iter().zip(self.working.iter_mut()) {
+                *working = to_bf16(&master.view());
+            }
+        }
+        changed
+    }
+
+    // Replaces the masters, e.g. from a checkpoint, and refreshes the working copies.
+    pub fn set_master(&mut self, index: usize, value: Array2<f32>) {
+        assert_eq!(value.dim(), self.master[index].dim(), "master {} changes shape", self.names[index]);
+        self.working[index] = to_bf16(&value.view());
+        self.master[index] = value;
+    }
+
+    // Cast-on-forward: writes the widened working weights into the network.
+    pub fn load_into(&self, network: &mut NeuralNetwork) {
+        for ((_, mut param), working) in network.parameters_mut().into_iter().zip(self.working.iter()) {
+            param.zip_mut_with(working, |p, &w| *p = w.to_f32());
+        }
+    }
+
+    pub fn master_bytes(&self) -> usize {
+        self.master.iter().map(|m| m.len() * std::mem::size_of::<f32>()).sum()
+    }
+
+    pub fn working_bytes(&self) -> usize {
+        self.working.iter().map(|w| w.len() * std::mem::size_of::<bf16>()).sum()
+    }
+}
+
+pub fn to_bf16(matrix: &ArrayView2<f32>) -> Array2<bf16> {
+    matrix.mapv(bf16::from_f32)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::galore::neural_network::{Activation, Layer};
+
+    #[test]
+    fn small_updates_accumulate_in_the_master_only() {
+        let mut layer = Layer::new(3, 2, Activation::Tanh, false, 0.0);
+        for (_, mut param) in layer.parameters_mut("") {
+            param.fill(1.0);
+        }
+        let mut network = NeuralNetwork::from_layers(vec![layer]);
+        let mut weights = MasterWeights::from_network(&mut network);
+        assert_eq!(weights.master_bytes(), 8 * 4);
+        assert_eq!(weights.working_bytes(), 8 * 2);
+
+        // bf16 spacing at 1.0 is 2^-7; each update is under half of it and rounds away alone.
+        let updates: Vec<Array2<f32>> = weights.master().iter().map(|m| Array2::from_elem(m.dim(), 2f32.powi(-9))).collect();
+        weights.apply_updates(&updates);
+        assert!(weights.working().iter().flatten().all(|&w| w == bf16::ONE));
+        for _ in 0..3 {
+            weights.apply_updates(&updates);
+        }
+        // Four of them make one bf16 step.
+        let expected = 1.0 + 2f32.powi(-7);
+        assert!(weights.master().iter().flatten().all(|&m| m == expected));
+        assert!(weights.working().iter().flatten().all(|&w| w.to_f32() == expected));
+        weights.load_into(&mut network);
+        assert!(network.parameters_mut().iter().all(|(_, p)| p.iter().all(|&x| x == expected)));
+
+        // Applied straight to bf16 weights the same updates never move them.
+        let mut direct = bf16::ONE;
+        for _ in 0..4 {
+            direct = bf16::from_f32(direct.to_f32() + 2f32.powi(-9));
+        }
+        assert_eq!(direct, bf16::ONE);
+    }
+}
@@ -1,15 +1,214 @@
-use ndarray::{Array2, ArrayView2, Axis};
-use ndarray_linalg::{Eigh, SVD};
+use ndarray::linalg::general_mat_mul;
+use ndarray::{arr0, s, Array1, Array2, ArrayBase, ArrayView2, Axis, CowArray, DataMut, Ix2};
+use ndarray_linalg::error::LinalgError;
+use ndarray_linalg::{Eigh, UPLO};
+use ndarray_rand::rand_distr::StandardNormal;
+use ndarray_rand::RandomExt;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::ops::Range;
+use std::path::Path;
 use std::sync::Arc;
+use std::time::{Duration, Instant};
+use rand::rngs::StdRng;
+use rand::SeedableRng;
 use rayon::prelude::*;
 
+use super::checkpoint::{Checkpoint, CheckpointError, CheckpointFormat};
+use super::decompose::{orthonormalize, Decomposer, Decomposition, ExactSvd};
+use super::distributed::{all_reduce_stages, AllReduceMode, Collective};
+use super::fallback::{check_decomposition, FallbackAction, FallbackEvent, FallbackLog, FallbackReason};
+#[cfg(feature = "half")]
+use super::half_gemm::{gemm, gemm_acc, HalfCache};
+#[cfg(feature = "half")]
+use ndarray::ArrayViewMut2;
+#[cfg(feature = "experimental-fp8")]
+use super::fp8::{Fp8Format, Fp8Matrix};
+use super::heads::{block_diagonal, head_block, head_len, random_head_basis, HeadAxis};
+use super::metrics::Metrics;
+use super::optimizer::Optimizer;
+use super::pipeline::{
+    default_pipeline, precondition_stages, ClipGradNorm, ClipSpace, Grad, GradTransform, ParamGroup, PipelineError,
+    RmsEstimate, TransformContext, UpdateNormCap, WeightDecay, WeightDecayMode,
+};
+use super::restart::{RestartBoost, RestartPolicy, RestartSchedule};
+use super::rollback::{is_spike, push_bounded, KeptWeights, RefreshSnapshot, RollbackError, RollbackPolicy};
+use super::shared::{check_group, combined_gradient, SharedMember, SharedProjectionError};
+use super::sparse::CsrMatrix;
+use super::registry::{param_keys, RegistryError, TensorRegistry};
+use super::subspace::{principal_cosines, Subspace, SubspaceError, SubspaceImport, SubspaceSimilarity};
+use super::timings::StepTimings;
+
+
+// Per-layer projection choice, typically produced by `planner::MemoryPlanner`.
+#[derive(Clone, Debug, PartialEq)]
+pub enum LayerRank {
+    // No projection: the base optimizer sees the full gradient.
+    Full,
+    // One-sided projects only the smaller dimension, as in the reference implementation.
+    LowRank { rank: usize, one_sided: bool },
+}
+
+// Which factor a one-sided projection keeps, P (projecting the rows, P^T G) or Q (G Q).
+// `Shorter`, the default, projects the smaller dimension and takes P on square weights. The
+// others follow the reference implementation's `proj_type`: `Std` is `Shorter` except that
+// square weights take Q, `ReverseStd` projects the larger dimension, and `Left` / `Right`
+// always take P / Q.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum OneSidedRule {
+    #[default]
+    Shorter,
+    Std,
+    ReverseStd,
+    Left,
+    Right,
+}
+
+impl OneSidedRule {
+    pub fn takes_left(self, (m, n): (usize, usize)) -> bool {
+        match self {
+            OneSidedRule::Shorter => m <= n,
+            OneSidedRule::Std => m < n,
+            OneSidedRule::ReverseStd => m >= n,
+            OneSidedRule::Left => true,
+            OneSidedRule::Right => false,
+        }
+    }
+}
+
+// Per-parameter ranks, plus which parameters keep their optimizer moments in 8 bits. The
+// projection only reads `layers`; pass `eight_bit` to `Adam::with_eight_bit_states`.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct RankConfig {
+    pub layers: Vec<LayerRank>,
+    pub eight_bit: Vec<bool>,
+}
+
+impl RankConfig {
+    pub fn new(layers: Vec<LayerRank>) -> Self {
+        RankConfig { layers, eight_bit: Vec::new() }
+    }
+
+    pub fn with_eight_bit(mut self, eight_bit: Vec<bool>) -> Self {
+        self.eight_bit = eight_bit;
+        self
+    }
+
+    pub fn get(&self, index: usize) -> Option<&LayerRank> {
+        self.layers.get(index)
+    }
+
+    // Parameters without an entry keep f32 moments.
+    pub fn eight_bit(&self, index: usize) -> bool {
+        self.eight_bit.get(index).copied().unwrap_or(false)
+    }
+}
+
+// Structure hint for a parameter's gradient.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum GradStructure {
+    General,
+    // Square gradients that are symmetric (e.g. weight-tied bilinear forms). The basis is
+    // taken from an eigendecomposition, which costs about half of a full SVD, and P = Q.
+    Symmetric,
+    // An attention weight made of `heads` equal blocks along `axis` (rows for the Q/K/V
+    // projections, columns for the output projection). Each head gets its own rank-r basis,
+    // one-sided along that axis; dense gradients only, sparse ones get a global projection.
+    Heads { heads: usize, axis: HeadAxis },
+}
+
+// A gradient handed to `project_gradient`. Sparse gradients (e.g. from embedding layers) are
+// projected and decomposed through sparse-dense products and never densified, unless the
+// parameter is left unprojected.
+#[derive(Clone, Copy)]
+pub enum GradInput<'a> {
+    Dense(ArrayView2<'a, f32>),
+    Sparse(&'a CsrMatrix),
+}
+
+impl GradInput<'_> {
+    pub fn dim(&self) -> (usize, usize) {
+        match self {
+            GradInput::Dense(grad) => grad.dim(),
+            GradInput::Sparse(grad) => grad.dim(),
+        }
+    }
+
+    pub fn to_dense(self) -> Array2<f32> {
+        match self {
+            GradInput::Dense(grad) => grad.to_owned(),
+            GradInput::Sparse(grad) => grad.to_dense(),
+        }
+    }
+
+    // G * rhs
+    pub fn dot(&self, rhs: &ArrayView2<f32>) -> Array2<f32> {
+        match self {
+            GradInput::Dense(grad) => grad.dot(rhs),
+            GradInput::Sparse(grad) => grad.dot(rhs),
+        }
+    }
+
+    // G^T * rhs
+    pub fn t_dot(&self, rhs: &ArrayView2<f32>) -> Array2<f32> {
+        match self {
+            GradInput::Dense(grad) => grad.t().dot(rhs),
+            GradInput::Sparse(grad) => grad.t_dot(rhs),
+        }
+    }
+}
+
+impl<'a> From<ArrayView2<'a, f32>> for GradInput<'a> {
+    fn from(grad: ArrayView2<'a, f32>) -> Self {
+        GradInput::Dense(grad)
+    }
+}
+
+impl<'a> From<&'a CsrMatrix> for GradInput<'a> {
+    fn from(grad: &'a CsrMatrix) -> Self {
+        GradInput::Sparse(grad)
+    }
+}
+
+#[derive(Clone)]
+pub(crate) enum Projection {
+    // P^T G Q
+    TwoSided(Arc<Array2<f32>>, Arc<Array2<f32>>),
+    // P^T G, used when rows <= cols
+    Left(Arc<Array2<f32>>),
+    // G Q, used when rows > cols
+    Right(Arc<Array2<f32>>),
+}
 
 pub struct GaLoreProjection {
     rank: usize,
     update_freq: usize,
     ema_decay: f32,
+    min_param_size: usize,
+    param_order: Option<Vec<usize>>,
+    targets: ParamGroup,
+    registry: Option<Arc<TensorRegistry>>,
+    rank_config: Option<RankConfig>,
+    one_sided: Option<OneSidedRule>,
+    refresh_phase: usize,
+    structure_hints: HashMap<usize, GradStructure>,
+    shared_groups: Vec<Vec<SharedMember>>,
+    staggered_refresh: bool,
+    refresh_offsets: HashMap<usize, usize>,
+    pinned: HashSet<usize>,
+    ema_overrides: HashMap<usize, f32>,
+    spectrum_top_k: Option<usize>,
+    decomposer: Box<dyn Decomposer>,
+    #[cfg(feature = "half")]
+    half_precision: bool,
+    #[cfg(feature = "half")]
+    half_factors: HalfCache,
+    frozen: bool,
+    restart: RestartSchedule,
     step: usize,
-    projections: Vec<(Arc<Array2<f32>>, Arc<Array2<f32>>)>,
+    projections: Vec<Option<Projection>>,
+    last_refresh_time: Duration,
+    fallbacks: FallbackLog,
+    metrics: Metrics,
 }
 
 impl GaLoreProjection {
@@ -18,22 +217,552 @@ impl GaLoreProjection {
             rank,
             update_freq,
             ema_decay,
+            min_param_size: 0,
+            param_order: None,
+            targets: ParamGroup::All,
+            registry: None,
+            rank_config: None,
+            one_sided: None,
+            refresh_phase: 0,
+            structure_hints: HashMap::new(),
+            shared_groups: Vec::new(),
+            staggered_refresh: false,
+            refresh_offsets: HashMap::new(),
+            pinned: HashSet::new(),
+            ema_overrides: HashMap::new(),
+            spectrum_top_k: None,
+            decomposer: Box::new(ExactSvd::new()),
+            #[cfg(feature = "half")]
+            half_precision: false,
+            #[cfg(feature = "half")]
+            half_factors: HalfCache::new(),
+            frozen: false,
+            restart: RestartSchedule::default(),
             step: 0,
             projections: Vec::new(),
+            last_refresh_time: Duration::ZERO,
+            fallbacks: FallbackLog::default(),
+            metrics: Metrics::new(),
         }
     }
 
-    pub fn project_gradient(&mut self, gradients: Vec<ArrayView2<f32>>) -> Vec<Array2<f32>> {
-        self.step += 1;
+    // Parameters with fewer elements than this keep their full-rank gradient.
+    pub fn with_min_param_size(mut self, min_param_size: usize) -> Self {
+        self.min_param_size = min_param_size;
+        self
+    }
+
+    // Order in which a refresh decomposes the gradients. Without an order the decompositions
+    // run in parallel; with one they run one at a time in that order, so only a single SVD
+    // workspace is alive at once, for when memory is tight. Parameters left out follow the
+    // listed ones in index order, so this never decides what is projected: use
+    // `with_targets` or `with_min_param_size` to skip parameters.
+    pub fn with_param_order(mut self, order: Vec<usize>) -> Self {
+        let mut seen = HashSet::new();
+        self.param_order = Some(order.into_iter().filter(|&i| seen.insert(i)).collect());
+        self
+    }
+
+    // Only parameters in `targets` are projected, e.g. `registry.select(&["*.weight"])`.
+    pub fn with_targets(mut self, targets: ParamGroup) -> Self {
+        self.targets = targets;
+        self
+    }
+
+    // Names the parameters: metrics are labelled and checkpoint state keyed by name instead
+    // of position, so a checkpoint still loads after parameters are added or reordered.
+    pub fn with_registry(mut self, registry: Arc<TensorRegistry>) -> Self {
+        self.registry = Some(registry);
+        self
+    }
+
+    pub fn registry(&self) -> Option<&TensorRegistry> {
+        self.registry.as_deref()
+    }
+
+    // Per-layer ranks and sidedness; layers beyond the config use the global rank.
+    pub fn with_rank_config(mut self, config: RankConfig) -> Self {
+        self.rank_config = Some(config);
+        self
+    }
+
+    // Projects layers outside the rank config one-sided, and every one-sided layer by `rule`.
+    pub fn with_one_sided(mut self, rule: OneSidedRule) -> Self {
+        self.one_sided = Some(rule);
+        self
+    }
+
+    pub fn with_structure_hint(mut self, index: usize, structure: GradStructure) -> Self {
+        self.structure_hints.insert(index, structure);
+        self
+    }
+
+    // Parameters projected with a single P/Q pair computed from the sum of their gradients,
+    // such as tied embeddings or the Q/K/V slices of a fused attention weight. The first
+    // member fixes the orientation and decides, like any parameter, whether the group is
+    // projected at all. Sparse members are densified for the shared refresh, and a group whose
+    // gradients do not match in shape is refreshed member by member.
+    pub fn with_shared_projection(mut self, members: Vec<SharedMember>) -> Result<Self, SharedProjectionError> {
+        check_group(&self.shared_groups, &members)?;
+        self.shared_groups.push(members);
+        Ok(self)
+    }
+
+    // Spread SVD refreshes over the `update_freq` window instead of refreshing every
+    // parameter on the same step: parameter i refreshes when step % update_freq == i % update_freq.
+    pub fn with_staggered_refresh(mut self, enabled: bool) -> Self {
+        self.staggered_refresh = enabled;
+        self
+    }
+
+    // Shifts every parameter's refresh schedule (staggered or not) by `phase` steps; per-parameter
+    // offsets are absolute and ignore it. With phase 1 refreshes land on steps 1, update_freq + 1,
+    // ..., which is where a projector that checks `step % gap == 0` before counting the step
+    // refreshes.
+    pub fn with_refresh_phase(mut self, phase: usize) -> Self {
+        self.refresh_phase = phase;
+        self
+    }
+
+    // Refresh phase for one parameter, overriding the staggered (or unstaggered) default.
+    // Members of a shared projection follow their group's first member.
+    pub fn with_refresh_offset(mut self, index: usize, offset: usize) -> Self {
+        self.refresh_offsets.insert(index, offset);
+        self
+    }
+
+    // On every refresh, record the top-k singular values and the effective rank of each gradient.
+    pub fn with_spectrum_telemetry(mut self, top_k: usize) -> Self {
+        self.spectrum_top_k = Some(top_k);
+        self
+    }
+
+    // Decomposition used for projection refreshes; `ExactSvd` by default. Parameters with a
+    // `GradStructure::Symmetric` hint still use their eigendecomposition.
+    pub fn with_decomposer(mut self, decomposer: Box<dyn Decomposer>) -> Self {
+        self.decomposer = decomposer;
+        self
+    }
+
+    // Shorthand for `ExactSvd` with the given randomized sketch for sparse gradients.
+    pub fn with_sparse_sketch(self, oversample: usize, power_iters: usize) -> Self {
+        self.with_decomposer(Box::new(ExactSvd::new().with_sparse_sketch(oversample, power_iters)))
+    }
+
+    // Read P and Q as f16 in the dense `project`/`project_back` products, accumulating in
+    // f32. The refresh, EMA and checkpoints keep using the f32 factors; each one also gets an
+    // f16 copy (half its size again) the first time it is used. See `half_gemm`.
+    #[cfg(feature = "half")]
+    pub fn with_half_precision(mut self, enabled: bool) -> Self {
+        self.half_precision = enabled;
+        self
+    }
+
+    pub fn with_restart_policy(mut self, policy: RestartPolicy) -> Self {
+        self.restart.set_policy(policy);
+        self
+    }
+
+    pub fn metrics(&self) -> &Metrics {
+        &self.metrics
+    }
+
+    pub fn metrics_mut(&mut self) -> &mut Metrics {
+        &mut self.metrics
+    }
+
+    pub fn projection_order(&self, shapes: &[(usize, usize)]) -> Vec<usize> {
+        let eligible = |i: &usize| {
+            let large_enough = shapes.get(*i).is_some_and(|&(m, n)| m * n >= self.min_param_size);
+            large_enough && self.targets.contains(*i) && self.layer_rank(*i) != LayerRank::Full
+        };
+        match &self.param_order {
+            Some(order) => {
+                let listed: HashSet<usize> = order.iter().copied().collect();
+                let rest = (0..shapes.len()).filter(|i| !listed.contains(i));
+                order.iter().copied().chain(rest).filter(eligible).collect()
+            }
+            None => (0..shapes.len()).filter(eligible).collect(),
+        }
+    }
+
+    // Configuration problems for parameters of the given shapes, as (parameter index, message),
+    // all collected rather than stopping at the first. Projection itself clamps ranks and
+    // refreshes mis-shaped shared members on their own; this lets a caller catch both before
+    // training.
+    pub fn check_shapes(&self, shapes: &[(usize, usize)]) -> Vec<(usize, String)> {
+        let mut issues = Vec::new();
+        if let Some(config) = &self.rank_config {
+            for (i, layer) in config.layers.iter().enumerate() {
+                let (rank, &(m, n)) = match (layer, shapes.get(i)) {
+                    (LayerRank::Full, _) => continue,
+                    (LayerRank::LowRank { rank, .. }, Some(shape)) => (*rank, shape),
+                    (LayerRank::LowRank { .. }, None) => {
+                        issues.push((i, "rank config entry for a parameter that does not exist".to_string()));
+                        continue;
+                    }
+                };
+                if rank == 0 || rank > m.min(n) {
+                    issues.push((i, format!("rank {} is not feasible for a {}x{} weight", rank, m, n)));
+                }
+            }
+        }
+        for (&i, &structure) in &self.structure_hints {
+            match shapes.get(i) {
+                Some(&(m, n)) if structure == GradStructure::Symmetric && m != n => {
+                    issues.push((i, format!("symmetric structure hint on a non-square {}x{} weight", m, n)));
+                }
+                Some(&shape) => {
+                    if let GradStructure::Heads { heads, axis } = structure {
+                        if let Err(issue) = head_len(heads, axis, shape) {
+                            issues.push((i, issue));
+                        }
+                    }
+                }
+                None => issues.push((i, "structure hint for a parameter that does not exist".to_string())),
+            }
+        }
+        for group in &self.shared_groups {
+            let leader = group[0].index;
+            for member in group {
+                let expected = shapes.get(leader).map(|&(m, n)| if member.transposed { (n, m) } else { (m, n) });
+                match (shapes.get(member.index), expected) {
+                    (None, _) => issues.push((member.index, "shared projection member does not exist".to_string())),
+                    (Some(&shape), Some(expected)) if shape != expected => issues.push((
+                        member.index,
+                        format!("shape {}x{} does not match shared projection {}x{}", shape.0, shape.1, expected.0, expected.1),
+                    )),
+                    _ => {}
+                }
+            }
+        }
+        issues.sort_by_key(|(i, _)| *i);
+        issues
+    }
+
+    // While frozen, `project_gradient` reuses the current projections and does not advance
+    // the refresh schedule.
+    pub fn set_frozen(&mut self, frozen: bool) {
+        self.frozen = frozen;
+    }
+
+    // Time spent recomputing projections during the last `project_gradient` call.
+    pub fn last_refresh_time(&self) -> Duration {
+        self.last_refresh_time
+    }
+
+    // Refreshes that could not use a fresh decomposition, oldest first. The per-parameter
+    // totals are also recorded as "param<i>/fallbacks" in the metrics.
+    pub fn fallback_events(&self) -> &[FallbackEvent] {
+        self.fallbacks.events()
+    }
+
+    pub fn clear_fallback_events(&mut self) {
+        self.fallbacks.clear();
+    }
+
+    pub fn fallback_count(&self, index: usize) -> usize {
+        self.fallbacks.count(index)
+    }
+
+    pub fn is_projected(&self, index: usize) -> bool {
+        matches!(self.projections.get(index), Some(Some(_)))
+    }
+
+    // The current bases of every parameter, None for unprojected ones, e.g. to reuse them
+    // for a later fine-tuning run via `import_subspaces`.
+    pub fn export_subspaces(&self) -> Vec<Option<Subspace>> {
+        self.projections
+            .iter()
+            .map(|projection| {
+                projection.as_ref().map(|projection| match projection {
+                    Projection::TwoSided(p, q) => Subspace { p: Some((**p).clone()), q: Some((**q).clone()) },
+                    Projection::Left(p) => Subspace { p: Some((**p).clone()), q: None },
+                    Projection::Right(q) => Subspace { p: None, q: Some((**q).clone()) },
+                })
+            })
+            .collect()
+    }
+
+    // The current P/Q of every projected parameter as "<name>.p" / "<name>.q" (registered
+    // name, or "param<i>"), for offline study of how the subspaces evolve. Alongside: the
+    // projection step, "<name>.rank" and "<name>.ema_decay", as metadata where the format
+    // has it and as 0-d tensors otherwise (npz), so they load with plain numpy.
+    pub fn projections_checkpoint(&self, format: CheckpointFormat) -> Checkpoint {
+        let mut checkpoint = Checkpoint::new();
+        let scalar = |checkpoint: &mut Checkpoint, key: String, value: f32| {
+            if format.supports_metadata() {
+                checkpoint.set_metadata(key, value);
+            } else {
+                checkpoint.insert_tensor(key, arr0(value));
+            }
+        };
+        scalar(&mut checkpoint, "step".to_string(), self.step as f32);
+        for (i, subspace) in self.export_subspaces().into_iter().enumerate() {
+            let Some(Subspace { p, q }) = subspace else {
+                continue;
+            };
+            let label = self.param_label(i);
+            let rank = p.as_ref().or(q.as_ref()).map_or(0, |basis| basis.ncols());
+            let decay = self.ema_overrides.get(&i).copied().unwrap_or(self.ema_decay);
+            scalar(&mut checkpoint, format!("{}.rank", label), rank as f32);
+            scalar(&mut checkpoint, format!("{}.ema_decay", label), decay);
+            if let Some(p) = p {
+                checkpoint.insert_tensor(format!("{}.p", label), p);
+            }
+            if let Some(q) = q {
+                checkpoint.insert_tensor(format!("{}.q", label), q);
+            }
+        }
+        checkpoint
+    }
+
+    // Writes `projections_checkpoint` to `path`, in the format its extension names.
+    pub fn export_projections(&self, path: impl AsRef<Path>) -> Result<(), CheckpointError> {
+        let path = path.as_ref();
+        let format = CheckpointFormat::from_path(path)
+            .ok_or_else(|| CheckpointError::Unsupported(format!("no checkpoint format for {}", path.display())))?;
+        self.projections_checkpoint(format).save(path, format)
+    }
+
+    // Installs exported subspaces as the current projections, one entry per parameter.
+    // Parameters given None stay unprojected until their next scheduled refresh; the
+    // others skip the initial refresh and then behave as `mode` says.
+    pub fn import_subspaces(&mut self, subspaces: Vec<Option<Subspace>>, mode: SubspaceImport) -> Result<(), SubspaceError> {
+        self.replace_subspaces(subspaces)?;
+        for (i, projection) in self.projections.iter().enumerate() {
+            if projection.is_none() {
+                continue;
+            }
+            match mode {
+                SubspaceImport::Fixed => {
+                    self.pinned.insert(i);
+                }
+                SubspaceImport::Adapting { ema_decay } => {
+                    self.ema_overrides.insert(i, ema_decay);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Swaps in `subspaces` as the current projections and leaves the refresh schedule and
+    // per-parameter settings as they are. On error nothing is replaced.
+    pub fn replace_subspaces(&mut self, subspaces: Vec<Option<Subspace>>) -> Result<(), SubspaceError> {
+        for (i, subspace) in subspaces.iter().enumerate() {
+            match subspace {
+                Some(Subspace { p: None, q: None }) => return Err(SubspaceError::Empty(i)),
+                Some(Subspace { p: Some(p), q: Some(q) }) if p.ncols() != q.ncols() => {
+                    return Err(SubspaceError::RankMismatch { param: i, left: p.ncols(), right: q.ncols() });
+                }
+                _ => {}
+            }
+        }
+        self.projections = subspaces
+            .into_iter()
+            .map(|subspace| {
+                subspace.map(|Subspace { p, q }| match (p, q) {
+                    (Some(p), Some(q)) => Projection::TwoSided(Arc::new(p), Arc::new(q)),
+                    (Some(p), None) => Projection::Left(Arc::new(p)),
+                    (None, Some(q)) => Projection::Right(Arc::new(q)),
+                    (None, None) => unreachable!("checked above"),
+                })
+            })
+            .collect();
+        Ok(())
+    }
+
+    // Principal-angle comparison of the current projections with the dominant subspaces of
+    // `gradients`, found with the configured decomposer at the same rank. Parameters without
+    // a projection, or whose decomposition fails, are skipped.
+    pub fn subspace_similarity<'a, G: Into<GradInput<'a>>>(&self, gradients: Vec<G>) -> Vec<SubspaceSimilarity> {
+        let gradients: Vec<GradInput> = gradients.into_iter().map(Into::into).collect();
+        gradients
+            .par_iter()
+            .zip(self.projections.par_iter())
+            .enumerate()
+            .filter_map(|(i, (grad, projection))| {
+                let (p, q) = match projection.as_ref()? {
+                    Projection::TwoSided(p, q) => (Some(p), Some(q)),
+                    Projection::Left(p) => (Some(p), None),
+                    Projection::Right(q) => (None, Some(q)),
+                };
+                let rank = p.or(q)?.ncols();
+                let Decomposition { u, v, .. } =
+                    self.decomposer.decompose(grad, rank, p.is_some(), q.is_some(), self.refresh_seed(i)).ok()?;
+                let compare = |old: Option<&Arc<Array2<f32>>>, new: Option<Array2<f32>>| match (old, new) {
+                    (Some(old), Some(new)) => principal_cosines(old, &new.slice(s![.., ..rank]).to_owned()).ok(),
+                    _ => None,
+                };
+                Some(SubspaceSimilarity { param: i, left: compare(p, u), right: compare(q, v) })
+            })
+            .collect()
+    }
+
+    pub fn save_state(&self, checkpoint: &mut Checkpoint, prefix: &str) {
+        checkpoint.set_metadata(format!("{}.step", prefix), self.step);
+        self.restart.save_state(checkpoint, prefix);
+        checkpoint.set_metadata(format!("{}.num_params", prefix), self.projections.len());
+        for (i, name) in self.param_keys().iter().enumerate() {
+            let key = format!("{}.projection.{}", prefix, name);
+            let kind = match self.projections.get(i).and_then(|p| p.as_ref()) {
+                None => "none",
+                Some(Projection::TwoSided(p, q)) if Arc::ptr_eq(p, q) => {
+                    checkpoint.insert_tensor(format!("{}.p", key), (**p).clone());
+                    "shared"
+                }
+                Some(Projection::TwoSided(p, q)) => {
+                    checkpoint.insert_tensor(format!("{}.p", key), (**p).clone());
+                    checkpoint.insert_tensor(format!("{}.q", key), (**q).clone());
+                    "two_sided"
+                }
+                Some(Projection::Left(p)) => {
+                    checkpoint.insert_tensor(format!("{}.p", key), (**p).clone());
+                    "left"
+                }
+                Some(Projection::Right(q)) => {
+                    checkpoint.insert_tensor(format!("{}.q", key), (**q).clone());
+                    "right"
+                }
+            };
+            checkpoint.set_metadata(format!("{}.kind", key), kind);
+        }
+    }
+
+    // With a registry, parameters the checkpoint has no entry for (added since it was
+    // written) start unprojected and pick up a projection on their next refresh.
+    pub fn load_state(&mut self, checkpoint: &Checkpoint, prefix: &str) -> Result<(), CheckpointError> {
+        let num_params: usize = checkpoint.parse_metadata(&format!("{}.num_params", prefix))?;
+        let keys = param_keys(self.registry.as_deref(), num_params);
+        let mut projections = Vec::with_capacity(keys.len());
+        for name in &keys {
+            let key = format!("{}.projection.{}", prefix, name);
+            let kind = match checkpoint.metadata(&format!("{}.kind", key)) {
+                Some(kind) => kind,
+                None if self.registry.is_some() => "none",
+                None => return Err(CheckpointError::Missing(format!("{}.kind", key))),
+            };
+            let matrix = |side: &str| checkpoint.matrix(&format!("{}.{}", key, side)).map(Arc::new);
+            let projection = match kind {
+                "none" => None,
+                "shared" => {
+                    let p = matrix("p")?;
+                    Some(Projection::TwoSided(Arc::clone(&p), p))
+                }
+                "two_sided" => Some(Projection::TwoSided(matrix("p")?, matrix("q")?)),
+                "left" => Some(Projection::Left(matrix("p")?)),
+                "right" => Some(Projection::Right(matrix("q")?)),
+                other => return Err(CheckpointError::Format(format!("unknown projection kind {}", other))),
+            };
+            projections.push(projection);
+        }
+        self.projections = projections;
+        self.step = checkpoint.parse_metadata(&format!("{}.step", prefix))?;
+        self.restart.load_state(checkpoint, prefix)?;
+        Ok(())
+    }
+
+    fn layer_rank(&self, index: usize) -> LayerRank {
+        self.rank_config
+            .as_ref()
+            .and_then(|config| config.get(index).cloned())
+            .unwrap_or(LayerRank::LowRank { rank: self.rank, one_sided: self.one_sided.is_some() })
+    }
+
+    pub fn project_gradient<'a, G: Into<GradInput<'a>>>(&mut self, gradients: Vec<G>) -> Vec<Array2<f32>> {
+        let gradients: Vec<GradInput> = gradients.into_iter().map(Into::into).collect();
+        if !self.frozen {
+            self.step += 1;
+        }
 
-        if self.step % self.update_freq == 0 || self.projections.is_empty() {
-            self.update_projections(&gradients);
+        let start = Instant::now();
+        let mut refreshed = false;
+        let restart_over = self.restart.active() && !self.frozen;
+        let initial = self.projections.is_empty();
+        let round = !self.frozen && self.step.is_multiple_of(self.update_freq);
+        if round || restart_over || initial {
+            let boost = if restart_over {
+                self.restart.finish();
+                None
+            } else {
+                self.restart.next_refresh()
+            };
+            match boost {
+                Some(boost) => {
+                    self.metrics.record("restart", self.step, 1.0);
+                    match boost {
+                        RestartBoost::FullRank => {
+                            let mut projections = vec![None; gradients.len()];
+                            for &i in self.pinned.iter().filter(|&&i| i < gradients.len()) {
+                                projections[i] = self.projections.get(i).cloned().flatten();
+                            }
+                            self.projections = projections;
+                        }
+                        RestartBoost::Rank(rank) => self.update_projections(&gradients, Some(rank), |_| true),
+                    }
+                    refreshed = true;
+                }
+                None if restart_over || initial => {
+                    self.update_projections(&gradients, None, |_| true);
+                    refreshed = true;
+                }
+                None => {}
+            }
+        }
+        if !refreshed && !self.frozen {
+            let due: Vec<usize> = (0..gradients.len()).filter(|&i| self.refresh_due(i, self.step)).collect();
+            if !due.is_empty() {
+                self.update_projections(&gradients, None, |i| due.contains(&i));
+                refreshed = true;
+            }
         }
+        self.last_refresh_time = if refreshed { start.elapsed() } else { Duration::ZERO };
 
         gradients
             .par_iter()
             .zip(self.projections.par_iter())
-            .map(|(grad, (p, q))| self.project(grad, p, q))
+            .map(|(grad, projection)| match projection {
+                Some(projection) => self.project(grad, projection),
+                None => grad.to_dense(),
+            })
+            .collect()
+    }
+
+    // Counts a step for `project_stream` and returns true if no projection is due for a
+    // refresh on it; otherwise leaves everything to `project_gradient`.
+    pub(crate) fn step_without_refresh(&mut self) -> bool {
+        if self.refresh_pending(self.projections.len()) {
+            return false;
+        }
+        if !self.frozen {
+            self.step += 1;
+            if self.step.is_multiple_of(self.update_freq) {
+                // A scheduled round still counts towards the restart policy; `refresh_pending`
+                // has ruled out that it triggers one.
+                self.restart.next_refresh();
+            }
+        }
+        self.last_refresh_time = Duration::ZERO;
+        true
+    }
+
+    // Projects gradient `index` with its current projection, consuming it.
+    pub(crate) fn project_current_one(&self, index: usize, grad: Grad) -> Array2<f32> {
+        match self.projections.get(index) {
+            Some(Some(projection)) => self.project(&GradInput::Dense(grad.view()), projection),
+            _ => grad.into_owned(),
+        }
+    }
+
+    // Projects with the current projections, without counting a step or refreshing.
+    pub fn project_current(&self, tensors: &[ArrayView2<f32>]) -> Vec<Array2<f32>> {
+        tensors
+            .par_iter()
+            .enumerate()
+            .map(|(i, tensor)| match self.projections.get(i) {
+                Some(Some(projection)) => self.project(&GradInput::Dense(tensor.view()), projection),
+                _ => tensor.to_owned(),
+            })
             .collect()
     }
 
@@ -41,119 +770,1725 @@ impl GaLoreProjection {
         updates
             .par_iter()
             .zip(self.projections.par_iter())
-            .map(|(update, (p, q))| self.project_back(update, p, q))
+            .map(|(update, projection)| match projection {
+                Some(projection) => self.project_back(update, projection),
+                None => update.to_owned(),
+            })
             .collect()
     }
 
-    fn update_projections(&mut self, gradients: &[ArrayView2<f32>]) {
-        self.projections = gradients
-            .par_iter()
-            .map(|grad| {
-                let (p, q) = self.compute_projection_matrices(grad);
-                (Arc::new(p), Arc::new(q))
-            })
+    // Whether the next `project_gradient` call over `count` gradients will recompute (or,
+    // on a full-rank restart, drop) any projection.
+    pub fn refresh_pending(&self, count: usize) -> bool {
+        // Before the first refresh callers may not know the count yet and pass zero.
+        self.projections.is_empty() || self.refresh_pending_params(count).contains(&true)
+    }
+
+    // `refresh_pending` per parameter: whether the next `project_gradient` call reads that
+    // gradient in full. A shared group's followers are due with their leader, whose
+    // projection comes from the group's combined gradient; pinned parameters never are.
+    pub fn refresh_pending_params(&self, count: usize) -> Vec<bool> {
+        if self.projections.is_empty() {
+            return vec![true; count];
+        }
+        if self.frozen {
+            return vec![false; count];
+        }
+        let step = self.step + 1;
+        if self.restart.active() || (step.is_multiple_of(self.update_freq) && self.restart.next_is_restart()) {
+            return vec![true; count];
+        }
+        let mut due: Vec<bool> = (0..count).map(|i| !self.pinned.contains(&i) && self.refresh_due(i, step)).collect();
+        for group in &self.shared_groups {
+            let leader_due = due.get(group[0].index).copied().unwrap_or(false);
+            for member in group[1..].iter().filter(|m| m.index < count) {
+                due[member.index] = leader_due;
+            }
+        }
+        due
+    }
+
+    // Recomputes every eligible projection from `gradients` now, outside the refresh
+    // schedule (which is not advanced).
+    pub fn refresh<'a, G: Into<GradInput<'a>>>(&mut self, gradients: Vec<G>) {
+        let gradients: Vec<GradInput> = gradients.into_iter().map(Into::into).collect();
+        let start = Instant::now();
+        self.update_projections(&gradients, None, |_| true);
+        self.last_refresh_time = start.elapsed();
+    }
+
+    // params[i] += back-projection of updates[i], accumulated straight into the weights by
+    // the GEMM (beta = 1) so no full-size update is allocated. Two-sided projections still
+    // form the r x n product U Q^T first.
+    pub fn apply_update<S: DataMut<Elem = f32> + Send>(&self, params: &mut [ArrayBase<S, Ix2>], updates: Vec<ArrayView2<f32>>) {
+        params
+            .par_iter_mut()
+            .zip(updates.par_iter())
+            .enumerate()
+            .for_each(|(i, (param, update))| match self.projections.get(i).and_then(|p| p.as_ref()) {
+                #[cfg(feature = "half")]
+                Some(projection) if self.half_precision => self.project_back_f16_into(update, projection, &mut param.view_mut()),
+                Some(Projection::TwoSided(p, q)) => general_mat_mul(1.0, &**p, &update.dot(&q.t()), 1.0, param),
+                Some(Projection::Left(p)) => general_mat_mul(1.0, &**p, update, 1.0, param),
+                Some(Projection::Right(q)) => general_mat_mul(1.0, update, &q.t(), 1.0, param),
+                None => *param += update,
+            });
+    }
+
+    // Parameter i refreshes on steps congruent to its offset modulo `update_freq`.
+    fn refresh_due(&self, index: usize, step: usize) -> bool {
+        let offset = match self.refresh_offsets.get(&index) {
+            Some(&offset) => offset,
+            None if self.staggered_refresh => index + self.refresh_phase,
+            None => self.refresh_phase,
+        };
+        step % self.update_freq == offset % self.update_freq
+    }
+
+    // Recomputes the projections of the eligible parameters selected by `due`; the others
+    // keep their current projection.
+    fn update_projections<F: Fn(usize) -> bool>(&mut self, gradients: &[GradInput], rank_override: Option<usize>, due: F) {
+        let shapes: Vec<(usize, usize)> = gradients.iter().map(|g| g.dim()).collect();
+        let eligible: Vec<usize> = self.projection_order(&shapes).into_iter().filter(|i| !self.pinned.contains(i)).collect();
+
+        // Groups whose leader is refreshed are refreshed from their combined gradient and the
+        // followers take the leader's projection; otherwise the followers keep theirs. A group
+        // whose gradients differ in shape is refreshed member by member, with its leader.
+        let mut combined: HashMap<usize, Array2<f32>> = HashMap::new();
+        let mut followers = Vec::new();
+        let mut unshared = Vec::new();
+        for group in &self.shared_groups {
+            if eligible.contains(&group[0].index) && due(group[0].index) {
+                let Some(sum) = combined_gradient(group, gradients) else {
+                    unshared.extend(group[1..].iter().map(|m| m.index));
+                    continue;
+                };
+                combined.insert(group[0].index, sum);
+            }
+            followers.extend(group[1..].iter().map(|m| m.index));
+        }
+        let order: Vec<usize> = eligible
+            .into_iter()
+            .filter(|&i| unshared.contains(&i) || (due(i) && !followers.contains(&i)))
             .collect();
+
+        let mut projections = if self.projections.len() == gradients.len() {
+            self.projections.clone()
+        } else {
+            vec![None; gradients.len()]
+        };
+        // Decompose (in parallel unless an order is set, see `with_param_order`), then record
+        // the results in refresh order.
+        let decompose = |&i: &usize| {
+            let result = match combined.get(&i) {
+                Some(sum) => self.compute_projection_matrices(i, &GradInput::Dense(sum.view()), rank_override),
+                None => self.compute_projection_matrices(i, &gradients[i], rank_override),
+            };
+            (i, result)
+        };
+        let results: Vec<(usize, Refreshed)> = match self.param_order {
+            Some(_) => order.iter().map(decompose).collect(),
+            None => order.par_iter().map(decompose).collect(),
+        };
+        for (i, result) in results {
+            match result {
+                Ok((projection, sigma)) => {
+                    if let Some(top_k) = self.spectrum_top_k {
+                        self.record_spectrum(i, &sigma, top_k);
+                    }
+                    projections[i] = Some(projection);
+                }
+                Err(reason) => {
+                    let (projection, action) = match self.projections.get(i).cloned().flatten() {
+                        Some(previous) => (previous, FallbackAction::KeptPrevious),
+                        None => (self.random_projection(i, shapes[i], rank_override), FallbackAction::RandomBasis),
+                    };
+                    let label = self.param_label(i);
+                    self.fallbacks.record(FallbackEvent { step: self.step, param: i, reason, action }, &label, &mut self.metrics);
+                    projections[i] = Some(projection);
+                }
+            }
+        }
+        for group in self.shared_groups.iter().filter(|group| combined.contains_key(&group[0].index)) {
+            let Some(projection) = projections[group[0].index].clone() else {
+                continue;
+            };
+            for member in group[1..].iter().filter(|m| m.index < gradients.len()) {
+                projections[member.index] = Some(if member.transposed { projection.transposed() } else { projection.clone() });
+            }
+        }
+        self.projections = projections;
     }
 
-    fn compute_projection_matrices(&self, grad: &ArrayView2<f32>) -> (Array2<f32>, Array2<f32>) {
+    fn compute_projection_matrices(
+        &self,
+        index: usize,
+        grad: &GradInput,
+        rank_override: Option<usize>,
+    ) -> Refreshed {
         let (m, n) = grad.dim();
-        let (mut u, s, mut vt) = grad.svd(true, true).unwrap();
+        let (rank, one_sided) = self.projection_rank(index, (m, n), rank_override);
+        let old = self.projections.get(index).cloned().flatten();
 
-        u.slice_axis_inplace(Axis(1), ndarray::Slice::from(0..self.rank));
-        vt.slice_axis_inplace(Axis(0), ndarray::Slice::from(0..self.rank));
+        let structure = self.structure_hints.get(&index).copied().unwrap_or(GradStructure::General);
+        if let (GradStructure::Symmetric, GradInput::Dense(grad)) = (structure, grad) {
+            if m == n {
+                let (basis, sigma) = symmetric_basis(grad, rank).map_err(|e| FallbackReason::DecompositionFailed(e.to_string()))?;
+                check_decomposition(&sigma, &[Some(&basis)])?;
+                let basis = match old {
+                    Some(Projection::TwoSided(p_old, _)) | Some(Projection::Left(p_old)) if p_old.dim() == basis.dim() => {
+                        self.ema_update(index, &p_old, &basis)
+                    }
+                    _ => basis,
+                };
+                let basis = Arc::new(basis);
+                let projection = if one_sided {
+                    Projection::Left(basis)
+                } else {
+                    Projection::TwoSided(Arc::clone(&basis), basis)
+                };
+                return Ok((projection, sigma));
+            }
+        }
+        if let (GradStructure::Heads { heads, axis }, GradInput::Dense(grad)) = (structure, grad) {
+            return self.head_projection(index, grad, (heads, axis), rank_override, old);
+        }
 
-        if let Some((p_old, q_old)) = self.projections.get(0) {
-            let p = self.ema_update(&p_old, &u);
-            let q = self.ema_update(&q_old, &vt.t());
-            (p, q)
-        } else {
-            (u, vt.t().to_owned())
+        let left = self.one_sided.unwrap_or_default().takes_left((m, n));
+        let need_u = !one_sided || left;
+        let need_v = !one_sided || !left;
+        let Decomposition { u, sigma, v } = self
+            .decomposer
+            .decompose(grad, rank, need_u, need_v, self.refresh_seed(index))
+            .map_err(FallbackReason::DecompositionFailed)?;
+
+        let u = u.map(|u| u.slice(s![.., ..rank]).to_owned());
+        let v = v.map(|v| v.slice(s![.., ..rank]).to_owned());
+        check_decomposition(&sigma, &[u.as_ref(), v.as_ref()])?;
+
+        let projection = match (u, v) {
+            (Some(u), Some(v)) => match old {
+                Some(Projection::TwoSided(p_old, q_old)) if p_old.dim() == u.dim() && q_old.dim() == v.dim() => {
+                    Projection::TwoSided(Arc::new(self.ema_update(index, &p_old, &u)), Arc::new(self.ema_update(index, &q_old, &v)))
+                }
+                _ => Projection::TwoSided(Arc::new(u), Arc::new(v)),
+            },
+            (Some(u), None) => match old {
+                Some(Projection::Left(p_old)) if p_old.dim() == u.dim() => Projection::Left(Arc::new(self.ema_update(index, &p_old, &u))),
+                _ => Projection::Left(Arc::new(u)),
+            },
+            (None, Some(v)) => match old {
+                Some(Projection::Right(q_old)) if q_old.dim() == v.dim() => Projection::Right(Arc::new(self.ema_update(index, &q_old, &v))),
+                _ => Projection::Right(Arc::new(v)),
+            },
+            (None, None) => unreachable!("SVD returned no singular vectors"),
+        };
+        Ok((projection, sigma))
+    }
+
+    // One basis per head, one-sided along the head axis (see `heads::block_diagonal`). The
+    // zero blocks cost as much as a global basis of rank heads * r would. A hint that does
+    // not fit the gradient fails the refresh like a failed decomposition.
+    fn head_projection(
+        &self,
+        index: usize,
+        grad: &ArrayView2<f32>,
+        (heads, axis): (usize, HeadAxis),
+        rank_override: Option<usize>,
+        old: Option<Projection>,
+    ) -> Refreshed {
+        let (m, n) = grad.dim();
+        let head_len = head_len(heads, axis, (m, n)).map_err(FallbackReason::BadHint)?;
+        let rank = self.projection_rank(index, (m, n), rank_override).0.min(head_len);
+        let rows = axis == HeadAxis::Rows;
+
+        let mut blocks = Vec::with_capacity(heads);
+        let mut sigma = Vec::with_capacity(heads * rank);
+        for h in 0..heads {
+            let seed = self.refresh_seed(index) ^ ((h as u64) << 16);
+            let Decomposition { u, sigma: head_sigma, v } = self
+                .decomposer
+                .decompose(&GradInput::Dense(head_block(grad, axis, head_len, h)), rank, rows, !rows, seed)
+                .map_err(FallbackReason::DecompositionFailed)?;
+            let vectors = if rows { u } else { v }
+                .ok_or_else(|| FallbackReason::DecompositionFailed("no singular vectors".to_string()))?;
+            blocks.push(vectors.slice(s![.., ..rank]).to_owned());
+            sigma.extend(head_sigma.iter().take(rank));
+        }
+        let basis = block_diagonal(&blocks);
+        let sigma = Array1::from(sigma);
+        check_decomposition(&sigma, &[Some(&basis)])?;
+
+        let basis = match (axis, old) {
+            (HeadAxis::Rows, Some(Projection::Left(old))) | (HeadAxis::Cols, Some(Projection::Right(old))) if old.dim() == basis.dim() => {
+                self.ema_update(index, &old, &basis)
+            }
+            _ => basis,
+        };
+        let projection = match axis {
+            HeadAxis::Rows => Projection::Left(Arc::new(basis)),
+            HeadAxis::Cols => Projection::Right(Arc::new(basis)),
+        };
+        Ok((projection, sigma))
+    }
+
+    // Effective (rank, one_sided) of a parameter for a refresh.
+    fn projection_rank(&self, index: usize, (m, n): (usize, usize), rank_override: Option<usize>) -> (usize, bool) {
+        let (rank, one_sided) = match self.layer_rank(index) {
+            LayerRank::LowRank { rank, one_sided } => (rank_override.unwrap_or(rank), one_sided),
+            LayerRank::Full => (m.min(n), false),
+        };
+        (rank.min(m).min(n), one_sided)
+    }
+
+    // A random orthonormal projection with the shape a refresh of this parameter would produce.
+    fn random_projection(&self, index: usize, (m, n): (usize, usize), rank_override: Option<usize>) -> Projection {
+        let (rank, one_sided) = self.projection_rank(index, (m, n), rank_override);
+        let mut rng = StdRng::seed_from_u64(self.refresh_seed(index));
+        // A hint that does not fit falls through to a global basis.
+        if let Some(GradStructure::Heads { heads, axis }) = self.structure_hints.get(&index).copied() {
+            if let Ok(head_len) = head_len(heads, axis, (m, n)) {
+                let basis = random_head_basis(heads, head_len, rank.min(head_len), &mut rng);
+                return match axis {
+                    HeadAxis::Rows => Projection::Left(Arc::new(basis)),
+                    HeadAxis::Cols => Projection::Right(Arc::new(basis)),
+                };
+            }
+        }
+        let mut basis = |rows: usize| {
+            let gaussian = Array2::<f32>::random_using((rows, rank), StandardNormal, &mut rng);
+            Arc::new(orthonormalize(gaussian).expect("QR of a Gaussian matrix failed"))
+        };
+        let structure = self.structure_hints.get(&index).copied().unwrap_or(GradStructure::General);
+        match (one_sided, structure) {
+            (true, _) if self.one_sided.unwrap_or_default().takes_left((m, n)) => Projection::Left(basis(m)),
+            (true, _) => Projection::Right(basis(n)),
+            (false, GradStructure::Symmetric) if m == n => {
+                let p = basis(m);
+                Projection::TwoSided(Arc::clone(&p), p)
+            }
+            (false, _) => Projection::TwoSided(basis(m), basis(n)),
+        }
+    }
+
+    fn refresh_seed(&self, index: usize) -> u64 {
+        ((self.step as u64) << 32) ^ index as u64
+    }
+
+    fn record_spectrum(&mut self, index: usize, sigma: &Array1<f32>, top_k: usize) {
+        let label = self.param_label(index);
+        for (k, &value) in sigma.iter().take(top_k).enumerate() {
+            self.metrics.record(format!("{}/singular_value/{}", label, k), self.step, value);
+        }
+        self.metrics.record(format!("{}/effective_rank", label), self.step, effective_rank(sigma));
+    }
+
+    // "param<i>", or the registered name.
+    fn param_label(&self, index: usize) -> String {
+        match &self.registry {
+            Some(registry) if index < registry.len() => registry.name(index).to_string(),
+            _ => format!("param{}", index),
+        }
+    }
+
+    // Checkpoint keys of the parameters; see `registry::param_keys`.
+    pub fn param_keys(&self) -> Vec<String> {
+        param_keys(self.registry.as_deref(), self.projections.len())
+    }
+
+    fn project(&self, grad: &GradInput, projection: &Projection) -> Array2<f32> {
+        #[cfg(feature = "half")]
+        if let (true, GradInput::Dense(grad)) = (self.half_precision, grad) {
+            return self.project_f16(grad, projection);
+        }
+        match (grad, projection) {
+            (GradInput::Dense(grad), Projection::TwoSided(p, q)) => p.t().dot(&grad.dot(&**q)),
+            (GradInput::Dense(grad), Projection::Left(p)) => p.t().dot(grad),
+            (GradInput::Dense(grad), Projection::Right(q)) => grad.dot(&**q),
+            (GradInput::Sparse(grad), Projection::TwoSided(p, q)) => p.t().dot(&grad.dot(&q.view())),
+            // P^T G = (G^T P)^T
+            (GradInput::Sparse(grad), Projection::Left(p)) => grad.t_dot(&p.view()).reversed_axes(),
+            (GradInput::Sparse(grad), Projection::Right(q)) => grad.dot(&q.view()),
+        }
+    }
+
+    fn project_back(&self, update: &ArrayView2<f32>, projection: &Projection) -> Array2<f32> {
+        #[cfg(feature = "half")]
+        if self.half_precision {
+            return self.project_back_f16(update, projection);
+        }
+        match projection {
+            Projection::TwoSided(p, q) => p.dot(&update.dot(&q.t())),
+            Projection::Left(p) => p.dot(update),
+            Projection::Right(q) => update.dot(&q.t()),
+        }
+    }
+
+    fn ema_update(&self, index: usize, old: &Array2<f32>, new: &Array2<f32>) -> Array2<f32> {
+        let decay = self.ema_overrides.get(&index).copied().unwrap_or(self.ema_decay);
+        old * decay + new * (1.0 - decay)
+    }
+}
+
+#[cfg(feature = "half")]
+impl GaLoreProjection {
+    // The gradient stays f32; only the factors are read as f16.
+    fn project_f16(&self, grad: &ArrayView2<f32>, projection: &Projection) -> Array2<f32> {
+        match projection {
+            Projection::TwoSided(p, q) => {
+                let gq = gemm(grad, &self.half_factors.get(q).view());
+                gemm(&self.half_factors.get(p).t(), &gq.view())
+            }
+            Projection::Left(p) => gemm(&self.half_factors.get(p).t(), grad),
+            Projection::Right(q) => gemm(grad, &self.half_factors.get(q).view()),
         }
     }
 
-    fn project(&self, grad: &ArrayView2<f32>, p: &Array2<f32>, q: &Array2<f32>) -> Array2<f32> {
-        p.t().dot(&grad.dot(q))
+    fn project_back_f16(&self, update: &ArrayView2<f32>, projection: &Projection) -> Array2<f32> {
+        let (rows, cols) = match projection {
+            Projection::TwoSided(p, q) => (p.nrows(), q.nrows()),
+            Projection::Left(p) => (p.nrows(), update.ncols()),
+            Projection::Right(q) => (update.nrows(), q.nrows()),
+        };
+        let mut out = Array2::zeros((rows, cols));
+        self.project_back_f16_into(update, projection, &mut out.view_mut());
+        out
     }
 
-    fn project_back(&self, update: &ArrayView2<f32>, p: &Array2<f32>, q: &Array2<f32>) -> Array2<f32> {
-        p.dot(&update.dot(&q.t()))
+    fn project_back_f16_into(&self, update: &ArrayView2<f32>, projection: &Projection, out: &mut ArrayViewMut2<f32>) {
+        match projection {
+            Projection::TwoSided(p, q) => {
+                let uq = gemm(update, &self.half_factors.get(q).t());
+                gemm_acc(&self.half_factors.get(p).view(), &uq.view(), out)
+            }
+            Projection::Left(p) => gemm_acc(&self.half_factors.get(p).view(), update, out),
+            Projection::Right(q) => gemm_acc(update, &self.half_factors.get(q).t(), out),
+        }
     }
+}
+
+// A recomputed projection with the spectrum it came from, or why it could not be computed.
+type Refreshed = Result<(Projection, Array1<f32>), FallbackReason>;
+
+impl Projection {
+    // The projection of G^T: P^T G Q = (Q^T G^T P)^T.
+    fn transposed(&self) -> Projection {
+        match self {
+            Projection::TwoSided(p, q) => Projection::TwoSided(Arc::clone(q), Arc::clone(p)),
+            Projection::Left(p) => Projection::Right(Arc::clone(p)),
+            Projection::Right(q) => Projection::Left(Arc::clone(q)),
+        }
+    }
+}
+
+// Top-`rank` eigenvectors by eigenvalue magnitude, with the magnitudes (sorted) standing in
+// for singular values.
+fn symmetric_basis(grad: &ArrayView2<f32>, rank: usize) -> Result<(Array2<f32>, Array1<f32>), LinalgError> {
+    let sym = (grad + &grad.t()) * 0.5;
+    let (eigvals, eigvecs) = sym.eigh(UPLO::Lower)?;
+
+    let mut order: Vec<usize> = (0..eigvals.len()).collect();
+    order.sort_by(|&a, &b| eigvals[b].abs().total_cmp(&eigvals[a].abs()));
+    let basis = eigvecs.select(Axis(1), &order[..rank]);
+    let sigma = order.iter().map(|&i| eigvals[i].abs()).collect();
+    Ok((basis, sigma))
+}
 
-    fn ema_update(&self, old: &Array2<f32>, new: &Array2<f32>) -> Array2<f32> {
-        old * self.ema_decay + new * (1.0 - self.ema_decay)
+// Entropy-based effective rank (Roy & Vetterli): exp of the Shannon entropy of the
+// normalized singular values. Close to 1 when one direction dominates the gradient.
+pub fn effective_rank(sigma: &Array1<f32>) -> f32 {
+    let total: f32 = sigma.sum();
+    if total <= 0.0 {
+        return 0.0;
     }
+    let entropy: f32 = sigma
+        .iter()
+        .map(|&s| s / total)
+        .filter(|&p| p > 0.0)
+        .map(|p| -p * p.ln())
+        .sum();
+    entropy.exp()
 }
 
 pub struct GaLoreOptimizer<O: Optimizer> {
     base_optimizer: O,
     galore: GaLoreProjection,
+    pipeline: Vec<Box<dyn GradTransform>>,
+    step: usize,
+    timings: StepTimings,
+    micro_batches: usize,
+    accumulated: usize,
+    accumulation: Vec<Array2<f32>>,
+    weight_decay: Option<(f32, WeightDecayMode)>,
+    rollback: Option<RollbackPolicy>,
+    recent_losses: VecDeque<f32>,
+    snapshot: Option<RefreshSnapshot>,
+    #[cfg(feature = "experimental-fp8")]
+    fp8_accumulation: Option<(Fp8Format, usize)>,
+    #[cfg(feature = "experimental-fp8")]
+    accumulation_fp8: Vec<Fp8Matrix>,
 }
 
 impl<O: Optimizer> GaLoreOptimizer<O> {
     pub fn new(base_optimizer: O, rank: usize, update_freq: usize, ema_decay: f32) -> Self {
+        Self::with_projection(base_optimizer, GaLoreProjection::new(rank, update_freq, ema_decay))
+    }
+
+    pub fn with_projection(base_optimizer: O, galore: GaLoreProjection) -> Self {
         GaLoreOptimizer {
             base_optimizer,
-            galore: GaLoreProjection::new(rank, update_freq, ema_decay),
+            galore,
+            pipeline: default_pipeline(),
+            step: 0,
+            timings: StepTimings::default(),
+            micro_batches: 1,
+            accumulated: 0,
+            accumulation: Vec::new(),
+            weight_decay: None,
+            rollback: None,
+            recent_losses: VecDeque::new(),
+            snapshot: None,
+            #[cfg(feature = "experimental-fp8")]
+            fp8_accumulation: None,
+            #[cfg(feature = "experimental-fp8")]
+            accumulation_fp8: Vec::new(),
         }
     }
 
-    pub fn step(&mut self, gradients: Vec<ArrayView2<f32>>) -> Vec<Array2<f32>> {
-        let projected_grads = self.galore.project_gradient(gradients);
-        let updates = self.base_optimizer.compute_updates(&projected_grads);
-        self.galore.project_update(updates.iter().map(|u| u.view()).collect())
+    // e.g. `.with_transform_before("project", Box::new(ClipGradNorm::new(1.0)))?`
+    pub fn with_transform_before(mut self, stage: &str, transform: Box<dyn GradTransform>) -> Result<Self, PipelineError> {
+        let index = self.stage_index(stage).ok_or_else(|| PipelineError::UnknownStage(stage.to_string()))?;
+        self.pipeline.insert(index, transform);
+        Ok(self)
     }
-}
 
-pub trait Optimizer {
-    fn compute_updates(&mut self, gradients: &[Array2<f32>]) -> Vec<Array2<f32>>;
-}
+    pub fn with_transform_after(mut self, stage: &str, transform: Box<dyn GradTransform>) -> Result<Self, PipelineError> {
+        let index = self.stage_index(stage).ok_or_else(|| PipelineError::UnknownStage(stage.to_string()))?;
+        self.pipeline.insert(index + 1, transform);
+        Ok(self)
+    }
 
-// Example implementation of Adam optimizer
-pub struct Adam {
-    lr: f32,
-    beta1: f32,
-    beta2: f32,
-    epsilon: f32,
-    m: Vec<Array2<f32>>,
-    v: Vec<Array2<f32>>,
-    t: usize,
-}
+    // Installs a gradient-norm clip on the chosen side of "project". A full-space clip goes
+    // ahead of any "precondition", so it sees the raw gradients.
+    pub fn with_grad_clipping(mut self, max_norm: f32, space: ClipSpace) -> Self {
+        let clip = Box::new(ClipGradNorm::new(max_norm).with_space(space));
+        match space {
+            ClipSpace::Full => {
+                let index = self.stage_index("precondition").or_else(|| self.stage_index("project")).expect("core stages are always present");
+                self.pipeline.insert(index, clip);
+                self
+            }
+            ClipSpace::Projected => self.with_core_after("project", clip),
+        }
+    }
+
+    // Decoupled weight decay; see `WeightDecayMode`. Requires driving the optimizer through
+    // `apply_updates` or `accumulate_updates`, and a base optimizer that reports its
+    // learning rate (`PipelineError::NoLearningRate` otherwise). Full decay goes after the last
+    // "unprecondition", so it is in the parameter's own coordinates whichever of this and
+    // `with_preconditioner` is called first. Subspace decay is part of the projected update,
+    // so on preconditioned parameters it is scaled by the RMS like the rest of it.
+    pub fn with_weight_decay(mut self, weight_decay: f32, mode: WeightDecayMode) -> Result<Self, PipelineError> {
+        if self.base_optimizer.learning_rate().is_none() {
+            return Err(PipelineError::NoLearningRate);
+        }
+        self.weight_decay = Some((weight_decay, mode));
+        let stage = Box::new(WeightDecay::new(weight_decay, mode));
+        match mode {
+            WeightDecayMode::Full => {
+                let index = self
+                    .pipeline
+                    .iter()
+                    .rposition(|t| t.name() == "unprecondition")
+                    .or_else(|| self.stage_index("project_back"))
+                    .expect("core stages are always present");
+                self.pipeline.insert(index + 1, stage);
+                Ok(self)
+            }
+            WeightDecayMode::Subspace => Ok(self.with_core_after("optimize", stage)),
+        }
+    }
 
-impl Adam {
-    pub fn new(lr: f32, beta1: f32, beta2: f32, epsilon: f32) -> Self {
-        Adam {
-            lr,
-            beta1,
-            beta2,
-            epsilon,
-            m: Vec::new(),
-            v: Vec::new(),
-            t: 0,
+    // Caps each update in `group` at `max_ratio` times its parameter's norm; call once per
+    // group for different ratios. Requires `apply_updates` or `accumulate_updates`.
+    pub fn with_update_norm_cap(mut self, max_ratio: f32, group: ParamGroup) -> Self {
+        self.push_transform(Box::new(UpdateNormCap::new(max_ratio).with_group(group)));
+        self
+    }
+
+    // RMS whitening of the parameters in `group` around the projection, with decay `beta`
+    // for the running estimate (e.g. 0.99), inverted directly after "project_back"; see
+    // `pipeline::Precondition`.
+    pub fn with_preconditioner(self, beta: f32, estimate: RmsEstimate, group: ParamGroup) -> Self {
+        let (precondition, unprecondition) = precondition_stages(beta, estimate, group);
+        self.with_core_before("project", Box::new(precondition))
+            .with_core_after("project_back", Box::new(unprecondition))
+    }
+
+    // Opt-in variant of `with_preconditioner` without the inverse: the update stays in
+    // whitened coordinates, so e.g. Adam moves every element by about lr instead of lr times
+    // its RMS. Only the subspace choice matches `with_preconditioner`.
+    pub fn with_whitened_preconditioner(self, beta: f32, estimate: RmsEstimate, group: ParamGroup) -> Self {
+        let (precondition, _) = precondition_stages(beta, estimate, group);
+        self.with_core_before("project", Box::new(precondition))
+    }
+
+    // Averages gradients across data-parallel replicas; see `AllReduceMode`. The full-size
+    // reduction runs first, ahead of any other pre-projection stage such as clipping.
+    pub fn with_all_reduce(mut self, collective: Arc<dyn Collective>, mode: AllReduceMode) -> Self {
+        let (full, projected) = all_reduce_stages(collective, mode);
+        self.pipeline.insert(0, Box::new(full));
+        match projected {
+            Some(projected) => self.with_core_after("project", Box::new(projected)),
+            None => self,
+        }
+    }
+
+    // Number of micro-batches `accumulate` sums (after projection) before taking a step.
+    pub fn with_projected_accumulation(mut self, micro_batches: usize) -> Self {
+        assert!(micro_batches > 0, "need at least one micro-batch per step");
+        self.micro_batches = micro_batches;
+        self
+    }
+
+    pub fn micro_batches(&self) -> usize {
+        self.micro_batches
+    }
+
+    // Experimental: keep the `accumulate` buffers in fp8 with one scale per `tile` elements,
+    // a quarter of the f32 footprint. Every micro-batch re-quantizes the running sum.
+    #[cfg(feature = "experimental-fp8")]
+    pub fn with_fp8_accumulation(mut self, format: Fp8Format, tile: usize) -> Self {
+        assert!(tile > 0, "tile size must be positive");
+        self.fp8_accumulation = Some((format, tile));
+        self
+    }
+
+    pub fn push_transform(&mut self, transform: Box<dyn GradTransform>) {
+        self.pipeline.push(transform);
+    }
+
+    pub fn stage_names(&self) -> Vec<&str> {
+        self.pipeline.iter().map(|t| t.name()).collect()
+    }
+
+    fn stage_index(&self, stage: &str) -> Option<usize> {
+        self.pipeline.iter().position(|t| t.name() == stage)
+    }
+
+    // Checked before any state changes, so a refused step leaves the optimizer as it was.
+    fn check_no_param_stages(&self) -> Result<(), PipelineError> {
+        match self.pipeline.iter().find(|t| t.needs_params()) {
+            Some(stage) => Err(PipelineError::NeedsParams(stage.name().to_string())),
+            None => Ok(()),
+        }
+    }
+
+    // `GradTransform::check` for every stage, likewise before any state changes.
+    fn check_stages(&mut self) -> Result<(), PipelineError> {
+        for stage in self.pipeline.iter_mut() {
+            stage.check(&self.galore, &self.base_optimizer)?;
+        }
+        Ok(())
+    }
+
+    // For the built-in stages, which are installed by `new` and never removed.
+    fn with_core_before(self, stage: &str, transform: Box<dyn GradTransform>) -> Self {
+        self.with_transform_before(stage, transform).unwrap_or_else(|e| unreachable!("{}", e))
+    }
+
+    fn with_core_after(self, stage: &str, transform: Box<dyn GradTransform>) -> Self {
+        self.with_transform_after(stage, transform).unwrap_or_else(|e| unreachable!("{}", e))
+    }
+
+    pub fn metrics(&self) -> &Metrics {
+        self.galore.metrics()
+    }
+
+    pub fn projection(&self) -> &GaLoreProjection {
+        &self.galore
+    }
+
+    // See `RollbackPolicy`; losses are reported through `observe_loss`.
+    pub fn with_refresh_rollback(mut self, policy: RollbackPolicy) -> Self {
+        self.rollback = Some(policy);
+        self
+    }
+
+    pub fn refresh_rollback(&self) -> Option<RollbackPolicy> {
+        self.rollback
+    }
+
+    // The loss of the step just taken (computed from the weights before its update), once
+    // per step, with the weights the optimizer steps. Returns whether it rolled back the last
+    // projection refresh, in which case `params` hold the weights from before it again;
+    // rollbacks are also recorded in the projection metrics as "refresh/rollback" with the
+    // loss ratio. Fails, leaving everything as it was, if the kept state does not fit or if
+    // an accumulation window is still open.
+    pub fn observe_loss<S: DataMut<Elem = f32>>(&mut self, loss: f32, params: &mut [ArrayBase<S, Ix2>]) -> Result<bool, RollbackError> {
+        let Some(policy) = self.rollback else {
+            return Ok(false);
+        };
+        if self.accumulated != 0 {
+            return Err(RollbackError::MidAccumulation);
+        }
+        let Some(snapshot) = &mut self.snapshot else {
+            push_bounded(&mut self.recent_losses, loss, policy.window);
+            return Ok(false);
+        };
+        let Some(baseline) = snapshot.baseline else {
+            push_bounded(&mut self.recent_losses, loss, policy.window);
+            snapshot.baseline = Some(self.recent_losses.iter().sum::<f32>() / self.recent_losses.len() as f32);
+            return Ok(false);
+        };
+        if is_spike(loss, baseline, &policy) {
+            let snapshot = self.snapshot.take().expect("snapshot checked above");
+            if let Err(e) = self.restore(&snapshot, params) {
+                self.snapshot = Some(snapshot);
+                return Err(RollbackError::Restore(e));
+            }
+            self.galore.metrics.record("refresh/rollback", self.galore.step, loss / baseline);
+            return Ok(true);
+        }
+        push_bounded(&mut self.recent_losses, loss, policy.window);
+        snapshot.remaining -= 1;
+        if snapshot.remaining == 0 {
+            self.snapshot = None;
+        }
+        Ok(false)
+    }
+
+    // Puts back the weights, projections and optimizer state kept by `snapshot`. The
+    // optimizer state is loaded first, from a copy, so a failure changes nothing.
+    fn restore<S: DataMut<Elem = f32>>(&mut self, snapshot: &RefreshSnapshot, params: &mut [ArrayBase<S, Ix2>]) -> Result<(), CheckpointError> {
+        if !snapshot.weights.fits(params) {
+            return Err(CheckpointError::Format("weights do not match the ones kept before the refresh".to_string()));
+        }
+        let mut current = Checkpoint::new();
+        self.save_optimizer_state(&mut current, "rollback");
+        if let Err(e) = self.load_optimizer_state(&snapshot.optimizer, "rollback") {
+            self.load_optimizer_state(&current, "rollback").expect("reloading the current optimizer state failed");
+            return Err(e);
+        }
+        self.galore.projections = snapshot.projections.clone();
+        snapshot.weights.restore(params);
+        Ok(())
+    }
+
+    // Keeps the state a refresh in the coming step would replace: the weights if `params`
+    // are given, otherwise the updates handed out from here on (see `RollbackPolicy`). A
+    // refresh inside the window of an earlier one keeps the earlier snapshot, so a rollback
+    // undoes both.
+    fn snapshot_before_refresh(&mut self, params: Option<&[ArrayView2<f32>]>) {
+        let Some(policy) = self.rollback else {
+            return;
+        };
+        let count = self.galore.projections.len();
+        if self.snapshot.is_some() || count == 0 || !self.galore.refresh_pending(count) {
+            return;
+        }
+        let mut optimizer = Checkpoint::new();
+        self.save_optimizer_state(&mut optimizer, "rollback");
+        let weights = match params {
+            Some(params) => KeptWeights::Weights(params.iter().map(|p| p.to_owned()).collect()),
+            None => KeptWeights::Updates(Vec::new()),
+        };
+        self.snapshot = Some(RefreshSnapshot { projections: self.galore.projections.clone(), optimizer, weights, baseline: None, remaining: policy.window });
+    }
+
+    // Adds updates handed to the caller to a snapshot that keeps updates instead of weights.
+    fn keep_updates(&mut self, updates: &[Array2<f32>]) {
+        if let Some(snapshot) = &mut self.snapshot {
+            snapshot.weights.add_updates(updates);
+        }
+    }
+
+    // The base optimizer's state under `prefix`, and that of the pipeline stages under
+    // "<prefix>.pipeline.<stage>".
+    fn save_optimizer_state(&self, checkpoint: &mut Checkpoint, prefix: &str) {
+        let keys = self.galore.param_keys();
+        self.base_optimizer.save_state(checkpoint, prefix, &keys);
+        for stage in &self.pipeline {
+            stage.save_state(checkpoint, &format!("{}.pipeline.{}", prefix, stage.name()), &keys);
+        }
+    }
+
+    fn load_optimizer_state(&mut self, checkpoint: &Checkpoint, prefix: &str) -> Result<(), CheckpointError> {
+        let keys = self.galore.param_keys();
+        self.base_optimizer.load_state(checkpoint, prefix, &keys)?;
+        for stage in &mut self.pipeline {
+            let stage_prefix = format!("{}.pipeline.{}", prefix, stage.name());
+            stage.load_state(checkpoint, &stage_prefix, &keys)?;
+        }
+        Ok(())
+    }
+
+    pub fn refresh_projection(&mut self, gradients: Vec<ArrayView2<f32>>) {
+        self.galore.refresh(gradients);
+    }
+
+    pub fn last_timings(&self) -> &StepTimings {
+        &self.timings
+    }
+
+    // Projection state goes under "galore.", base optimizer state under "optimizer.".
+    pub fn save_state(&self, checkpoint: &mut Checkpoint) {
+        checkpoint.set_metadata("step", self.step);
+        if let Some((weight_decay, mode)) = self.weight_decay {
+            checkpoint.set_metadata("weight_decay", weight_decay);
+            checkpoint.set_metadata("weight_decay_mode", mode);
+        }
+        if let Some(registry) = self.galore.registry() {
+            registry.save(checkpoint, "registry");
+        }
+        self.galore.save_state(checkpoint, "galore");
+        self.save_optimizer_state(checkpoint, "optimizer");
+    }
+
+    pub fn load_state(&mut self, checkpoint: &Checkpoint) -> Result<(), CheckpointError> {
+        // The two modes produce different weights, so resuming under the other one is refused.
+        if checkpoint.metadata("weight_decay_mode").is_some() {
+            let saved: WeightDecayMode = checkpoint.parse_metadata("weight_decay_mode")?;
+            let configured = self.weight_decay.map(|(_, mode)| mode);
+            if configured.is_some_and(|mode| mode != saved) {
+                return Err(CheckpointError::Unsupported(format!(
+                    "checkpoint was trained with {} weight decay, optimizer uses {}",
+                    saved,
+                    configured.unwrap()
+                )));
+            }
+        }
+        self.galore.load_state(checkpoint, "galore")?;
+        self.load_optimizer_state(checkpoint, "optimizer")?;
+        self.step = checkpoint.parse_metadata("step")?;
+        Ok(())
+    }
+
+    // Gradients may be views or owned arrays handed over one at a time as backward produces
+    // them. When "project" is the first stage each one is projected as it arrives (see
+    // `GaLoreProjection::project_stream`), so on steps without a refresh the full-size
+    // gradients need not all be alive at once. The "project" timing then includes any time
+    // spent producing them. Fails with `PipelineError::NeedsParams` if a stage reads the
+    // parameters, which only `apply_updates` provides.
+    pub fn step<'g, I>(&mut self, gradients: I) -> Result<Vec<Array2<f32>>, PipelineError>
+    where
+        I: IntoIterator,
+        I::Item: Into<Grad<'g>>,
+    {
+        self.check_no_param_stages()?;
+        self.check_stages()?;
+        let gradients = gradients.into_iter().map(Into::into);
+        if self.stage_index("project") != Some(0) {
+            let updates = self.step_with_params(gradients.collect(), None);
+            self.keep_updates(&updates);
+            return Ok(updates);
+        }
+        assert_eq!(self.accumulated, 0, "step called in the middle of an accumulation window");
+        self.snapshot_before_refresh(None);
+        self.step += 1;
+        let mut timings = StepTimings::default();
+        let start = Instant::now();
+        let projected = self.galore.project_stream(gradients);
+        timings.record("project", start.elapsed(), self.galore.last_refresh_time());
+        let grads = projected.into_iter().map(CowArray::from).collect();
+        let updates = self.run_stages(1..self.pipeline.len(), grads, self.step, None, &mut timings);
+        self.timings = timings;
+        let updates: Vec<_> = updates.into_iter().map(|u| u.into_owned()).collect();
+        self.keep_updates(&updates);
+        Ok(updates)
+    }
+
+    // `step` with gradients given by registered name, in any order; the updates come back
+    // named in registry order. Without `GaLoreProjection::with_registry` this is
+    // `RegistryError::NoRegistry`; pipeline errors from `step` come back as
+    // `RegistryError::Pipeline`.
+    pub fn step_named<S: AsRef<str>>(&mut self, gradients: Vec<(S, ArrayView2<f32>)>) -> Result<Vec<(String, Array2<f32>)>, RegistryError> {
+        let registry = Arc::clone(self.galore.registry.as_ref().ok_or(RegistryError::NoRegistry)?);
+        let gradients = registry.order(gradients)?;
+        for (i, gradient) in gradients.iter().enumerate() {
+            registry.check_shape(i, gradient.dim())?;
+        }
+        Ok(registry.named(self.step(gradients)?))
+    }
+
+    // Computes the updates and adds them to `params`, which may be owned arrays or mutable
+    // views such as those of `NeuralNetwork::parameters_mut`, updated in place. Stages that
+    // read the parameters, such as weight decay, only work through this entry point or
+    // `accumulate_updates`. When
+    // "project_back" is the last stage the back-projection is fused with the write-back
+    // (`GaLoreProjection::apply_update`) and the full-size updates are never materialized.
+    // Fails, leaving `params` and the optimizer as they were, if a stage's check does.
+    pub fn apply_updates<S: DataMut<Elem = f32> + Send>(&mut self, params: &mut [ArrayBase<S, Ix2>], gradients: Vec<ArrayView2<f32>>) -> Result<(), PipelineError> {
+        self.check_stages()?;
+        if self.pipeline.last().map(|t| t.name()) == Some("project_back") {
+            self.apply_updates_fused(params, gradients);
+            return Ok(());
+        }
+        let views: Vec<ArrayView2<f32>> = params.iter().map(|p| p.view()).collect();
+        let updates = self.step_with_params(gradients.into_iter().map(CowArray::from).collect(), Some(&views));
+        for (param, update) in params.iter_mut().zip(updates.iter()) {
+            *param += update;
+        }
+        Ok(())
+    }
+
+    fn apply_updates_fused<S: DataMut<Elem = f32> + Send>(&mut self, params: &mut [ArrayBase<S, Ix2>], gradients: Vec<ArrayView2<f32>>) {
+        assert_eq!(self.accumulated, 0, "step called in the middle of an accumulation window");
+        let views: Vec<ArrayView2<f32>> = params.iter().map(|p| p.view()).collect();
+        self.snapshot_before_refresh(Some(&views));
+        self.step += 1;
+        let mut timings = StepTimings::default();
+        let grads = gradients.into_iter().map(CowArray::from).collect();
+        let updates = self.run_stages(0..self.pipeline.len() - 1, grads, self.step, Some(&views), &mut timings);
+
+        let start = Instant::now();
+        self.galore.apply_update(params, updates.iter().map(|u| u.view()).collect());
+        timings.record("project_back", start.elapsed(), Duration::ZERO);
+        self.timings = timings;
+    }
+
+    fn step_with_params<'p>(&mut self, grads: Vec<Grad>, params: Option<&'p [ArrayView2<'p, f32>]>) -> Vec<Array2<f32>> {
+        assert_eq!(self.accumulated, 0, "step called in the middle of an accumulation window");
+        self.snapshot_before_refresh(params);
+        self.step += 1;
+        let mut timings = StepTimings::default();
+        let grads = self.run_stages(0..self.pipeline.len(), grads, self.step, params, &mut timings);
+        self.timings = timings;
+        grads.into_iter().map(|g| g.into_owned()).collect()
+    }
+
+    // Gradient accumulation in the projected space: stages up to and including "project" run
+    // on each micro-batch, and the projected gradients are summed into a rank x n buffer
+    // instead of a full m x n one. After `micro_batches` calls the mean goes through the rest
+    // of the pipeline and the updates are returned; earlier calls return None.
+    //
+    // The projection is refreshed (if due) on the first micro-batch of a window, from that
+    // micro-batch's gradient alone, and is frozen for the rest of the window so that every
+    // summed gradient lives in the same subspace. Fails like `step` if a stage reads the
+    // parameters; see `accumulate_updates`.
+    pub fn accumulate(&mut self, gradients: Vec<ArrayView2<f32>>) -> Result<Option<Vec<Array2<f32>>>, PipelineError> {
+        self.check_no_param_stages()?;
+        self.check_stages()?;
+        let Some(updates) = self.accumulate_with_params(gradients, None) else {
+            return Ok(None);
+        };
+        self.keep_updates(&updates);
+        Ok(Some(updates))
+    }
+
+    // `accumulate` for pipelines with stages that read the parameters, such as weight decay:
+    // when a window completes its updates are added to `params`. Returns whether they were.
+    pub fn accumulate_updates<S: DataMut<Elem = f32>>(&mut self, params: &mut [ArrayBase<S, Ix2>], gradients: Vec<ArrayView2<f32>>) -> Result<bool, PipelineError> {
+        self.check_stages()?;
+        let updates = {
+            let views: Vec<ArrayView2<f32>> = params.iter().map(|p| p.view()).collect();
+            self.accumulate_with_params(gradients, Some(&views))
+        };
+        let Some(updates) = updates else {
+            return Ok(false);
+        };
+        for (param, update) in params.iter_mut().zip(updates.iter()) {
+            *param += update;
+        }
+        Ok(true)
+    }
+
+    fn accumulate_with_params<'p>(&mut self, gradients: Vec<ArrayView2<f32>>, params: Option<&'p [ArrayView2<'p, f32>]>) -> Option<Vec<Array2<f32>>> {
+        let split = self.stage_index("project").expect("accumulation needs a \"project\" stage") + 1;
+        let mut timings = if self.accumulated == 0 { StepTimings::default() } else { self.timings.clone() };
+        if self.accumulated == 0 {
+            self.snapshot_before_refresh(params);
+        }
+
+        self.galore.set_frozen(self.accumulated > 0);
+        let grads = gradients.into_iter().map(CowArray::from).collect();
+        let projected = self.run_stages(0..split, grads, self.step + 1, params, &mut timings);
+        self.galore.set_frozen(false);
+
+        self.add_to_accumulation(projected);
+        self.accumulated += 1;
+        if self.accumulated < self.micro_batches {
+            self.timings = timings;
+            return None;
+        }
+
+        let scale = 1.0 / self.accumulated as f32;
+        let grads = self
+            .take_accumulation()
+            .into_iter()
+            .map(|sum| CowArray::from(sum * scale))
+            .collect();
+        self.accumulated = 0;
+        self.step += 1;
+        let updates = self.run_stages(split..self.pipeline.len(), grads, self.step, params, &mut timings);
+        self.timings = timings;
+        Some(updates.into_iter().map(|g| g.into_owned()).collect())
+    }
+
+    // One step from gradients already projected with the current projections (e.g. by a
+    // remote worker): the stages after "project" run, the updates are added to `params` and
+    // returned. Nothing is refreshed here, and refreshes made through `refresh_projection`
+    // are already out with the workers, so this path keeps no rollback snapshot and
+    // `observe_loss` never rolls it back.
+    pub fn apply_projected_updates<S: DataMut<Elem = f32>>(&mut self, params: &mut [ArrayBase<S, Ix2>], projected: Vec<ArrayView2<f32>>) -> Result<Vec<Array2<f32>>, PipelineError> {
+        self.check_stages()?;
+        assert_eq!(self.accumulated, 0, "step called in the middle of an accumulation window");
+        let split = self.stage_index("project").expect("projected updates need a \"project\" stage") + 1;
+        self.step += 1;
+        let mut timings = StepTimings::default();
+        let grads = projected.into_iter().map(CowArray::from).collect();
+        let updates: Vec<Array2<f32>> = {
+            let views: Vec<ArrayView2<f32>> = params.iter().map(|p| p.view()).collect();
+            let updates = self.run_stages(split..self.pipeline.len(), grads, self.step, Some(&views), &mut timings);
+            updates.into_iter().map(|u| u.into_owned()).collect()
+        };
+        for (param, update) in params.iter_mut().zip(updates.iter()) {
+            *param += update;
+        }
+        self.timings = timings;
+        Ok(updates)
+    }
+
+    fn add_to_accumulation(&mut self, projected: Vec<Grad>) {
+        #[cfg(feature = "experimental-fp8")]
+        if let Some((format, tile)) = self.fp8_accumulation {
+            if self.accumulated == 0 {
+                self.accumulation_fp8 = projected.iter().map(|g| Fp8Matrix::encode(&g.view(), format, tile)).collect();
+            } else {
+                for (sum, grad) in self.accumulation_fp8.iter_mut().zip(projected.iter()) {
+                    sum.add_assign(&grad.view());
+                }
+            }
+            return;
+        }
+        if self.accumulated == 0 {
+            self.accumulation = projected.into_iter().map(|g| g.into_owned()).collect();
+        } else {
+            for (sum, grad) in self.accumulation.iter_mut().zip(projected.iter()) {
+                *sum += grad;
+            }
         }
     }
+
+    fn take_accumulation(&mut self) -> Vec<Array2<f32>> {
+        #[cfg(feature = "experimental-fp8")]
+        if self.fp8_accumulation.is_some() {
+            return std::mem::take(&mut self.accumulation_fp8).iter().map(Fp8Matrix::decode).collect();
+        }
+        std::mem::take(&mut self.accumulation)
+    }
+
+    fn run_stages<'a, 'p>(
+        &mut self,
+        stages: Range<usize>,
+        mut grads: Vec<Grad<'a>>,
+        step: usize,
+        params: Option<&'p [ArrayView2<'p, f32>]>,
+        timings: &mut StepTimings,
+    ) -> Vec<Grad<'a>> {
+        let mut ctx = TransformContext {
+            galore: &mut self.galore,
+            optimizer: &mut self.base_optimizer,
+            step,
+            params,
+        };
+
+        for stage in self.pipeline[stages].iter_mut() {
+            let start = Instant::now();
+            grads = stage.apply(grads, &mut ctx);
+            timings.record(stage.name(), start.elapsed(), ctx.galore.last_refresh_time());
+        }
+        grads
+    }
 }
 
-impl Optimizer for Adam {
-    fn compute_updates(&mut self, gradients: &[Array2<f32>]) -> Vec<Array2<f32>> {
-        self.t += 1;
-        if self.m.is_empty() {
-            self.m = gradients.iter().map(|g| Array2::zeros(g.dim())).collect();
-            self.v = gradients.iter().map(|g| Array2::zeros(g.dim())).collect();
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::galore::optimizer::Adam;
+    use crate::galore::testing::{adam, gradient, weights, Sgd, LR};
+
+    #[test]
+    fn min_param_size_and_param_order_pick_the_projected_layers() {
+        let small = Array2::from_shape_fn((4, 6), |(i, j)| (i + j) as f32);
+        let shapes = [(24, 40), (4, 6), (24, 40)];
+        let galore = GaLoreProjection::new(4, 10, 0.0).with_min_param_size(100);
+        assert_eq!(galore.projection_order(&shapes), [0, 2]);
+        // Unlisted parameters follow the listed ones; the order never excludes any.
+        let mut galore = galore.with_param_order(vec![2, 1, 2]);
+        assert_eq!(galore.projection_order(&shapes), [2, 0]);
+
+        let (first, last) = (gradient(0), gradient(1));
+        let projected = galore.project_gradient(vec![first.view(), small.view(), last.view()]);
+        assert!(galore.is_projected(0) && !galore.is_projected(1) && galore.is_projected(2));
+        assert_eq!(projected[0].dim(), (4, 4));
+        assert_eq!(projected[1], small);
+        assert_eq!(projected[2].dim(), (4, 4));
+    }
+
+    #[test]
+    fn effective_rank_counts_equally_weighted_directions() {
+        assert!((effective_rank(&Array1::ones(4)) - 4.0).abs() < 1e-5);
+        assert!((effective_rank(&Array1::from(vec![2.0, 0.0, 0.0])) - 1.0).abs() < 1e-6);
+        assert_eq!(effective_rank(&Array1::zeros(3)), 0.0);
+    }
+
+    #[test]
+    fn spectrum_telemetry_records_each_refresh() {
+        let mut galore = GaLoreProjection::new(4, 2, 0.0).with_spectrum_telemetry(3);
+        for step in 0..3 {
+            galore.project_gradient(vec![gradient(step).view()]);
         }
+        let metrics = galore.metrics();
+        let steps = |name: &str| metrics.series(name).unwrap().iter().map(|&(step, _)| step).collect::<Vec<_>>();
+        assert_eq!(steps("param0/effective_rank"), [1, 2]);
+        assert!(metrics.series("param0/singular_value/3").is_none());
+        let sigma: Vec<f32> = (0..3).map(|k| metrics.latest(&format!("param0/singular_value/{}", k)).unwrap()).collect();
+        assert!(sigma[0] >= sigma[1] && sigma[1] >= sigma[2] && sigma[1] > 0.0);
+        let rank = metrics.latest("param0/effective_rank").unwrap();
+        assert!(rank > 1.0 && rank < 24.0);
+    }
 
-        gradients
-            .iter()
-            .zip(self.m.iter_mut())
-            .zip(self.v.iter_mut())
-            .map(|((g, m), v)| {
-                *m = self.beta1 * &*m + (1.0 - self.beta1) * g;
-                *v = self.beta2 * &*v + (1.0 - self.beta2) * g * g;
+    // P P^T, which is the same for any orthonormal basis of the subspace (signs included).
+    fn projector(basis: &Array2<f32>) -> Array2<f32> {
+        basis.dot(&basis.t())
+    }
+
+    #[test]
+    fn symmetric_hint_spans_the_same_subspace_as_the_svd() {
+        let g = gradient(0).slice_move(s![.., ..24]);
+        let sym = &g + &g.t();
+        let mut svd = GaLoreProjection::new(4, 100, 0.0);
+        let mut eigh = GaLoreProjection::new(4, 100, 0.0).with_structure_hint(0, GradStructure::Symmetric);
+        svd.project_gradient(vec![sym.view()]);
+        eigh.project_gradient(vec![sym.view()]);
+
+        let (Some(Projection::TwoSided(p, q)), Some(Projection::TwoSided(e, f))) = (&svd.projections[0], &eigh.projections[0]) else {
+            panic!("two-sided projections");
+        };
+        // The eigenbasis serves as both P and Q; the SVD's left and right vectors differ only
+        // by the signs of the eigenvalues, so all four span one subspace.
+        assert!(Arc::ptr_eq(e, f));
+        for basis in [p, q] {
+            assert!(projector(basis).abs_diff_eq(&projector(e), 1e-4));
+        }
+    }
+
+    #[test]
+    fn step_timings_split_the_refresh_out_of_projection() {
+        let mut optimizer = GaLoreOptimizer::new(Adam::new(1e-2, 0.9, 0.999, 1e-8), 4, 3, 0.0);
+        let mut refreshed = Vec::new();
+        for step in 0..3 {
+            let grad = gradient(step);
+            optimizer.step(vec![grad.view()]).unwrap();
+            let timings = optimizer.last_timings();
+            let stages: Vec<&str> = timings.stages.iter().map(|(name, _)| name.as_str()).collect();
+            assert_eq!(stages, ["project", "optimize", "project_back"]);
+            assert_eq!(timings.total(), timings.stages.iter().map(|&(_, elapsed)| elapsed).sum());
+            assert!(timings.base_optimizer > Duration::ZERO && timings.back_projection > Duration::ZERO);
+            assert_eq!(timings.other, Duration::ZERO);
+            refreshed.push(timings.svd_refresh > Duration::ZERO);
+        }
+        // Projections are computed on the first step and refreshed on every third.
+        assert_eq!(refreshed, [true, false, true]);
+    }
+
+    #[test]
+    fn projected_accumulation_steps_on_the_mean_of_its_window() {
+        // gradient(step) always lies in the same rank-2 row and column spaces.
+        let make = |update_freq| GaLoreOptimizer::new(Adam::new(1e-2, 0.9, 0.999, 1e-8), 2, update_freq, 0.0);
+        let mut accumulating = make(100).with_projected_accumulation(2);
+        let mut reference = make(100);
+        let windows = [(gradient(0), gradient(0)), (gradient(1), gradient(2))];
+        for (first, second) in &windows {
+            assert!(accumulating.accumulate(vec![first.view()]).unwrap().is_none());
+            let updates = accumulating.accumulate(vec![second.view()]).unwrap().unwrap();
+            let mean = (first + second) * 0.5;
+            let expected = reference.step(vec![mean.view()]).unwrap();
+            assert!(updates[0].abs_diff_eq(&expected[0], 1e-5));
+        }
+
+        // A refresh due inside a window happens on its first micro-batch only.
+        let mut refreshing = make(1).with_projected_accumulation(2);
+        for step in 0..4 {
+            refreshing.accumulate(vec![gradient(step).view()]).unwrap();
+            let refreshed = refreshing.galore.last_refresh_time() > Duration::ZERO;
+            assert_eq!(refreshed, step % 2 == 0, "micro-batch {}", step);
+        }
+    }
+
+    #[test]
+    fn restarts_take_one_step_at_the_boosted_rank() {
+        let shapes = |boost| {
+            let mut galore = GaLoreProjection::new(2, 1, 0.0).with_restart_policy(RestartPolicy { every: 2, boost });
+            let shapes: Vec<(usize, usize)> =
+                (0..5).map(|step| galore.project_gradient(vec![gradient(step).view()])[0].dim()).collect();
+            (shapes, galore.metrics().series("restart").map(|s| s.to_vec()))
+        };
+        // Refreshes are counted on steps 1, 2, 4 and 5; step 3 only ends the restart.
+        let (full, restarts) = shapes(RestartBoost::FullRank);
+        assert_eq!(full, [(2, 2), (24, 40), (2, 2), (2, 2), (24, 40)]);
+        assert_eq!(restarts, Some(vec![(2, 1.0), (5, 1.0)]));
+        let (boosted, _) = shapes(RestartBoost::Rank(6));
+        assert_eq!(boosted, [(2, 2), (6, 6), (2, 2), (2, 2), (6, 6)]);
+    }
+
+    #[test]
+    fn tied_parameters_share_one_projection() {
+        let (g, h) = (gradient(0), gradient(1).reversed_axes());
+        let tied = || GaLoreProjection::new(2, 100, 0.0).with_shared_projection(vec![SharedMember::new(0), SharedMember::transposed(1)]);
+        let mut galore = tied().unwrap();
+        let projected = galore.project_gradient(vec![g.view(), h.view()]);
+        let (Some(Projection::TwoSided(p, q)), Some(Projection::TwoSided(q1, p1))) = (&galore.projections[0], &galore.projections[1]) else {
+            panic!("two-sided projections");
+        };
+        assert!(Arc::ptr_eq(p, p1) && Arc::ptr_eq(q, q1));
+        // The pair comes from g + h^T, and h is projected as its transpose would be.
+        let mut combined = GaLoreProjection::new(2, 100, 0.0);
+        let expected = combined.project_gradient(vec![(&g + &h.t()).view()]);
+        assert!((&projected[0] + &projected[1].t()).abs_diff_eq(&expected[0], 1e-4));
+
+        // A transposed member with the leader's shape cannot share; both are projected alone.
+        let mut mismatched = tied().unwrap();
+        mismatched.project_gradient(vec![g.view(), g.view()]);
+        let (Some(Projection::TwoSided(p, _)), Some(Projection::TwoSided(p1, _))) = (&mismatched.projections[0], &mismatched.projections[1]) else {
+            panic!("two-sided projections");
+        };
+        assert!(!Arc::ptr_eq(p, p1));
+
+        let error = tied().unwrap().with_shared_projection(vec![SharedMember::new(2), SharedMember::new(1)]).err();
+        assert_eq!(error, Some(SharedProjectionError::AlreadyShared(1)));
+    }
+
+    #[test]
+    fn staggered_refreshes_spread_over_the_window() {
+        let mut galore = GaLoreProjection::new(2, 3, 0.0)
+            .with_staggered_refresh(true)
+            .with_refresh_offset(3, 1)
+            .with_spectrum_telemetry(1);
+        for step in 0..6 {
+            let grads: Vec<Array2<f32>> = (0..4).map(|i| gradient(step + i)).collect();
+            galore.project_gradient(grads.iter().map(|g| g.view()).collect());
+        }
+        let refreshes = |i: usize| -> Vec<usize> {
+            let series = galore.metrics().series(&format!("param{}/effective_rank", i)).unwrap();
+            series.iter().map(|&(step, _)| step).collect()
+        };
+        // Everything is projected on the first step, then parameter i refreshes when
+        // step % 3 == i % 3; parameter 3 has its phase overridden to 1.
+        assert_eq!(refreshes(0), [1, 3, 6]);
+        assert_eq!(refreshes(1), [1, 4]);
+        assert_eq!(refreshes(2), [1, 2, 5]);
+        assert_eq!(refreshes(3), [1, 4]);
+    }
+
+    #[test]
+    fn grad_clipping_sits_on_the_chosen_side_of_project() {
+        let full = GaLoreOptimizer::new(Adam::new(1e-2, 0.9, 0.999, 1e-8), 4, 100, 0.0).with_grad_clipping(1.0, ClipSpace::Full);
+        assert_eq!(full.stage_names(), ["clip", "project", "optimize", "project_back"]);
+        let projected =
+            GaLoreOptimizer::new(Adam::new(1e-2, 0.9, 0.999, 1e-8), 4, 100, 0.0).with_grad_clipping(1.0, ClipSpace::Projected);
+        assert_eq!(projected.stage_names(), ["project", "clip_projected", "optimize", "project_back"]);
+    }
+
+    #[test]
+    fn unknown_stage_names_are_an_error() {
+        let galore = GaLoreOptimizer::new(Adam::new(1e-2, 0.9, 0.999, 1e-8), 4, 100, 0.0);
+        let result = galore.with_transform_after("projectback", Box::new(ClipGradNorm::new(1.0)));
+        assert_eq!(result.err(), Some(PipelineError::UnknownStage("projectback".to_string())));
+    }
+
+    #[test]
+    fn degenerate_refreshes_fall_back() {
+        let zeros = Array2::<f32>::zeros((24, 40));
+        let two_sided = |projection: &Option<Projection>| match projection {
+            Some(Projection::TwoSided(p, q)) => (Arc::clone(p), Arc::clone(q)),
+            _ => panic!("two-sided projection"),
+        };
+        let mut galore = GaLoreProjection::new(4, 2, 0.0);
+
+        // Without a previous projection, a random orthonormal basis of the refresh's shape.
+        let projected = galore.project_gradient(vec![zeros.view()]);
+        assert_eq!(projected[0].dim(), (4, 4));
+        let (p, q) = two_sided(&galore.projections[0]);
+        assert_eq!((p.dim(), q.dim()), ((24, 4), (40, 4)));
+        assert!(p.t().dot(&*p).abs_diff_eq(&Array2::eye(4), 1e-5));
+
+        // Step 2 refreshes from a real gradient, which step 4's zero gradient keeps.
+        galore.project_gradient(vec![gradient(1).view()]);
+        galore.project_gradient(vec![gradient(2).view()]);
+        let (working, _) = two_sided(&galore.projections[0]);
+        galore.project_gradient(vec![zeros.view()]);
+        assert!(Arc::ptr_eq(&working, &two_sided(&galore.projections[0]).0));
+
+        let events: Vec<_> = galore.fallback_events().iter().map(|e| (e.step, e.reason.clone(), e.action)).collect();
+        assert_eq!(
+            events,
+            [
+                (1, FallbackReason::ZeroSpectrum, FallbackAction::RandomBasis),
+                (4, FallbackReason::ZeroSpectrum, FallbackAction::KeptPrevious),
+            ]
+        );
+        assert_eq!(galore.fallback_count(0), 2);
+        assert_eq!(galore.metrics().latest("param0/fallbacks"), Some(2.0));
+    }
+
+    // Exact SVD that logs the shape of each gradient it decomposes and the most calls it saw
+    // running at once.
+    #[derive(Default)]
+    struct LoggingDecomposer {
+        svd: ExactSvd,
+        calls: Arc<std::sync::Mutex<Vec<(usize, usize)>>>,
+        running: Arc<std::sync::atomic::AtomicUsize>,
+        most_running: Arc<std::sync::atomic::AtomicUsize>,
+    }
+
+    impl Decomposer for LoggingDecomposer {
+        fn name(&self) -> &str {
+            "logging"
+        }
+
+        fn decompose(&self, grad: &GradInput, rank: usize, need_u: bool, need_v: bool, seed: u64) -> Result<Decomposition, String> {
+            use std::sync::atomic::Ordering;
+            let running = self.running.fetch_add(1, Ordering::SeqCst) + 1;
+            self.most_running.fetch_max(running, Ordering::SeqCst);
+            self.calls.lock().unwrap().push(grad.dim());
+            let result = self.svd.decompose(grad, rank, need_u, need_v, seed);
+            self.running.fetch_sub(1, Ordering::SeqCst);
+            result
+        }
+    }
+
+    #[test]
+    fn param_order_runs_the_decompositions_one_at_a_time_in_order() {
+        let decomposer = LoggingDecomposer::default();
+        let (calls, most_running) = (Arc::clone(&decomposer.calls), Arc::clone(&decomposer.most_running));
+        let mut galore = GaLoreProjection::new(4, 10, 0.0).with_decomposer(Box::new(decomposer)).with_param_order(vec![2, 0]);
+        let grads = [gradient(0), gradient(1).slice(s![..20, ..30]).to_owned(), gradient(2).slice(s![..16, ..24]).to_owned()];
+        galore.project_gradient(grads.iter().map(|g| g.view()).collect());
+        assert_eq!(*calls.lock().unwrap(), [(16, 24), (24, 40), (20, 30)]);
+        assert_eq!(most_running.load(std::sync::atomic::Ordering::SeqCst), 1);
+    }
+
+    // Fails on every call, like a LAPACK error.
+    struct FailingDecomposer;
+
+    impl Decomposer for FailingDecomposer {
+        fn name(&self) -> &str {
+            "failing"
+        }
+
+        fn decompose(&self, _: &GradInput, _: usize, _: bool, _: bool, _: u64) -> Result<Decomposition, String> {
+            Err("did not converge".to_string())
+        }
+    }
+
+    #[test]
+    fn decomposer_errors_fall_back_to_the_previous_projection() {
+        let mut galore = GaLoreProjection::new(4, 1, 0.0);
+        galore.project_gradient(vec![gradient(0).view()]);
+        let Some(Projection::TwoSided(working, _)) = galore.projections[0].clone() else {
+            panic!("two-sided projection");
+        };
+
+        let mut galore = galore.with_decomposer(Box::new(FailingDecomposer));
+        let projected = galore.project_gradient(vec![gradient(1).view()]);
+        assert!(projected[0].iter().all(|x| x.is_finite()));
+        let Some(Projection::TwoSided(kept, _)) = &galore.projections[0] else {
+            panic!("two-sided projection");
+        };
+        assert!(Arc::ptr_eq(&working, kept));
+        let event = &galore.fallback_events()[0];
+        assert_eq!(event.reason, FallbackReason::DecompositionFailed("did not converge".to_string()));
+        assert_eq!(event.action, FallbackAction::KeptPrevious);
+    }
+
+    #[test]
+    fn apply_updates_decays_the_weights_and_pins_the_mode() {
+        let wd = 0.1;
+        let step = |optimizer: &mut GaLoreOptimizer<Adam>| {
+            let mut params = vec![weights()];
+            optimizer.apply_updates(&mut params, vec![gradient(0).view()]).unwrap();
+            &params[0] - &weights()
+        };
+        let baseline = step(&mut GaLoreOptimizer::new(adam(), 4, 100, 0.0));
+        let mut decayed = GaLoreOptimizer::new(adam(), 4, 100, 0.0).with_weight_decay(wd, WeightDecayMode::Full).unwrap();
+        let decay = step(&mut decayed) - &baseline;
+        assert!(decay.abs_diff_eq(&(weights() * (-LR * wd)), 1e-6));
+
+        let mut checkpoint = Checkpoint::new();
+        decayed.save_state(&mut checkpoint);
+        let mut other = GaLoreOptimizer::new(adam(), 4, 100, 0.0).with_weight_decay(wd, WeightDecayMode::Subspace).unwrap();
+        assert!(matches!(other.load_state(&checkpoint), Err(CheckpointError::Unsupported(_))));
+    }
+
+    #[test]
+    fn param_stages_are_rejected_before_a_step_without_params_starts() {
+        let apply = |optimizer: &mut GaLoreOptimizer<Adam>| {
+            let mut params = vec![weights()];
+            optimizer.apply_updates(&mut params, vec![gradient(0).view()]).unwrap();
+            params.remove(0)
+        };
+        let mut decayed = GaLoreOptimizer::new(adam(), 4, 100, 0.0).with_weight_decay(0.1, WeightDecayMode::Full).unwrap();
+        let refused = decayed.step(vec![gradient(0).view()]);
+        assert_eq!(refused, Err(PipelineError::NeedsParams("weight_decay".to_string())));
+
+        // Nothing ran, so the next real step matches a fresh optimizer's first one.
+        let mut fresh = GaLoreOptimizer::new(adam(), 4, 100, 0.0).with_weight_decay(0.1, WeightDecayMode::Full).unwrap();
+        assert_eq!(apply(&mut decayed), apply(&mut fresh));
+    }
 
-                let m_hat = m / (1.0 - self.beta1.powi(self.t as i32));
-                let v_hat = v / (1.0 - self.beta2.powi(self.t as i32));
+    #[test]
+    fn weight_decay_needs_a_learning_rate() {
+        // Reports no learning rate, so there is nothing to scale the decay by.
+        struct Unscaled;
+        impl Optimizer for Unscaled {
+            fn compute_updates(&mut self, gradients: &[Array2<f32>]) -> Vec<Array2<f32>> {
+                gradients.to_vec()
+            }
+        }
+        let refused = GaLoreOptimizer::new(Unscaled, 4, 100, 0.0).with_weight_decay(0.1, WeightDecayMode::Full);
+        assert!(matches!(refused, Err(PipelineError::NoLearningRate)));
+
+        // Installing the stage by hand is refused before the step instead.
+        let mut manual = GaLoreOptimizer::new(Unscaled, 4, 100, 0.0)
+            .with_transform_after("project_back", Box::new(WeightDecay::new(0.1, WeightDecayMode::Full)))
+            .unwrap();
+        let mut params = vec![weights()];
+        let result = manual.apply_updates(&mut params, vec![gradient(0).view()]);
+        assert_eq!(result, Err(PipelineError::NoLearningRate));
+        assert_eq!(params[0], weights());
+    }
+
+    #[test]
+    fn update_caps_refuse_entry_points_without_params() {
+        let mut capped = GaLoreOptimizer::new(adam(), 4, 100, 0.0)
+            .with_projected_accumulation(2)
+            .with_update_norm_cap(1e-3, ParamGroup::All);
+        let refused = PipelineError::NeedsParams("update_cap".to_string());
+        assert_eq!(capped.accumulate(vec![gradient(0).view()]), Err(refused.clone()));
+        assert_eq!(capped.step(vec![gradient(0).view()]), Err(refused));
+
+        // The refusals left the window empty, and the cap runs once it completes.
+        let mut params = vec![weights()];
+        assert!(!capped.accumulate_updates(&mut params, vec![gradient(0).view()]).unwrap());
+        assert!(capped.accumulate_updates(&mut params, vec![gradient(1).view()]).unwrap());
+        assert_eq!(capped.metrics().latest("update_cap/capped"), Some(1.0));
+    }
+
+    #[cfg(feature = "half")]
+    #[test]
+    fn half_precision_projections_track_the_f32_path() {
+        let relative = |x: &Array2<f32>, y: &Array2<f32>| (x - y).mapv(|d| d * d).sum().sqrt() / y.mapv(|v| v * v).sum().sqrt();
+        let grads = [gradient(0), gradient(0).t().to_owned()];
+        let make = |half: bool| GaLoreProjection::new(6, 100, 0.0).with_half_precision(half);
+        let (mut full, mut half) = (make(false), make(true));
+        let expected = full.project_gradient(grads.iter().map(|g| g.view()).collect());
+        let projected = half.project_gradient(grads.iter().map(|g| g.view()).collect());
+        for (x, y) in projected.iter().zip(&expected) {
+            assert_eq!(x.dim(), y.dim());
+            assert!(relative(x, y) < 2e-3, "projected: {}", relative(x, y));
+        }
+
+        let expected_back = full.project_update(expected.iter().map(|u| u.view()).collect());
+        let back = half.project_update(expected.iter().map(|u| u.view()).collect());
+        for (x, y) in back.iter().zip(&expected_back) {
+            assert_eq!(x.dim(), y.dim());
+            assert!(relative(x, y) < 2e-3, "projected back: {}", relative(x, y));
+        }
+    }
+
+    // Three weights covering the two-sided, left (24x40 takes P) and right (40x24 takes Q)
+    // projections.
+    fn mixed_projection() -> GaLoreProjection {
+        let ranks = vec![
+            LayerRank::LowRank { rank: 4, one_sided: false },
+            LayerRank::LowRank { rank: 4, one_sided: true },
+            LayerRank::LowRank { rank: 4, one_sided: true },
+        ];
+        GaLoreProjection::new(4, 3, 0.0).with_rank_config(RankConfig::new(ranks))
+    }
+
+    fn mixed_gradients(step: usize) -> Vec<Array2<f32>> {
+        vec![gradient(step), gradient(step + 1), gradient(step + 2).t().to_owned()]
+    }
+
+    #[test]
+    fn fused_apply_updates_match_adding_the_step() {
+        let mut fused = GaLoreOptimizer::with_projection(adam(), mixed_projection());
+        let mut in_place = GaLoreOptimizer::with_projection(adam(), mixed_projection());
+        let mut unfused = GaLoreOptimizer::with_projection(adam(), mixed_projection());
+        let mut params = mixed_gradients(10);
+        let mut viewed = params.clone();
+        let mut expected = params.clone();
+        for step in 0..5 {
+            let grads = mixed_gradients(step);
+            fused.apply_updates(&mut params, grads.iter().map(|g| g.view()).collect()).unwrap();
+            // Mutable views, as a network hands out its parameters, are written in place.
+            let mut views: Vec<_> = viewed.iter_mut().map(|p| p.view_mut()).collect();
+            in_place.apply_updates(&mut views, grads.iter().map(|g| g.view()).collect()).unwrap();
+            for (param, update) in expected.iter_mut().zip(unfused.step(grads.iter().map(|g| g.view())).unwrap()) {
+                *param += &update;
+            }
+        }
+        for (viewed, param) in viewed.iter().zip(&params) {
+            assert!(viewed.abs_diff_eq(param, 1e-6));
+        }
+        let kinds: Vec<(bool, bool)> =
+            fused.galore.export_subspaces().into_iter().map(|s| s.map_or((false, false), |s| (s.p.is_some(), s.q.is_some()))).collect();
+        assert_eq!(kinds, [(true, true), (true, false), (false, true)]);
+        for (actual, expected) in params.iter().zip(&expected) {
+            assert!(actual.abs_diff_eq(expected, 1e-5));
+        }
+        assert_eq!(fused.last_timings().stages.last().map(|(name, _)| name.as_str()), Some("project_back"));
+    }
+
+    #[test]
+    fn imported_subspaces_round_trip_and_stay_fixed() {
+        let mut source = mixed_projection();
+        source.project_gradient(mixed_gradients(0).iter().map(|g| g.view()).collect());
+        let exported = source.export_subspaces();
+        let kinds: Vec<(bool, bool)> = exported.iter().map(|s| s.as_ref().map_or((false, false), |s| (s.p.is_some(), s.q.is_some()))).collect();
+        assert_eq!(kinds, [(true, true), (true, false), (false, true)]);
+
+        let mut imported = mixed_projection();
+        imported.import_subspaces(exported.clone(), SubspaceImport::Fixed).unwrap();
+        assert_eq!(imported.export_subspaces(), exported);
+        // Fixed subspaces skip the initial refresh and every scheduled one after it.
+        let project = |s: &Subspace, g: &Array2<f32>| match (&s.p, &s.q) {
+            (Some(p), Some(q)) => p.t().dot(g).dot(q),
+            (Some(p), None) => p.t().dot(g),
+            (None, Some(q)) => g.dot(q),
+            (None, None) => unreachable!(),
+        };
+        for step in 1..8 {
+            let grads = mixed_gradients(step);
+            let projected = imported.project_gradient(grads.iter().map(|g| g.view()).collect());
+            for ((actual, s), g) in projected.iter().zip(&exported).zip(&grads) {
+                assert!(actual.abs_diff_eq(&project(s.as_ref().unwrap(), g), 1e-4), "step {}", step);
+            }
+        }
+        assert_eq!(imported.export_subspaces(), exported);
+    }
+
+    #[test]
+    fn malformed_subspaces_are_rejected_without_replacing_anything() {
+        let mut galore = mixed_projection();
+        galore.project_gradient(mixed_gradients(0).iter().map(|g| g.view()).collect());
+        let current = galore.export_subspaces();
+
+        let mut empty = current.clone();
+        empty[1] = Some(Subspace { p: None, q: None });
+        assert_eq!(galore.replace_subspaces(empty), Err(SubspaceError::Empty(1)));
+        let mut mismatched = current.clone();
+        mismatched[0].as_mut().unwrap().q = Some(Array2::zeros((40, 3)));
+        assert_eq!(
+            galore.import_subspaces(mismatched, SubspaceImport::Fixed),
+            Err(SubspaceError::RankMismatch { param: 0, left: 4, right: 3 })
+        );
+        assert_eq!(galore.export_subspaces(), current);
+        assert!(galore.pinned.is_empty());
+    }
+
+    #[test]
+    fn named_steps_match_positional_ones_and_need_a_registry() {
+        let registry = Arc::new(TensorRegistry::from_shapes([("wq", (24, 40)), ("wk", (24, 40))]).unwrap());
+        let mut named = GaLoreOptimizer::with_projection(adam(), GaLoreProjection::new(4, 3, 0.0).with_registry(registry).with_spectrum_telemetry(1));
+        let mut positional = GaLoreOptimizer::new(adam(), 4, 3, 0.0);
+        for step in 0..4 {
+            let (wq, wk) = (gradient(step), gradient(step + 1));
+            let updates = named.step_named(vec![("wk", wk.view()), ("wq", wq.view())]).unwrap();
+            let expected = positional.step(vec![wq.view(), wk.view()]).unwrap();
+            assert_eq!(updates, vec![("wq".to_string(), expected[0].clone()), ("wk".to_string(), expected[1].clone())]);
+        }
+        assert!(named.galore.metrics().series("wk/effective_rank").is_some());
+
+        let mut checkpoint = Checkpoint::new();
+        named.galore.save_state(&mut checkpoint, "galore");
+        assert!(checkpoint.metadata("galore.projection.wq.kind").is_some());
+        assert_eq!(positional.step_named(vec![("wq", gradient(0).view())]), Err(RegistryError::NoRegistry));
+    }
+
+    #[test]
+    fn similarity_is_full_for_the_same_dominant_subspace() {
+        // Every `gradient(step)` has the same rank-2 row and column spaces.
+        let mut galore = GaLoreProjection::new(2, 100, 0.0);
+        galore.project_gradient(vec![gradient(0).view()]);
+        let similarity = galore.subspace_similarity(vec![gradient(5).view()]);
+        assert_eq!(similarity.len(), 1);
+        assert!(similarity[0].overlap() > 0.999, "{}", similarity[0]);
+
+        assert_eq!(similarity[0].left.as_ref().map(|c| c.len()), Some(2));
+
+        // Parameters without a stored projection are skipped.
+        let fresh = GaLoreProjection::new(2, 100, 0.0);
+        assert!(fresh.subspace_similarity(vec![gradient(0).view()]).is_empty());
+    }
+
+    #[test]
+    fn first_step_projects_and_returns_every_update() {
+        let mut optimizer = GaLoreOptimizer::new(Adam::new(1e-2, 0.9, 0.999, 1e-8), 4, 10, 0.0);
+        assert!(optimizer.projection().refresh_pending(0));
+        let grads = [gradient(0), gradient(1).slice_move(ndarray::s![..8, ..])];
+        let updates = optimizer.step(grads.iter().map(|g| g.view()).collect::<Vec<_>>()).unwrap();
+        assert_eq!(updates.len(), 2);
+        for (update, grad) in updates.iter().zip(grads.iter()) {
+            assert_eq!(update.dim(), grad.dim());
+        }
+        assert!(!optimizer.projection().refresh_pending(2));
+    }
 
-                -self.lr * &m_hat / (v_hat.map(|x| x.sqrt()) + self.epsilon)
+    fn preconditioned(rank: usize, estimate: RmsEstimate) -> GaLoreOptimizer<Sgd> {
+        GaLoreOptimizer::new(Sgd, rank, 2, 0.0).with_preconditioner(0.9, estimate, ParamGroup::All)
+    }
+
+    #[test]
+    fn constant_rms_gradients_leave_the_sgd_step_unchanged() {
+        // Entries of +-scale, so every element, row and column has the same RMS: whitening
+        // only divides by it and "unprecondition" multiplies it back.
+        let scale = 1e-3;
+        let signs = |step: usize| {
+            Array2::from_shape_fn((24, 40), |(i, j)| {
+                let x = ((i * j + 3 * i + j * j + step) as f32 * 0.7).sin();
+                if x >= 0.0 { scale } else { -scale }
             })
-            .collect()
+        };
+        for estimate in [RmsEstimate::Diagonal, RmsEstimate::Factored] {
+            let mut plain = GaLoreOptimizer::new(Sgd, 4, 2, 0.0);
+            let mut whitened = preconditioned(4, estimate);
+            for step in 0..4 {
+                let g = signs(step);
+                let expected = plain.step(vec![g.view()]).unwrap().remove(0);
+                let actual = whitened.step(vec![g.view()]).unwrap().remove(0);
+                let max = expected.iter().fold(0.0f32, |m, x| m.max(x.abs()));
+                assert!(actual.abs_diff_eq(&expected, max * 1e-3), "{:?}, step {}", estimate, step);
+            }
+        }
+    }
+
+    #[test]
+    fn preconditioner_with_adam_whitens_the_subspace_and_scales_the_update_back() {
+        // Two rows 100 times larger than the rest take over an unwhitened rank-4 basis.
+        let mut skewed = gradient(0);
+        skewed.slice_mut(ndarray::s![..2, ..]).mapv_inplace(|x| x * 100.0);
+        let mass = |optimizer: &GaLoreOptimizer<Adam>| {
+            let p = optimizer.projection().export_subspaces().remove(0).and_then(|s| s.p).expect("left projection");
+            p.slice(ndarray::s![..2, ..]).iter().map(|x| x * x).sum::<f32>()
+        };
+        // The first step's RMS scale: the gradient's own magnitude, or its row and column
+        // mean squares for the factored estimate.
+        let squared = skewed.mapv(|x| x * x);
+        let rms_scale = |estimate: RmsEstimate| match estimate {
+            RmsEstimate::Diagonal => squared.mapv(f32::sqrt) + 1e-8,
+            RmsEstimate::Factored => {
+                let rows = squared.mean_axis(ndarray::Axis(1)).unwrap();
+                let cols = squared.mean_axis(ndarray::Axis(0)).unwrap();
+                let mean = rows.mean().unwrap();
+                Array2::from_shape_fn(skewed.dim(), |(i, j)| (rows[i] / mean).sqrt() * cols[j].sqrt() + 1e-8)
+            }
+        };
+
+        let mut plain = GaLoreOptimizer::new(adam(), 4, 2, 0.0);
+        plain.step(vec![skewed.view()]).unwrap();
+        assert!(mass(&plain) > 1.9);
+        for estimate in [RmsEstimate::Diagonal, RmsEstimate::Factored] {
+            let mut whitened = GaLoreOptimizer::new(adam(), 4, 2, 0.0).with_preconditioner(0.9, estimate, ParamGroup::All);
+            assert_eq!(whitened.stage_names(), ["precondition", "project", "optimize", "project_back", "unprecondition"]);
+            let update = whitened.step(vec![skewed.view()]).unwrap().remove(0);
+            assert!(mass(&whitened) < 1.0, "{:?}", estimate);
+
+            // Opting out of the inverse picks the same subspace but leaves Adam's step in
+            // whitened coordinates; the default maps it back through the RMS scale.
+            let mut opted_out = GaLoreOptimizer::new(adam(), 4, 2, 0.0).with_whitened_preconditioner(0.9, estimate, ParamGroup::All);
+            assert_eq!(opted_out.stage_names(), ["precondition", "project", "optimize", "project_back"]);
+            let whitened_update = opted_out.step(vec![skewed.view()]).unwrap().remove(0);
+            let expected = whitened_update * rms_scale(estimate);
+            let max = expected.iter().fold(0.0f32, |m, x| m.max(x.abs()));
+            assert!(update.abs_diff_eq(&expected, max * 1e-4), "{:?}", estimate);
+        }
+    }
+
+    #[test]
+    fn preconditioner_placement_does_not_depend_on_builder_order() {
+        let estimate = RmsEstimate::Factored;
+        let first = GaLoreOptimizer::new(adam(), 4, 2, 0.0)
+            .with_preconditioner(0.9, estimate, ParamGroup::All)
+            .with_weight_decay(0.1, WeightDecayMode::Full).unwrap()
+            .with_grad_clipping(1.0, ClipSpace::Full);
+        let last = GaLoreOptimizer::new(adam(), 4, 2, 0.0)
+            .with_grad_clipping(1.0, ClipSpace::Full)
+            .with_weight_decay(0.1, WeightDecayMode::Full).unwrap()
+            .with_preconditioner(0.9, estimate, ParamGroup::All);
+        let expected = ["clip", "precondition", "project", "optimize", "project_back", "unprecondition", "weight_decay"];
+        assert_eq!(first.stage_names(), expected);
+        assert_eq!(last.stage_names(), expected);
+
+        let apply = |mut optimizer: GaLoreOptimizer<Adam>| {
+            let mut params = vec![weights()];
+            optimizer.apply_updates(&mut params, vec![gradient(0).view()]).unwrap();
+            params.remove(0)
+        };
+        assert_eq!(apply(first), apply(last));
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn preconditioner_estimate_round_trips_through_checkpoints() {
+        // Row scales that move from step to step, so the running estimate matters.
+        let gradient = |step: usize| {
+            Array2::from_shape_fn((24, 40), |(i, j)| ((i * j) as f32 * 0.37 + (i + j * j) as f32).sin() * (1.0 + ((i + step) % 4) as f32))
+        };
+        for estimate in [RmsEstimate::Diagonal, RmsEstimate::Factored] {
+            let mut original = preconditioned(4, estimate);
+            for step in 0..3 {
+                original.step(vec![gradient(step).view()]).unwrap();
+            }
+            let mut checkpoint = Checkpoint::new();
+            original.save_state(&mut checkpoint);
+
+            let mut restored = preconditioned(4, estimate);
+            restored.load_state(&checkpoint).unwrap();
+            let next = gradient(3);
+            let expected = original.step(vec![next.view()]).unwrap();
+            assert_eq!(restored.step(vec![next.view()]).unwrap(), expected);
+
+            // Without the saved estimate the step differs.
+            let names: Vec<String> = checkpoint.tensors().map(|(name, _)| name.to_string()).filter(|name| name.contains(".pipeline.")).collect();
+            assert!(!names.is_empty());
+            for name in &names {
+                checkpoint.remove_tensor(name);
+            }
+            let mut rewarmed = preconditioned(4, estimate);
+            rewarmed.load_state(&checkpoint).unwrap();
+            assert_ne!(rewarmed.step(vec![next.view()]).unwrap(), expected);
+        }
+    }
+
+    #[test]
+    fn exported_projections_load_back_in_every_format() {
+        let mut galore = mixed_projection();
+        galore.project_gradient(mixed_gradients(0).iter().map(|g| g.view()).collect());
+        let exported = galore.export_subspaces();
+        // Adapting imports carry their own EMA decay, which the export reports.
+        galore.import_subspaces(exported.clone(), SubspaceImport::Adapting { ema_decay: 0.5 }).unwrap();
+        for extension in ["galore", "safetensors", "npz"] {
+            let path = std::env::temp_dir().join(format!("galore-projections-{}.{}", std::process::id(), extension));
+            galore.export_projections(&path).unwrap();
+            let format = CheckpointFormat::from_path(&path).unwrap();
+            let loaded = Checkpoint::load(&path, format).unwrap();
+            std::fs::remove_file(&path).unwrap();
+
+            let scalar = |key: &str| match format.supports_metadata() {
+                true => loaded.parse_metadata::<f32>(key).unwrap(),
+                false => *loaded.tensor(key).unwrap().first().unwrap(),
+            };
+            assert_eq!(scalar("step"), 1.0);
+            for (i, subspace) in exported.iter().enumerate() {
+                let Subspace { p, q } = subspace.as_ref().unwrap();
+                let side = |name: &str| loaded.matrix(&format!("param{}.{}", i, name)).ok();
+                assert_eq!((side("p"), side("q")), (p.clone(), q.clone()), "{} param{}", extension, i);
+                assert_eq!(scalar(&format!("param{}.rank", i)), 4.0);
+                assert_eq!(scalar(&format!("param{}.ema_decay", i)), 0.5);
+            }
+        }
+    }
+}
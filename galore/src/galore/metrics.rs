@@ -0,0 +1,45 @@
+use std::collections::BTreeMap;
+use std::io::{self, Write};
+
+// Named scalar series keyed by step, e.g. "param3/effective_rank".
+#[derive(Clone, Debug, Default)]
+pub struct Metrics {
+    series: BTreeMap<String, Vec<(usize, f32)>>,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Metrics::default()
+    }
+
+    pub fn record(&mut self, name: impl Into<String>, step: usize, value: f32) {
+        self.series.entry(name.into()).or_default().push((step, value));
+    }
+
+    pub fn series(&self, name: &str) -> Option<&[(usize, f32)]> {
+        self.series.get(name).map(|s| s.as_slice())
+    }
+
+    pub fn latest(&self, name: &str) -> Option<f32> {
+        self.series.get(name).and_then(|s| s.last()).map(|&(_, v)| v)
+    }
+
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.series.keys().map(|k| k.as_str())
+    }
+
+    pub fn clear(&mut self) {
+        self.series.clear();
+    }
+
+    // One "name,step,value" line per point, series in name order.
+    pub fn write_csv<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        writeln!(writer, "name,step,value")?;
+        for (name, points) in &self.series {
+            for (step, value) in points {
+                writeln!(writer, "{},{},{}", name, step, value)?;
+            }
+        }
+        writer.flush()
+    }
+}
@@ -1,3 +1,42 @@
+pub mod checkpoint;
+pub mod compat;
+pub mod decompose;
+pub mod distributed;
+pub mod ensemble;
+pub mod eval;
+pub mod fallback;
+#[cfg(feature = "experimental-fp8")]
+pub mod fp8;
+pub mod golden;
+#[cfg(feature = "half")]
+pub mod half_gemm;
+pub mod heads;
+pub mod loss;
+#[cfg(feature = "half")]
+pub mod master;
 pub mod matrix_ops;
+pub mod metrics;
+mod moment;
 pub mod neural_network;
-pub mod optimizer;
\ No newline at end of file
+pub mod offload;
+pub mod optimizer;
+pub mod packing;
+pub mod pipeline;
+pub mod planner;
+pub mod quantize;
+pub mod registry;
+pub mod restart;
+pub mod rollback;
+pub mod shared;
+#[cfg(feature = "trainer")]
+pub mod shutdown;
+pub mod sparse;
+pub mod stream;
+pub mod subspace;
+pub mod sweep;
+#[cfg(test)]
+mod testing;
+pub mod timings;
+#[cfg(feature = "trainer")]
+pub mod trainer;
+pub mod transformer;
@@ -0,0 +1,51 @@
+use ndarray::Array2;
+
+use super::quantize::BlockQuantized;
+
+// An Adam moment, in f32 or block-quantized to 8 bits. The second moment is quantized
+// through its square root, which halves its dynamic range in log terms, so small entries
+// next to large ones in the same block do not round to zero.
+#[derive(Clone)]
+pub(crate) enum Moment {
+    Full(Array2<f32>),
+    Quantized(BlockQuantized),
+}
+
+impl Moment {
+    pub(crate) fn new(value: Array2<f32>, eight_bit: bool, root: bool) -> Self {
+        match (eight_bit, root) {
+            (false, _) => Moment::Full(value),
+            (true, false) => Moment::Quantized(BlockQuantized::quantize(&value.view())),
+            (true, true) => Moment::Quantized(BlockQuantized::quantize(&value.mapv(f32::sqrt).view())),
+        }
+    }
+
+    pub(crate) fn into_dense(self, root: bool) -> Array2<f32> {
+        match self {
+            Moment::Full(value) => value,
+            Moment::Quantized(value) if root => value.dequantize().mapv(|s| s * s),
+            Moment::Quantized(value) => value.dequantize(),
+        }
+    }
+
+    pub(crate) fn to_dense(&self, root: bool) -> Array2<f32> {
+        match self {
+            Moment::Full(value) => value.clone(),
+            quantized => quantized.clone().into_dense(root),
+        }
+    }
+
+    pub(crate) fn dim(&self) -> (usize, usize) {
+        match self {
+            Moment::Full(value) => value.dim(),
+            Moment::Quantized(value) => value.dim(),
+        }
+    }
+
+    pub(crate) fn bytes(&self) -> usize {
+        match self {
+            Moment::Full(value) => value.len() * std::mem::size_of::<f32>(),
+            Moment::Quantized(value) => value.bytes(),
+        }
+    }
+}
@@ -1,8 +1,39 @@
-use ndarray::{Array1, Array2, ArrayView1, ArrayView2, Axis};
+use ndarray::{Array1, Array2, ArrayView1, ArrayView2, ArrayViewMut2, Axis};
+use std::fmt;
+use std::io;
 use ndarray_rand::RandomExt;
 use ndarray_rand::rand_distr::Uniform;
-use rand::thread_rng;
-use rand::Rng;
+use rand::rngs::StdRng;
+use rand::{thread_rng, Rng, SeedableRng};
+
+use super::matrix_ops::{GaLoreProjection, LayerRank, RankConfig};
+use super::offload::ActivationSpill;
+use super::planner::{state_bytes, Estimate, LayerEstimate};
+use super::registry::TensorRegistry;
+
+// Train draws dropout masks and folds each sample into the running statistics of
+// `RunningNorm`; Eval makes every layer deterministic, normalizing with the running
+// statistics as they stand. Set on the network with `train()` / `eval()`, which propagate
+// to the layers.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Mode {
+    #[default]
+    Train,
+    Eval,
+}
+
+// A problem found by `NeuralNetwork::validate`, attributed to a layer or parameter name.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ValidationIssue {
+    pub name: String,
+    pub message: String,
+}
+
+impl fmt::Display for ValidationIssue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.name, self.message)
+    }
+}
 
 #[derive(Clone)]
 pub enum Activation {
@@ -34,8 +65,8 @@ impl Activation {
 }
 
 pub struct LayerNorm {
-    gamma: Array1<f32>,
-    beta: Array1<f32>,
+    pub(crate) gamma: Array1<f32>,
+    pub(crate) beta: Array1<f32>,
     eps: f32,
 }
 
@@ -54,30 +85,158 @@ impl LayerNorm {
         *x = (&*x - mean) / (var + self.eps).sqrt() * &self.gamma + &self.beta;
     }
 
-    pub fn backward(&self, x: &Array1<f32>, grad: &mut Array1<f32>) -> (Array1<f32>, Array1<f32>) {
-        let mean = x.mean().unwrap();
-        let var = x.var(0.0);
-        let std = (var + self.eps).sqrt();
-        let n = x.len() as f32;
+    // Normalizes each row of a batch independently.
+    pub fn forward_batch(&self, x: &mut Array2<f32>) {
+        for mut row in x.rows_mut() {
+            let mut normalized = row.to_owned();
+            self.forward(&mut normalized);
+            row.assign(&normalized);
+        }
+    }
+
+    // `x` is the input of the forward pass (before normalization). Returns (dx, dgamma, dbeta).
+    pub fn backward(&self, x: &ArrayView1<f32>, grad: &ArrayView1<f32>) -> (Array1<f32>, Array1<f32>, Array1<f32>) {
+        let (dx, dgamma, dbeta) = self.backward_batch(&x.insert_axis(Axis(0)), &grad.insert_axis(Axis(0)));
+        (dx.row(0).to_owned(), dgamma, dbeta)
+    }
+
+    // Rows are samples; dgamma and dbeta are summed over the batch.
+    pub fn backward_batch(&self, x: &ArrayView2<f32>, grad: &ArrayView2<f32>) -> (Array2<f32>, Array1<f32>, Array1<f32>) {
+        let mut dx = Array2::zeros(x.dim());
+        let mut dgamma = Array1::zeros(self.gamma.len());
+        for ((x, grad), mut dx) in x.rows().into_iter().zip(grad.rows()).zip(dx.rows_mut()) {
+            let mean = x.mean().unwrap();
+            let std = (x.var(0.0) + self.eps).sqrt();
+            let x_hat = (&x - mean) / std;
+
+            // dx = (dx_hat - mean(dx_hat) - x_hat * mean(dx_hat * x_hat)) / std
+            let dx_hat = &grad * &self.gamma;
+            let mean_dx_hat = dx_hat.mean().unwrap();
+            let mean_dx_hat_x_hat = (&dx_hat * &x_hat).mean().unwrap();
+            dx.assign(&((&dx_hat - mean_dx_hat - &x_hat * mean_dx_hat_x_hat) / std));
+            dgamma += &(&grad * &x_hat);
+        }
+        let dbeta = grad.sum_axis(Axis(0));
+        (dx, dgamma, dbeta)
+    }
+}
+
+// Per-feature normalization by a running mean and variance, the single-sample counterpart
+// of batch norm. In training each sample first updates the statistics (an exponential
+// moving average with weight `momentum` on the sample) and is then normalized with them;
+// in eval the statistics are used as they are. Backward treats the statistics as
+// constants, as batch renormalization does.
+pub struct RunningNorm {
+    gamma: Array1<f32>,
+    beta: Array1<f32>,
+    running_mean: Array1<f32>,
+    running_var: Array1<f32>,
+    momentum: f32,
+    eps: f32,
+}
+
+impl RunningNorm {
+    pub fn new(size: usize, momentum: f32, eps: f32) -> Self {
+        assert!(momentum > 0.0 && momentum <= 1.0, "momentum must be in (0, 1]");
+        RunningNorm {
+            gamma: Array1::ones(size),
+            beta: Array1::zeros(size),
+            running_mean: Array1::zeros(size),
+            running_var: Array1::ones(size),
+            momentum,
+            eps,
+        }
+    }
+
+    // Normalizes `x` in place and returns the (mean, variance) it used.
+    pub fn forward(&mut self, x: &mut Array1<f32>, mode: Mode) -> (Array1<f32>, Array1<f32>) {
+        if mode == Mode::Train {
+            let delta = &*x - &self.running_mean;
+            self.running_mean.scaled_add(self.momentum, &delta);
+            self.running_var = (&self.running_var + &(&delta * &delta * self.momentum)) * (1.0 - self.momentum);
+        }
+        let stats = (self.running_mean.clone(), self.running_var.clone());
+        *x = self.normalize(&x.view(), &stats) * &self.gamma + &self.beta;
+        stats
+    }
+
+    // `x` is the input of the forward pass and `stats` what it returned. Returns (dx, dgamma, dbeta).
+    pub fn backward(&self, x: &ArrayView1<f32>, grad: &ArrayView1<f32>, stats: &(Array1<f32>, Array1<f32>)) -> (Array1<f32>, Array1<f32>, Array1<f32>) {
+        let x_hat = self.normalize(x, stats);
+        let dx = &(grad * &self.gamma) / &stats.1.mapv(|v| (v + self.eps).sqrt());
+        (dx, grad * &x_hat, grad.to_owned())
+    }
 
-        let dx_norm = grad * &self.gamma;
-        let dvar = (-0.5 * &dx_norm * (x - mean) / (var + self.eps).powf(1.5)).sum();
-        let dmean = (-&dx_norm / std).sum() - 2.0 * dvar * (x - mean).sum() / n;
+    pub fn running_mean(&self) -> &Array1<f32> {
+        &self.running_mean
+    }
 
-        let dx = &dx_norm / std + dvar * 2.0 * (x - mean) / n + dmean / n;
-        let dgamma = (grad * ((x - mean) / std)).to_owned();
-        let dbeta = grad.to_owned();
+    pub fn running_var(&self) -> &Array1<f32> {
+        &self.running_var
+    }
 
-        (dgamma, dbeta)
+    fn normalize(&self, x: &ArrayView1<f32>, (mean, var): &(Array1<f32>, Array1<f32>)) -> Array1<f32> {
+        (x - mean) / &var.mapv(|v| (v + self.eps).sqrt())
     }
 }
 
+// The normalization applied after a layer's activation.
+enum Norm {
+    Layer(LayerNorm),
+    Running(RunningNorm),
+}
+
+impl Norm {
+    fn gamma(&self) -> &Array1<f32> {
+        match self {
+            Norm::Layer(ln) => &ln.gamma,
+            Norm::Running(rn) => &rn.gamma,
+        }
+    }
+
+    fn beta(&self) -> &Array1<f32> {
+        match self {
+            Norm::Layer(ln) => &ln.beta,
+            Norm::Running(rn) => &rn.beta,
+        }
+    }
+
+    // Parameter name prefix, e.g. "ln" for "layer0.ln_gamma".
+    fn label(&self) -> &'static str {
+        match self {
+            Norm::Layer(_) => "ln",
+            Norm::Running(_) => "rn",
+        }
+    }
+}
+
+// What one layer's forward pass leaves for its backward pass: the dropout mask it drew and
+// the running-norm statistics it normalized with, if any.
+#[derive(Clone, Debug, Default)]
+pub struct LayerCache {
+    dropout_mask: Option<Array1<f32>>,
+    norm_stats: Option<(Array1<f32>, Array1<f32>)>,
+}
+
+// What `NeuralNetwork::forward` leaves for `backward`: every layer's input and cache. Each
+// pass returns its own, so any number of forward passes can be awaiting their backward at
+// once, e.g. the micro-batches of one step.
+#[derive(Clone, Debug, Default)]
+pub struct ForwardCache {
+    inputs: Vec<Array1<f32>>,
+    layers: Vec<LayerCache>,
+}
+
+// (dW, db, d_input, normalization (dgamma, dbeta)) of `Layer::backward`.
+pub type LayerGradients = (Array2<f32>, Array1<f32>, Array1<f32>, Option<(Array1<f32>, Array1<f32>)>);
+
 pub struct Layer {
     weights: Array2<f32>,
     biases: Array1<f32>,
     activation: Activation,
-    layer_norm: Option<LayerNorm>,
+    norm: Option<Norm>,
     dropout_rate: f32,
+    mode: Mode,
 }
 
 impl Layer {
@@ -85,45 +244,139 @@ impl Layer {
         let mut rng = thread_rng();
         let weights = Array2::random_using((output_size, input_size), Uniform::new(-0.08, 0.08), &mut rng);
         let biases = Array1::zeros(output_size);
-        let layer_norm = if use_layer_norm { Some(LayerNorm::new(output_size, 1e-5)) } else { None };
+        let norm = if use_layer_norm { Some(Norm::Layer(LayerNorm::new(output_size, 1e-5))) } else { None };
+
+        Layer { weights, biases, activation, norm, dropout_rate, mode: Mode::Train }
+    }
 
-        Layer { weights, biases, activation, layer_norm, dropout_rate }
+    // Normalizes the activations with a `RunningNorm` instead of a layer norm.
+    pub fn with_running_norm(mut self, momentum: f32) -> Self {
+        self.norm = Some(Norm::Running(RunningNorm::new(self.weights.nrows(), momentum, 1e-5)));
+        self
     }
 
-    pub fn forward(&self, input: &ArrayView1<f32>, training: bool) -> Array1<f32> {
+    pub fn set_mode(&mut self, mode: Mode) {
+        self.mode = mode;
+    }
+
+    // Dropout masks are drawn from `rng`.
+    pub fn forward<R: Rng + ?Sized>(&mut self, input: &ArrayView1<f32>, rng: &mut R) -> (Array1<f32>, LayerCache) {
         let mut output = self.weights.dot(input) + &self.biases;
         self.activation.forward(&mut output);
-        if let Some(ln) = &self.layer_norm {
-            ln.forward(&mut output);
+        let mut cache = LayerCache::default();
+        match &mut self.norm {
+            Some(Norm::Layer(ln)) => ln.forward(&mut output),
+            Some(Norm::Running(rn)) => cache.norm_stats = Some(rn.forward(&mut output, self.mode)),
+            None => {}
         }
-        if training && self.dropout_rate > 0.0 {
-            let mask = Array1::random(output.len(), Uniform::new(0.0, 1.0))
+        if self.mode == Mode::Train && self.dropout_rate > 0.0 {
+            let mask = Array1::random_using(output.len(), Uniform::new(0.0, 1.0), rng)
                 .map(|&x| if x > self.dropout_rate { 1.0 } else { 0.0 }) / (1.0 - self.dropout_rate);
             output *= &mask;
+            cache.dropout_mask = Some(mask);
         }
-        output
+        (output, cache)
     }
 
-    pub fn backward(&self, grad_output: &mut Array1<f32>, input: &ArrayView1<f32>) -> (Array2<f32>, Array1<f32>, Array1<f32>, Option<(Array1<f32>, Array1<f32>)>) {
+    // The activation output is recomputed from `input` rather than cached; the dropout mask
+    // and normalization statistics come from the forward pass's `cache`.
+    pub fn backward(&self, grad_output: &Array1<f32>, input: &ArrayView1<f32>, cache: &LayerCache) -> LayerGradients {
+        let mut grad = grad_output.clone();
+        if let Some(mask) = &cache.dropout_mask {
+            grad *= mask;
+        }
+
+        let mut activated = self.weights.dot(input) + &self.biases;
+        self.activation.forward(&mut activated);
+
         let mut ln_grads = None;
-    
-        if let Some(ln) = &self.layer_norm {
-            let (dgamma, dbeta) = ln.backward(grad_output, grad_output);
+        let norm_grads = match (&self.norm, &cache.norm_stats) {
+            (Some(Norm::Layer(ln)), _) => Some(ln.backward(&activated.view(), &grad.view())),
+            (Some(Norm::Running(rn)), Some(stats)) => Some(rn.backward(&activated.view(), &grad.view(), stats)),
+            (Some(Norm::Running(_)), None) => panic!("running norm backward without the statistics of its forward pass"),
+            (None, _) => None,
+        };
+        if let Some((dx, dgamma, dbeta)) = norm_grads {
+            grad = dx;
             ln_grads = Some((dgamma, dbeta));
         }
-    
-        self.activation.backward(grad_output, grad_output);
-    
-        let grad_weights = grad_output.outer(input);
-        let grad_biases = grad_output.to_owned();
-        let grad_input = self.weights.t().dot(grad_output);
-    
-        (grad_weights, grad_biases, grad_input, ln_grads)
+
+        self.activation.backward(&activated, &mut grad);
+
+        let grad_weights = grad.view().insert_axis(Axis(1)).dot(&input.insert_axis(Axis(0)));
+        let grad_input = self.weights.t().dot(&grad);
+
+        (grad_weights, grad, grad_input, ln_grads)
+    }
+
+    fn validate(&self, name: &str, input_size: usize, issues: &mut Vec<ValidationIssue>) {
+        let mut issue = |message: String| issues.push(ValidationIssue { name: name.to_string(), message });
+        let (rows, cols) = self.weights.dim();
+        if cols != input_size {
+            issue(format!("expects {} inputs but receives {}", cols, input_size));
+        }
+        if self.biases.len() != rows {
+            issue(format!("bias has {} entries for {} outputs", self.biases.len(), rows));
+        }
+        if let Some(norm) = &self.norm {
+            if norm.gamma().len() != rows || norm.beta().len() != rows {
+                issue(format!("normalization covers {}/{} features for {} outputs", norm.gamma().len(), norm.beta().len(), rows));
+            }
+        }
+        if !(0.0..1.0).contains(&self.dropout_rate) {
+            issue(format!("dropout rate {} is outside [0, 1)", self.dropout_rate));
+        }
+    }
+
+    fn parameter_shapes(&self, prefix: &str) -> Vec<(String, (usize, usize))> {
+        let mut shapes = vec![
+            (format!("{}.weight", prefix), self.weights.dim()),
+            (format!("{}.bias", prefix), (1, self.biases.len())),
+        ];
+        if let Some(norm) = &self.norm {
+            shapes.push((format!("{}.{}_gamma", prefix, norm.label()), (1, norm.gamma().len())));
+            shapes.push((format!("{}.{}_beta", prefix, norm.label()), (1, norm.beta().len())));
+        }
+        shapes
+    }
+
+    // Trainable tensors in a fixed order: weight, bias, then the normalization's gamma and
+    // beta if present ("ln_" for a layer norm, "rn_" for a running norm). Vectors are
+    // exposed as 1 x n matrices so they go through the optimizer like the weights.
+    pub fn parameters_mut(&mut self, prefix: &str) -> Vec<(String, ArrayViewMut2<'_, f32>)> {
+        let mut params = vec![
+            (format!("{}.weight", prefix), self.weights.view_mut()),
+            (format!("{}.bias", prefix), self.biases.view_mut().insert_axis(Axis(0))),
+        ];
+        let Some(norm) = &mut self.norm else { return params };
+        let label = norm.label();
+        let (gamma, beta) = match norm {
+            Norm::Layer(ln) => (&mut ln.gamma, &mut ln.beta),
+            Norm::Running(rn) => (&mut rn.gamma, &mut rn.beta),
+        };
+        params.push((format!("{}.{}_gamma", prefix, label), gamma.view_mut().insert_axis(Axis(0))));
+        params.push((format!("{}.{}_beta", prefix, label), beta.view_mut().insert_axis(Axis(0))));
+        params
+    }
+
+    // Running-norm statistics, "<prefix>.rn_mean" and "<prefix>.rn_var", as 1 x n matrices:
+    // state that is not trained but belongs in checkpoints.
+    pub fn buffers_mut(&mut self, prefix: &str) -> Vec<(String, ArrayViewMut2<'_, f32>)> {
+        match &mut self.norm {
+            Some(Norm::Running(rn)) => vec![
+                (format!("{}.rn_mean", prefix), rn.running_mean.view_mut().insert_axis(Axis(0))),
+                (format!("{}.rn_var", prefix), rn.running_var.view_mut().insert_axis(Axis(0))),
+            ],
+            _ => Vec::new(),
+        }
     }
 }
 
 pub struct NeuralNetwork {
     layers: Vec<Layer>,
+    mode: Mode,
+    // Source of the dropout masks; see `seed_dropout`.
+    rng: StdRng,
 }
 
 impl NeuralNetwork {
@@ -131,29 +384,437 @@ impl NeuralNetwork {
         let mut layers = Vec::new();
         for i in 0..layer_specs.len() - 1 {
             let (input_size, _, _, _) = layer_specs[i];
-            let (output_size, activation, use_layer_norm, dropout_rate) = layer_specs[i + 1];
+            let (output_size, activation, use_layer_norm, dropout_rate) = layer_specs[i + 1].clone();
             layers.push(Layer::new(input_size, output_size, activation, use_layer_norm, dropout_rate));
         }
-        NeuralNetwork { layers }
+        Self::from_layers(layers)
+    }
+
+    // Layers built individually are not checked against each other; see `validate`.
+    pub fn from_layers(layers: Vec<Layer>) -> Self {
+        NeuralNetwork { layers, mode: Mode::Train, rng: StdRng::from_entropy() }
+    }
+
+    // Restarts the dropout masks from `seed`; by default they are seeded from entropy.
+    pub fn seed_dropout(&mut self, seed: u64) {
+        self.rng = StdRng::seed_from_u64(seed);
+    }
+
+    pub fn train(&mut self) {
+        self.set_mode(Mode::Train);
+    }
+
+    pub fn eval(&mut self) {
+        self.set_mode(Mode::Eval);
+    }
+
+    pub fn set_mode(&mut self, mode: Mode) {
+        self.mode = mode;
+        for layer in &mut self.layers {
+            layer.set_mode(mode);
+        }
+    }
+
+    pub fn mode(&self) -> Mode {
+        self.mode
+    }
+
+    // The output and what `backward` needs to differentiate this pass.
+    pub fn forward(&mut self, input: &ArrayView1<f32>) -> (Array1<f32>, ForwardCache) {
+        let mut cache = ForwardCache::default();
+        let mut output = input.to_owned();
+        for layer in &mut self.layers {
+            let (next, layer_cache) = layer.forward(&output.view(), &mut self.rng);
+            cache.inputs.push(output);
+            cache.layers.push(layer_cache);
+            output = next;
+        }
+        (output, cache)
     }
 
-    pub fn forward(&self, input: &ArrayView1<f32>, training: bool) -> Array1<f32> {
+    // Gradients in the order of `parameters_mut`, for the forward pass that returned `cache`.
+    pub fn backward(&self, grad_output: Array1<f32>, cache: &ForwardCache) -> Vec<Array2<f32>> {
+        assert_eq!(cache.inputs.len(), self.layers.len(), "cache of a full forward pass");
+        let mut per_layer = Vec::new();
+        let mut grad_input = grad_output;
+        for ((layer, input), layer_cache) in self.layers.iter().zip(&cache.inputs).zip(&cache.layers).rev() {
+            let (grads, new_grad_input) = layer_gradients(layer, &grad_input, &input.view(), layer_cache);
+            per_layer.push(grads);
+            grad_input = new_grad_input;
+        }
+        per_layer.into_iter().rev().flatten().collect()
+    }
+
+    // Forward pass that saves every layer's input in `spill` for `backward_spilled`, so the
+    // activations of a deep network can live on disk between the two passes. `spill` must
+    // be empty; the returned cache holds only the dropout masks and norm statistics.
+    pub fn forward_spilled(&mut self, input: &ArrayView1<f32>, spill: &mut ActivationSpill) -> io::Result<(Array1<f32>, ForwardCache)> {
+        assert!(spill.is_empty(), "activation spill still holds a previous pass");
+        let mut cache = ForwardCache::default();
         let mut output = input.to_owned();
-        for layer in &self.layers {
-            output = layer.forward(&output.view(), training);
+        for layer in &mut self.layers {
+            let (next, layer_cache) = layer.forward(&output.view(), &mut self.rng);
+            spill.push(output)?;
+            cache.layers.push(layer_cache);
+            output = next;
         }
-        output
+        Ok((output, cache))
     }
 
-    pub fn backward(&self, mut grad_output: Array1<f32>, inputs: &[ArrayView1<f32>]) -> Vec<(Array2<f32>, Array1<f32>, Option<(Array1<f32>, Array1<f32>)>)> {
-        let mut grads = Vec::new();
+    // `backward` with the inputs saved by `forward_spilled`, read back (and prefetched) in
+    // reverse layer order. Leaves `spill` empty.
+    pub fn backward_spilled(&self, grad_output: Array1<f32>, cache: &ForwardCache, spill: &mut ActivationSpill) -> io::Result<Vec<Array2<f32>>> {
+        assert_eq!(spill.len(), self.layers.len(), "one saved input per layer");
+        let mut per_layer = Vec::new();
         let mut grad_input = grad_output;
-        for (layer, input) in self.layers.iter().zip(inputs.iter()).rev() {
-            let (grad_weights, grad_biases, new_grad_input, ln_grads) = layer.backward(&mut grad_input, input);
-            grads.push((grad_weights, grad_biases, ln_grads));
+        for (i, (layer, layer_cache)) in self.layers.iter().zip(&cache.layers).enumerate().rev() {
+            let input = spill.take(i)?;
+            let (grads, new_grad_input) = layer_gradients(layer, &grad_input, &input.view(), layer_cache);
+            per_layer.push(grads);
             grad_input = new_grad_input;
         }
-        grads.reverse();
-        grads
+        spill.clear();
+        Ok(per_layer.into_iter().rev().flatten().collect())
+    }
+
+    // Checks that consecutive layers fit together, starting from `input_size` inputs, and, if
+    // given, that the GaLore configuration is feasible for the network's parameters (indexed
+    // in `parameters_mut` order). Reports every issue found; run it before training.
+    pub fn validate(&self, input_size: usize, galore: Option<&GaLoreProjection>) -> Result<(), Vec<ValidationIssue>> {
+        let mut issues = Vec::new();
+        let mut size = input_size;
+        for (i, layer) in self.layers.iter().enumerate() {
+            layer.validate(&format!("layer{}", i), size, &mut issues);
+            size = layer.weights.nrows();
+        }
+
+        if let Some(galore) = galore {
+            let named = self.parameter_shapes();
+            let shapes: Vec<(usize, usize)> = named.iter().map(|(_, shape)| *shape).collect();
+            for (index, message) in galore.check_shapes(&shapes) {
+                let name = named.get(index).map_or_else(|| format!("parameter {}", index), |(name, _)| name.clone());
+                issues.push(ValidationIssue { name, message });
+            }
+        }
+
+        if issues.is_empty() {
+            Ok(())
+        } else {
+            Err(issues)
+        }
+    }
+
+    // FLOPs and memory per layer for one step at `batch_size`; see `planner::LayerEstimate`.
+    // `config` is indexed like `parameters_mut`, and parameters without an entry are
+    // counted with full-rank Adam state.
+    pub fn estimate(&self, batch_size: usize, config: &RankConfig) -> Estimate {
+        let mut index = 0;
+        let layers = self
+            .layers
+            .iter()
+            .enumerate()
+            .map(|(i, layer)| {
+                let shapes = layer.parameter_shapes(&format!("layer{}", i));
+                let rank_of = |k: usize| config.get(index + k).cloned().unwrap_or(LayerRank::Full);
+                let (rows, cols) = shapes[0].1;
+                let mut estimate =
+                    LayerEstimate::linear(format!("layer{}", i), rows, cols, batch_size, &rank_of(0), config.eight_bit(index));
+                // Bias and layer norm vectors get their own state.
+                estimate.param_bytes = shapes.iter().map(|(_, (r, c))| r * c * std::mem::size_of::<f32>()).sum();
+                estimate.optimizer_bytes = shapes
+                    .iter()
+                    .enumerate()
+                    .map(|(k, (_, (r, c)))| state_bytes(*r, *c, &rank_of(k), config.eight_bit(index + k)))
+                    .sum();
+                index += shapes.len();
+                estimate
+            })
+            .collect();
+        Estimate { batch_size, layers }
+    }
+
+    // The parameters under their `parameters_mut` names.
+    pub fn registry(&self) -> TensorRegistry {
+        TensorRegistry::from_shapes(self.parameter_shapes()).expect("parameter names are unique")
+    }
+
+    pub fn parameter_shapes(&self) -> Vec<(String, (usize, usize))> {
+        self.layers
+            .iter()
+            .enumerate()
+            .flat_map(|(i, layer)| layer.parameter_shapes(&format!("layer{}", i)))
+            .collect()
+    }
+
+    // Every trainable tensor, named "layer{i}.weight", "layer{i}.bias" and, with a
+    // normalization, "layer{i}.ln_gamma"/"layer{i}.ln_beta" or "layer{i}.rn_gamma"/"layer{i}.rn_beta".
+    pub fn parameters_mut(&mut self) -> Vec<(String, ArrayViewMut2<'_, f32>)> {
+        self.layers
+            .iter_mut()
+            .enumerate()
+            .flat_map(|(i, layer)| layer.parameters_mut(&format!("layer{}", i)))
+            .collect()
+    }
+
+    // The running-norm statistics, named "layer{i}.rn_mean" and "layer{i}.rn_var".
+    pub fn buffers_mut(&mut self) -> Vec<(String, ArrayViewMut2<'_, f32>)> {
+        self.layers
+            .iter_mut()
+            .enumerate()
+            .flat_map(|(i, layer)| layer.buffers_mut(&format!("layer{}", i)))
+            .collect()
+    }
+}
+
+// One layer's parameter gradients, in `parameters_mut` order, and the gradient of its input.
+fn layer_gradients(layer: &Layer, grad_output: &Array1<f32>, input: &ArrayView1<f32>, cache: &LayerCache) -> (Vec<Array2<f32>>, Array1<f32>) {
+    let (grad_weights, grad_biases, grad_input, ln_grads) = layer.backward(grad_output, input, cache);
+    let mut grads = vec![grad_weights, grad_biases.insert_axis(Axis(0))];
+    if let Some((dgamma, dbeta)) = ln_grads {
+        grads.push(dgamma.insert_axis(Axis(0)));
+        grads.push(dbeta.insert_axis(Axis(0)));
+    }
+    (grads, grad_input)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::galore::matrix_ops::{LayerRank, RankConfig};
+    use ndarray::Array;
+
+    const STEP: f32 = 1e-2;
+
+    fn values(rows: usize, cols: usize, seed: f32) -> Array2<f32> {
+        Array::from_shape_fn((rows, cols), |(i, j)| ((i * j) as f32 * seed + (i + j * j) as f32).sin())
+    }
+
+    // Central difference of `loss` with respect to every entry of the tensor `select` picks.
+    fn numeric<T, F, L>(model: &mut T, select: F, loss: L) -> Vec<f32>
+    where
+        F: Fn(&mut T) -> ArrayViewMut2<'_, f32>,
+        L: Fn(&mut T) -> f32,
+    {
+        let len = select(model).len();
+        (0..len)
+            .map(|k| {
+                let nudge = |model: &mut T, by: f32| {
+                    let mut tensor = select(model);
+                    let cols = tensor.ncols();
+                    tensor[[k / cols, k % cols]] += by;
+                };
+                nudge(model, STEP);
+                let up = loss(model);
+                nudge(model, -2.0 * STEP);
+                let down = loss(model);
+                nudge(model, STEP);
+                (up - down) / (2.0 * STEP)
+            })
+            .collect()
+    }
+
+    fn assert_close(name: &str, analytic: impl IntoIterator<Item = f32>, numeric: &[f32]) {
+        let analytic: Vec<f32> = analytic.into_iter().collect();
+        assert_eq!(analytic.len(), numeric.len(), "{}", name);
+        for (i, (a, n)) in analytic.iter().zip(numeric).enumerate() {
+            assert!((a - n).abs() <= 1e-2 * (1.0 + n.abs()), "{}[{}]: analytic {} numeric {}", name, i, a, n);
+        }
+    }
+
+    // A layer norm with non-trivial gamma and beta, and the input and upstream gradient.
+    struct NormCase {
+        norm: LayerNorm,
+        x: Array2<f32>,
+        upstream: Array2<f32>,
+    }
+
+    impl NormCase {
+        fn loss(&mut self) -> f32 {
+            let mut out = self.x.clone();
+            self.norm.forward_batch(&mut out);
+            (out * &self.upstream).sum()
+        }
+    }
+
+    #[test]
+    fn layer_norm_gradients_match_finite_differences() {
+        let mut norm = LayerNorm::new(6, 1e-5);
+        norm.gamma = values(1, 6, 0.3).row(0).mapv(|g| 1.0 + 0.5 * g);
+        norm.beta = values(1, 6, 0.8).row(0).to_owned();
+        let mut case = NormCase { norm, x: values(3, 6, 0.47) * 2.0, upstream: values(3, 6, 0.91) };
+
+        let (dx, dgamma, dbeta) = case.norm.backward_batch(&case.x.view(), &case.upstream.view());
+        assert_close("dx", dx.iter().copied(), &numeric(&mut case, |c| c.x.view_mut(), NormCase::loss));
+        let gamma = numeric(&mut case, |c| c.norm.gamma.view_mut().insert_axis(Axis(0)), NormCase::loss);
+        assert_close("dgamma", dgamma.iter().copied(), &gamma);
+        let beta = numeric(&mut case, |c| c.norm.beta.view_mut().insert_axis(Axis(0)), NormCase::loss);
+        assert_close("dbeta", dbeta.iter().copied(), &beta);
+
+        // The single-sample form agrees with the batch one.
+        let (dx0, dgamma0, dbeta0) = case.norm.backward(&case.x.row(1), &case.upstream.row(1));
+        let (dx1, dgamma1, dbeta1) = case.norm.backward_batch(&case.x.slice(ndarray::s![1..2, ..]), &case.upstream.slice(ndarray::s![1..2, ..]));
+        assert_eq!((dx0, dgamma0, dbeta0), (dx1.row(0).to_owned(), dgamma1, dbeta1));
+    }
+
+    type Select = fn(&mut LayerCase) -> ArrayViewMut2<'_, f32>;
+
+    struct LayerCase {
+        layer: Layer,
+        input: Array2<f32>,
+        upstream: Array1<f32>,
+    }
+
+    impl LayerCase {
+        fn loss(&mut self) -> f32 {
+            let input = self.input.row(0).to_owned();
+            (self.layer.forward(&input.view(), &mut thread_rng()).0 * &self.upstream).sum()
+        }
+    }
+
+    #[test]
+    fn layer_gradients_match_finite_differences() {
+        for activation in [Activation::Tanh, Activation::Sigmoid] {
+            let mut layer = Layer::new(5, 4, activation, true, 0.0);
+            layer.weights = values(4, 5, 0.21) * 0.7;
+            layer.biases = values(1, 4, 0.6).row(0).to_owned();
+            layer.set_mode(Mode::Eval);
+            let Some(Norm::Layer(ln)) = layer.norm.as_mut() else { unreachable!() };
+            ln.gamma = values(1, 4, 0.35).row(0).mapv(|g| 1.0 + 0.5 * g);
+            ln.beta = values(1, 4, 0.55).row(0).to_owned();
+            let mut case = LayerCase { layer, input: values(1, 5, 0.73), upstream: values(1, 4, 0.19).row(0).to_owned() };
+
+            let input = case.input.row(0).to_owned();
+            let (dw, db, dinput, ln_grads) = case.layer.backward(&case.upstream, &input.view(), &LayerCache::default());
+            let (dgamma, dbeta) = ln_grads.unwrap();
+            let checks: [(&str, Vec<f32>, Select); 5] = [
+                ("dW", dw.iter().copied().collect(), |c| c.layer.weights.view_mut()),
+                ("db", db.to_vec(), |c| c.layer.biases.view_mut().insert_axis(Axis(0))),
+                ("dinput", dinput.to_vec(), |c| c.input.view_mut()),
+                ("dgamma", dgamma.to_vec(), |c| c.layer.parameters_mut("").swap_remove(2).1),
+                ("dbeta", dbeta.to_vec(), |c| c.layer.parameters_mut("").swap_remove(3).1),
+            ];
+            for (name, analytic, select) in checks {
+                assert_close(name, analytic, &numeric(&mut case, select, LayerCase::loss));
+            }
+        }
+    }
+
+    #[test]
+    fn validate_reports_every_mismatch_by_name() {
+        let layers = vec![
+            Layer::new(5, 8, Activation::Tanh, true, 0.0),
+            Layer::new(6, 3, Activation::Tanh, false, 1.5),
+        ];
+        let network = NeuralNetwork::from_layers(layers);
+        let ranks = vec![LayerRank::LowRank { rank: 9, one_sided: false }, LayerRank::Full];
+        let galore = GaLoreProjection::new(4, 200, 0.0).with_rank_config(RankConfig::new(ranks));
+
+        let issues = network.validate(5, Some(&galore)).unwrap_err();
+        let messages: Vec<String> = issues.iter().map(|issue| issue.to_string()).collect();
+        assert_eq!(
+            messages,
+            [
+                "layer1: expects 6 inputs but receives 8",
+                "layer1: dropout rate 1.5 is outside [0, 1)",
+                "layer0.weight: rank 9 is not feasible for a 8x5 weight",
+            ]
+        );
+        assert_eq!(NeuralNetwork::new(vec![(5, Activation::Tanh, false, 0.0), (8, Activation::ReLU, true, 0.1)]).validate(5, None), Ok(()));
+    }
+
+    #[test]
+    fn estimates_count_bias_and_norm_vectors() {
+        let network = NeuralNetwork::new(vec![(5, Activation::Tanh, false, 0.0), (8, Activation::ReLU, true, 0.0), (3, Activation::Tanh, false, 0.0)]);
+        let estimate = network.estimate(4, &RankConfig::new(Vec::new()));
+        assert_eq!(estimate.layers.len(), 2);
+        // Weight, bias, gamma and beta of layer 0, each with two f32 moments.
+        assert_eq!(estimate.layers[0].param_bytes, (8 * 5 + 3 * 8) * 4);
+        assert_eq!(estimate.layers[0].optimizer_bytes, 2 * estimate.layers[0].param_bytes);
+        assert_eq!(estimate.layers[1].param_bytes, (3 * 8 + 3) * 4);
+    }
+
+    // Two forwards of a dropout network are differentiated in the opposite order; each must
+    // get the gradients of its own mask, as if it had been run alone.
+    #[test]
+    fn interleaved_forwards_keep_their_own_dropout_masks() {
+        let mut network = NeuralNetwork::new(vec![(5, Activation::Tanh, false, 0.0), (8, Activation::Tanh, false, 0.5), (3, Activation::Tanh, false, 0.0)]);
+        let inputs = [values(1, 5, 0.3).row(0).to_owned(), values(1, 5, 0.7).row(0).to_owned()];
+        let upstream = values(1, 3, 0.4).row(0).to_owned();
+
+        let alone: Vec<Vec<Array2<f32>>> = inputs
+            .iter()
+            .enumerate()
+            .map(|(i, input)| {
+                network.seed_dropout(i as u64);
+                let (_, cache) = network.forward(&input.view());
+                network.backward(upstream.clone(), &cache)
+            })
+            .collect();
+
+        let caches: Vec<ForwardCache> = inputs
+            .iter()
+            .enumerate()
+            .map(|(i, input)| {
+                network.seed_dropout(i as u64);
+                network.forward(&input.view()).1
+            })
+            .collect();
+        assert_ne!(caches[0].layers[0].dropout_mask, caches[1].layers[0].dropout_mask);
+        for (cache, expected) in caches.iter().zip(&alone).rev() {
+            assert_eq!(&network.backward(upstream.clone(), cache), expected);
+        }
+
+        // Eval draws no masks.
+        network.eval();
+        let (_, cache) = network.forward(&inputs[0].view());
+        assert!(cache.layers.iter().all(|layer| layer.dropout_mask.is_none()));
+    }
+
+    #[test]
+    fn running_norm_updates_its_statistics_in_training_only() {
+        let mut layer = Layer::new(4, 3, Activation::Tanh, false, 0.0).with_running_norm(0.5);
+        layer.weights = values(3, 4, 0.21);
+        let input = values(1, 4, 0.73).row(0).to_owned();
+        let Some(Norm::Running(rn)) = &layer.norm else { unreachable!() };
+        let (mean, var) = (rn.running_mean().clone(), rn.running_var().clone());
+
+        let (train_out, cache) = layer.forward(&input.view(), &mut thread_rng());
+        let Some(Norm::Running(rn)) = &layer.norm else { unreachable!() };
+        assert_ne!(rn.running_mean(), &mean);
+        assert_ne!(rn.running_var(), &var);
+        assert_eq!(cache.norm_stats.as_ref(), Some(&(rn.running_mean().clone(), rn.running_var().clone())));
+
+        // Eval leaves the statistics alone and, with them unchanged, repeats the output.
+        layer.set_mode(Mode::Eval);
+        let (first, _) = layer.forward(&input.view(), &mut thread_rng());
+        let (second, _) = layer.forward(&input.view(), &mut thread_rng());
+        assert_eq!(first, second);
+        assert_eq!(first, train_out);
+    }
+
+    // With the statistics held fixed, the running norm's backward is the exact gradient.
+    #[test]
+    fn running_norm_gradients_match_finite_differences() {
+        let mut layer = Layer::new(5, 4, Activation::Sigmoid, false, 0.0).with_running_norm(0.3);
+        layer.weights = values(4, 5, 0.21) * 0.7;
+        for k in 0..3 {
+            layer.forward(&values(1, 5, 0.1 * k as f32).row(0), &mut thread_rng());
+        }
+        layer.set_mode(Mode::Eval);
+        let mut case = LayerCase { layer, input: values(1, 5, 0.73), upstream: values(1, 4, 0.19).row(0).to_owned() };
+
+        let input = case.input.row(0).to_owned();
+        let (_, cache) = case.layer.forward(&input.view(), &mut thread_rng());
+        let (dw, _, dinput, norm_grads) = case.layer.backward(&case.upstream, &input.view(), &cache);
+        let (dgamma, dbeta) = norm_grads.unwrap();
+        let checks: [(&str, Vec<f32>, Select); 4] = [
+            ("dW", dw.iter().copied().collect(), |c| c.layer.weights.view_mut()),
+            ("dinput", dinput.to_vec(), |c| c.input.view_mut()),
+            ("dgamma", dgamma.to_vec(), |c| c.layer.parameters_mut("").swap_remove(2).1),
+            ("dbeta", dbeta.to_vec(), |c| c.layer.parameters_mut("").swap_remove(3).1),
+        ];
+        for (name, analytic, select) in checks {
+            assert_close(name, analytic, &numeric(&mut case, select, LayerCase::loss));
+        }
     }
 }
@@ -0,0 +1,263 @@
+use ndarray::Array1;
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+
+const BYTES_PER_ELEMENT: usize = std::mem::size_of::<f32>();
+
+static NEXT_FILE: AtomicUsize = AtomicUsize::new(0);
+
+enum Slot {
+    Resident(Array1<f32>),
+    Spilled { offset: u64, len: usize },
+    Taken,
+}
+
+struct Prefetch {
+    index: usize,
+    handle: JoinHandle<io::Result<Array1<f32>>>,
+}
+
+// Activations saved for backward, kept in RAM up to `budget_bytes` and spilled to a
+// scratch file beyond that. Forward pushes them in layer order; when the budget is
+// exceeded the oldest resident ones go to disk first, since backward needs them last.
+// Backward takes them in reverse, and each `take` starts reading the next spilled
+// activation on a background thread so the disk read overlaps the layer's backward.
+//
+// The scratch file is ordinary buffered file I/O rather than a memory map: activations
+// are written and read with positional reads and writes (pread/pwrite on Unix), which
+// need no shared cursor, so a prefetch in flight does not serialize the spills of the
+// next forward pass or the synchronous reads of backward. It is reused across steps
+// (`clear` rewinds it, the OS page cache keeps recently written pages warm) and deleted on
+// drop.
+pub struct ActivationSpill {
+    path: PathBuf,
+    file: Arc<File>,
+    budget_bytes: usize,
+    slots: Vec<Slot>,
+    end: u64,
+    resident_bytes: usize,
+    peak_resident_bytes: usize,
+    spilled_bytes: usize,
+    // Slots below this are spilled or taken.
+    oldest_resident: usize,
+    prefetch: Option<Prefetch>,
+}
+
+impl ActivationSpill {
+    // Creates the scratch file in `dir`.
+    pub fn new<P: AsRef<Path>>(dir: P, budget_bytes: usize) -> io::Result<Self> {
+        let name = format!("galore-activations-{}-{}.bin", std::process::id(), NEXT_FILE.fetch_add(1, Ordering::Relaxed));
+        let path = dir.as_ref().join(name);
+        let file = OpenOptions::new().read(true).write(true).create_new(true).open(&path)?;
+        Ok(ActivationSpill {
+            path,
+            file: Arc::new(file),
+            budget_bytes,
+            slots: Vec::new(),
+            end: 0,
+            resident_bytes: 0,
+            peak_resident_bytes: 0,
+            spilled_bytes: 0,
+            oldest_resident: 0,
+            prefetch: None,
+        })
+    }
+
+    // Saves an activation and returns its index.
+    pub fn push(&mut self, activation: Array1<f32>) -> io::Result<usize> {
+        self.resident_bytes += activation.len() * BYTES_PER_ELEMENT;
+        self.slots.push(Slot::Resident(activation));
+        while self.resident_bytes > self.budget_bytes && self.oldest_resident < self.slots.len() {
+            let oldest = self.oldest_resident;
+            if let Slot::Resident(resident) = &self.slots[oldest] {
+                let (len, offset) = (resident.len(), self.end);
+                write_at(&self.file, offset, resident)?;
+                self.end += (len * BYTES_PER_ELEMENT) as u64;
+                self.slots[oldest] = Slot::Spilled { offset, len };
+                self.resident_bytes -= len * BYTES_PER_ELEMENT;
+                self.spilled_bytes += len * BYTES_PER_ELEMENT;
+            }
+            self.oldest_resident += 1;
+        }
+        self.peak_resident_bytes = self.peak_resident_bytes.max(self.resident_bytes);
+        Ok(self.slots.len() - 1)
+    }
+
+    // Removes and returns activation `index`, then starts prefetching the closest spilled
+    // activation below it.
+    pub fn take(&mut self, index: usize) -> io::Result<Array1<f32>> {
+        let slot = std::mem::replace(&mut self.slots[index], Slot::Taken);
+        let activation = match slot {
+            Slot::Resident(activation) => {
+                self.resident_bytes -= activation.len() * BYTES_PER_ELEMENT;
+                activation
+            }
+            Slot::Spilled { offset, len } => match self.prefetch.take() {
+                Some(prefetch) if prefetch.index == index => {
+                    prefetch.handle.join().unwrap_or_else(|panic| std::panic::resume_unwind(panic))?
+                }
+                other => {
+                    self.prefetch = other;
+                    read_at(&self.file, offset, len)?
+                }
+            },
+            Slot::Taken => panic!("activation {} was already taken", index),
+        };
+
+        let next = self.slots[..index].iter().rposition(|slot| matches!(slot, Slot::Spilled { .. }));
+        if let (Some(next), None) = (next, &self.prefetch) {
+            if let Slot::Spilled { offset, len } = self.slots[next] {
+                let file = Arc::clone(&self.file);
+                let handle = thread::spawn(move || read_at(&file, offset, len));
+                self.prefetch = Some(Prefetch { index: next, handle });
+            }
+        }
+        Ok(activation)
+    }
+
+    // Drops everything saved so far and rewinds the scratch file for the next step.
+    pub fn clear(&mut self) {
+        if let Some(prefetch) = self.prefetch.take() {
+            // Only the thread needs finishing; its result is not wanted any more.
+            let _ = prefetch.handle.join();
+        }
+        self.slots.clear();
+        self.oldest_resident = 0;
+        self.end = 0;
+        self.resident_bytes = 0;
+    }
+
+    pub fn len(&self) -> usize {
+        self.slots.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.slots.is_empty()
+    }
+
+    pub fn resident_bytes(&self) -> usize {
+        self.resident_bytes
+    }
+
+    pub fn peak_resident_bytes(&self) -> usize {
+        self.peak_resident_bytes
+    }
+
+    // Total written to disk since creation.
+    pub fn spilled_bytes(&self) -> usize {
+        self.spilled_bytes
+    }
+}
+
+impl Drop for ActivationSpill {
+    fn drop(&mut self) {
+        self.clear();
+        // Best effort: a scratch file left behind is harmless.
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+fn write_at(file: &File, offset: u64, activation: &Array1<f32>) -> io::Result<()> {
+    let bytes: Vec<u8> = activation.iter().flat_map(|x| x.to_le_bytes()).collect();
+    write_all_at(file, &bytes, offset)
+}
+
+fn read_at(file: &File, offset: u64, len: usize) -> io::Result<Array1<f32>> {
+    let mut bytes = vec![0u8; len * BYTES_PER_ELEMENT];
+    read_exact_at(file, &mut bytes, offset)?;
+    Ok(bytes.chunks_exact(BYTES_PER_ELEMENT).map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]])).collect())
+}
+
+#[cfg(unix)]
+fn write_all_at(file: &File, bytes: &[u8], offset: u64) -> io::Result<()> {
+    std::os::unix::fs::FileExt::write_all_at(file, bytes, offset)
+}
+
+#[cfg(unix)]
+fn read_exact_at(file: &File, bytes: &mut [u8], offset: u64) -> io::Result<()> {
+    std::os::unix::fs::FileExt::read_exact_at(file, bytes, offset)
+}
+
+// seek_write/seek_read move the file cursor, but nothing here relies on it.
+#[cfg(windows)]
+fn write_all_at(file: &File, mut bytes: &[u8], mut offset: u64) -> io::Result<()> {
+    use std::os::windows::fs::FileExt;
+    while !bytes.is_empty() {
+        match file.seek_write(bytes, offset) {
+            Ok(0) => return Err(io::Error::new(io::ErrorKind::WriteZero, "failed to write activation")),
+            Ok(n) => {
+                bytes = &bytes[n..];
+                offset += n as u64;
+            }
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(windows)]
+fn read_exact_at(file: &File, mut bytes: &mut [u8], mut offset: u64) -> io::Result<()> {
+    use std::os::windows::fs::FileExt;
+    while !bytes.is_empty() {
+        match file.seek_read(bytes, offset) {
+            Ok(0) => return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "activation file is truncated")),
+            Ok(n) => {
+                bytes = &mut bytes[n..];
+                offset += n as u64;
+            }
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn activation(len: usize, seed: usize) -> Array1<f32> {
+        Array1::from_shape_fn(len, |i| (i + 31 * seed) as f32 * 0.25)
+    }
+
+    #[test]
+    fn spills_beyond_the_budget_and_restores_in_reverse() {
+        let dir = std::env::temp_dir();
+        // Room for two resident activations of 100 floats.
+        let mut spill = ActivationSpill::new(&dir, 800).unwrap();
+        for step in 0..2 {
+            let indices: Vec<usize> = (0..6).map(|k| spill.push(activation(100, k + step)).unwrap()).collect();
+            assert_eq!(spill.resident_bytes(), 800);
+            assert_eq!(spill.spilled_bytes(), (step + 1) * 4 * 400);
+            for &k in indices.iter().rev() {
+                assert_eq!(spill.take(k).unwrap(), activation(100, k + step));
+            }
+            assert_eq!(spill.resident_bytes(), 0);
+            spill.clear();
+        }
+        assert_eq!(spill.peak_resident_bytes(), 800);
+        let path = spill.path.clone();
+        assert!(path.exists());
+        drop(spill);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn out_of_order_takes_bypass_the_prefetch() {
+        let mut spill = ActivationSpill::new(std::env::temp_dir(), 0).unwrap();
+        for k in 0..5 {
+            spill.push(activation(10 + k, k)).unwrap();
+        }
+        // Taking 4 prefetches 3; taking 1 first must still read the right data.
+        assert_eq!(spill.take(4).unwrap(), activation(14, 4));
+        assert_eq!(spill.take(1).unwrap(), activation(11, 1));
+        assert_eq!(spill.take(3).unwrap(), activation(13, 3));
+        assert_eq!(spill.take(2).unwrap(), activation(12, 2));
+        assert_eq!(spill.take(0).unwrap(), activation(10, 0));
+    }
+}
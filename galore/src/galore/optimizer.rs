@@ -0,0 +1,261 @@
+use ndarray::Array2;
+
+use super::checkpoint::{Checkpoint, CheckpointError};
+use super::moment::Moment;
+
+pub trait Optimizer {
+    fn compute_updates(&mut self, gradients: &[Array2<f32>]) -> Vec<Array2<f32>>;
+
+    // Used to scale decoupled weight decay, which needs an optimizer that reports one.
+    fn learning_rate(&self) -> Option<f32> {
+        None
+    }
+
+    // Checkpoint hooks; stateless optimizers can keep the defaults. `keys` names the
+    // parameters in gradient order (see `registry::param_keys`) for per-parameter entries.
+    fn save_state(&self, _checkpoint: &mut Checkpoint, _prefix: &str, _keys: &[String]) {}
+
+    fn load_state(&mut self, _checkpoint: &Checkpoint, _prefix: &str, _keys: &[String]) -> Result<(), CheckpointError> {
+        Ok(())
+    }
+}
+
+// Example implementation of Adam optimizer
+pub struct Adam {
+    lr: f32,
+    beta1: f32,
+    beta2: f32,
+    epsilon: f32,
+    m: Vec<Moment>,
+    v: Vec<Moment>,
+    t: usize,
+    // Per parameter: the steps since its moments started, for bias correction, and the
+    // moments it set aside on its last shape change (see `switch_shape`).
+    steps: Vec<usize>,
+    stashed: Vec<Option<(Moment, Moment, usize)>>,
+    // By gradient index; parameters past the end keep f32 moments.
+    eight_bit: Vec<bool>,
+}
+
+impl Adam {
+    pub fn new(lr: f32, beta1: f32, beta2: f32, epsilon: f32) -> Self {
+        Adam {
+            lr,
+            beta1,
+            beta2,
+            epsilon,
+            m: Vec::new(),
+            v: Vec::new(),
+            t: 0,
+            steps: Vec::new(),
+            stashed: Vec::new(),
+            eight_bit: Vec::new(),
+        }
+    }
+
+    // Stores the moments of the flagged parameters (by gradient index, as in
+    // `RankConfig::eight_bit`) in 8 bits, re-quantizing them after every update.
+    pub fn with_eight_bit_states(mut self, eight_bit: Vec<bool>) -> Self {
+        self.eight_bit = eight_bit;
+        self
+    }
+
+    // Memory held by both moments, set-aside ones included.
+    pub fn state_bytes(&self) -> usize {
+        let stashed = self.stashed.iter().flatten().flat_map(|(m, v, _)| [m, v]);
+        self.m.iter().chain(self.v.iter()).chain(stashed).map(Moment::bytes).sum()
+    }
+
+    // A gradient that changes shape (a restart step switching between projected and full
+    // rank) sets its moments aside and starts new ones, with bias correction counted from
+    // the new start. Switching back to the set-aside shape picks those up where they
+    // stopped, so a one-step restart keeps the low-rank moments; the full-rank ones are
+    // dropped then.
+    fn switch_shape(&mut self, i: usize, dim: (usize, usize)) {
+        let zeros = || Moment::Full(Array2::zeros(dim));
+        let m = std::mem::replace(&mut self.m[i], zeros());
+        let v = std::mem::replace(&mut self.v[i], zeros());
+        let steps = std::mem::replace(&mut self.steps[i], 0);
+        match self.stashed[i].take() {
+            Some((m, v, steps)) if m.dim() == dim => {
+                (self.m[i], self.v[i], self.steps[i]) = (m, v, steps);
+            }
+            // Empty placeholders have nothing worth keeping.
+            _ if m.dim() != (0, 0) => self.stashed[i] = Some((m, v, steps)),
+            _ => {}
+        }
+    }
+
+    fn is_eight_bit(&self, index: usize) -> bool {
+        self.eight_bit.get(index).copied().unwrap_or(false)
+    }
+}
+
+impl Optimizer for Adam {
+    fn compute_updates(&mut self, gradients: &[Array2<f32>]) -> Vec<Array2<f32>> {
+        self.t += 1;
+        // Empty placeholders are sized on first use by the shape check below.
+        let empty = || Moment::Full(Array2::zeros((0, 0)));
+        if self.m.len() < gradients.len() {
+            self.m.resize_with(gradients.len(), empty);
+            self.v.resize_with(gradients.len(), empty);
+        }
+        self.steps.resize(self.m.len(), 0);
+        self.stashed.resize_with(self.m.len(), || None);
+
+        let mut updates = Vec::with_capacity(gradients.len());
+        for (i, g) in gradients.iter().enumerate() {
+            let eight_bit = self.is_eight_bit(i);
+            if self.m[i].dim() != g.dim() {
+                self.switch_shape(i, g.dim());
+            }
+            self.steps[i] += 1;
+            let t = self.steps[i] as i32;
+            let mut m = std::mem::replace(&mut self.m[i], empty()).into_dense(false);
+            let mut v = std::mem::replace(&mut self.v[i], empty()).into_dense(true);
+
+            m = self.beta1 * &m + (1.0 - self.beta1) * g;
+            v = self.beta2 * &v + (1.0 - self.beta2) * g * g;
+
+            let m_hat = &m / (1.0 - self.beta1.powi(t));
+            let v_hat = &v / (1.0 - self.beta2.powi(t));
+            updates.push(-self.lr * &m_hat / (v_hat.map(|x| x.sqrt()) + self.epsilon));
+
+            self.m[i] = Moment::new(m, eight_bit, false);
+            self.v[i] = Moment::new(v, eight_bit, true);
+        }
+        updates
+    }
+
+    fn learning_rate(&self) -> Option<f32> {
+        Some(self.lr)
+    }
+
+    fn save_state(&self, checkpoint: &mut Checkpoint, prefix: &str, keys: &[String]) {
+        checkpoint.set_metadata(format!("{}.t", prefix), self.t);
+        checkpoint.set_metadata(format!("{}.num_params", prefix), self.m.len());
+        // 8-bit moments are written dequantized, so checkpoints do not depend on the storage.
+        for (i, (key, (m, v))) in keys.iter().zip(self.m.iter().zip(self.v.iter())).enumerate() {
+            checkpoint.insert_tensor(format!("{}.m.{}", prefix, key), m.to_dense(false));
+            checkpoint.insert_tensor(format!("{}.v.{}", prefix, key), v.to_dense(true));
+            checkpoint.set_metadata(format!("{}.steps.{}", prefix, key), self.steps.get(i).copied().unwrap_or(0));
+            if let Some(Some((m, v, steps))) = self.stashed.get(i) {
+                checkpoint.insert_tensor(format!("{}.m_stashed.{}", prefix, key), m.to_dense(false));
+                checkpoint.insert_tensor(format!("{}.v_stashed.{}", prefix, key), v.to_dense(true));
+                checkpoint.set_metadata(format!("{}.steps_stashed.{}", prefix, key), steps);
+            }
+        }
+    }
+
+    // A parameter with neither moment in the checkpoint (added since it was written) starts
+    // from zero moments; the empty placeholder is resized on its first update. Checkpoints
+    // without per-parameter step counts use the global one.
+    fn load_state(&mut self, checkpoint: &Checkpoint, prefix: &str, keys: &[String]) -> Result<(), CheckpointError> {
+        let num_params: usize = checkpoint.parse_metadata(&format!("{}.num_params", prefix))?;
+        let t: usize = checkpoint.parse_metadata(&format!("{}.t", prefix))?;
+        if num_params == 0 {
+            (self.m, self.v) = (Vec::new(), Vec::new());
+            (self.steps, self.stashed) = (Vec::new(), Vec::new());
+        } else {
+            let moments = keys.iter().map(|key| {
+                let (m, v) = (format!("{}.m.{}", prefix, key), format!("{}.v.{}", prefix, key));
+                if checkpoint.tensor(&m).is_none() && checkpoint.tensor(&v).is_none() {
+                    return Ok((Array2::zeros((0, 0)), Array2::zeros((0, 0))));
+                }
+                Ok((checkpoint.matrix(&m)?, checkpoint.matrix(&v)?))
+            });
+            let moments = moments.collect::<Result<Vec<_>, CheckpointError>>()?;
+            (self.m, self.v) = moments
+                .into_iter()
+                .enumerate()
+                .map(|(i, (m, v))| (Moment::new(m, self.is_eight_bit(i), false), Moment::new(v, self.is_eight_bit(i), true)))
+                .unzip();
+            let mut steps = Vec::with_capacity(keys.len());
+            let mut stashed = Vec::with_capacity(keys.len());
+            for (i, key) in keys.iter().enumerate() {
+                let count = format!("{}.steps.{}", prefix, key);
+                steps.push(if self.m[i].dim() == (0, 0) {
+                    0
+                } else if checkpoint.metadata(&count).is_some() {
+                    checkpoint.parse_metadata(&count)?
+                } else {
+                    t
+                });
+                let (m, v) = (format!("{}.m_stashed.{}", prefix, key), format!("{}.v_stashed.{}", prefix, key));
+                stashed.push(match checkpoint.tensor(&m) {
+                    Some(_) => {
+                        let eight_bit = self.is_eight_bit(i);
+                        let count = checkpoint.parse_metadata(&format!("{}.steps_stashed.{}", prefix, key))?;
+                        Some((Moment::new(checkpoint.matrix(&m)?, eight_bit, false), Moment::new(checkpoint.matrix(&v)?, eight_bit, true), count))
+                    }
+                    None => None,
+                });
+            }
+            (self.steps, self.stashed) = (steps, stashed);
+        }
+        self.t = t;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::galore::testing::gradient;
+
+    #[test]
+    fn eight_bit_adam_tracks_f32_adam() {
+        let mut full = Adam::new(1e-2, 0.9, 0.999, 1e-8);
+        let mut eight_bit = Adam::new(1e-2, 0.9, 0.999, 1e-8).with_eight_bit_states(vec![true]);
+        for step in 0..20 {
+            let g = gradient(step);
+            let expected = &full.compute_updates(std::slice::from_ref(&g))[0];
+            let actual = &eight_bit.compute_updates(std::slice::from_ref(&g))[0];
+            let error = (actual - expected).mapv(|x| x * x).sum().sqrt() / expected.mapv(|x| x * x).sum().sqrt();
+            assert!(error < 0.05, "step {}: relative error {}", step, error);
+        }
+        assert!(eight_bit.state_bytes() * 3 < full.state_bytes());
+    }
+
+    #[test]
+    fn eight_bit_moments_round_trip_through_checkpoints() {
+        let keys = vec!["w".to_string()];
+        let mut adam = Adam::new(1e-2, 0.9, 0.999, 1e-8).with_eight_bit_states(vec![true]);
+        adam.compute_updates(&[gradient(0)]);
+        let mut checkpoint = Checkpoint::new();
+        adam.save_state(&mut checkpoint, "optimizer", &keys);
+
+        let mut restored = Adam::new(1e-2, 0.9, 0.999, 1e-8).with_eight_bit_states(vec![true]);
+        restored.load_state(&checkpoint, "optimizer", &keys).unwrap();
+        assert_eq!(restored.compute_updates(&[gradient(1)]), adam.compute_updates(&[gradient(1)]));
+    }
+
+    #[test]
+    fn a_restart_step_starts_fresh_moments_and_keeps_the_projected_ones() {
+        let lr = 1e-2;
+        let projected = |step: usize| gradient(step).slice(ndarray::s![..4, ..]).to_owned();
+        let mut adam = Adam::new(lr, 0.9, 0.999, 1e-8);
+        let mut reference = Adam::new(lr, 0.9, 0.999, 1e-8);
+        for step in 0..50 {
+            adam.compute_updates(&[projected(step)]);
+            reference.compute_updates(&[projected(step)]);
+        }
+
+        // A first step from zero moments moves every element by about lr, not by a
+        // multiple of it as the global step count's bias correction would.
+        let full = adam.compute_updates(&[gradient(50)]).remove(0);
+        let largest = full.iter().fold(0.0f32, |m, x| m.max(x.abs()));
+        assert!((largest - lr).abs() < 1e-3 * lr, "{}", largest);
+
+        // Back at projected rank the moments carry on as if the restart had not happened.
+        let keys = vec!["w".to_string()];
+        let mut checkpoint = Checkpoint::new();
+        adam.save_state(&mut checkpoint, "optimizer", &keys);
+        let mut restored = Adam::new(lr, 0.9, 0.999, 1e-8);
+        restored.load_state(&checkpoint, "optimizer", &keys).unwrap();
+        let expected = reference.compute_updates(&[projected(51)]);
+        assert_eq!(adam.compute_updates(&[projected(51)]), expected);
+        assert_eq!(restored.compute_updates(&[projected(51)]), expected);
+        assert_eq!(adam.state_bytes(), reference.state_bytes());
+    }
+}
@@ -0,0 +1,145 @@
+use ndarray::{Array1, Array2};
+
+// Several documents packed into fixed-length rows, so short documents do not waste a whole
+// row on padding. `segments` numbers the documents of each row from 1, with 0 for padding;
+// `positions` restart at 0 at each document start. Documents longer than a row are split
+// into row-sized pieces, each its own segment.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PackedBatch {
+    pub tokens: Array2<usize>,
+    pub segments: Array2<usize>,
+    pub positions: Array2<usize>,
+}
+
+impl PackedBatch {
+    // Greedy, in order: a document that does not fit in the rest of the current row starts
+    // a new one.
+    pub fn pack(documents: &[&[usize]], seq_len: usize, pad_token: usize) -> Self {
+        assert!(seq_len > 0, "sequence length must be positive");
+        let mut rows: Vec<Vec<(usize, usize, usize)>> = vec![Vec::new()];
+        for document in documents {
+            for piece in document.chunks(seq_len) {
+                if rows.last().unwrap().len() + piece.len() > seq_len {
+                    rows.push(Vec::new());
+                }
+                let row = rows.last_mut().unwrap();
+                let segment = row.last().map_or(1, |&(_, s, _)| s + 1);
+                row.extend(piece.iter().enumerate().map(|(p, &token)| (token, segment, p)));
+            }
+        }
+
+        let mut tokens = Array2::from_elem((rows.len(), seq_len), pad_token);
+        let mut segments = Array2::zeros((rows.len(), seq_len));
+        let mut positions = Array2::zeros((rows.len(), seq_len));
+        for (r, row) in rows.iter().enumerate() {
+            for (t, &(token, segment, position)) in row.iter().enumerate() {
+                tokens[[r, t]] = token;
+                segments[[r, t]] = segment;
+                positions[[r, t]] = position;
+            }
+        }
+        PackedBatch { tokens, segments, positions }
+    }
+
+    pub fn rows(&self) -> usize {
+        self.tokens.nrows()
+    }
+
+    pub fn seq_len(&self) -> usize {
+        self.tokens.ncols()
+    }
+
+    // Next-token targets: position t predicts token t + 1 of the same document. Positions
+    // without one (padding, last token of a document) get `pad_token` and a 0 in `loss_mask`.
+    pub fn targets(&self, pad_token: usize) -> Array2<usize> {
+        Array2::from_shape_fn(self.tokens.dim(), |(r, t)| {
+            if self.predicts_next(r, t) {
+                self.tokens[[r, t + 1]]
+            } else {
+                pad_token
+            }
+        })
+    }
+
+    // 1 where `targets` holds a real next token, flattened row-major to match logits of
+    // shape (rows * seq_len) x vocab, e.g. for `LossBatch::with_mask`.
+    pub fn loss_mask(&self) -> Array1<f32> {
+        let (rows, seq_len) = self.tokens.dim();
+        Array1::from_shape_fn(rows * seq_len, |i| if self.predicts_next(i / seq_len, i % seq_len) { 1.0 } else { 0.0 })
+    }
+
+    // Additive attention mask for one row: 0 where query i may attend to key j (same
+    // document and, if `causal`, j <= i), -inf elsewhere. Add it to the attention scores
+    // before the softmax. Padding queries attend only to themselves so their softmax stays
+    // finite.
+    pub fn attention_mask(&self, row: usize, causal: bool) -> Array2<f32> {
+        let segments = self.segments.row(row);
+        let seq_len = segments.len();
+        Array2::from_shape_fn((seq_len, seq_len), |(i, j)| {
+            let allowed = match segments[i] {
+                0 => i == j,
+                segment => segments[j] == segment && (!causal || j <= i),
+            };
+            if allowed {
+                0.0
+            } else {
+                f32::NEG_INFINITY
+            }
+        })
+    }
+
+    fn predicts_next(&self, row: usize, t: usize) -> bool {
+        let segment = self.segments[[row, t]];
+        segment != 0 && t + 1 < self.seq_len() && self.segments[[row, t + 1]] == segment
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::galore::loss::{CrossEntropy, Loss, LossBatch, PackedTargets, Targets};
+    use crate::galore::transformer::TransformerModel;
+
+    const PAD: usize = 0;
+
+    // Each document scored on its own: causal logits, targets shifted by one, the last
+    // token predicting nothing.
+    fn unpacked_loss(model: &TransformerModel, document: &[usize]) -> f32 {
+        let logits = model.forward(document);
+        let n = document.len() - 1;
+        let batch = LossBatch::new(logits.slice(ndarray::s![..n, ..]), Targets::Classes(&document[1..]));
+        CrossEntropy.compute(&batch).value
+    }
+
+    #[test]
+    fn packed_batch_gives_each_document_its_unpacked_loss() {
+        let model = TransformerModel::new(10, 8, 2, 16, 2, 8);
+        let documents: [&[usize]; 4] = [&[3, 1, 4, 1], &[5, 9, 2], &[6, 5], &[3, 5, 8, 9, 7]];
+        let batch = PackedBatch::pack(&documents, 8, PAD);
+        assert_eq!(batch.rows(), 2);
+
+        let logits = model.forward_packed(&batch);
+        let targets = PackedTargets::new(&batch, PAD);
+        let mut document = 0;
+        let mut weighted = 0.0;
+        for r in 0..batch.rows() {
+            for segment in 1..=*batch.segments.row(r).iter().max().unwrap() {
+                // The loss mask restricted to one document of the row.
+                let mask = Array1::from_shape_fn(targets.mask.len(), |i| {
+                    let in_document = i / batch.seq_len() == r && batch.segments[[r, i % batch.seq_len()]] == segment;
+                    if in_document { targets.mask[i] } else { 0.0 }
+                });
+                let packed = CrossEntropy.compute(&LossBatch::new(logits.view(), Targets::Classes(&targets.classes)).with_mask(mask.view())).value;
+                let alone = unpacked_loss(&model, documents[document]);
+                assert!((packed - alone).abs() < 1e-5, "document {}: packed {} vs unpacked {}", document, packed, alone);
+                weighted += alone * mask.sum();
+                document += 1;
+            }
+        }
+        assert_eq!(document, documents.len());
+
+        // The whole packed batch averages over real next-token positions only.
+        let total = CrossEntropy.compute(&targets.batch(logits.view())).value;
+        assert!((total - weighted / targets.mask.sum()).abs() < 1e-5);
+    }
+}
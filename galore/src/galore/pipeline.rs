@@ -0,0 +1,795 @@
+use ndarray::{Array1, Array2, ArrayView2, Axis, CowArray, Ix1, Ix2};
+use ndarray_rand::rand_distr::Normal;
+use ndarray_rand::RandomExt;
+
+use std::fmt;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+
+use super::checkpoint::{Checkpoint, CheckpointError};
+use super::matrix_ops::GaLoreProjection;
+use super::optimizer::Optimizer;
+
+// Gradients flow through the pipeline borrowed until a stage needs to modify them, so
+// full-size gradients are not copied unless a pre-projection transform is installed.
+pub type Grad<'a> = CowArray<'a, f32, Ix2>;
+
+pub struct TransformContext<'c, 'p> {
+    pub galore: &'c mut GaLoreProjection,
+    pub optimizer: &'c mut dyn Optimizer,
+    pub step: usize,
+    // The current parameter values, when the caller provided them (`apply_updates`).
+    pub params: Option<&'p [ArrayView2<'p, f32>]>,
+}
+
+// One stage of `GaLoreOptimizer::step`. Stages run in order and each receives the output
+// of the previous one, so a stage placed before "project" sees full-size gradients and a
+// stage placed after "project_back" sees full-size updates.
+pub trait GradTransform: Send {
+    fn name(&self) -> &str;
+
+    fn apply<'a>(&mut self, grads: Vec<Grad<'a>>, ctx: &mut TransformContext) -> Vec<Grad<'a>>;
+
+    // Stages that read `TransformContext::params`. `GaLoreOptimizer` refuses to run a
+    // pipeline containing one through an entry point without parameters (`step`,
+    // `accumulate`) with `PipelineError::NeedsParams`, before the step starts, so such a
+    // stage can rely on getting them.
+    fn needs_params(&self) -> bool {
+        false
+    }
+
+    // Runs on every stage before each call into the optimizer, ahead of any state change;
+    // an error refuses the call. For conditions a stage cannot recover from inside `apply`.
+    fn check(&mut self, _galore: &GaLoreProjection, _optimizer: &dyn Optimizer) -> Result<(), PipelineError> {
+        Ok(())
+    }
+
+    // Checkpoint hooks for stages that carry state across steps, as on `Optimizer`. They are
+    // also used for the refresh rollback snapshot.
+    fn save_state(&self, _checkpoint: &mut Checkpoint, _prefix: &str, _keys: &[String]) {}
+
+    fn load_state(&mut self, _checkpoint: &Checkpoint, _prefix: &str, _keys: &[String]) -> Result<(), CheckpointError> {
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum PipelineError {
+    UnknownStage(String),
+    // A stage that reads the parameters, run through an entry point that has none.
+    NeedsParams(String),
+    // A stage's `GradTransform::check` failed.
+    Check { stage: String, reason: String },
+    // Weight decay on a base optimizer whose `learning_rate` is None.
+    NoLearningRate,
+}
+
+impl fmt::Display for PipelineError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PipelineError::UnknownStage(stage) => write!(f, "no pipeline stage named {}", stage),
+            PipelineError::NeedsParams(stage) => {
+                write!(f, "stage {} reads the parameters; use apply_updates or accumulate_updates", stage)
+            }
+            PipelineError::Check { stage, reason } => write!(f, "stage {}: {}", stage, reason),
+            PipelineError::NoLearningRate => write!(f, "weight decay needs a base optimizer that reports its learning rate"),
+        }
+    }
+}
+
+impl std::error::Error for PipelineError {}
+
+// The parameters a transform applies to, by position in the gradient list.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub enum ParamGroup {
+    #[default]
+    All,
+    Only(Vec<usize>),
+}
+
+impl ParamGroup {
+    pub fn contains(&self, index: usize) -> bool {
+        match self {
+            ParamGroup::All => true,
+            ParamGroup::Only(indices) => indices.contains(&index),
+        }
+    }
+}
+
+pub fn default_pipeline() -> Vec<Box<dyn GradTransform>> {
+    vec![Box::new(Project), Box::new(Optimize), Box::new(ProjectBack)]
+}
+
+pub struct Project;
+
+impl GradTransform for Project {
+    fn name(&self) -> &str {
+        "project"
+    }
+
+    fn apply<'a>(&mut self, grads: Vec<Grad<'a>>, ctx: &mut TransformContext) -> Vec<Grad<'a>> {
+        let views = grads.iter().map(|g| g.view()).collect();
+        ctx.galore.project_gradient(views).into_iter().map(CowArray::from).collect()
+    }
+}
+
+pub struct Optimize;
+
+impl GradTransform for Optimize {
+    fn name(&self) -> &str {
+        "optimize"
+    }
+
+    fn apply<'a>(&mut self, grads: Vec<Grad<'a>>, ctx: &mut TransformContext) -> Vec<Grad<'a>> {
+        let grads: Vec<Array2<f32>> = grads.into_iter().map(|g| g.into_owned()).collect();
+        ctx.optimizer.compute_updates(&grads).into_iter().map(CowArray::from).collect()
+    }
+}
+
+pub struct ProjectBack;
+
+impl GradTransform for ProjectBack {
+    fn name(&self) -> &str {
+        "project_back"
+    }
+
+    fn apply<'a>(&mut self, grads: Vec<Grad<'a>>, ctx: &mut TransformContext) -> Vec<Grad<'a>> {
+        let views = grads.iter().map(|g| g.view()).collect();
+        ctx.galore.project_update(views).into_iter().map(CowArray::from).collect()
+    }
+}
+
+// Where a gradient clip is measured. Full-space clipping goes before "project"; projected
+// clipping goes after it, where the norm is cheaper to compute and, with orthonormal P/Q,
+// never larger than the full one, so the same threshold clips less often. Unprojected
+// parameters count at full size in either space.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ClipSpace {
+    #[default]
+    Full,
+    Projected,
+}
+
+// Rescales all gradients together so that their global L2 norm is at most `max_norm`.
+// Records "<stage>/norm" and "<stage>/applied" (0 or 1) in the projection metrics, where
+// the stage is "clip" or "clip_projected".
+pub struct ClipGradNorm {
+    max_norm: f32,
+    space: ClipSpace,
+}
+
+impl ClipGradNorm {
+    pub fn new(max_norm: f32) -> Self {
+        ClipGradNorm { max_norm, space: ClipSpace::Full }
+    }
+
+    pub fn with_space(mut self, space: ClipSpace) -> Self {
+        self.space = space;
+        self
+    }
+
+    pub fn space(&self) -> ClipSpace {
+        self.space
+    }
+}
+
+impl GradTransform for ClipGradNorm {
+    fn name(&self) -> &str {
+        match self.space {
+            ClipSpace::Full => "clip",
+            ClipSpace::Projected => "clip_projected",
+        }
+    }
+
+    fn apply<'a>(&mut self, grads: Vec<Grad<'a>>, ctx: &mut TransformContext) -> Vec<Grad<'a>> {
+        let norm = global_norm(&grads);
+        let applied = norm > self.max_norm && norm > 0.0;
+        let metrics = ctx.galore.metrics_mut();
+        metrics.record(format!("{}/norm", self.name()), ctx.step, norm);
+        metrics.record(format!("{}/applied", self.name()), ctx.step, if applied { 1.0 } else { 0.0 });
+        if !applied {
+            return grads;
+        }
+        let factor = self.max_norm / norm;
+        grads.into_iter().map(|g| CowArray::from(g.into_owned() * factor)).collect()
+    }
+}
+
+// Where decoupled weight decay enters the update. Full applies -lr * wd * W to the
+// back-projected update, exactly as AdamW. Subspace folds -lr * wd * P^T W (or W Q) into
+// the low-rank update before "project_back", so only the part of W inside the current
+// subspace decays; cheaper, but weights outside the subspace are never shrunk. Unprojected
+// parameters decay the same way in both modes.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum WeightDecayMode {
+    #[default]
+    Full,
+    Subspace,
+}
+
+impl fmt::Display for WeightDecayMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WeightDecayMode::Full => write!(f, "full"),
+            WeightDecayMode::Subspace => write!(f, "subspace"),
+        }
+    }
+}
+
+impl FromStr for WeightDecayMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "full" => Ok(WeightDecayMode::Full),
+            "subspace" => Ok(WeightDecayMode::Subspace),
+            _ => Err(format!("unknown weight decay mode {}", s)),
+        }
+    }
+}
+
+// Decoupled weight decay scaled by the base optimizer's learning rate. Needs the parameters,
+// so the optimizer must be driven through `apply_updates`. Install it after "project_back"
+// (Full) or after "optimize" (Subspace).
+pub struct WeightDecay {
+    weight_decay: f32,
+    mode: WeightDecayMode,
+}
+
+impl WeightDecay {
+    pub fn new(weight_decay: f32, mode: WeightDecayMode) -> Self {
+        WeightDecay { weight_decay, mode }
+    }
+
+    pub fn mode(&self) -> WeightDecayMode {
+        self.mode
+    }
+}
+
+impl GradTransform for WeightDecay {
+    fn name(&self) -> &str {
+        "weight_decay"
+    }
+
+    fn needs_params(&self) -> bool {
+        true
+    }
+
+    // Covers stages installed with `with_transform_after` rather than `with_weight_decay`.
+    fn check(&mut self, _galore: &GaLoreProjection, optimizer: &dyn Optimizer) -> Result<(), PipelineError> {
+        optimizer.learning_rate().map(|_| ()).ok_or(PipelineError::NoLearningRate)
+    }
+
+    fn apply<'a>(&mut self, grads: Vec<Grad<'a>>, ctx: &mut TransformContext) -> Vec<Grad<'a>> {
+        let params = ctx.params.expect("weight decay declares needs_params, so it is never run without the parameters");
+        let lr = ctx.optimizer.learning_rate().expect("weight decay checks for a learning rate before each step");
+        let factor = -lr * self.weight_decay;
+        if factor == 0.0 {
+            return grads;
+        }
+        let decay: Vec<Array2<f32>> = match self.mode {
+            WeightDecayMode::Full => params.iter().map(|p| p.to_owned()).collect(),
+            WeightDecayMode::Subspace => ctx.galore.project_current(params),
+        };
+        grads
+            .into_iter()
+            .zip(decay)
+            .map(|(g, d)| {
+                let mut g = g.into_owned();
+                g.scaled_add(factor, &d);
+                CowArray::from(g)
+            })
+            .collect()
+    }
+}
+
+// Trust-region style guard on the final update: any parameter in `group` whose update norm
+// exceeds `max_ratio * ||W||` is scaled down to that bound. Mostly catches the large step
+// right after a subspace switch, when Adam's moments still describe the old subspace.
+// Parameters with zero norm are left alone. Needs the parameters, like `WeightDecay`; goes
+// at the end of the pipeline. Records "update_cap/capped", the number of capped parameters.
+pub struct UpdateNormCap {
+    max_ratio: f32,
+    group: ParamGroup,
+}
+
+impl UpdateNormCap {
+    pub fn new(max_ratio: f32) -> Self {
+        UpdateNormCap { max_ratio, group: ParamGroup::All }
+    }
+
+    pub fn with_group(mut self, group: ParamGroup) -> Self {
+        self.group = group;
+        self
+    }
+}
+
+impl GradTransform for UpdateNormCap {
+    fn name(&self) -> &str {
+        "update_cap"
+    }
+
+    fn needs_params(&self) -> bool {
+        true
+    }
+
+    fn apply<'a>(&mut self, grads: Vec<Grad<'a>>, ctx: &mut TransformContext) -> Vec<Grad<'a>> {
+        let params = ctx.params.expect("update norm caps declare needs_params, so they never run without the parameters");
+        let mut capped = 0;
+        let grads = grads
+            .into_iter()
+            .zip(params.iter())
+            .enumerate()
+            .map(|(i, (g, param))| {
+                if !self.group.contains(i) {
+                    return g;
+                }
+                let bound = self.max_ratio * param.iter().map(|x| x * x).sum::<f32>().sqrt();
+                let norm = g.iter().map(|x| x * x).sum::<f32>().sqrt();
+                if bound == 0.0 || norm <= bound {
+                    return g;
+                }
+                capped += 1;
+                CowArray::from(g.into_owned() * (bound / norm))
+            })
+            .collect();
+        ctx.galore.metrics_mut().record(format!("{}/capped", self.name()), ctx.step, capped as f32);
+        grads
+    }
+}
+
+// Adds Gaussian noise with standard deviation std / (1 + step)^(decay / 2).
+pub struct GradNoise {
+    std: f32,
+    decay: f32,
+}
+
+impl GradNoise {
+    pub fn new(std: f32) -> Self {
+        GradNoise { std, decay: 0.0 }
+    }
+
+    pub fn with_decay(mut self, decay: f32) -> Self {
+        self.decay = decay;
+        self
+    }
+}
+
+impl GradTransform for GradNoise {
+    fn name(&self) -> &str {
+        "noise"
+    }
+
+    fn apply<'a>(&mut self, grads: Vec<Grad<'a>>, ctx: &mut TransformContext) -> Vec<Grad<'a>> {
+        let std = self.std / (1.0 + ctx.step as f32).powf(self.decay / 2.0);
+        if std <= 0.0 {
+            return grads;
+        }
+        let noise = Normal::new(0.0, std).unwrap();
+        grads
+            .into_iter()
+            .map(|g| {
+                let mut g = g.into_owned();
+                g += &Array2::random(g.dim(), noise);
+                CowArray::from(g)
+            })
+            .collect()
+    }
+}
+
+// Multiplies the gradients or updates in `group` by a constant, e.g. GaLore's scale factor.
+pub struct Scale {
+    factor: f32,
+    group: ParamGroup,
+}
+
+impl Scale {
+    pub fn new(factor: f32) -> Self {
+        Scale { factor, group: ParamGroup::All }
+    }
+
+    pub fn with_group(mut self, group: ParamGroup) -> Self {
+        self.group = group;
+        self
+    }
+}
+
+impl GradTransform for Scale {
+    fn name(&self) -> &str {
+        "scale"
+    }
+
+    fn apply<'a>(&mut self, grads: Vec<Grad<'a>>, _ctx: &mut TransformContext) -> Vec<Grad<'a>> {
+        if self.factor == 1.0 {
+            return grads;
+        }
+        grads
+            .into_iter()
+            .enumerate()
+            .map(|(i, g)| if self.group.contains(i) { CowArray::from(g.into_owned() * self.factor) } else { g })
+            .collect()
+    }
+}
+
+// Gradient centralization: subtracts each row's mean, i.e. removes the component along the
+// all-ones input direction. Place it before "project". That component is often the
+// dominant singular direction of a raw gradient, so removing it leaves the rank budget for
+// the remaining structure. The projected update is also mean-free along rows.
+pub struct GradCentralization {
+    group: ParamGroup,
+}
+
+impl GradCentralization {
+    pub fn new() -> Self {
+        GradCentralization { group: ParamGroup::All }
+    }
+
+    pub fn with_group(mut self, group: ParamGroup) -> Self {
+        self.group = group;
+        self
+    }
+}
+
+impl Default for GradCentralization {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl GradTransform for GradCentralization {
+    fn name(&self) -> &str {
+        "centralize"
+    }
+
+    fn apply<'a>(&mut self, grads: Vec<Grad<'a>>, _ctx: &mut TransformContext) -> Vec<Grad<'a>> {
+        grads
+            .into_iter()
+            .enumerate()
+            .map(|(i, g)| {
+                // Single-column gradients (biases) would be zeroed entirely.
+                if !self.group.contains(i) || g.ncols() < 2 {
+                    return g;
+                }
+                let mean = g.mean_axis(Axis(1)).unwrap().insert_axis(Axis(1));
+                CowArray::from(g.into_owned() - &mean)
+            })
+            .collect()
+    }
+}
+
+// Per-tensor normalization: rescales each gradient to Frobenius norm `target_norm`.
+// Scaling leaves singular vectors unchanged, so the projection subspace is unaffected. Only
+// the magnitude the base optimizer sees changes, which matters for SGD-style optimizers and
+// for later clipping, not for Adam apart from epsilon.
+pub struct GradNormalization {
+    target_norm: f32,
+    eps: f32,
+    group: ParamGroup,
+}
+
+impl GradNormalization {
+    pub fn new(target_norm: f32) -> Self {
+        GradNormalization { target_norm, eps: 1e-8, group: ParamGroup::All }
+    }
+
+    pub fn with_group(mut self, group: ParamGroup) -> Self {
+        self.group = group;
+        self
+    }
+}
+
+impl GradTransform for GradNormalization {
+    fn name(&self) -> &str {
+        "normalize"
+    }
+
+    fn apply<'a>(&mut self, grads: Vec<Grad<'a>>, _ctx: &mut TransformContext) -> Vec<Grad<'a>> {
+        grads
+            .into_iter()
+            .enumerate()
+            .map(|(i, g)| {
+                if !self.group.contains(i) {
+                    return g;
+                }
+                let norm = g.iter().map(|x| x * x).sum::<f32>().sqrt();
+                CowArray::from(g.into_owned() * (self.target_norm / (norm + self.eps)))
+            })
+            .collect()
+    }
+}
+
+const PRECONDITION_EPS: f32 = 1e-8;
+
+// How the preconditioner estimates the RMS of each gradient element. `Factored`, the
+// default, keeps EMAs of the row and column mean squares, O(m + n) memory as in Adafactor,
+// and estimates element (i, j) from row i and column j: exact when the badly-scaled
+// directions are whole rows or columns (the usual case for a weight matrix, e.g. a few hot
+// input features or output units), an approximation otherwise. `Diagonal` keeps an EMA of
+// every element's square, the full diagonal preconditioner, but that is m x n floats per
+// parameter: the same full-size optimizer state GaLore exists to avoid, so only use it
+// where that memory is affordable. `planner::preconditioner_bytes` gives both costs.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum RmsEstimate {
+    Diagonal,
+    #[default]
+    Factored,
+}
+
+enum RmsState {
+    Diagonal(Array2<f32>),
+    Factored { rows: Array1<f32>, cols: Array1<f32> },
+}
+
+impl RmsState {
+    // The gradient's own estimate, used for the first one without bias towards zero.
+    fn new(estimate: RmsEstimate, squared: Array2<f32>) -> Self {
+        match estimate {
+            RmsEstimate::Diagonal => RmsState::Diagonal(squared),
+            RmsEstimate::Factored => RmsState::Factored { rows: squared.mean_axis(Axis(1)).unwrap(), cols: squared.mean_axis(Axis(0)).unwrap() },
+        }
+    }
+
+    fn dim(&self) -> (usize, usize) {
+        match self {
+            RmsState::Diagonal(sq) => sq.dim(),
+            RmsState::Factored { rows, cols } => (rows.len(), cols.len()),
+        }
+    }
+
+    fn update(&mut self, new: RmsState, beta: f32) {
+        match (self, new) {
+            (RmsState::Diagonal(sq), RmsState::Diagonal(new)) => *sq = &*sq * beta + new * (1.0 - beta),
+            (RmsState::Factored { rows, cols }, RmsState::Factored { rows: new_rows, cols: new_cols }) => {
+                *rows = &*rows * beta + new_rows * (1.0 - beta);
+                *cols = &*cols * beta + new_cols * (1.0 - beta);
+            }
+            (state, new) => *state = new,
+        }
+    }
+
+    // The estimated RMS of each element, plus eps; for the factored estimate
+    // sqrt(row_i * col_j / mean(row)).
+    fn scale(&self, eps: f32) -> Array2<f32> {
+        let mut scale = match self {
+            RmsState::Diagonal(sq) => sq.mapv(f32::sqrt),
+            RmsState::Factored { rows, cols } => {
+                let mean = rows.mean().unwrap_or(0.0).max(f32::MIN_POSITIVE);
+                let rows = rows.mapv(|r| (r / mean).sqrt());
+                rows.insert_axis(Axis(1)).dot(&cols.mapv(f32::sqrt).insert_axis(Axis(0)))
+            }
+        };
+        scale += eps;
+        scale
+    }
+}
+
+// Diagonal whitening around the projection. "precondition" divides each gradient element by
+// the RMS of its recent values (see `RmsEstimate`) before "project", so the SVD picks
+// directions by relative rather than raw magnitude and a few large rows or columns of a
+// badly-scaled layer do not take over the subspace.
+//
+// "unprecondition" multiplies the update back by the same RMS scale after "project_back",
+// so the update is in the parameter's own coordinates again. For a base optimizer whose
+// update is proportional to its input (SGD and the like) the step has the size it would
+// have had on the raw gradient, and the whitening only shapes the subspace. A base
+// optimizer that normalizes by its own second moment, like Adam, takes +-lr sized steps in
+// whitened coordinates, which "unprecondition" scales by each element's RMS.
+// `GaLoreOptimizer::with_whitened_preconditioner` leaves it out, for callers who want those
+// steps to stay whitened.
+//
+// Both stages share the estimate, which "precondition" saves with the optimizer state and
+// in the refresh rollback snapshot.
+pub struct Precondition {
+    beta: f32,
+    estimate: RmsEstimate,
+    group: ParamGroup,
+    states: Arc<Mutex<Vec<Option<RmsState>>>>,
+}
+
+pub struct Unprecondition {
+    states: Arc<Mutex<Vec<Option<RmsState>>>>,
+}
+
+// The stage pair for `GaLoreOptimizer::with_preconditioner`: the first goes before
+// "project", the second after "project_back".
+pub fn precondition_stages(beta: f32, estimate: RmsEstimate, group: ParamGroup) -> (Precondition, Unprecondition) {
+    assert!((0.0..1.0).contains(&beta), "preconditioner decay must be in [0, 1)");
+    let states = Arc::new(Mutex::new(Vec::new()));
+    (Precondition { beta, estimate, group, states: Arc::clone(&states) }, Unprecondition { states })
+}
+
+impl GradTransform for Precondition {
+    fn name(&self) -> &str {
+        "precondition"
+    }
+
+    fn apply<'a>(&mut self, grads: Vec<Grad<'a>>, _ctx: &mut TransformContext) -> Vec<Grad<'a>> {
+        let mut states = self.states.lock().unwrap();
+        states.resize_with(grads.len(), || None);
+        grads
+            .into_iter()
+            .zip(states.iter_mut())
+            .enumerate()
+            .map(|(i, (g, state))| {
+                if !self.group.contains(i) {
+                    *state = None;
+                    return g;
+                }
+                let new = RmsState::new(self.estimate, g.mapv(|x| x * x));
+                let updated = match state.take() {
+                    Some(mut old) if old.dim() == g.dim() => {
+                        old.update(new, self.beta);
+                        old
+                    }
+                    _ => new,
+                };
+                let whitened = g.into_owned() / updated.scale(PRECONDITION_EPS);
+                *state = Some(updated);
+                CowArray::from(whitened)
+            })
+            .collect()
+    }
+
+    fn save_state(&self, checkpoint: &mut Checkpoint, prefix: &str, keys: &[String]) {
+        let states = self.states.lock().unwrap();
+        for (key, state) in keys.iter().zip(states.iter()) {
+            match state {
+                Some(RmsState::Diagonal(sq)) => checkpoint.insert_tensor(format!("{}.sq.{}", prefix, key), sq.clone()),
+                Some(RmsState::Factored { rows, cols }) => {
+                    checkpoint.insert_tensor(format!("{}.rows.{}", prefix, key), rows.clone());
+                    checkpoint.insert_tensor(format!("{}.cols.{}", prefix, key), cols.clone());
+                }
+                None => {}
+            }
+        }
+    }
+
+    // Parameters without a saved estimate (outside the group, not yet seen, or saved with
+    // the other `RmsEstimate`) start a new one on their next gradient.
+    fn load_state(&mut self, checkpoint: &Checkpoint, prefix: &str, keys: &[String]) -> Result<(), CheckpointError> {
+        let vector = |name: String| -> Result<Option<Array1<f32>>, CheckpointError> {
+            let Some(tensor) = checkpoint.tensor(&name) else {
+                return Ok(None);
+            };
+            let vector = tensor.clone().into_dimensionality::<Ix1>();
+            vector.map(Some).map_err(|_| CheckpointError::Format(format!("{} is not a vector", name)))
+        };
+        let mut loaded = Vec::with_capacity(keys.len());
+        for key in keys {
+            let state = match self.estimate {
+                RmsEstimate::Diagonal => {
+                    let name = format!("{}.sq.{}", prefix, key);
+                    checkpoint.tensor(&name).map(|_| checkpoint.matrix(&name)).transpose()?.map(RmsState::Diagonal)
+                }
+                RmsEstimate::Factored => {
+                    let rows = vector(format!("{}.rows.{}", prefix, key))?;
+                    let cols = vector(format!("{}.cols.{}", prefix, key))?;
+                    rows.zip(cols).map(|(rows, cols)| RmsState::Factored { rows, cols })
+                }
+            };
+            loaded.push(state);
+        }
+        *self.states.lock().unwrap() = loaded;
+        Ok(())
+    }
+}
+
+impl GradTransform for Unprecondition {
+    fn name(&self) -> &str {
+        "unprecondition"
+    }
+
+    fn apply<'a>(&mut self, grads: Vec<Grad<'a>>, _ctx: &mut TransformContext) -> Vec<Grad<'a>> {
+        let states = self.states.lock().unwrap();
+        grads
+            .into_iter()
+            .enumerate()
+            .map(|(i, g)| match states.get(i) {
+                Some(Some(state)) => CowArray::from(g.into_owned() * state.scale(PRECONDITION_EPS)),
+                _ => g,
+            })
+            .collect()
+    }
+}
+
+pub fn global_norm(grads: &[Grad]) -> f32 {
+    grads
+        .iter()
+        .map(|g| g.iter().map(|x| x * x).sum::<f32>())
+        .sum::<f32>()
+        .sqrt()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::galore::matrix_ops::GaLoreProjection;
+    use crate::galore::testing::{gradient, run_stage, run_stage_on, run_stage_with_params, weights, LR};
+
+    fn norm(a: &Array2<f32>) -> f32 {
+        a.iter().map(|x| x * x).sum::<f32>().sqrt()
+    }
+
+    #[test]
+    fn centralization_zeroes_row_means_in_its_group() {
+        let bias = Array2::from_elem((24, 1), 0.5);
+        let grads = [gradient(0), gradient(1), bias.clone()];
+        let mut stage = GradCentralization::new().with_group(ParamGroup::Only(vec![0, 2]));
+        let out = run_stage(&mut stage, &grads);
+
+        assert!(out[0].mean_axis(Axis(1)).unwrap().iter().all(|m| m.abs() < 1e-5));
+        // The removed part is constant along each row.
+        let removed = &grads[0] - &out[0];
+        assert!(removed.rows().into_iter().all(|row| row.iter().all(|&x| (x - row[0]).abs() < 1e-5)));
+        assert_eq!(out[1], grads[1]);
+        assert_eq!(out[2], bias);
+    }
+
+    #[test]
+    fn normalization_rescales_to_the_target_norm() {
+        let grads = [gradient(0), gradient(1) * 20.0];
+        let mut stage = GradNormalization::new(1.5).with_group(ParamGroup::Only(vec![1]));
+        let out = run_stage(&mut stage, &grads);
+
+        assert_eq!(out[0], grads[0]);
+        assert!((norm(&out[1]) - 1.5).abs() < 1e-4);
+        assert!((&out[1] * (norm(&grads[1]) / 1.5)).abs_diff_eq(&grads[1], 1e-3));
+    }
+
+    #[test]
+    fn clipping_bounds_the_global_norm() {
+        let grads = [gradient(0), gradient(1) * 0.5];
+        let total = |grads: &[Array2<f32>]| grads.iter().map(|g| norm(g).powi(2)).sum::<f32>().sqrt();
+        let before = total(&grads);
+        let mut galore = GaLoreProjection::new(4, 10, 0.0);
+        let clipped = run_stage_on(&mut galore, &mut ClipGradNorm::new(1.0), &grads);
+        assert!((total(&clipped) - 1.0).abs() < 1e-5);
+        for (c, g) in clipped.iter().zip(&grads) {
+            assert!(c.abs_diff_eq(&(g / before), 1e-6));
+        }
+        assert!((galore.metrics().latest("clip/norm").unwrap() - before).abs() < 1e-3);
+        assert_eq!(galore.metrics().latest("clip/applied"), Some(1.0));
+
+        // Under the threshold the gradients pass through untouched.
+        let mut loose = ClipGradNorm::new(2.0 * before).with_space(ClipSpace::Projected);
+        assert_eq!(run_stage_on(&mut galore, &mut loose, &grads), grads);
+        assert_eq!(galore.metrics().latest("clip_projected/applied"), Some(0.0));
+    }
+
+    #[test]
+    fn weight_decay_shrinks_by_lr_times_wd() {
+        let wd = 0.1;
+        let params = [weights()];
+        let zero = Array2::zeros((24, 40));
+        let mut galore = GaLoreProjection::new(4, 10, 0.0);
+        let out = run_stage_with_params(&mut galore, &mut WeightDecay::new(wd, WeightDecayMode::Full), &[zero], Some(&params));
+        assert!(out[0].abs_diff_eq(&(&params[0] * (-LR * wd)), 1e-6));
+
+        // Subspace decay is the projected weights, so back-projected it is the part of W
+        // inside the subspace. W is far from rank 4, so most of it is left undecayed.
+        galore.project_gradient(vec![gradient(0).view()]);
+        let zero = Array2::zeros((4, 4));
+        let out = run_stage_with_params(&mut galore, &mut WeightDecay::new(wd, WeightDecayMode::Subspace), &[zero], Some(&params));
+        let inside = galore.project_update(galore.project_current(&[params[0].view()]).iter().map(|p| p.view()).collect());
+        let decay = galore.project_update(vec![out[0].view()]);
+        assert!(decay[0].abs_diff_eq(&(&inside[0] * (-LR * wd)), 1e-6));
+        assert!(norm(&inside[0]) < 0.75 * norm(&params[0]));
+    }
+
+    #[test]
+    fn update_cap_bounds_only_its_group() {
+        let ratio = 1e-2;
+        let params = [weights(), weights()];
+        let updates = [gradient(0), gradient(1)];
+        let mut galore = GaLoreProjection::new(4, 10, 0.0);
+        let mut stage = UpdateNormCap::new(ratio).with_group(ParamGroup::Only(vec![0]));
+        let out = run_stage_with_params(&mut galore, &mut stage, &updates, Some(&params));
+
+        let bound = ratio * norm(&params[0]);
+        assert!(norm(&updates[0]) > bound);
+        assert!((norm(&out[0]) - bound).abs() < 1e-4 * bound);
+        assert!((&out[0] * (norm(&updates[0]) / bound)).abs_diff_eq(&updates[0], 1e-3));
+        assert_eq!(out[1], updates[1]);
+        assert_eq!(galore.metrics().latest("update_cap/capped"), Some(1.0));
+    }
+}
@@ -0,0 +1,512 @@
+use std::fmt;
+
+use super::matrix_ops::{LayerRank, RankConfig};
+use super::pipeline::RmsEstimate;
+use super::quantize::quantized_bytes;
+
+const BYTES_PER_ELEMENT: usize = std::mem::size_of::<f32>();
+
+#[derive(Clone, Debug)]
+pub struct LayerShape {
+    pub rows: usize,
+    pub cols: usize,
+    // Singular values of a representative gradient, largest first. When absent the
+    // planner assumes a power-law spectrum.
+    pub spectrum: Option<Vec<f32>>,
+}
+
+impl LayerShape {
+    pub fn new(rows: usize, cols: usize) -> Self {
+        LayerShape { rows, cols, spectrum: None }
+    }
+
+    pub fn with_spectrum(mut self, spectrum: Vec<f32>) -> Self {
+        self.spectrum = Some(spectrum);
+        self
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum PlanError {
+    BudgetTooSmall { required: usize, budget: usize },
+}
+
+impl fmt::Display for PlanError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PlanError::BudgetTooSmall { required, budget } => write!(
+                f,
+                "memory budget of {} bytes is below the {} bytes needed for rank-1 projections",
+                budget, required
+            ),
+        }
+    }
+}
+
+impl std::error::Error for PlanError {}
+
+// What the planner assumes an 8-bit layer loses, as a fraction of its retained energy.
+pub const DEFAULT_EIGHT_BIT_PENALTY: f32 = 0.01;
+
+// Bytes of projection matrices plus the two Adam moments for one layer. Projections stay in
+// f32; `eight_bit` moments take `quantize::quantized_bytes` each.
+pub fn state_bytes(rows: usize, cols: usize, layer: &LayerRank, eight_bit: bool) -> usize {
+    let (projection, moment) = match *layer {
+        LayerRank::Full => (0, rows * cols),
+        LayerRank::LowRank { rank, one_sided: false } => ((rows + cols) * rank, rank * rank),
+        LayerRank::LowRank { rank, one_sided: true } => (rows.min(cols) * rank, rank * rows.max(cols)),
+    };
+    let moment_bytes = if eight_bit { quantized_bytes(moment) } else { moment * BYTES_PER_ELEMENT };
+    projection * BYTES_PER_ELEMENT + 2 * moment_bytes
+}
+
+// Bytes of the running RMS estimate `GaLoreOptimizer::with_preconditioner` keeps for one
+// layer, whatever its rank.
+pub fn preconditioner_bytes(rows: usize, cols: usize, estimate: RmsEstimate) -> usize {
+    match estimate {
+        RmsEstimate::Diagonal => rows * cols * BYTES_PER_ELEMENT,
+        RmsEstimate::Factored => (rows + cols) * BYTES_PER_ELEMENT,
+    }
+}
+
+pub fn config_bytes(layers: &[LayerShape], config: &RankConfig) -> usize {
+    layers
+        .iter()
+        .zip(config.layers.iter())
+        .enumerate()
+        .map(|(i, (layer, rank))| state_bytes(layer.rows, layer.cols, rank, config.eight_bit(i)))
+        .sum()
+}
+
+// Cost of one layer for one training step. FLOPs count the matrix products (forward
+// X W^T + b, backward dX and dW); elementwise work such as activations is left out.
+// Activation memory is what backward needs: the layer input and output per sample.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct LayerEstimate {
+    pub name: String,
+    pub forward_flops: u64,
+    pub backward_flops: u64,
+    pub activation_bytes: usize,
+    pub param_bytes: usize,
+    pub optimizer_bytes: usize,
+}
+
+impl LayerEstimate {
+    // A `rows` x `cols` weight (cols inputs, rows outputs) with bias, its optimizer state
+    // sized by `rank` and `eight_bit`.
+    pub fn linear(name: impl Into<String>, rows: usize, cols: usize, batch_size: usize, rank: &LayerRank, eight_bit: bool) -> Self {
+        let (r, c, b) = (rows as u64, cols as u64, batch_size as u64);
+        LayerEstimate {
+            name: name.into(),
+            forward_flops: 2 * b * r * c + b * r,
+            backward_flops: 4 * b * r * c + b * r,
+            activation_bytes: batch_size * (rows + cols) * BYTES_PER_ELEMENT,
+            param_bytes: (rows * cols + rows) * BYTES_PER_ELEMENT,
+            optimizer_bytes: state_bytes(rows, cols, rank, eight_bit) + state_bytes(1, rows, &LayerRank::Full, eight_bit),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Estimate {
+    pub batch_size: usize,
+    pub layers: Vec<LayerEstimate>,
+}
+
+impl Estimate {
+    pub fn total(&self) -> LayerEstimate {
+        self.layers.iter().fold(LayerEstimate { name: "total".to_string(), ..Default::default() }, |mut total, layer| {
+            total.forward_flops += layer.forward_flops;
+            total.backward_flops += layer.backward_flops;
+            total.activation_bytes += layer.activation_bytes;
+            total.param_bytes += layer.param_bytes;
+            total.optimizer_bytes += layer.optimizer_bytes;
+            total
+        })
+    }
+
+    pub fn table(&self) -> String {
+        let header = ["layer", "fwd FLOPs", "bwd FLOPs", "activations", "params", "optimizer"];
+        let mut rows = vec![header.iter().map(|h| h.to_string()).collect::<Vec<_>>()];
+        for layer in self.layers.iter().chain(std::iter::once(&self.total())) {
+            rows.push(vec![
+                layer.name.clone(),
+                format_flops(layer.forward_flops),
+                format_flops(layer.backward_flops),
+                format_bytes(layer.activation_bytes),
+                format_bytes(layer.param_bytes),
+                format_bytes(layer.optimizer_bytes),
+            ]);
+        }
+
+        // Names left-aligned, numbers right-aligned.
+        let widths: Vec<usize> = (0..header.len()).map(|c| rows.iter().map(|r| r[c].len()).max().unwrap_or(0)).collect();
+        let mut out = format!("batch size {}\n", self.batch_size);
+        for row in &rows {
+            let cells: Vec<String> = row
+                .iter()
+                .zip(widths.iter())
+                .enumerate()
+                .map(|(c, (cell, &w))| if c == 0 { format!("{:<w$}", cell, w = w) } else { format!("{:>w$}", cell, w = w) })
+                .collect();
+            out.push_str(&cells.join("  "));
+            out.push('\n');
+        }
+        out
+    }
+}
+
+impl fmt::Display for Estimate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.table())
+    }
+}
+
+// Per-layer estimate for linear layers. `config` is indexed like `layers`; layers without an
+// entry are counted with full-rank Adam state.
+pub fn estimate(layers: &[LayerShape], batch_size: usize, config: &RankConfig) -> Estimate {
+    let layers = layers
+        .iter()
+        .enumerate()
+        .map(|(i, layer)| {
+            let rank = config.get(i).unwrap_or(&LayerRank::Full);
+            LayerEstimate::linear(format!("layer{}", i), layer.rows, layer.cols, batch_size, rank, config.eight_bit(i))
+        })
+        .collect();
+    Estimate { batch_size, layers }
+}
+
+fn format_flops(flops: u64) -> String {
+    scaled(flops as f64, 1000.0, &["", "K", "M", "G", "T", "P"])
+}
+
+fn format_bytes(bytes: usize) -> String {
+    scaled(bytes as f64, 1024.0, &["B", "KiB", "MiB", "GiB", "TiB"])
+}
+
+fn scaled(mut value: f64, base: f64, units: &[&str]) -> String {
+    let mut unit = 0;
+    while value >= base && unit + 1 < units.len() {
+        value /= base;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{}{}", value, units[0])
+    } else {
+        format!("{:.1}{}", value, units[unit])
+    }
+}
+
+// Chooses per-layer ranks that fit a byte budget for optimizer state (projections and
+// moments), maximizing the retained spectral energy summed over layers, each layer
+// weighted by its parameter count. A layer may also keep its moments in 8 bits, which is
+// scored as retaining `1 - eight_bit_penalty` of its energy: the planner takes it where
+// the memory it frees buys more rank elsewhere (or at all, when f32 moments do not fit).
+// With `with_preconditioner`, each layer's RMS estimate is charged to the budget as well.
+pub struct MemoryPlanner {
+    budget_bytes: usize,
+    spectrum_decay: f32,
+    eight_bit: bool,
+    eight_bit_penalty: f32,
+    preconditioner: Option<RmsEstimate>,
+}
+
+impl MemoryPlanner {
+    pub fn new(budget_bytes: usize) -> Self {
+        MemoryPlanner {
+            budget_bytes,
+            spectrum_decay: 1.0,
+            eight_bit: true,
+            eight_bit_penalty: DEFAULT_EIGHT_BIT_PENALTY,
+            preconditioner: None,
+        }
+    }
+
+    // Exponent of the assumed spectrum s_k ~ (k + 1)^-decay for layers without measurements.
+    pub fn with_spectrum_decay(mut self, decay: f32) -> Self {
+        self.spectrum_decay = decay;
+        self
+    }
+
+    // Whether plans may use 8-bit moments (on by default).
+    pub fn with_eight_bit_states(mut self, enabled: bool) -> Self {
+        self.eight_bit = enabled;
+        self
+    }
+
+    pub fn with_eight_bit_penalty(mut self, penalty: f32) -> Self {
+        assert!((0.0..1.0).contains(&penalty), "penalty must be in [0, 1)");
+        self.eight_bit_penalty = penalty;
+        self
+    }
+
+    // Plans for an optimizer built with `with_preconditioner(_, estimate, ParamGroup::All)`.
+    pub fn with_preconditioner(mut self, estimate: RmsEstimate) -> Self {
+        self.preconditioner = Some(estimate);
+        self
+    }
+
+    pub fn plan(&self, layers: &[LayerShape]) -> Result<RankConfig, PlanError> {
+        let energies: Vec<Vec<f32>> = layers.iter().map(|layer| self.cumulative_energy(layer)).collect();
+        let weights: Vec<f32> = layers.iter().map(|layer| (layer.rows * layer.cols) as f32).collect();
+        let preconditioner = |i: usize| self.preconditioner.map_or(0, |estimate| preconditioner_bytes(layers[i].rows, layers[i].cols, estimate));
+        let bytes_of = |i: usize, choice: &Choice| state_bytes(layers[i].rows, layers[i].cols, &choice.0, choice.1) + preconditioner(i);
+        let value_of = |i: usize, choice: &Choice| {
+            let fidelity = if choice.1 { 1.0 - self.eight_bit_penalty } else { 1.0 };
+            weights[i] * energy_of(&energies[i], &choice.0) * fidelity
+        };
+
+        // Start from rank 1 everywhere, with 8-bit moments if f32 ones do not fit.
+        let start = |eight_bit: bool| -> Vec<Choice> {
+            layers.iter().map(|_| (LayerRank::LowRank { rank: 1, one_sided: false }, eight_bit)).collect()
+        };
+        let total = |plan: &[Choice]| -> usize { plan.iter().enumerate().map(|(i, choice)| bytes_of(i, choice)).sum() };
+        let mut plan = start(false);
+        let mut used = total(&plan);
+        if used > self.budget_bytes && self.eight_bit {
+            plan = start(true);
+            used = total(&plan);
+        }
+        if used > self.budget_bytes {
+            return Err(PlanError::BudgetTooSmall { required: used, budget: self.budget_bytes });
+        }
+
+        // Greedy: repeatedly take the single-layer move with the best value gain per byte. A
+        // move that frees memory while gaining value always wins; every move gains value, so
+        // the search ends.
+        loop {
+            let mut best: Option<(usize, Choice, usize, f32)> = None;
+            for (i, layer) in layers.iter().enumerate() {
+                let current = &plan[i];
+                let current_bytes = bytes_of(i, current);
+                let current_value = value_of(i, current);
+
+                for candidate in grow_candidates(layer, current, self.eight_bit) {
+                    let bytes = bytes_of(i, &candidate);
+                    let gain = value_of(i, &candidate) - current_value;
+                    if gain <= 0.0 || used - current_bytes + bytes > self.budget_bytes {
+                        continue;
+                    }
+                    let extra = bytes.saturating_sub(current_bytes).max(1);
+                    let score = gain / extra as f32;
+                    if best.as_ref().is_none_or(|b| score > b.3) {
+                        best = Some((i, candidate, bytes, score));
+                    }
+                }
+            }
+
+            match best {
+                Some((i, candidate, bytes, _)) => {
+                    used = used - bytes_of(i, &plan[i]) + bytes;
+                    plan[i] = candidate;
+                }
+                None => break,
+            }
+        }
+
+        // Two-sided projection retains the same energy for less memory, so one-sided (the
+        // reference semantics, with moments spanning the long dimension) is only adopted
+        // with leftover budget, largest layers first. Layers already at full rank drop the
+        // projection entirely when that fits.
+        let mut by_size: Vec<usize> = (0..layers.len()).collect();
+        by_size.sort_by(|&a, &b| weights[b].partial_cmp(&weights[a]).unwrap());
+        for i in by_size {
+            if let (LayerRank::LowRank { rank, one_sided: false }, eight_bit) = plan[i] {
+                let candidate = if rank >= layers[i].rows.min(layers[i].cols) {
+                    LayerRank::Full
+                } else {
+                    LayerRank::LowRank { rank, one_sided: true }
+                };
+                let current_bytes = bytes_of(i, &plan[i]);
+                let bytes = bytes_of(i, &(candidate.clone(), eight_bit));
+                if used - current_bytes + bytes <= self.budget_bytes {
+                    used = used - current_bytes + bytes;
+                    plan[i] = (candidate, eight_bit);
+                }
+            }
+        }
+
+        let (ranks, eight_bit): (Vec<LayerRank>, Vec<bool>) = plan.into_iter().unzip();
+        Ok(RankConfig::new(ranks).with_eight_bit(eight_bit))
+    }
+
+    // energy[k] is the fraction of squared spectral mass in the top k + 1 directions.
+    fn cumulative_energy(&self, layer: &LayerShape) -> Vec<f32> {
+        let len = layer.rows.min(layer.cols);
+        let spectrum: Vec<f32> = match &layer.spectrum {
+            Some(measured) => measured.iter().copied().take(len).collect(),
+            None => (0..len).map(|k| ((k + 1) as f32).powf(-self.spectrum_decay)).collect(),
+        };
+        let total: f32 = spectrum.iter().map(|s| s * s).sum();
+        let mut running = 0.0;
+        spectrum
+            .iter()
+            .map(|s| {
+                running += s * s;
+                if total > 0.0 { running / total } else { 0.0 }
+            })
+            .collect()
+    }
+}
+
+fn energy_of(cumulative: &[f32], layer: &LayerRank) -> f32 {
+    match *layer {
+        LayerRank::Full => 1.0,
+        LayerRank::LowRank { rank: 0, .. } => 0.0,
+        LayerRank::LowRank { rank, .. } => cumulative
+            .get(rank - 1)
+            .or_else(|| cumulative.last())
+            .copied()
+            .unwrap_or(0.0),
+    }
+}
+
+// A layer's rank and whether its moments are 8-bit.
+type Choice = (LayerRank, bool);
+
+fn grow_candidates(layer: &LayerShape, (rank, eight_bit): &Choice, allow_eight_bit: bool) -> Vec<Choice> {
+    let formats: &[bool] = if allow_eight_bit { &[false, true] } else { &[false] };
+    let mut candidates = Vec::new();
+    for &format in formats {
+        match *rank {
+            // Full rank can only change its moment format.
+            LayerRank::Full => {
+                if format != *eight_bit {
+                    candidates.push((LayerRank::Full, format));
+                }
+            }
+            LayerRank::LowRank { rank, one_sided } => {
+                candidates.push((LayerRank::Full, format));
+                if rank < layer.rows.min(layer.cols) {
+                    candidates.push((LayerRank::LowRank { rank: rank + 1, one_sided }, format));
+                }
+                if format != *eight_bit {
+                    candidates.push((LayerRank::LowRank { rank, one_sided }, format));
+                }
+            }
+        }
+    }
+    candidates
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn shapes() -> Vec<LayerShape> {
+        vec![LayerShape::new(64, 32), LayerShape::new(32, 32), LayerShape::new(128, 16).with_spectrum(vec![4.0, 2.0, 1.0, 0.5])]
+    }
+
+    #[test]
+    fn plans_fit_the_budget() {
+        let layers = shapes();
+        for budget in [2_000, 5_000, 20_000, 60_000, 200_000, 1 << 20] {
+            for eight_bit in [false, true] {
+                let Ok(config) = MemoryPlanner::new(budget).with_eight_bit_states(eight_bit).plan(&layers) else {
+                    continue;
+                };
+                assert_eq!(config.layers.len(), layers.len());
+                assert!(config_bytes(&layers, &config) <= budget, "{:?} exceeds {} bytes", config, budget);
+                if !eight_bit {
+                    assert!(config.eight_bit.iter().all(|&e| !e));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn rejects_budgets_below_rank_one() {
+        let layers = shapes();
+        let minimum = MemoryPlanner::new(usize::MAX).with_eight_bit_states(false);
+        let rank_one = RankConfig::new(vec![LayerRank::LowRank { rank: 1, one_sided: false }; layers.len()]);
+        let required = config_bytes(&layers, &rank_one);
+        assert!(minimum.plan(&layers).is_ok());
+        assert_eq!(
+            MemoryPlanner::new(required - 1).with_eight_bit_states(false).plan(&layers),
+            Err(PlanError::BudgetTooSmall { required, budget: required - 1 })
+        );
+    }
+
+    #[test]
+    fn ample_budget_keeps_full_rank_f32_moments() {
+        let layers = vec![LayerShape::new(64, 32), LayerShape::new(32, 32)];
+        let config = MemoryPlanner::new(1 << 30).plan(&layers).unwrap();
+        assert!(config.layers.iter().all(|layer| *layer == LayerRank::Full));
+        assert!(config.eight_bit.iter().all(|&e| !e));
+    }
+
+    #[test]
+    fn takes_eight_bit_moments_when_they_retain_more() {
+        // f32 moments for a full-rank 32x32 layer need 8 KiB; 8-bit ones fit in this budget,
+        // which only allows rank 6 with f32 moments.
+        let layers = vec![LayerShape::new(32, 32)];
+        let budget = state_bytes(32, 32, &LayerRank::Full, true);
+        let config = MemoryPlanner::new(budget).plan(&layers).unwrap();
+        assert_eq!(config.layers, vec![LayerRank::Full]);
+        assert_eq!(config.eight_bit, vec![true]);
+
+        let config = MemoryPlanner::new(budget).with_eight_bit_states(false).plan(&layers).unwrap();
+        assert!(matches!(config.layers[0], LayerRank::LowRank { rank: 6, .. }), "{:?}", config);
+        assert_eq!(config.eight_bit, vec![false]);
+    }
+
+    #[test]
+    fn eight_bit_moments_cost_a_quarter_plus_scales() {
+        assert_eq!(state_bytes(64, 64, &LayerRank::Full, false), 2 * 64 * 64 * 4);
+        assert_eq!(state_bytes(64, 64, &LayerRank::Full, true), 2 * (64 * 64 + 16 * 4));
+        let low = LayerRank::LowRank { rank: 4, one_sided: false };
+        assert_eq!(state_bytes(64, 32, &low, true), (64 + 32) * 4 * 4 + 2 * (16 + 4));
+    }
+
+    #[test]
+    fn preconditioner_state_is_charged_to_the_budget() {
+        let layers = shapes();
+        let overhead = |estimate| layers.iter().map(|l| preconditioner_bytes(l.rows, l.cols, estimate)).sum::<usize>();
+        assert_eq!(overhead(RmsEstimate::Factored), (64 + 32 + 32 + 32 + 128 + 16) * 4);
+        assert_eq!(overhead(RmsEstimate::Diagonal), (64 * 32 + 32 * 32 + 128 * 16) * 4);
+
+        let budget = 60_000;
+        let plain = MemoryPlanner::new(budget).plan(&layers).unwrap();
+        for estimate in [RmsEstimate::Factored, RmsEstimate::Diagonal] {
+            let config = MemoryPlanner::new(budget).with_preconditioner(estimate).plan(&layers).unwrap();
+            assert!(config_bytes(&layers, &config) + overhead(estimate) <= budget, "{:?}", estimate);
+        }
+        let diagonal = MemoryPlanner::new(budget).with_preconditioner(RmsEstimate::Diagonal).plan(&layers).unwrap();
+        assert!(config_bytes(&layers, &diagonal) < config_bytes(&layers, &plain));
+
+        // A budget that only fits rank 1 without the estimate fails with it.
+        let rank_one = RankConfig::new(vec![LayerRank::LowRank { rank: 1, one_sided: false }; layers.len()]);
+        let minimum = config_bytes(&layers, &rank_one);
+        let planner = MemoryPlanner::new(minimum).with_eight_bit_states(false);
+        assert!(planner.plan(&layers).is_ok());
+        assert_eq!(
+            planner.with_preconditioner(RmsEstimate::Factored).plan(&layers),
+            Err(PlanError::BudgetTooSmall { required: minimum + overhead(RmsEstimate::Factored), budget: minimum })
+        );
+    }
+
+    #[test]
+    fn linear_estimates_count_the_products_and_state() {
+        let full = LayerEstimate::linear("layer0", 64, 32, 8, &LayerRank::Full, false);
+        assert_eq!(full.forward_flops, 2 * 8 * 64 * 32 + 8 * 64);
+        assert_eq!(full.backward_flops, 4 * 8 * 64 * 32 + 8 * 64);
+        assert_eq!(full.activation_bytes, 8 * (64 + 32) * 4);
+        assert_eq!(full.param_bytes, (64 * 32 + 64) * 4);
+        assert_eq!(full.optimizer_bytes, 2 * (64 * 32 + 64) * 4);
+
+        // Low rank only changes the weight's optimizer state.
+        let low = LayerEstimate::linear("layer0", 64, 32, 8, &LayerRank::LowRank { rank: 4, one_sided: false }, false);
+        assert_eq!((low.forward_flops, low.param_bytes), (full.forward_flops, full.param_bytes));
+        assert_eq!(low.optimizer_bytes, state_bytes(64, 32, &LayerRank::LowRank { rank: 4, one_sided: false }, false) + 2 * 64 * 4);
+
+        let layers = [LayerShape::new(64, 32), LayerShape::new(32, 32)];
+        let estimate = estimate(&layers, 8, &RankConfig::new(vec![LayerRank::Full]));
+        let total = estimate.total();
+        assert_eq!(total.forward_flops, estimate.layers.iter().map(|l| l.forward_flops).sum::<u64>());
+        assert_eq!(total.optimizer_bytes, full.optimizer_bytes + estimate.layers[1].optimizer_bytes);
+        let table = estimate.table();
+        assert_eq!(table.lines().count(), 1 + 1 + layers.len() + 1);
+        assert!(table.lines().last().unwrap().starts_with("total"));
+    }
+}
+
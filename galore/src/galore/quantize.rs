@@ -0,0 +1,116 @@
+use ndarray::{Array2, ArrayView2};
+
+// Elements sharing one absmax scale.
+pub const QUANT_BLOCK: usize = 256;
+
+// 8-bit block-wise storage for optimizer state. Each block of `QUANT_BLOCK` elements (in
+// row-major order) keeps its absmax as an f32 and every element as a signed byte. Codes are
+// square-root companded, x = absmax * sign(c) * (c / 127)^2, so values well below the block
+// maximum keep more relative precision than a linear code would: the smallest nonzero
+// magnitude is absmax / 16129.
+#[derive(Clone, Debug, PartialEq)]
+pub struct BlockQuantized {
+    dim: (usize, usize),
+    codes: Vec<i8>,
+    scales: Vec<f32>,
+}
+
+impl BlockQuantized {
+    pub fn quantize(matrix: &ArrayView2<f32>) -> Self {
+        let values: Vec<f32> = matrix.iter().copied().collect();
+        let mut codes = Vec::with_capacity(values.len());
+        let scales = values
+            .chunks(QUANT_BLOCK)
+            .map(|block| {
+                let absmax = block.iter().fold(0.0f32, |max, x| max.max(x.abs()));
+                codes.extend(block.iter().map(|&x| encode(x, absmax)));
+                absmax
+            })
+            .collect();
+        BlockQuantized { dim: matrix.dim(), codes, scales }
+    }
+
+    pub fn dequantize(&self) -> Array2<f32> {
+        let values = self
+            .codes
+            .chunks(QUANT_BLOCK)
+            .zip(&self.scales)
+            .flat_map(|(block, &absmax)| block.iter().map(move |&c| decode(c) * absmax))
+            .collect();
+        Array2::from_shape_vec(self.dim, values).expect("codes match the shape")
+    }
+
+    pub fn dim(&self) -> (usize, usize) {
+        self.dim
+    }
+
+    pub fn bytes(&self) -> usize {
+        quantized_bytes(self.codes.len())
+    }
+}
+
+// Storage for `elements` values: one byte each plus an f32 scale per block.
+pub fn quantized_bytes(elements: usize) -> usize {
+    elements + elements.div_ceil(QUANT_BLOCK) * std::mem::size_of::<f32>()
+}
+
+fn encode(x: f32, absmax: f32) -> i8 {
+    if absmax == 0.0 || !x.is_finite() {
+        return 0;
+    }
+    let companded = (x.abs() / absmax).sqrt() * 127.0;
+    (companded.round().min(127.0) as i8) * if x < 0.0 { -1 } else { 1 }
+}
+
+fn decode(code: i8) -> f32 {
+    let q = code as f32 / 127.0;
+    q * q.abs()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ndarray::Array;
+
+    #[test]
+    fn round_trip_is_close_relative_to_block_max() {
+        let matrix = Array::from_shape_fn((7, 100), |(i, j)| ((i * 100 + j) as f32 * 0.37).sin() * (1.0 + i as f32));
+        let restored = BlockQuantized::quantize(&matrix.view()).dequantize();
+        assert_eq!(restored.dim(), matrix.dim());
+        let flat: Vec<f32> = matrix.iter().copied().collect();
+        for (block, restored) in flat.chunks(QUANT_BLOCK).zip(restored.iter().copied().collect::<Vec<_>>().chunks(QUANT_BLOCK)) {
+            let absmax = block.iter().fold(0.0f32, |max, x| max.max(x.abs()));
+            for (&x, &y) in block.iter().zip(restored) {
+                // The code step is largest at the top of the range: 2 / 127 of absmax.
+                assert!((x - y).abs() <= absmax / 127.0 + 1e-6, "{} restored as {}", x, y);
+                assert!(x * y >= 0.0, "{} changed sign", x);
+            }
+        }
+    }
+
+    #[test]
+    fn keeps_block_maximum_and_zeros_exactly() {
+        let mut matrix = Array2::zeros((2, QUANT_BLOCK));
+        matrix[[0, 3]] = -5.0;
+        matrix[[1, 7]] = 0.25;
+        let restored = BlockQuantized::quantize(&matrix.view()).dequantize();
+        assert_eq!(restored, matrix);
+    }
+
+    #[test]
+    fn small_values_keep_relative_precision() {
+        let mut matrix = Array2::from_elem((1, 64), 1e-3f32);
+        matrix[[0, 0]] = 1.0;
+        let restored = BlockQuantized::quantize(&matrix.view()).dequantize();
+        // Linear 8-bit codes would round 1e-3 of the maximum to zero.
+        assert!((restored[[0, 1]] - 1e-3).abs() < 0.2e-3, "{}", restored[[0, 1]]);
+    }
+
+    #[test]
+    fn counts_one_byte_per_element_plus_scales() {
+        let matrix = Array2::<f32>::ones((3, QUANT_BLOCK + 1));
+        let quantized = BlockQuantized::quantize(&matrix.view());
+        assert_eq!(quantized.bytes(), 3 * (QUANT_BLOCK + 1) + 4 * 4);
+        assert_eq!(quantized_bytes(0), 0);
+    }
+}
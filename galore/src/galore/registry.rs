@@ -0,0 +1,286 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+
+use super::checkpoint::{Checkpoint, CheckpointError};
+use super::pipeline::{ParamGroup, PipelineError};
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum RegistryError {
+    Duplicate(String),
+    Unknown(String),
+    Missing(String),
+    Shape { name: String, expected: (usize, usize), found: (usize, usize) },
+    // A by-name call on an optimizer built without a registry.
+    NoRegistry,
+    Pipeline(PipelineError),
+}
+
+impl fmt::Display for RegistryError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RegistryError::Duplicate(name) => write!(f, "tensor {} registered twice", name),
+            RegistryError::Unknown(name) => write!(f, "no registered tensor named {}", name),
+            RegistryError::Missing(name) => write!(f, "no value given for tensor {}", name),
+            RegistryError::Shape { name, expected, found } => write!(
+                f,
+                "tensor {} has shape {}x{}, registered as {}x{}",
+                name, found.0, found.1, expected.0, expected.1
+            ),
+            RegistryError::NoRegistry => write!(f, "tensors are addressed by name but no registry is set"),
+            RegistryError::Pipeline(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for RegistryError {}
+
+impl From<PipelineError> for RegistryError {
+    fn from(e: PipelineError) -> Self {
+        RegistryError::Pipeline(e)
+    }
+}
+
+// Stable names for the trainable tensors, in the order every positional list (gradients,
+// updates, optimizer state) uses. Tooling refers to tensors by name and converts through
+// the registry, so checkpoints, metrics and filters keep pointing at the same tensor when
+// the model gains, loses or reorders parameters.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct TensorRegistry {
+    names: Vec<String>,
+    shapes: Vec<(usize, usize)>,
+    index: HashMap<String, usize>,
+}
+
+impl TensorRegistry {
+    pub fn new() -> Self {
+        TensorRegistry::default()
+    }
+
+    pub fn from_shapes<S: Into<String>>(entries: impl IntoIterator<Item = (S, (usize, usize))>) -> Result<Self, RegistryError> {
+        let mut registry = TensorRegistry::new();
+        for (name, shape) in entries {
+            registry.register(name, shape)?;
+        }
+        Ok(registry)
+    }
+
+    // Appends a tensor and returns its position.
+    pub fn register(&mut self, name: impl Into<String>, shape: (usize, usize)) -> Result<usize, RegistryError> {
+        let name = name.into();
+        if self.index.contains_key(&name) {
+            return Err(RegistryError::Duplicate(name));
+        }
+        self.index.insert(name.clone(), self.names.len());
+        self.names.push(name);
+        self.shapes.push(shape);
+        Ok(self.names.len() - 1)
+    }
+
+    pub fn len(&self) -> usize {
+        self.names.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.names.is_empty()
+    }
+
+    pub fn index_of(&self, name: &str) -> Option<usize> {
+        self.index.get(name).copied()
+    }
+
+    pub fn name(&self, index: usize) -> &str {
+        &self.names[index]
+    }
+
+    pub fn names(&self) -> &[String] {
+        &self.names
+    }
+
+    pub fn shape(&self, index: usize) -> (usize, usize) {
+        self.shapes[index]
+    }
+
+    pub fn shapes(&self) -> &[(usize, usize)] {
+        &self.shapes
+    }
+
+    // The tensors matching any of `patterns`, where `*` matches any run of characters, e.g.
+    // `["layer*.weight"]` to project only weight matrices.
+    pub fn select(&self, patterns: &[&str]) -> ParamGroup {
+        let indices = (0..self.len())
+            .filter(|&i| patterns.iter().any(|pattern| glob_match(pattern, &self.names[i])))
+            .collect();
+        ParamGroup::Only(indices)
+    }
+
+    // Puts named values in registry order. Every registered tensor needs exactly one value.
+    pub fn order<S: AsRef<str>, T>(&self, named: impl IntoIterator<Item = (S, T)>) -> Result<Vec<T>, RegistryError> {
+        let mut slots: Vec<Option<T>> = (0..self.len()).map(|_| None).collect();
+        for (name, value) in named {
+            let name = name.as_ref();
+            let index = self.index_of(name).ok_or_else(|| RegistryError::Unknown(name.to_string()))?;
+            if slots[index].replace(value).is_some() {
+                return Err(RegistryError::Duplicate(name.to_string()));
+            }
+        }
+        slots
+            .into_iter()
+            .enumerate()
+            .map(|(i, slot)| slot.ok_or_else(|| RegistryError::Missing(self.names[i].clone())))
+            .collect()
+    }
+
+    pub fn check_shape(&self, index: usize, found: (usize, usize)) -> Result<(), RegistryError> {
+        let expected = self.shapes[index];
+        if expected != found {
+            return Err(RegistryError::Shape { name: self.names[index].clone(), expected, found });
+        }
+        Ok(())
+    }
+
+    // Pairs positional values with their names.
+    pub fn named<T>(&self, values: Vec<T>) -> Vec<(String, T)> {
+        assert_eq!(values.len(), self.len(), "one value per registered tensor");
+        self.names.iter().cloned().zip(values).collect()
+    }
+
+    // For each tensor of `self`, its position in `old`, or None if it is new.
+    pub fn remap_from(&self, old: &TensorRegistry) -> Vec<Option<usize>> {
+        self.names.iter().map(|name| old.index_of(name)).collect()
+    }
+
+    // Hash of the names and shapes in order; equal on every replica of a run that agrees
+    // on the model structure.
+    pub fn fingerprint(&self) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        self.names.hash(&mut hasher);
+        self.shapes.hash(&mut hasher);
+        hasher.finish()
+    }
+
+    pub fn save(&self, checkpoint: &mut Checkpoint, prefix: &str) {
+        checkpoint.set_metadata(format!("{}.count", prefix), self.len());
+        for (i, (name, (rows, cols))) in self.names.iter().zip(self.shapes.iter()).enumerate() {
+            checkpoint.set_metadata(format!("{}.{}.name", prefix, i), name);
+            checkpoint.set_metadata(format!("{}.{}.shape", prefix, i), format!("{}x{}", rows, cols));
+        }
+    }
+
+    pub fn load(checkpoint: &Checkpoint, prefix: &str) -> Result<Self, CheckpointError> {
+        let count: usize = checkpoint.parse_metadata(&format!("{}.count", prefix))?;
+        let mut registry = TensorRegistry::new();
+        for i in 0..count {
+            let key = format!("{}.{}.name", prefix, i);
+            let name = checkpoint.metadata(&key).ok_or(CheckpointError::Missing(key))?;
+            let key = format!("{}.{}.shape", prefix, i);
+            let shape = checkpoint.metadata(&key).ok_or_else(|| CheckpointError::Missing(key.clone()))?;
+            let shape = shape
+                .split_once('x')
+                .and_then(|(rows, cols)| Some((rows.parse().ok()?, cols.parse().ok()?)))
+                .ok_or_else(|| CheckpointError::Format(format!("bad shape {} for {}", shape, key)))?;
+            registry.register(name, shape).map_err(|e| CheckpointError::Format(e.to_string()))?;
+        }
+        Ok(registry)
+    }
+}
+
+// Checkpoint keys for `count` positional values: the registered names if there is a
+// registry, positions otherwise (the layout of checkpoints written without one).
+pub fn param_keys(registry: Option<&TensorRegistry>, count: usize) -> Vec<String> {
+    match registry {
+        Some(registry) => registry.names().to_vec(),
+        None => (0..count).map(|i| i.to_string()).collect(),
+    }
+}
+
+fn glob_match(pattern: &str, name: &str) -> bool {
+    let mut parts = pattern.split('*');
+    let first = parts.next().unwrap_or("");
+    let Some(mut rest) = name.strip_prefix(first) else {
+        return false;
+    };
+    let parts: Vec<&str> = parts.collect();
+    let Some((last, middle)) = parts.split_last() else {
+        return rest.is_empty();
+    };
+    for part in middle {
+        match rest.find(part) {
+            Some(at) => rest = &rest[at + part.len()..],
+            None => return false,
+        }
+    }
+    rest.len() >= last.len() && rest.ends_with(last)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn registry() -> TensorRegistry {
+        TensorRegistry::from_shapes([("layer0.weight", (4, 3)), ("layer0.bias", (1, 4)), ("layer1.weight", (2, 4))]).unwrap()
+    }
+
+    #[test]
+    fn names_map_to_positions_and_back() {
+        let registry = registry();
+        assert_eq!(registry.index_of("layer1.weight"), Some(2));
+        assert_eq!(registry.index_of("layer2.weight"), None);
+        assert_eq!((registry.name(1), registry.shape(1)), ("layer0.bias", (1, 4)));
+        assert_eq!(registry.named(vec![1, 2, 3])[2], ("layer1.weight".to_string(), 3));
+
+        let mut twice = registry.clone();
+        assert_eq!(twice.register("layer0.bias", (1, 4)), Err(RegistryError::Duplicate("layer0.bias".to_string())));
+        assert_eq!(twice, registry);
+    }
+
+    #[test]
+    fn order_needs_exactly_one_value_per_tensor() {
+        let registry = registry();
+        let ordered = registry.order([("layer1.weight", 'c'), ("layer0.weight", 'a'), ("layer0.bias", 'b')]);
+        assert_eq!(ordered, Ok(vec!['a', 'b', 'c']));
+        assert_eq!(
+            registry.order([("layer0.weight", 'a'), ("layer0.bias", 'b')]),
+            Err(RegistryError::Missing("layer1.weight".to_string()))
+        );
+        assert_eq!(
+            registry.order([("layer0.weight", 'a'), ("layer0.weight", 'b')]),
+            Err(RegistryError::Duplicate("layer0.weight".to_string()))
+        );
+        assert_eq!(registry.order([("head", 'a')]), Err(RegistryError::Unknown("head".to_string())));
+        assert_eq!(
+            registry.check_shape(0, (3, 4)),
+            Err(RegistryError::Shape { name: "layer0.weight".to_string(), expected: (4, 3), found: (3, 4) })
+        );
+    }
+
+    #[test]
+    fn select_matches_globs() {
+        let registry = registry();
+        assert_eq!(registry.select(&["layer*.weight"]), ParamGroup::Only(vec![0, 2]));
+        assert_eq!(registry.select(&["*.bias", "layer1*"]), ParamGroup::Only(vec![1, 2]));
+        assert_eq!(registry.select(&["layer0"]), ParamGroup::Only(vec![]));
+        assert!(glob_match("*", ""));
+        assert!(glob_match("a*b*c", "abbc"));
+        assert!(!glob_match("a*bc", "abc_"));
+    }
+
+    #[test]
+    fn remapping_follows_names_across_reorders() {
+        let old = registry();
+        let new = TensorRegistry::from_shapes([("layer1.weight", (2, 4)), ("head", (3, 2)), ("layer0.weight", (4, 3))]).unwrap();
+        assert_eq!(new.remap_from(&old), [Some(2), None, Some(0)]);
+        assert_ne!(new.fingerprint(), old.fingerprint());
+        assert_eq!(registry().fingerprint(), old.fingerprint());
+    }
+
+    #[test]
+    fn registries_round_trip_through_checkpoints() {
+        let mut checkpoint = Checkpoint::new();
+        registry().save(&mut checkpoint, "registry");
+        assert_eq!(TensorRegistry::load(&checkpoint, "registry").unwrap(), registry());
+        assert_eq!(param_keys(None, 2), ["0", "1"]);
+        assert_eq!(param_keys(Some(&registry()), 3), registry().names());
+    }
+}
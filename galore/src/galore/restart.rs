@@ -0,0 +1,97 @@
+use super::checkpoint::{Checkpoint, CheckpointError};
+
+// Every `every`-th projection refresh is replaced by a one-step restart: a full-rank step
+// (or a step at a boosted rank) that lets the optimizer see directions the low-rank subspace
+// has been missing. The next step refreshes at the normal rank. `Adam` gives the restart
+// step fresh moments and picks the projected ones up again after it.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RestartPolicy {
+    pub every: usize,
+    pub boost: RestartBoost,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RestartBoost {
+    FullRank,
+    Rank(usize),
+}
+
+// Counts scheduled refreshes against the policy and remembers whether the last one was
+// turned into a restart, which the following step has to undo.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct RestartSchedule {
+    policy: Option<RestartPolicy>,
+    refreshes: usize,
+    active: bool,
+}
+
+impl RestartSchedule {
+    pub(crate) fn set_policy(&mut self, policy: RestartPolicy) {
+        assert!(policy.every > 0, "restart interval must be positive");
+        self.policy = Some(policy);
+    }
+
+    // Whether the previous step was a restart.
+    pub(crate) fn active(&self) -> bool {
+        self.active
+    }
+
+    // Counts a scheduled refresh and returns the boost if the policy turns it into a restart.
+    pub(crate) fn next_refresh(&mut self) -> Option<RestartBoost> {
+        self.refreshes += 1;
+        let boost = self
+            .policy
+            .as_ref()
+            .filter(|policy| self.refreshes.is_multiple_of(policy.every))
+            .map(|policy| policy.boost);
+        self.active = boost.is_some();
+        boost
+    }
+
+    // Whether the next counted refresh will be turned into a restart.
+    pub(crate) fn next_is_restart(&self) -> bool {
+        self.policy.as_ref().is_some_and(|policy| (self.refreshes + 1).is_multiple_of(policy.every))
+    }
+
+    // Ends a restart step without counting a refresh.
+    pub(crate) fn finish(&mut self) {
+        self.active = false;
+    }
+
+    pub(crate) fn save_state(&self, checkpoint: &mut Checkpoint, prefix: &str) {
+        checkpoint.set_metadata(format!("{}.refreshes", prefix), self.refreshes);
+        checkpoint.set_metadata(format!("{}.restart_active", prefix), self.active);
+    }
+
+    pub(crate) fn load_state(&mut self, checkpoint: &Checkpoint, prefix: &str) -> Result<(), CheckpointError> {
+        self.refreshes = checkpoint.parse_metadata(&format!("{}.refreshes", prefix))?;
+        self.active = checkpoint.parse_metadata(&format!("{}.restart_active", prefix))?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn every_nth_refresh_is_a_restart_and_survives_checkpoints() {
+        let mut schedule = RestartSchedule::default();
+        schedule.set_policy(RestartPolicy { every: 3, boost: RestartBoost::Rank(8) });
+        let boosts: Vec<_> = (0..6).map(|_| schedule.next_refresh()).collect();
+        let restart = Some(RestartBoost::Rank(8));
+        assert_eq!(boosts, [None, None, restart, None, None, restart]);
+        assert!(schedule.active());
+
+        let mut checkpoint = Checkpoint::new();
+        schedule.save_state(&mut checkpoint, "galore");
+        schedule.finish();
+        assert!(!schedule.active());
+
+        let mut restored = RestartSchedule::default();
+        restored.set_policy(RestartPolicy { every: 3, boost: RestartBoost::Rank(8) });
+        restored.load_state(&checkpoint, "galore").unwrap();
+        assert!(restored.active());
+        assert_eq!((0..3).map(|_| restored.next_refresh()).collect::<Vec<_>>(), [None, None, restart]);
+    }
+}
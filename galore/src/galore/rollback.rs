@@ -0,0 +1,222 @@
+use ndarray::{Array2, ArrayBase, Data, DataMut, Ix2};
+use std::collections::VecDeque;
+use std::fmt;
+
+use super::checkpoint::{Checkpoint, CheckpointError};
+use super::matrix_ops::Projection;
+
+// Two-phase projection refreshes: before a step that refreshes a projection the weights, the
+// previous projections and the base optimizer state are kept, and for the `window` steps
+// after it each loss passed to `GaLoreOptimizer::observe_loss` is compared with the mean loss
+// of the `window` steps before the refresh. A loss above that mean by more than
+// `threshold - 1` times its magnitude (above `threshold` times it, for positive losses), or a
+// NaN, restores the kept state, weights included, so the updates taken in the bad subspace
+// are undone; otherwise the refresh is committed and the snapshot dropped. Through
+// `apply_updates` or `accumulate_updates` the weights themselves are kept; through `step` or
+// `accumulate`, which never see them, the updates returned since the snapshot are summed
+// instead and subtracted from the weights on rollback, so those must be the weights the
+// updates were added to. `refresh_projection` and `apply_projected_updates` are not covered.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RollbackPolicy {
+    pub window: usize,
+    pub threshold: f32,
+}
+
+impl RollbackPolicy {
+    pub fn new(window: usize, threshold: f32) -> Self {
+        assert!(window > 0, "rollback window must be positive");
+        assert!(threshold > 1.0, "rollback threshold must exceed 1");
+        RollbackPolicy { window, threshold }
+    }
+}
+
+#[derive(Debug)]
+pub enum RollbackError {
+    // `GaLoreOptimizer::observe_loss` called while an accumulation window is still open.
+    MidAccumulation,
+    // The kept state does not fit the weights or optimizer it would be restored into.
+    Restore(CheckpointError),
+}
+
+impl fmt::Display for RollbackError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RollbackError::MidAccumulation => write!(f, "loss observed in the middle of an accumulation window"),
+            RollbackError::Restore(e) => write!(f, "cannot roll back the refresh: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for RollbackError {}
+
+// Whether `loss` is a spike against `baseline` under `policy`.
+pub(crate) fn is_spike(loss: f32, baseline: f32, policy: &RollbackPolicy) -> bool {
+    loss.is_nan() || loss - baseline > (policy.threshold - 1.0) * baseline.abs()
+}
+
+pub(crate) struct RefreshSnapshot {
+    pub(crate) projections: Vec<Option<Projection>>,
+    // Base optimizer and pipeline stage state.
+    pub(crate) optimizer: Checkpoint,
+    pub(crate) weights: KeptWeights,
+    // Mean pre-refresh loss; None until the loss of the refreshing step is observed.
+    pub(crate) baseline: Option<f32>,
+    pub(crate) remaining: usize,
+}
+
+pub(crate) enum KeptWeights {
+    Weights(Vec<Array2<f32>>),
+    // Sum of the updates handed out since the snapshot; empty until the first.
+    Updates(Vec<Array2<f32>>),
+}
+
+impl KeptWeights {
+    pub(crate) fn add_updates(&mut self, updates: &[Array2<f32>]) {
+        let KeptWeights::Updates(sum) = self else {
+            return;
+        };
+        if sum.is_empty() {
+            sum.extend(updates.iter().cloned());
+        } else {
+            for (total, update) in sum.iter_mut().zip(updates) {
+                *total += update;
+            }
+        }
+    }
+
+    pub(crate) fn fits<S: Data<Elem = f32>>(&self, params: &[ArrayBase<S, Ix2>]) -> bool {
+        let kept = match self {
+            KeptWeights::Weights(weights) => weights,
+            KeptWeights::Updates(sum) if sum.is_empty() => return true,
+            KeptWeights::Updates(sum) => sum,
+        };
+        kept.len() == params.len() && kept.iter().zip(params).all(|(k, p)| k.dim() == p.dim())
+    }
+
+    // Puts `params`, which must fit, back to the weights from before the snapshot.
+    pub(crate) fn restore<S: DataMut<Elem = f32>>(&self, params: &mut [ArrayBase<S, Ix2>]) {
+        match self {
+            KeptWeights::Weights(weights) => params.iter_mut().zip(weights).for_each(|(param, w)| param.assign(w)),
+            KeptWeights::Updates(sum) => params.iter_mut().zip(sum).for_each(|(param, total)| *param -= total),
+        }
+    }
+}
+
+pub(crate) fn push_bounded(values: &mut VecDeque<f32>, value: f32, capacity: usize) {
+    if values.len() == capacity {
+        values.pop_front();
+    }
+    values.push_back(value);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::galore::matrix_ops::GaLoreOptimizer;
+    use crate::galore::testing::{adam, gradient};
+
+    #[test]
+    fn loss_spike_after_a_refresh_restores_weights_and_moments() {
+        let mut optimizer = GaLoreOptimizer::new(adam(), 4, 3, 0.0).with_refresh_rollback(RollbackPolicy::new(2, 2.0));
+        let mut params = vec![Array2::<f32>::zeros((24, 40))];
+        for step in 0..2 {
+            optimizer.apply_updates(&mut params, vec![gradient(step).view()]).unwrap();
+            assert!(!optimizer.observe_loss(1.0, &mut params).unwrap());
+        }
+        // The third step refreshes the projection.
+        assert!(optimizer.projection().refresh_pending(1));
+        let weights = params.clone();
+        let mut before = Checkpoint::new();
+        optimizer.save_state(&mut before);
+
+        optimizer.apply_updates(&mut params, vec![gradient(2).view()]).unwrap();
+        assert!(!optimizer.observe_loss(1.0, &mut params).unwrap());
+        optimizer.apply_updates(&mut params, vec![gradient(3).view()]).unwrap();
+        assert_ne!(params, weights);
+        // Weights that do not fit the snapshot leave it in place.
+        assert!(optimizer.observe_loss(10.0, &mut [Array2::<f32>::zeros((2, 2))]).is_err());
+        assert!(optimizer.observe_loss(10.0, &mut params).unwrap());
+
+        assert_eq!(params, weights);
+        let mut after = Checkpoint::new();
+        optimizer.save_state(&mut after);
+        let restored = |name: &&str| name.starts_with("optimizer.") || name.starts_with("galore.projection.");
+        for (name, tensor) in before.tensors().filter(|(name, _)| restored(name)) {
+            assert_eq!(after.tensor(name), Some(tensor), "{}", name);
+        }
+        assert_eq!(after.metadata("optimizer.t"), before.metadata("optimizer.t"));
+        assert_eq!(optimizer.metrics().series("refresh/rollback").map(|s| s.len()), Some(1));
+    }
+
+    #[test]
+    fn rollback_through_step_undoes_the_updates_it_handed_out() {
+        let mut optimizer = GaLoreOptimizer::new(adam(), 4, 3, 0.0).with_refresh_rollback(RollbackPolicy::new(2, 2.0));
+        let mut params = vec![Array2::<f32>::zeros((24, 40))];
+        let take_step = |optimizer: &mut GaLoreOptimizer<_>, params: &mut [Array2<f32>], step| {
+            for (param, update) in params.iter_mut().zip(optimizer.step(vec![gradient(step).view()]).unwrap()) {
+                *param += &update;
+            }
+        };
+        for step in 0..2 {
+            take_step(&mut optimizer, &mut params, step);
+            assert!(!optimizer.observe_loss(1.0, &mut params).unwrap());
+        }
+        let weights = params.clone();
+        let mut before = Checkpoint::new();
+        optimizer.save_state(&mut before);
+
+        // The refresh step and the one after it are undone together.
+        take_step(&mut optimizer, &mut params, 2);
+        assert!(!optimizer.observe_loss(1.0, &mut params).unwrap());
+        take_step(&mut optimizer, &mut params, 3);
+        assert!(optimizer.observe_loss(10.0, &mut [Array2::<f32>::zeros((2, 2))]).is_err());
+        assert!(optimizer.observe_loss(f32::NAN, &mut params).unwrap());
+
+        assert!(params[0].abs_diff_eq(&weights[0], 1e-5));
+        let mut after = Checkpoint::new();
+        optimizer.save_state(&mut after);
+        for (name, tensor) in before.tensors().filter(|(name, _)| name.starts_with("optimizer.") || name.starts_with("galore.projection.")) {
+            assert_eq!(after.tensor(name), Some(tensor), "{}", name);
+        }
+    }
+
+    #[test]
+    fn spikes_are_measured_against_the_baseline_magnitude() {
+        let policy = RollbackPolicy::new(2, 2.0);
+        assert!(is_spike(2.5, 1.0, &policy) && !is_spike(1.9, 1.0, &policy));
+        // Negative baselines, e.g. from log-likelihood terms: a loss slightly above one is
+        // not a spike, one a whole magnitude above it is.
+        assert!(!is_spike(-1.5, -2.0, &policy));
+        assert!(is_spike(0.5, -2.0, &policy));
+        assert!(is_spike(f32::NAN, 1.0, &policy));
+    }
+
+    #[test]
+    fn losses_inside_an_accumulation_window_are_refused() {
+        let mut optimizer = GaLoreOptimizer::new(adam(), 4, 3, 0.0)
+            .with_projected_accumulation(2)
+            .with_refresh_rollback(RollbackPolicy::new(2, 2.0));
+        let mut params = vec![Array2::<f32>::zeros((24, 40))];
+        assert!(!optimizer.accumulate_updates(&mut params, vec![gradient(0).view()]).unwrap());
+        assert!(matches!(optimizer.observe_loss(1.0, &mut params), Err(RollbackError::MidAccumulation)));
+        assert!(optimizer.accumulate_updates(&mut params, vec![gradient(1).view()]).unwrap());
+        assert!(!optimizer.observe_loss(1.0, &mut params).unwrap());
+    }
+
+    #[test]
+    fn projected_updates_are_never_rolled_back() {
+        let mut optimizer = GaLoreOptimizer::new(adam(), 4, 3, 0.0).with_refresh_rollback(RollbackPolicy::new(2, 2.0));
+        let mut params = vec![Array2::<f32>::zeros((24, 40))];
+        for step in 0..4 {
+            if step % 2 == 0 {
+                optimizer.refresh_projection(vec![gradient(step).view()]);
+            }
+            let projected = optimizer.projection().project_current(&[gradient(step).view()]);
+            optimizer.apply_projected_updates(&mut params, projected.iter().map(|g| g.view()).collect()).unwrap();
+            let weights = params.clone();
+            assert!(!optimizer.observe_loss(if step < 2 { 1.0 } else { f32::NAN }, &mut params).unwrap());
+            assert_eq!(params, weights);
+        }
+        assert_eq!(optimizer.metrics().series("refresh/rollback"), None);
+    }
+}
@@ -0,0 +1,103 @@
+use ndarray::Array2;
+use std::fmt;
+
+use super::matrix_ops::GradInput;
+
+// A member of a group of parameters sharing one P/Q pair. Transposed members (e.g. an LM
+// head stored as the transpose of its tied embedding) use the pair swapped.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SharedMember {
+    pub index: usize,
+    pub transposed: bool,
+}
+
+impl SharedMember {
+    pub fn new(index: usize) -> Self {
+        SharedMember { index, transposed: false }
+    }
+
+    pub fn transposed(index: usize) -> Self {
+        SharedMember { index, transposed: true }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum SharedProjectionError {
+    // A group needs at least two members.
+    TooFewMembers(usize),
+    // The first member sets the group's orientation and cannot be transposed.
+    TransposedLeader(usize),
+    // The parameter is already in this or another shared group.
+    AlreadyShared(usize),
+}
+
+impl fmt::Display for SharedProjectionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SharedProjectionError::TooFewMembers(n) => write!(f, "a shared projection needs at least two members, got {}", n),
+            SharedProjectionError::TransposedLeader(i) => {
+                write!(f, "parameter {} leads a shared projection and cannot be transposed", i)
+            }
+            SharedProjectionError::AlreadyShared(i) => write!(f, "parameter {} is already in a shared projection", i),
+        }
+    }
+}
+
+impl std::error::Error for SharedProjectionError {}
+
+// Checks `members` as a new group alongside the existing `groups`.
+pub(crate) fn check_group(groups: &[Vec<SharedMember>], members: &[SharedMember]) -> Result<(), SharedProjectionError> {
+    if members.len() < 2 {
+        return Err(SharedProjectionError::TooFewMembers(members.len()));
+    }
+    if members[0].transposed {
+        return Err(SharedProjectionError::TransposedLeader(members[0].index));
+    }
+    for (k, member) in members.iter().enumerate() {
+        let grouped = groups.iter().flatten().chain(&members[..k]).any(|m| m.index == member.index);
+        if grouped {
+            return Err(SharedProjectionError::AlreadyShared(member.index));
+        }
+    }
+    Ok(())
+}
+
+// Sum of the group's gradients in the orientation of its first member, or None if a
+// member's gradient does not have that shape.
+pub(crate) fn combined_gradient(group: &[SharedMember], gradients: &[GradInput]) -> Option<Array2<f32>> {
+    let dim = gradients.get(group[0].index)?.dim();
+    let mut sum = Array2::zeros(dim);
+    for member in group.iter().filter(|m| m.index < gradients.len()) {
+        let grad = gradients[member.index].to_dense();
+        let grad = if member.transposed { grad.reversed_axes() } else { grad };
+        if grad.dim() != dim {
+            return None;
+        }
+        sum += &grad;
+    }
+    Some(sum)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn groups_are_checked_against_each_other() {
+        let groups = vec![vec![SharedMember::new(0), SharedMember::transposed(3)]];
+        assert_eq!(check_group(&groups, &[SharedMember::new(1), SharedMember::new(2)]), Ok(()));
+        assert_eq!(check_group(&groups, &[SharedMember::new(1)]), Err(SharedProjectionError::TooFewMembers(1)));
+        assert_eq!(
+            check_group(&groups, &[SharedMember::transposed(1), SharedMember::new(2)]),
+            Err(SharedProjectionError::TransposedLeader(1))
+        );
+        assert_eq!(
+            check_group(&groups, &[SharedMember::new(1), SharedMember::new(3)]),
+            Err(SharedProjectionError::AlreadyShared(3))
+        );
+        assert_eq!(
+            check_group(&groups, &[SharedMember::new(1), SharedMember::transposed(1)]),
+            Err(SharedProjectionError::AlreadyShared(1))
+        );
+    }
+}
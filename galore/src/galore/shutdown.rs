@@ -0,0 +1,63 @@
+use std::io;
+use std::sync::atomic::{AtomicI32, Ordering};
+
+// 0 while running, otherwise the first signal received (-1 for `Shutdown::request`).
+static RECEIVED: AtomicI32 = AtomicI32::new(0);
+
+// Only touches an atomic and async-signal-safe libc calls. A second signal while the
+// first is still being handled restores the default action and re-raises it, so a job
+// stuck in its final checkpoint can still be killed with another Ctrl-C.
+#[cfg(unix)]
+extern "C" fn on_signal(signal: libc::c_int) {
+    if RECEIVED.compare_exchange(0, signal, Ordering::SeqCst, Ordering::SeqCst).is_err() {
+        unsafe {
+            libc::signal(signal, libc::SIG_DFL);
+            libc::raise(signal);
+        }
+    }
+}
+
+// Cooperative shutdown on SIGINT / SIGTERM: the handler only records that a signal
+// arrived, and the training loop polls `requested` between steps, finishes the step it is
+// in and saves before exiting. On platforms without Unix signals `install` does nothing
+// and the process is terminated as usual.
+#[derive(Clone, Copy, Debug)]
+pub struct Shutdown {
+    _installed: (),
+}
+
+impl Shutdown {
+    pub fn install() -> io::Result<Self> {
+        #[cfg(unix)]
+        for signal in [libc::SIGINT, libc::SIGTERM] {
+            // The handler is async-signal-safe (see `on_signal`).
+            let previous = unsafe { libc::signal(signal, on_signal as extern "C" fn(libc::c_int) as libc::sighandler_t) };
+            if previous == libc::SIG_ERR {
+                return Err(io::Error::last_os_error());
+            }
+        }
+        Ok(Shutdown { _installed: () })
+    }
+
+    pub fn requested(&self) -> bool {
+        RECEIVED.load(Ordering::SeqCst) != 0
+    }
+
+    // Shuts down as if a signal had arrived, e.g. when a wall-clock budget runs out.
+    pub fn request(&self) {
+        let _ = RECEIVED.compare_exchange(0, -1, Ordering::SeqCst, Ordering::SeqCst);
+    }
+
+    // What triggered the shutdown, if anything has.
+    pub fn reason(&self) -> Option<&'static str> {
+        match RECEIVED.load(Ordering::SeqCst) {
+            0 => None,
+            -1 => Some("requested"),
+            #[cfg(unix)]
+            libc::SIGINT => Some("SIGINT"),
+            #[cfg(unix)]
+            libc::SIGTERM => Some("SIGTERM"),
+            _ => Some("signal"),
+        }
+    }
+}
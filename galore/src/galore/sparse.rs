@@ -0,0 +1,201 @@
+use ndarray::{Array1, Array2, ArrayView2};
+use std::fmt;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum CsrError {
+    // The arrays disagree in length or `indptr` is not a valid row pointer.
+    Layout(String),
+    // A column or row id beyond the matrix shape.
+    OutOfRange { index: usize, bound: usize },
+}
+
+impl fmt::Display for CsrError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CsrError::Layout(msg) => write!(f, "malformed CSR matrix: {}", msg),
+            CsrError::OutOfRange { index, bound } => write!(f, "CSR index {} out of range (< {})", index, bound),
+        }
+    }
+}
+
+impl std::error::Error for CsrError {}
+
+// Compressed sparse row matrix. Embedding gradients touch only the rows of the tokens in
+// the batch, so they are kept sparse through projection instead of being densified.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CsrMatrix {
+    rows: usize,
+    cols: usize,
+    indptr: Vec<usize>,
+    indices: Vec<usize>,
+    values: Vec<f32>,
+}
+
+impl CsrMatrix {
+    pub fn new(rows: usize, cols: usize, indptr: Vec<usize>, indices: Vec<usize>, values: Vec<f32>) -> Result<Self, CsrError> {
+        if indptr.len() != rows + 1 {
+            return Err(CsrError::Layout(format!("indptr has {} entries for {} rows", indptr.len(), rows)));
+        }
+        if indices.len() != values.len() {
+            return Err(CsrError::Layout(format!("{} indices for {} values", indices.len(), values.len())));
+        }
+        if indptr[0] != 0 || indptr[rows] != values.len() || indptr.windows(2).any(|w| w[0] > w[1]) {
+            return Err(CsrError::Layout(format!("indptr {:?} does not cover {} values in order", indptr, values.len())));
+        }
+        if let Some(&c) = indices.iter().find(|&&c| c >= cols) {
+            return Err(CsrError::OutOfRange { index: c, bound: cols });
+        }
+        Ok(CsrMatrix { rows, cols, indptr, indices, values })
+    }
+
+    // Dense rows scattered into a `rows` x `row_values.ncols()` matrix, the usual shape of an
+    // embedding gradient. Repeated row ids are summed.
+    pub fn from_rows(rows: usize, row_ids: &[usize], row_values: &ArrayView2<f32>) -> Result<Self, CsrError> {
+        if row_ids.len() != row_values.nrows() {
+            return Err(CsrError::Layout(format!("{} row ids for {} value rows", row_ids.len(), row_values.nrows())));
+        }
+        if let Some(&row) = row_ids.iter().find(|&&row| row >= rows) {
+            return Err(CsrError::OutOfRange { index: row, bound: rows });
+        }
+        let cols = row_values.ncols();
+        let mut order: Vec<usize> = (0..row_ids.len()).collect();
+        order.sort_by_key(|&i| row_ids[i]);
+
+        let mut indptr = vec![0; rows + 1];
+        let mut indices = Vec::new();
+        let mut values = Vec::new();
+        let mut k = 0;
+        for row in 0..rows {
+            let mut dense_row = None::<Array1<f32>>;
+            while k < order.len() && row_ids[order[k]] == row {
+                let values_row = row_values.row(order[k]);
+                dense_row = Some(match dense_row {
+                    Some(acc) => acc + values_row,
+                    None => values_row.to_owned(),
+                });
+                k += 1;
+            }
+            if let Some(dense_row) = dense_row {
+                for (c, &v) in dense_row.iter().enumerate() {
+                    indices.push(c);
+                    values.push(v);
+                }
+            }
+            indptr[row + 1] = values.len();
+        }
+        Ok(CsrMatrix { rows, cols, indptr, indices, values })
+    }
+
+    pub fn from_dense(dense: &ArrayView2<f32>) -> Self {
+        let (rows, cols) = dense.dim();
+        let mut indptr = Vec::with_capacity(rows + 1);
+        let mut indices = Vec::new();
+        let mut values = Vec::new();
+        indptr.push(0);
+        for row in dense.rows() {
+            for (c, &v) in row.iter().enumerate() {
+                if v != 0.0 {
+                    indices.push(c);
+                    values.push(v);
+                }
+            }
+            indptr.push(values.len());
+        }
+        CsrMatrix { rows, cols, indptr, indices, values }
+    }
+
+    pub fn dim(&self) -> (usize, usize) {
+        (self.rows, self.cols)
+    }
+
+    pub fn nnz(&self) -> usize {
+        self.values.len()
+    }
+
+    pub fn to_dense(&self) -> Array2<f32> {
+        let mut dense = Array2::zeros((self.rows, self.cols));
+        for (row, c, v) in self.entries() {
+            dense[[row, c]] += v;
+        }
+        dense
+    }
+
+    // self * rhs
+    pub fn dot(&self, rhs: &ArrayView2<f32>) -> Array2<f32> {
+        assert_eq!(self.cols, rhs.nrows(), "inner dimensions differ");
+        let mut out = Array2::zeros((self.rows, rhs.ncols()));
+        for row in 0..self.rows {
+            let mut out_row = out.row_mut(row);
+            for k in self.indptr[row]..self.indptr[row + 1] {
+                out_row.scaled_add(self.values[k], &rhs.row(self.indices[k]));
+            }
+        }
+        out
+    }
+
+    // self^T * rhs
+    pub fn t_dot(&self, rhs: &ArrayView2<f32>) -> Array2<f32> {
+        assert_eq!(self.rows, rhs.nrows(), "inner dimensions differ");
+        let mut out = Array2::zeros((self.cols, rhs.ncols()));
+        for (row, c, v) in self.entries() {
+            out.row_mut(c).scaled_add(v, &rhs.row(row));
+        }
+        out
+    }
+
+    fn entries(&self) -> impl Iterator<Item = (usize, usize, f32)> + '_ {
+        (0..self.rows).flat_map(move |row| {
+            (self.indptr[row]..self.indptr[row + 1]).map(move |k| (row, self.indices[k], self.values[k]))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ndarray::array;
+
+    #[test]
+    fn from_rows_sums_repeated_ids() {
+        let values = array![[1.0, 2.0], [3.0, 4.0], [10.0, 20.0]];
+        let csr = CsrMatrix::from_rows(4, &[2, 0, 2], &values.view()).unwrap();
+        assert_eq!(csr.dim(), (4, 2));
+        assert_eq!(csr.nnz(), 4);
+        assert_eq!(csr.to_dense(), array![[3.0, 4.0], [0.0, 0.0], [11.0, 22.0], [0.0, 0.0]]);
+
+        assert_eq!(
+            CsrMatrix::from_rows(2, &[0, 2, 1], &values.view()),
+            Err(CsrError::OutOfRange { index: 2, bound: 2 })
+        );
+        assert!(matches!(CsrMatrix::from_rows(4, &[0], &values.view()), Err(CsrError::Layout(_))));
+    }
+
+    #[test]
+    fn products_match_the_dense_matrix() {
+        let dense = array![[0.0, 2.0, 0.0], [0.0, 0.0, 0.0], [-1.0, 0.0, 4.0], [0.5, 3.0, 0.0]];
+        let csr = CsrMatrix::from_dense(&dense.view());
+        assert_eq!(csr.nnz(), 5);
+        assert_eq!(csr.to_dense(), dense);
+
+        let rhs = Array2::from_shape_fn((3, 2), |(i, j)| (i * 2 + j) as f32 - 1.5);
+        assert_eq!(csr.dot(&rhs.view()), dense.dot(&rhs));
+        let lhs = Array2::from_shape_fn((4, 2), |(i, j)| (i + 3 * j) as f32 * 0.5);
+        assert_eq!(csr.t_dot(&lhs.view()), dense.t().dot(&lhs));
+    }
+
+    #[test]
+    fn new_rejects_malformed_input() {
+        let csr = CsrMatrix::new(2, 3, vec![0, 1, 2], vec![2, 0], vec![5.0, 6.0]).unwrap();
+        assert_eq!(csr.to_dense(), array![[0.0, 0.0, 5.0], [6.0, 0.0, 0.0]]);
+
+        let layout = |result: Result<CsrMatrix, CsrError>| matches!(result, Err(CsrError::Layout(_)));
+        assert!(layout(CsrMatrix::new(2, 3, vec![0, 2], vec![0, 1], vec![1.0, 1.0])));
+        assert!(layout(CsrMatrix::new(2, 3, vec![0, 1, 2], vec![0], vec![1.0, 1.0])));
+        assert!(layout(CsrMatrix::new(2, 3, vec![0, 2, 1], vec![0, 1], vec![1.0, 1.0])));
+        assert!(layout(CsrMatrix::new(2, 3, vec![0, 1, 3], vec![0, 1], vec![1.0, 1.0])));
+        assert_eq!(
+            CsrMatrix::new(1, 3, vec![0, 1], vec![3], vec![1.0]),
+            Err(CsrError::OutOfRange { index: 3, bound: 3 })
+        );
+    }
+}
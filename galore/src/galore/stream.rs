@@ -0,0 +1,62 @@
+use ndarray::Array2;
+
+use super::matrix_ops::GaLoreProjection;
+use super::pipeline::Grad;
+
+impl GaLoreProjection {
+    // `project_gradient` for gradients arriving one at a time. If no refresh is due, each is
+    // projected and dropped as soon as it arrives, so at most one full-size gradient is
+    // alive (counting only the ones passed in owned). Otherwise they are collected first,
+    // since a refresh decomposes them together.
+    pub fn project_stream<'g, I: IntoIterator<Item = Grad<'g>>>(&mut self, gradients: I) -> Vec<Array2<f32>> {
+        if !self.step_without_refresh() {
+            let gradients: Vec<Grad> = gradients.into_iter().collect();
+            return self.project_gradient(gradients.iter().map(|g| g.view()).collect());
+        }
+        gradients
+            .into_iter()
+            .enumerate()
+            .map(|(i, grad)| self.project_current_one(i, grad))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::galore::matrix_ops::GaLoreOptimizer;
+    use crate::galore::restart::{RestartBoost, RestartPolicy};
+    use crate::galore::testing::{adam, gradient};
+
+    fn projection() -> GaLoreProjection {
+        GaLoreProjection::new(4, 3, 0.0).with_restart_policy(RestartPolicy { every: 2, boost: RestartBoost::Rank(6) })
+    }
+
+    fn gradients(step: usize) -> Vec<Array2<f32>> {
+        vec![gradient(step), gradient(step + 1).t().to_owned()]
+    }
+
+    #[test]
+    fn project_stream_matches_project_gradient_across_refreshes_and_restarts() {
+        let (mut batch, mut stream) = (projection(), projection());
+        for step in 0..13 {
+            let grads = gradients(step);
+            let expected = batch.project_gradient(grads.iter().map(|g| g.view()).collect());
+            // Owned and borrowed gradients alike.
+            let streamed = stream.project_stream([Grad::from(grads[0].clone()), Grad::from(grads[1].view())]);
+            assert_eq!(streamed, expected, "step {}", step);
+        }
+        assert_eq!(stream.export_subspaces(), batch.export_subspaces());
+        assert_eq!(stream.metrics().series("restart"), batch.metrics().series("restart"));
+    }
+
+    #[test]
+    fn streamed_steps_match_collected_ones() {
+        let mut streamed = GaLoreOptimizer::new(adam(), 4, 3, 0.0);
+        let mut collected = GaLoreOptimizer::new(adam(), 4, 3, 0.0);
+        for step in 0..7 {
+            let expected = collected.step(gradients(step).iter().map(|g| g.view())).unwrap();
+            assert_eq!(streamed.step(gradients(step)).unwrap(), expected, "step {}", step);
+        }
+    }
+}
@@ -0,0 +1,99 @@
+use ndarray::{Array1, Array2, ArrayView2};
+use ndarray_linalg::SVD;
+use std::fmt;
+
+// The projection bases of one parameter, as exported by `GaLoreProjection::export_subspaces`.
+// `p` is m x r (left), `q` is n x r (right); one-sided projections have only one of them.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Subspace {
+    pub p: Option<Array2<f32>>,
+    pub q: Option<Array2<f32>>,
+}
+
+impl Subspace {
+    // P^T G Q, P^T G or G Q.
+    pub fn project(&self, grad: &ArrayView2<f32>) -> Array2<f32> {
+        match (&self.p, &self.q) {
+            (Some(p), Some(q)) => p.t().dot(&grad.dot(q)),
+            (Some(p), None) => p.t().dot(grad),
+            (None, Some(q)) => grad.dot(q),
+            (None, None) => grad.to_owned(),
+        }
+    }
+}
+
+// Why a list of subspaces cannot be installed as projections; each names the parameter.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum SubspaceError {
+    // Neither P nor Q.
+    Empty(usize),
+    // P and Q with different numbers of columns.
+    RankMismatch { param: usize, left: usize, right: usize },
+}
+
+impl fmt::Display for SubspaceError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SubspaceError::Empty(i) => write!(f, "subspace of parameter {} has neither P nor Q", i),
+            SubspaceError::RankMismatch { param, left, right } => {
+                write!(f, "subspace of parameter {} has P of rank {} but Q of rank {}", param, left, right)
+            }
+        }
+    }
+}
+
+impl std::error::Error for SubspaceError {}
+
+// How imported subspaces behave in the new run. Fixed ones are never refreshed. Adapting
+// ones refresh on the usual schedule but blend the new basis into the old one with their
+// own EMA decay, so a decay close to 1 drifts slowly away from the imported subspace.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SubspaceImport {
+    Fixed,
+    Adapting { ema_decay: f32 },
+}
+
+// Cosines of the principal angles between a stored subspace and the dominant subspace of a
+// new gradient, per side, largest first. 1 means the directions are shared, 0 orthogonal.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SubspaceSimilarity {
+    pub param: usize,
+    pub left: Option<Array1<f32>>,
+    pub right: Option<Array1<f32>>,
+}
+
+impl SubspaceSimilarity {
+    // Mean squared cosine over both sides: the fraction of the new subspace captured by the
+    // old one.
+    pub fn overlap(&self) -> f32 {
+        let cosines: Vec<f32> = self.left.iter().chain(self.right.iter()).flatten().copied().collect();
+        if cosines.is_empty() {
+            return 0.0;
+        }
+        cosines.iter().map(|c| c * c).sum::<f32>() / cosines.len() as f32
+    }
+}
+
+impl fmt::Display for SubspaceSimilarity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "param {}: overlap {:.3}", self.param, self.overlap())?;
+        for (side, cosines) in [("left", &self.left), ("right", &self.right)] {
+            if let Some(cosines) = cosines {
+                let min = cosines.iter().copied().fold(f32::INFINITY, f32::min);
+                write!(f, ", {} cos max {:.3} min {:.3}", side, cosines.get(0).copied().unwrap_or(0.0), min)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+// One line per parameter that has a stored subspace.
+pub fn similarity_report(similarities: &[SubspaceSimilarity]) -> String {
+    similarities.iter().map(|s| format!("{}\n", s)).collect()
+}
+
+// Singular values of A^T B for orthonormal bases A and B.
+pub(crate) fn principal_cosines(a: &Array2<f32>, b: &Array2<f32>) -> Result<Array1<f32>, String> {
+    let (_, sigma, _) = a.t().dot(b).svd(false, false).map_err(|e| e.to_string())?;
+    Ok(sigma.mapv(|c| c.min(1.0)))
+}
@@ -0,0 +1,475 @@
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use rayon::prelude::*;
+use std::fmt;
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+use std::time::{Duration, Instant};
+
+// Sweep spec files are `key = value` lines with `#` comments, except on the `command` line:
+//
+//     mode = grid                # or: random
+//     samples = 16               # random mode only
+//     seed = 0
+//     parallel = 4
+//     command = galore train run-{run}.galore --rank {rank} --update-freq {update_freq} --lr {lr} --scale {scale}
+//     rank = 4, 8, 16
+//     update_freq = 200
+//     lr = log_uniform(1e-4, 1e-2)
+//     scale = 0.25, 0.5
+//
+// Every other key is a hyperparameter: a comma-separated list of values, or (random mode)
+// `uniform(a, b)` / `log_uniform(a, b)`. `{run}` in the command is the configuration's index,
+// so each run can write its own checkpoint; `galore train` resumes from an existing one, so
+// sharing a path would make later runs continue earlier ones. The command is split on
+// whitespace and run without a shell, so it cannot use quoting, pipes or redirection. Its
+// line takes everything after the first `=` verbatim, so a trailing `# ...` there is passed
+// to the command as arguments rather than dropped; put comments about it on their own line.
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum SweepError {
+    Parse { line: usize, message: String },
+    Invalid(String),
+    Io(String),
+}
+
+impl fmt::Display for SweepError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SweepError::Parse { line, message } => write!(f, "sweep spec line {}: {}", line, message),
+            SweepError::Invalid(message) => write!(f, "invalid sweep: {}", message),
+            SweepError::Io(message) => write!(f, "sweep I/O error: {}", message),
+        }
+    }
+}
+
+impl std::error::Error for SweepError {}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ParamSpace {
+    Values(Vec<f64>),
+    Uniform(f64, f64),
+    LogUniform(f64, f64),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SearchMode {
+    Grid,
+    Random { samples: usize, seed: u64 },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SweepSpec {
+    pub mode: SearchMode,
+    pub params: Vec<(String, ParamSpace)>,
+    pub command: Option<String>,
+    pub parallel: usize,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SweepConfig {
+    // Position in the expanded sweep, substituted for `{run}`.
+    pub run: usize,
+    pub values: Vec<(String, f64)>,
+}
+
+impl SweepConfig {
+    pub fn get(&self, name: &str) -> Option<f64> {
+        self.values.iter().find(|(k, _)| k == name).map(|&(_, v)| v)
+    }
+
+    // Substitutes `{run}` and `{name}` placeholders; integral values are printed without a
+    // decimal point.
+    pub fn render(&self, template: &str) -> String {
+        self.values.iter().fold(template.replace("{run}", &self.run.to_string()), |acc, (name, value)| {
+            acc.replace(&format!("{{{}}}", name), &format_value(*value))
+        })
+    }
+
+    // The template split on whitespace, each argument rendered.
+    pub fn render_args(&self, template: &str) -> Vec<String> {
+        template.split_whitespace().map(|arg| self.render(arg)).collect()
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct SweepResult {
+    pub config: SweepConfig,
+    pub metric: Result<f64, String>,
+    pub duration: Duration,
+}
+
+impl SweepSpec {
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, SweepError> {
+        let text = fs::read_to_string(path.as_ref()).map_err(|e| SweepError::Io(e.to_string()))?;
+        Self::parse(&text)
+    }
+
+    pub fn parse(text: &str) -> Result<Self, SweepError> {
+        let mut mode = "grid".to_string();
+        let mut samples = None;
+        let mut seed = 0;
+        let mut spec = SweepSpec { mode: SearchMode::Grid, params: Vec::new(), command: None, parallel: 1 };
+
+        for (i, raw) in text.lines().enumerate() {
+            let line_no = i + 1;
+            let err = |message: String| SweepError::Parse { line: line_no, message };
+            let line = raw.split('#').next().unwrap().trim();
+            if line.is_empty() {
+                continue;
+            }
+            let (key, value) = line.split_once('=').ok_or_else(|| err("expected `key = value`".to_string()))?;
+            let (key, value) = (key.trim(), value.trim());
+            let integer = |v: &str| v.parse::<u64>().map_err(|_| err(format!("{} must be a non-negative integer", key)));
+
+            match key {
+                "mode" => mode = value.to_string(),
+                "samples" => samples = Some(integer(value)? as usize),
+                "seed" => seed = integer(value)?,
+                "parallel" => spec.parallel = integer(value)?.max(1) as usize,
+                // The command may itself contain '#' or '=', so take the raw remainder; this is
+                // why the command line cannot carry a trailing comment.
+                "command" => spec.command = Some(raw.split_once('=').unwrap().1.trim().to_string()),
+                "run" => return Err(err("`run` is reserved for the run index".to_string())),
+                _ => {
+                    let space = parse_space(value).map_err(err)?;
+                    spec.params.push((key.to_string(), space));
+                }
+            }
+        }
+
+        spec.mode = match mode.as_str() {
+            "grid" => SearchMode::Grid,
+            "random" => SearchMode::Random {
+                samples: samples.ok_or_else(|| SweepError::Invalid("random mode needs `samples`".to_string()))?,
+                seed,
+            },
+            other => return Err(SweepError::Invalid(format!("unknown mode {}", other))),
+        };
+        Ok(spec)
+    }
+
+    pub fn expand(&self) -> Result<Vec<SweepConfig>, SweepError> {
+        match self.mode {
+            SearchMode::Grid => {
+                let mut configs = vec![SweepConfig { run: 0, values: Vec::new() }];
+                for (name, space) in &self.params {
+                    let values = match space {
+                        ParamSpace::Values(values) => values,
+                        _ => return Err(SweepError::Invalid(format!("{}: ranges need random mode", name))),
+                    };
+                    configs = configs
+                        .into_iter()
+                        .flat_map(|config| {
+                            values.iter().map(move |&v| {
+                                let mut config = config.clone();
+                                config.values.push((name.clone(), v));
+                                config
+                            })
+                        })
+                        .collect();
+                }
+                for (run, config) in configs.iter_mut().enumerate() {
+                    config.run = run;
+                }
+                Ok(configs)
+            }
+            SearchMode::Random { samples, seed } => {
+                let mut rng = StdRng::seed_from_u64(seed);
+                Ok((0..samples)
+                    .map(|run| SweepConfig {
+                        run,
+                        values: self
+                            .params
+                            .iter()
+                            .map(|(name, space)| (name.clone(), sample(space, &mut rng)))
+                            .collect(),
+                    })
+                    .collect())
+            }
+        }
+    }
+}
+
+// Runs `objective` for every configuration on up to `parallel` threads. Use `run_command`
+// as the objective to run each configuration in its own process.
+pub fn run_sweep<F>(configs: Vec<SweepConfig>, parallel: usize, objective: F) -> Vec<SweepResult>
+where
+    F: Fn(&SweepConfig) -> Result<f64, String> + Sync,
+{
+    let run = || {
+        configs
+            .into_par_iter()
+            .map(|config| {
+                let start = Instant::now();
+                let metric = objective(&config);
+                SweepResult { config, metric, duration: start.elapsed() }
+            })
+            .collect()
+    };
+    match rayon::ThreadPoolBuilder::new().num_threads(parallel.max(1)).build() {
+        Ok(pool) => pool.install(run),
+        Err(_) => run(),
+    }
+}
+
+pub fn run_command(template: &str, config: &SweepConfig) -> Result<f64, String> {
+    run_program(&config.render_args(template))
+}
+
+// Runs `argv[0]` with the remaining arguments directly, without a shell, so substituted
+// values reach the program verbatim; the metric is the last number printed on stdout.
+pub fn run_program(argv: &[String]) -> Result<f64, String> {
+    let (program, args) = argv.split_first().ok_or("empty command")?;
+    let output = Command::new(program)
+        .args(args)
+        .output()
+        .map_err(|e| format!("failed to start `{}`: {}", argv.join(" "), e))?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(format!("exited with {}: {}", output.status, stderr.lines().last().unwrap_or("")));
+    }
+    String::from_utf8_lossy(&output.stdout)
+        .split_whitespace()
+        .rev()
+        .find_map(|token| token.trim_matches(|c: char| c == ',' || c == ';').parse::<f64>().ok())
+        .ok_or_else(|| "no numeric metric on stdout".to_string())
+}
+
+// Results sorted by metric, lowest (best loss) first; failed runs go last.
+pub fn comparison_table(results: &[SweepResult]) -> String {
+    let mut sorted: Vec<&SweepResult> = results.iter().collect();
+    sorted.sort_by(|a, b| match (&a.metric, &b.metric) {
+        (Ok(x), Ok(y)) => x.partial_cmp(y).unwrap_or(std::cmp::Ordering::Equal),
+        (Ok(_), Err(_)) => std::cmp::Ordering::Less,
+        (Err(_), Ok(_)) => std::cmp::Ordering::Greater,
+        (Err(_), Err(_)) => std::cmp::Ordering::Equal,
+    });
+
+    let names: Vec<&str> = results
+        .first()
+        .map(|r| r.config.values.iter().map(|(k, _)| k.as_str()).collect())
+        .unwrap_or_default();
+    let mut header = vec!["#".to_string()];
+    header.extend(names.iter().map(|name| name.to_string()));
+    header.push("metric".to_string());
+    header.push("time".to_string());
+    let mut rows = vec![header];
+    for (rank, result) in sorted.iter().enumerate() {
+        let mut row = vec![(rank + 1).to_string()];
+        row.extend(names.iter().map(|name| result.config.get(name).map(format_value).unwrap_or_default()));
+        row.push(match &result.metric {
+            Ok(metric) => format!("{:.6}", metric),
+            Err(e) => format!("failed: {}", e),
+        });
+        row.push(format!("{:.1}s", result.duration.as_secs_f64()));
+        rows.push(row);
+    }
+
+    let widths: Vec<usize> = (0..rows[0].len())
+        .map(|c| rows.iter().map(|r| r[c].len()).max().unwrap_or(0))
+        .collect();
+    rows.iter()
+        .map(|row| {
+            row.iter()
+                .zip(widths.iter())
+                .map(|(cell, &w)| format!("{:<w$}", cell, w = w))
+                .collect::<Vec<_>>()
+                .join("  ")
+                .trim_end()
+                .to_string()
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+fn parse_space(value: &str) -> Result<ParamSpace, String> {
+    let range = |prefix: &str| -> Option<Result<(f64, f64), String>> {
+        let inner = value.strip_prefix(prefix)?.strip_suffix(')')?;
+        let bounds: Vec<&str> = inner.split(',').map(|s| s.trim()).collect();
+        Some(match bounds[..] {
+            [a, b] => match (a.parse::<f64>(), b.parse::<f64>()) {
+                (Ok(a), Ok(b)) if a < b => Ok((a, b)),
+                _ => Err(format!("bad range {}", value)),
+            },
+            _ => Err(format!("bad range {}", value)),
+        })
+    };
+
+    if let Some(bounds) = range("log_uniform(") {
+        let (a, b) = bounds?;
+        if a <= 0.0 {
+            return Err("log_uniform bounds must be positive".to_string());
+        }
+        return Ok(ParamSpace::LogUniform(a, b));
+    }
+    if let Some(bounds) = range("uniform(") {
+        let (a, b) = bounds?;
+        return Ok(ParamSpace::Uniform(a, b));
+    }
+    value
+        .split(',')
+        .map(|v| v.trim().parse::<f64>().map_err(|_| format!("not a number: {}", v.trim())))
+        .collect::<Result<Vec<_>, _>>()
+        .map(ParamSpace::Values)
+}
+
+fn sample(space: &ParamSpace, rng: &mut StdRng) -> f64 {
+    match *space {
+        ParamSpace::Values(ref values) => values[rng.gen_range(0..values.len())],
+        ParamSpace::Uniform(a, b) => rng.gen_range(a..b),
+        ParamSpace::LogUniform(a, b) => rng.gen_range(a.ln()..b.ln()).exp(),
+    }
+}
+
+fn format_value(value: f64) -> String {
+    if value.fract() == 0.0 && value.abs() < 1e15 {
+        format!("{}", value as i64)
+    } else {
+        format!("{}", value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SPEC: &str = "
+# grid over three parameters
+mode = grid
+parallel = 2
+command = ./train --rank {rank} --lr {lr} --tag #1
+rank = 4, 8, 16
+lr = 1e-3, 3e-3
+scale = 0.25
+";
+
+    fn config(values: &[(&str, f64)]) -> SweepConfig {
+        SweepConfig { run: 0, values: values.iter().map(|&(k, v)| (k.to_string(), v)).collect() }
+    }
+
+    fn result(values: &[(&str, f64)], metric: Result<f64, String>) -> SweepResult {
+        SweepResult { config: config(values), metric, duration: Duration::from_millis(1500) }
+    }
+
+    #[test]
+    fn specs_parse_into_modes_params_and_command() {
+        let spec = SweepSpec::parse(SPEC).unwrap();
+        assert_eq!(spec.mode, SearchMode::Grid);
+        assert_eq!(spec.parallel, 2);
+        assert_eq!(spec.command.as_deref(), Some("./train --rank {rank} --lr {lr} --tag #1"));
+        assert_eq!(
+            spec.params,
+            [
+                ("rank".to_string(), ParamSpace::Values(vec![4.0, 8.0, 16.0])),
+                ("lr".to_string(), ParamSpace::Values(vec![1e-3, 3e-3])),
+                ("scale".to_string(), ParamSpace::Values(vec![0.25])),
+            ]
+        );
+
+        let random = SweepSpec::parse("mode = random\nsamples = 5\nseed = 7\nlr = log_uniform(1e-4, 1e-2)\nscale = uniform(0, 1)").unwrap();
+        assert_eq!(random.mode, SearchMode::Random { samples: 5, seed: 7 });
+        assert_eq!(random.params[0].1, ParamSpace::LogUniform(1e-4, 1e-2));
+        assert_eq!(random.params[1].1, ParamSpace::Uniform(0.0, 1.0));
+    }
+
+    #[test]
+    fn parse_errors_report_the_line() {
+        let line = |text: &str| match SweepSpec::parse(text) {
+            Err(SweepError::Parse { line, .. }) => line,
+            other => panic!("expected a parse error, got {:?}", other),
+        };
+        assert_eq!(line("mode = grid\n\nrank 4"), 3);
+        assert_eq!(line("# header\nrank = 4, eight"), 2);
+        assert_eq!(line("samples = -1"), 1);
+        assert_eq!(line("lr = log_uniform(0, 1)"), 1);
+        assert_eq!(line("lr = uniform(2, 1)"), 1);
+        assert_eq!(line("rank = 4\nrun = 1, 2"), 2);
+
+        assert!(matches!(SweepSpec::parse("mode = random\nrank = 4"), Err(SweepError::Invalid(_))));
+        assert!(matches!(SweepSpec::parse("mode = bayes"), Err(SweepError::Invalid(_))));
+    }
+
+    #[test]
+    fn grid_expands_to_the_cartesian_product() {
+        let configs = SweepSpec::parse(SPEC).unwrap().expand().unwrap();
+        assert_eq!(configs.len(), 3 * 2);
+        for (i, config) in configs.iter().enumerate() {
+            assert!(configs[..i].iter().all(|other| other != config));
+            assert_eq!(config.get("scale"), Some(0.25));
+        }
+        assert_eq!(configs[0], config(&[("rank", 4.0), ("lr", 1e-3), ("scale", 0.25)]));
+
+        let ranged = SweepSpec::parse("lr = uniform(0, 1)").unwrap();
+        assert!(matches!(ranged.expand(), Err(SweepError::Invalid(_))));
+    }
+
+    #[test]
+    fn random_samples_are_seeded_and_within_bounds() {
+        let text = "mode = random\nsamples = 200\nseed = 3\nlr = log_uniform(1e-4, 1e-2)\nscale = uniform(0.5, 2)\nrank = 4, 8";
+        let configs = SweepSpec::parse(text).unwrap().expand().unwrap();
+        assert_eq!(configs.len(), 200);
+        for config in &configs {
+            let lr = config.get("lr").unwrap();
+            assert!((1e-4..1e-2).contains(&lr));
+            let scale = config.get("scale").unwrap();
+            assert!((0.5..2.0).contains(&scale));
+            assert!([4.0, 8.0].contains(&config.get("rank").unwrap()));
+        }
+        // Log-uniform draws put about half of the samples below the geometric mean.
+        let below = configs.iter().filter(|c| c.get("lr").unwrap() < 1e-3).count();
+        assert!((60..140).contains(&below));
+
+        assert_eq!(SweepSpec::parse(text).unwrap().expand().unwrap(), configs);
+        let reseeded = SweepSpec::parse(&text.replace("seed = 3", "seed = 4")).unwrap().expand().unwrap();
+        assert_ne!(reseeded, configs);
+    }
+
+    #[test]
+    fn render_substitutes_placeholders() {
+        let config = config(&[("rank", 8.0), ("lr", 0.003), ("scale", -2.0)]);
+        assert_eq!(config.render("--rank {rank} --lr={lr} {scale} {other}"), "--rank 8 --lr=0.003 -2 {other}");
+        assert_eq!(config.render_args("  ./train --rank {rank}\t--lr {lr} "), ["./train", "--rank", "8", "--lr", "0.003"]);
+    }
+
+    #[test]
+    fn each_run_renders_its_own_index() {
+        for text in [SPEC, "mode = random\nsamples = 4\nlr = uniform(0, 1)"] {
+            let configs = SweepSpec::parse(text).unwrap().expand().unwrap();
+            let paths: Vec<String> = configs.iter().map(|config| config.render("run-{run}.galore")).collect();
+            for (i, path) in paths.iter().enumerate() {
+                assert_eq!(*path, format!("run-{}.galore", i));
+            }
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn commands_run_without_a_shell() {
+        let config = config(&[("lr", 0.5)]);
+        assert_eq!(run_command("echo loss {lr}", &config), Ok(0.5));
+        // A shell would redirect the output away.
+        assert_eq!(run_command("echo {lr} > /dev/null", &config), Ok(0.5));
+        assert!(run_command("false", &config).unwrap_err().starts_with("exited with"));
+        assert_eq!(run_command("echo done", &config), Err("no numeric metric on stdout".to_string()));
+    }
+
+    #[test]
+    fn comparison_table_ranks_by_metric_with_failures_last() {
+        let results = [
+            result(&[("rank", 4.0), ("lr", 0.01)], Ok(2.5)),
+            result(&[("rank", 8.0), ("lr", 0.001)], Err("diverged".to_string())),
+            result(&[("rank", 16.0), ("lr", 0.001)], Ok(1.25)),
+        ];
+        let table = comparison_table(&results);
+        let rows: Vec<Vec<&str>> = table.lines().map(|line| line.split_whitespace().collect()).collect();
+        assert_eq!(rows[0], ["#", "rank", "lr", "metric", "time"]);
+        assert_eq!(rows[1], ["1", "16", "0.001", "1.250000", "1.5s"]);
+        assert_eq!(rows[2], ["2", "4", "0.01", "2.500000", "1.5s"]);
+        assert_eq!(rows[3], ["3", "8", "0.001", "failed:", "diverged", "1.5s"]);
+        // Columns are padded to a common width.
+        let metric_column = table.lines().next().unwrap().find("metric").unwrap();
+        assert!(table.lines().skip(1).all(|line| line[metric_column..].starts_with(|c: char| c.is_ascii_digit() || c == 'f')));
+    }
+}
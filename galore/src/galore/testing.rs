@@ -0,0 +1,58 @@
+// Fixtures shared by the unit tests.
+use ndarray::{Array2, ArrayView2, CowArray};
+
+use super::matrix_ops::GaLoreProjection;
+use super::optimizer::{Adam, Optimizer};
+use super::pipeline::{GradTransform, TransformContext};
+
+pub const LR: f32 = 1e-2;
+
+pub fn adam() -> Adam {
+    Adam::new(LR, 0.9, 0.999, 1e-8)
+}
+
+// Plain gradient descent at `LR`, for code that needs a base optimizer whose step scales
+// with the gradient.
+pub struct Sgd;
+
+impl Optimizer for Sgd {
+    fn compute_updates(&mut self, gradients: &[Array2<f32>]) -> Vec<Array2<f32>> {
+        gradients.iter().map(|g| -LR * g).collect()
+    }
+
+    fn learning_rate(&self) -> Option<f32> {
+        Some(LR)
+    }
+}
+
+pub fn gradient(step: usize) -> Array2<f32> {
+    Array2::from_shape_fn((24, 40), |(i, j)| ((i * 40 + j + 7 * step) as f32 * 0.61).sin() * (1.0 + (j % 5) as f32))
+}
+
+pub fn weights() -> Array2<f32> {
+    Array2::from_shape_fn((24, 40), |(i, j)| ((i * 7 + j * 3 + i * j) as f32 * 0.37).sin())
+}
+
+// Runs one pipeline stage on its own, outside an optimizer step.
+pub fn run_stage(stage: &mut dyn GradTransform, grads: &[Array2<f32>]) -> Vec<Array2<f32>> {
+    run_stage_on(&mut GaLoreProjection::new(4, 10, 0.0), stage, grads)
+}
+
+// `run_stage` with the caller's projection, for stages that read or record into it.
+pub fn run_stage_on(galore: &mut GaLoreProjection, stage: &mut dyn GradTransform, grads: &[Array2<f32>]) -> Vec<Array2<f32>> {
+    run_stage_with_params(galore, stage, grads, None)
+}
+
+// `run_stage_on` for stages that read the parameters.
+pub fn run_stage_with_params(
+    galore: &mut GaLoreProjection,
+    stage: &mut dyn GradTransform,
+    grads: &[Array2<f32>],
+    params: Option<&[Array2<f32>]>,
+) -> Vec<Array2<f32>> {
+    let mut optimizer = adam();
+    let params: Option<Vec<ArrayView2<f32>>> = params.map(|params| params.iter().map(|p| p.view()).collect());
+    let mut ctx = TransformContext { galore, optimizer: &mut optimizer, step: 1, params: params.as_deref() };
+    let grads = grads.iter().map(|g| CowArray::from(g.view())).collect();
+    stage.apply(grads, &mut ctx).into_iter().map(|g| g.into_owned()).collect()
+}
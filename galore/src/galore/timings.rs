@@ -0,0 +1,33 @@
+use std::time::Duration;
+
+// Wall-clock breakdown of one `GaLoreOptimizer::step`. `projection` excludes the SVD refresh,
+// and `other` covers any custom pipeline stages.
+#[derive(Clone, Debug, Default)]
+pub struct StepTimings {
+    pub svd_refresh: Duration,
+    pub projection: Duration,
+    pub base_optimizer: Duration,
+    pub back_projection: Duration,
+    pub other: Duration,
+    pub stages: Vec<(String, Duration)>,
+}
+
+impl StepTimings {
+    pub fn total(&self) -> Duration {
+        self.svd_refresh + self.projection + self.base_optimizer + self.back_projection + self.other
+    }
+
+    // `refresh` is the projection's last refresh time, split out of the "project" stage.
+    pub(crate) fn record(&mut self, stage: &str, elapsed: Duration, refresh: Duration) {
+        match stage {
+            "project" => {
+                self.svd_refresh += refresh;
+                self.projection += elapsed.saturating_sub(refresh);
+            }
+            "optimize" => self.base_optimizer += elapsed,
+            "project_back" => self.back_projection += elapsed,
+            _ => self.other += elapsed,
+        }
+        self.stages.push((stage.to_string(), elapsed));
+    }
+}
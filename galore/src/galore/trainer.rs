@@ -0,0 +1,504 @@
+use ndarray::{Array2, ArrayViewMut2};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::fmt;
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::{Path, PathBuf};
+use std::sync::{mpsc, Arc};
+use std::thread;
+
+use super::checkpoint::storage::{LocalStorage, Storage};
+use super::checkpoint::{Checkpoint, CheckpointError, CheckpointFormat};
+#[cfg(feature = "half")]
+use super::master::MasterWeights;
+use super::matrix_ops::GaLoreOptimizer;
+use super::optimizer::Optimizer;
+use super::metrics::Metrics;
+use super::pipeline::PipelineError;
+use super::rollback::RollbackError;
+use super::neural_network::NeuralNetwork;
+use super::shutdown::Shutdown;
+
+// What one training step produced: gradients in `NeuralNetwork::parameters_mut` order and
+// scalars to log under their names. "loss", if present, is also reported to the optimizer
+// (see `GaLoreOptimizer::observe_loss`).
+pub struct StepOutput {
+    pub gradients: Vec<Array2<f32>>,
+    pub metrics: Vec<(String, f32)>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StopReason {
+    Finished,
+    // Stopped early by `Shutdown`, with its reason.
+    Interrupted(&'static str),
+}
+
+// Why `Trainer::run` stopped without finishing: saving or loading state failed, the
+// optimizer refused a step, or a refresh rollback failed.
+#[derive(Debug)]
+pub enum TrainError {
+    Checkpoint(CheckpointError),
+    Pipeline(PipelineError),
+    Rollback(RollbackError),
+}
+
+impl fmt::Display for TrainError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TrainError::Checkpoint(e) => write!(f, "{}", e),
+            TrainError::Pipeline(e) => write!(f, "{}", e),
+            TrainError::Rollback(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for TrainError {}
+
+impl From<CheckpointError> for TrainError {
+    fn from(e: CheckpointError) -> Self {
+        TrainError::Checkpoint(e)
+    }
+}
+
+impl From<PipelineError> for TrainError {
+    fn from(e: PipelineError) -> Self {
+        TrainError::Pipeline(e)
+    }
+}
+
+impl From<RollbackError> for TrainError {
+    fn from(e: RollbackError) -> Self {
+        TrainError::Rollback(e)
+    }
+}
+
+// Drives a network and a GaLore optimizer through a user-supplied step function. The
+// checkpoint holds the model ("model.<name>", running-norm statistics included), the
+// optimizer and projection state and the trainer's step and seed. The step function's
+// random generator and the network's dropout masks are derived from those two, so a
+// resumed run draws the same numbers it would have drawn uninterrupted.
+pub struct Trainer<O: Optimizer> {
+    network: NeuralNetwork,
+    optimizer: GaLoreOptimizer<O>,
+    metrics: Metrics,
+    seed: u64,
+    step: usize,
+    checkpoint: Option<(Arc<dyn Storage>, String, CheckpointFormat)>,
+    checkpoint_every: Option<usize>,
+    metrics_path: Option<PathBuf>,
+    shutdown: Option<Shutdown>,
+    #[cfg(feature = "half")]
+    master: Option<MasterWeights>,
+}
+
+impl<O: Optimizer> Trainer<O> {
+    pub fn new(network: NeuralNetwork, optimizer: GaLoreOptimizer<O>, seed: u64) -> Self {
+        Trainer {
+            network,
+            optimizer,
+            metrics: Metrics::new(),
+            seed,
+            step: 0,
+            checkpoint: None,
+            checkpoint_every: None,
+            metrics_path: None,
+            shutdown: None,
+            #[cfg(feature = "half")]
+            master: None,
+        }
+    }
+
+    // Where `save` writes, and `resume` reads, the checkpoint.
+    pub fn with_checkpoint(self, path: impl Into<PathBuf>, format: CheckpointFormat) -> Self {
+        let path = path.into();
+        let key = path.file_name().expect("checkpoint path names a file").to_string_lossy().into_owned();
+        let dir = path.parent().map(Path::to_path_buf).unwrap_or_default();
+        self.with_checkpoint_storage(Arc::new(LocalStorage::new(dir)), key, format)
+    }
+
+    // The checkpoint as object `key` of `storage`, e.g. a backend wrapped in `Retrying`.
+    pub fn with_checkpoint_storage(mut self, storage: Arc<dyn Storage>, key: impl Into<String>, format: CheckpointFormat) -> Self {
+        assert!(format.supports_metadata(), "trainer checkpoints need a format with metadata");
+        self.checkpoint = Some((storage, key.into(), format));
+        self
+    }
+
+    pub fn with_checkpoint_every(mut self, steps: usize) -> Self {
+        assert!(steps > 0, "checkpoint interval must be positive");
+        self.checkpoint_every = Some(steps);
+        self
+    }
+
+    // Metrics are written here as CSV whenever a checkpoint is.
+    pub fn with_metrics_file(mut self, path: impl Into<PathBuf>) -> Self {
+        self.metrics_path = Some(path.into());
+        self
+    }
+
+    // Stop between steps once `shutdown` is requested (SIGINT / SIGTERM), after saving.
+    pub fn with_shutdown(mut self, shutdown: Shutdown) -> Self {
+        self.shutdown = Some(shutdown);
+        self
+    }
+
+    // Simulates bf16 working weights against f32 masters (see `MasterWeights`: numerics
+    // only, no memory is saved). Checkpoints hold the masters.
+    #[cfg(feature = "half")]
+    pub fn with_master_weights(mut self) -> Self {
+        self.master = Some(MasterWeights::from_network(&mut self.network));
+        self
+    }
+
+    #[cfg(feature = "half")]
+    pub fn master_weights(&self) -> Option<&MasterWeights> {
+        self.master.as_ref()
+    }
+
+    pub fn network(&self) -> &NeuralNetwork {
+        &self.network
+    }
+
+    pub fn optimizer(&self) -> &GaLoreOptimizer<O> {
+        &self.optimizer
+    }
+
+    pub fn metrics(&self) -> &Metrics {
+        &self.metrics
+    }
+
+    pub fn step(&self) -> usize {
+        self.step
+    }
+
+    // Runs steps until `steps` have been taken in total (counting resumed ones) or a
+    // shutdown is requested, then saves the checkpoint and metrics. A shutdown never
+    // interrupts a step: the one in progress completes and is included in the save.
+    pub fn run<F>(&mut self, steps: usize, mut step_fn: F) -> Result<StopReason, TrainError>
+    where
+        F: FnMut(&mut NeuralNetwork, &mut StdRng, usize) -> StepOutput,
+    {
+        let mut reason = StopReason::Finished;
+        while self.step < steps {
+            if let Some(requested) = self.shutdown.as_ref().and_then(|s| s.reason()) {
+                reason = StopReason::Interrupted(requested);
+                break;
+            }
+            let mut rng = step_rng(self.seed, self.step, 0);
+            self.network.seed_dropout(dropout_seed(self.seed, self.step, 0));
+            let output = step_fn(&mut self.network, &mut rng, self.step);
+            let gradients = output.gradients.iter().map(|g| g.view()).collect();
+            self.update_weights(|optimizer, params| optimizer.apply_updates(params, gradients).map(|()| true))?;
+            self.finish_step(output.metrics, steps)?;
+        }
+        self.save()?;
+        Ok(reason)
+    }
+
+    // `run` with each step split into the optimizer's micro-batches
+    // (`GaLoreOptimizer::with_projected_accumulation`), double-buffered: a second thread
+    // fills the next batch with `prepare(buffer, rng, step, micro_batch)` while `compute`
+    // runs forward and backward on the current one and the optimizer projects and
+    // accumulates its gradients. Two buffers circulate between the threads and are reused,
+    // so preparing data allocates nothing once they have grown to size. Metrics are
+    // averaged over the micro-batches of a step.
+    pub fn run_micro_batched<B, P, F>(&mut self, steps: usize, mut prepare: P, mut compute: F) -> Result<StopReason, TrainError>
+    where
+        B: Default + Send,
+        P: FnMut(&mut B, &mut StdRng, usize, usize) + Send,
+        F: FnMut(&mut NeuralNetwork, &B) -> StepOutput,
+    {
+        let micro_batches = self.optimizer.micro_batches();
+        let (first, seed) = (self.step, self.seed);
+        let mut reason = StopReason::Finished;
+        thread::scope(|scope| -> Result<(), TrainError> {
+            let (full_tx, full_rx) = mpsc::sync_channel::<B>(1);
+            let (empty_tx, empty_rx) = mpsc::channel::<B>();
+            for _ in 0..2 {
+                empty_tx.send(B::default()).expect("receiver is alive");
+            }
+            scope.spawn(move || {
+                for step in first..steps {
+                    for micro in 0..micro_batches {
+                        // Either channel closing means the training loop has stopped.
+                        let Ok(mut buffer) = empty_rx.recv() else {
+                            return;
+                        };
+                        prepare(&mut buffer, &mut step_rng(seed, step, micro), step, micro);
+                        if full_tx.send(buffer).is_err() {
+                            return;
+                        }
+                    }
+                }
+            });
+
+            while self.step < steps {
+                if let Some(requested) = self.shutdown.as_ref().and_then(|s| s.reason()) {
+                    reason = StopReason::Interrupted(requested);
+                    break;
+                }
+                let mut sums: Vec<(String, f32)> = Vec::new();
+                for micro in 0..micro_batches {
+                    let buffer = full_rx.recv().expect("data preparation thread panicked");
+                    self.network.seed_dropout(dropout_seed(self.seed, self.step, micro));
+                    let output = compute(&mut self.network, &buffer);
+                    // Hand the buffer back before the optimizer work so the next fill overlaps it.
+                    let _ = empty_tx.send(buffer);
+                    for (name, value) in output.metrics {
+                        match sums.iter_mut().find(|(n, _)| *n == name) {
+                            Some((_, sum)) => *sum += value,
+                            None => sums.push((name, value)),
+                        }
+                    }
+                    let gradients = output.gradients.iter().map(|g| g.view()).collect();
+                    self.update_weights(|optimizer, params| optimizer.accumulate_updates(params, gradients))?;
+                }
+                let means = sums.into_iter().map(|(name, sum)| (name, sum / micro_batches as f32)).collect();
+                self.finish_step(means, steps)?;
+            }
+            // Unblocks the preparation thread so the scope can join it.
+            drop(full_rx);
+            drop(empty_tx);
+            Ok(())
+        })?;
+        self.save()?;
+        Ok(reason)
+    }
+
+    // Bookkeeping after a step's update: loss for the rollback guard, metrics, periodic save.
+    fn finish_step(&mut self, metrics: Vec<(String, f32)>, steps: usize) -> Result<(), TrainError> {
+        let loss = metrics.iter().find(|(name, _)| name == "loss").map(|&(_, loss)| loss);
+        if let (Some(loss), Some(_)) = (loss, self.optimizer.refresh_rollback()) {
+            let mut observed = Ok(false);
+            self.update_weights(|optimizer, params| {
+                observed = optimizer.observe_loss(loss, params);
+                Ok(observed.as_ref().is_ok_and(|&rolled_back| rolled_back))
+            })?;
+            observed?;
+        }
+        for (name, value) in metrics {
+            self.metrics.record(name, self.step, value);
+        }
+        self.step += 1;
+        if self.checkpoint_every.is_some_and(|every| self.step.is_multiple_of(every)) && self.step < steps {
+            self.save()?;
+        }
+        Ok(())
+    }
+
+    // Writes the checkpoint (if configured), then the metrics. Storage uploads only replace
+    // the previous checkpoint once complete, so a job killed while saving keeps it.
+    pub fn save(&mut self) -> Result<(), CheckpointError> {
+        if let Some((storage, key, format)) = self.checkpoint.clone() {
+            let mut checkpoint = Checkpoint::new();
+            for (name, param) in self.model_tensors() {
+                checkpoint.insert_tensor(format!("model.{}", name), param);
+            }
+            for (name, buffer) in self.network.buffers_mut() {
+                checkpoint.insert_tensor(format!("model.{}", name), buffer.to_owned());
+            }
+            self.optimizer.save_state(&mut checkpoint);
+            checkpoint.set_metadata("trainer.step", self.step);
+            checkpoint.set_metadata("trainer.seed", self.seed);
+            checkpoint.save_to(&*storage, &key, format)?;
+        }
+        if let Some(path) = &self.metrics_path {
+            let mut writer = BufWriter::new(File::create(path).map_err(CheckpointError::Io)?);
+            self.metrics.write_csv(&mut writer).map_err(CheckpointError::Io)?;
+        }
+        Ok(())
+    }
+
+    // Loads the configured checkpoint if it exists. Returns whether it did.
+    pub fn resume(&mut self) -> Result<bool, CheckpointError> {
+        let Some((storage, key, format)) = &self.checkpoint else {
+            return Ok(false);
+        };
+        if !storage.exists(key)? {
+            return Ok(false);
+        }
+        let checkpoint = Checkpoint::load_from(&**storage, key, *format)?;
+        load_model(self.network.parameters_mut(), &checkpoint)?;
+        load_model(self.network.buffers_mut(), &checkpoint)?;
+        #[cfg(feature = "half")]
+        if let Some(master) = &mut self.master {
+            for i in 0..master.names().len() {
+                let saved = checkpoint.matrix(&format!("model.{}", master.names()[i]))?;
+                master.set_master(i, saved);
+            }
+            master.load_into(&mut self.network);
+        }
+        self.optimizer.load_state(&checkpoint)?;
+        self.step = checkpoint.parse_metadata("trainer.step")?;
+        self.seed = checkpoint.parse_metadata("trainer.seed")?;
+        Ok(true)
+    }
+
+    // Runs `update` on views of the weights the optimizer steps: the f32 masters when there
+    // are any (see `MasterWeights::update_masters`; `update` reports whether it changed
+    // them), otherwise the network's parameters, which are written in place. The optimizer
+    // gets them so stages such as weight decay can read them.
+    fn update_weights(
+        &mut self,
+        update: impl FnOnce(&mut GaLoreOptimizer<O>, &mut [ArrayViewMut2<f32>]) -> Result<bool, PipelineError>,
+    ) -> Result<(), PipelineError> {
+        #[cfg(feature = "half")]
+        if let Some(master) = &mut self.master {
+            let optimizer = &mut self.optimizer;
+            let mut result = Ok(());
+            let changed = master.update_masters(|params| {
+                let mut views: Vec<ArrayViewMut2<f32>> = params.iter_mut().map(|p| p.view_mut()).collect();
+                update(optimizer, &mut views).unwrap_or_else(|e| {
+                    result = Err(e);
+                    false
+                })
+            });
+            if changed {
+                master.load_into(&mut self.network);
+            }
+            return result;
+        }
+        let mut params: Vec<ArrayViewMut2<f32>> = self.network.parameters_mut().into_iter().map(|(_, p)| p).collect();
+        update(&mut self.optimizer, &mut params).map(|_| ())
+    }
+
+    // The weights a checkpoint stores: the f32 masters when there are any.
+    fn model_tensors(&mut self) -> Vec<(String, Array2<f32>)> {
+        #[cfg(feature = "half")]
+        if let Some(master) = &self.master {
+            return master.names().iter().cloned().zip(master.master().iter().cloned()).collect();
+        }
+        self.network.parameters_mut().into_iter().map(|(name, param)| (name, param.to_owned())).collect()
+    }
+}
+
+// Assigns the checkpoint's "model.<name>" to each named tensor.
+fn load_model(tensors: Vec<(String, ArrayViewMut2<'_, f32>)>, checkpoint: &Checkpoint) -> Result<(), CheckpointError> {
+    for (name, mut param) in tensors {
+        let saved = checkpoint.matrix(&format!("model.{}", name))?;
+        if saved.dim() != param.dim() {
+            return Err(CheckpointError::Format(format!("model.{} has shape {:?}, network has {:?}", name, saved.dim(), param.dim())));
+        }
+        param.assign(&saved);
+    }
+    Ok(())
+}
+
+// The step function's generator for micro-batch `micro` of `step`; a function of the seed and
+// position only, so a resumed run sees the same data.
+fn step_rng(seed: u64, step: usize, micro: usize) -> StdRng {
+    StdRng::seed_from_u64(seed ^ ((step as u64) << 32) ^ micro as u64)
+}
+
+// The dropout seed for micro-batch `micro` of `step`, from a generator of its own so the
+// step function's stream is the same with and without dropout.
+fn dropout_seed(seed: u64, step: usize, micro: usize) -> u64 {
+    step_rng(!seed, step, micro).gen()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::galore::optimizer::Adam;
+    use crate::galore::neural_network::{Activation, Layer};
+    use crate::galore::pipeline::WeightDecayMode;
+    use ndarray::Array1;
+
+    const LR: f32 = 1e-2;
+    const DECAY: f32 = 0.5;
+
+    fn network() -> NeuralNetwork {
+        let mut layer = Layer::new(4, 6, Activation::Tanh, false, 0.0);
+        for (_, mut param) in layer.parameters_mut("") {
+            let cols = param.ncols();
+            param.indexed_iter_mut().for_each(|((i, j), x)| *x = ((i * cols + j) as f32 * 0.37 + (i * j) as f32).sin() * 0.5);
+        }
+        NeuralNetwork::from_layers(vec![layer])
+    }
+
+    fn optimizer(mode: WeightDecayMode) -> GaLoreOptimizer<Adam> {
+        GaLoreOptimizer::new(Adam::new(LR, 0.9, 0.999, 1e-8), 2, 2, 0.0).with_weight_decay(DECAY, mode).unwrap()
+    }
+
+    // One sample regressed onto a fixed function of itself.
+    fn regression_step(network: &mut NeuralNetwork, rng: &mut StdRng, _step: usize) -> StepOutput {
+        let input = Array1::from_shape_fn(4, |_| rng.gen_range(-1.0..1.0f32));
+        let (output, cache) = network.forward(&input.view());
+        let target = Array1::from_shape_fn(6, |i| input[i % 4] * 0.5);
+        let diff = output - target;
+        let loss = 0.5 * diff.dot(&diff);
+        let gradients = network.backward(diff, &cache);
+        StepOutput { gradients, metrics: vec![("loss".to_string(), loss)] }
+    }
+
+    fn weights(trainer: &mut Trainer<Adam>) -> Vec<Array2<f32>> {
+        trainer.network.parameters_mut().into_iter().map(|(_, p)| p.to_owned()).collect()
+    }
+
+    #[test]
+    fn weight_decay_runs_and_resumes() {
+        let mut uninterrupted = Trainer::new(network(), optimizer(WeightDecayMode::Full), 7);
+        uninterrupted.run(6, regression_step).unwrap();
+
+        let path = std::env::temp_dir().join(format!("galore-trainer-{}.galore", std::process::id()));
+        let mut first = Trainer::new(network(), optimizer(WeightDecayMode::Full), 7).with_checkpoint(&path, CheckpointFormat::Native);
+        first.run(3, regression_step).unwrap();
+        let mut resumed = Trainer::new(network(), optimizer(WeightDecayMode::Full), 0).with_checkpoint(&path, CheckpointFormat::Native);
+        assert!(resumed.resume().unwrap());
+        assert_eq!(resumed.step(), 3);
+        resumed.run(6, regression_step).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        for (a, b) in weights(&mut uninterrupted).iter().zip(weights(&mut resumed).iter()) {
+            assert!(a.abs_diff_eq(b, 1e-6), "{} vs {}", a, b);
+        }
+    }
+
+    // Dropout and running-norm statistics included: stopping after 3 steps and resuming
+    // from the checkpoint must end bit for bit where an uninterrupted run does.
+    #[test]
+    fn interrupted_run_resumes_bit_for_bit() {
+        let network = || {
+            let hidden = Layer::new(4, 8, Activation::Tanh, false, 0.5).with_running_norm(0.1);
+            let mut network = NeuralNetwork::from_layers(vec![hidden, Layer::new(8, 6, Activation::Tanh, false, 0.0)]);
+            for (_, mut param) in network.parameters_mut() {
+                let cols = param.ncols();
+                param.indexed_iter_mut().for_each(|((i, j), x)| *x = ((i * cols + j) as f32 * 0.37 + (i * j) as f32).sin() * 0.5);
+            }
+            network
+        };
+        let state = |trainer: &mut Trainer<Adam>| {
+            let mut state = weights(trainer);
+            state.extend(trainer.network.buffers_mut().into_iter().map(|(_, b)| b.to_owned()));
+            state
+        };
+        let mut uninterrupted = Trainer::new(network(), optimizer(WeightDecayMode::Full), 11);
+        uninterrupted.run(6, regression_step).unwrap();
+
+        let path = std::env::temp_dir().join(format!("galore-trainer-dropout-{}.galore", std::process::id()));
+        let mut first = Trainer::new(network(), optimizer(WeightDecayMode::Full), 11).with_checkpoint(&path, CheckpointFormat::Native);
+        first.run(3, regression_step).unwrap();
+        let mut resumed = Trainer::new(network(), optimizer(WeightDecayMode::Full), 0).with_checkpoint(&path, CheckpointFormat::Native);
+        assert!(resumed.resume().unwrap());
+        resumed.run(6, regression_step).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(state(&mut uninterrupted), state(&mut resumed));
+        assert_eq!(&uninterrupted.metrics().series("loss").unwrap()[3..], resumed.metrics().series("loss").unwrap());
+    }
+
+    #[test]
+    fn micro_batched_steps_apply_weight_decay() {
+        // With zero gradients Adam's update is zero, so each step only decays the weights.
+        let mut trainer = Trainer::new(network(), optimizer(WeightDecayMode::Full).with_projected_accumulation(2), 0);
+        let before = weights(&mut trainer);
+        let zeros: Vec<Array2<f32>> = before.iter().map(|w| Array2::zeros(w.dim())).collect();
+        let compute = |_: &mut NeuralNetwork, _: &()| StepOutput { gradients: zeros.clone(), metrics: Vec::new() };
+        trainer.run_micro_batched(3, |_: &mut (), _, _, _| {}, compute).unwrap();
+        let shrink = (1.0 - LR * DECAY).powi(3);
+        for (after, before) in weights(&mut trainer).iter().zip(before.iter()) {
+            assert!(after.abs_diff_eq(&(before * shrink), 1e-6));
+        }
+    }
+}
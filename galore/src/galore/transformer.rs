@@ -0,0 +1,322 @@
+use ndarray::{s, Array1, Array2, ArrayView2, ArrayViewMut2, Axis};
+use ndarray_rand::rand_distr::Uniform;
+use ndarray_rand::RandomExt;
+use rand::thread_rng;
+
+use super::matrix_ops::GaLoreProjection;
+use super::neural_network::{LayerNorm, ValidationIssue};
+use super::packing::PackedBatch;
+use super::registry::TensorRegistry;
+
+// A pre-norm decoder block: multi-head self-attention (wq, wk, wv, wo, all d_model x
+// d_model, no biases) and a ReLU MLP (w1 d_ff x d_model, w2 d_model x d_ff), each behind
+// a layer norm and added to the residual stream.
+pub struct TransformerBlock {
+    heads: usize,
+    ln1: LayerNorm,
+    wq: Array2<f32>,
+    wk: Array2<f32>,
+    wv: Array2<f32>,
+    wo: Array2<f32>,
+    ln2: LayerNorm,
+    w1: Array2<f32>,
+    b1: Array1<f32>,
+    w2: Array2<f32>,
+    b2: Array1<f32>,
+}
+
+impl TransformerBlock {
+    pub fn new(d_model: usize, heads: usize, d_ff: usize) -> Self {
+        let mut rng = thread_rng();
+        let mut weight = |rows, cols| Array2::random_using((rows, cols), Uniform::new(-0.08, 0.08), &mut rng);
+        TransformerBlock {
+            heads,
+            ln1: LayerNorm::new(d_model, 1e-5),
+            wq: weight(d_model, d_model),
+            wk: weight(d_model, d_model),
+            wv: weight(d_model, d_model),
+            wo: weight(d_model, d_model),
+            ln2: LayerNorm::new(d_model, 1e-5),
+            w1: weight(d_ff, d_model),
+            b1: Array1::zeros(d_ff),
+            w2: weight(d_model, d_ff),
+            b2: Array1::zeros(d_model),
+        }
+    }
+
+    // `x` is seq_len x d_model; `mask` is added to every head's attention scores.
+    fn forward(&self, x: &mut Array2<f32>, mask: &ArrayView2<f32>) {
+        let mut h = x.clone();
+        self.ln1.forward_batch(&mut h);
+        let (q, k, v) = (h.dot(&self.wq.t()), h.dot(&self.wk.t()), h.dot(&self.wv.t()));
+        let head_dim = q.ncols() / self.heads;
+        let scale = 1.0 / (head_dim as f32).sqrt();
+        let mut attended = Array2::zeros(q.dim());
+        for head in 0..self.heads {
+            let cols = s![.., head * head_dim..(head + 1) * head_dim];
+            let mut scores = q.slice(cols).dot(&k.slice(cols).t()) * scale + mask;
+            softmax_rows(&mut scores);
+            attended.slice_mut(cols).assign(&scores.dot(&v.slice(cols)));
+        }
+        *x += &attended.dot(&self.wo.t());
+
+        let mut h = x.clone();
+        self.ln2.forward_batch(&mut h);
+        let hidden = (h.dot(&self.w1.t()) + &self.b1).mapv(|a| a.max(0.0));
+        *x += &(hidden.dot(&self.w2.t()) + &self.b2);
+    }
+
+    fn validate(&self, name: &str, d_model: usize, issues: &mut Vec<ValidationIssue>) {
+        let mut issue = |param: &str, message: String| issues.push(ValidationIssue { name: format!("{}.{}", name, param), message });
+        if self.heads == 0 || !d_model.is_multiple_of(self.heads) {
+            issue("heads", format!("{} heads do not divide the model width {}", self.heads, d_model));
+        }
+        for (param, norm) in [("ln1", &self.ln1), ("ln2", &self.ln2)] {
+            if norm.gamma.len() != d_model || norm.beta.len() != d_model {
+                issue(param, format!("covers {}/{} features for a model width of {}", norm.gamma.len(), norm.beta.len(), d_model));
+            }
+        }
+        for (param, weight) in [("wq", &self.wq), ("wk", &self.wk), ("wv", &self.wv), ("wo", &self.wo)] {
+            if weight.dim() != (d_model, d_model) {
+                issue(param, format!("is {}x{} but attention needs {}x{}", weight.nrows(), weight.ncols(), d_model, d_model));
+            }
+        }
+        let d_ff = self.w1.nrows();
+        if self.w1.ncols() != d_model {
+            issue("w1", format!("expects {} inputs but the model width is {}", self.w1.ncols(), d_model));
+        }
+        if self.b1.len() != d_ff {
+            issue("b1", format!("has {} entries for {} hidden units", self.b1.len(), d_ff));
+        }
+        if self.w2.dim() != (d_model, d_ff) {
+            issue("w2", format!("is {}x{} but maps {} hidden units back to {}", self.w2.nrows(), self.w2.ncols(), d_ff, d_model));
+        }
+        if self.b2.len() != d_model {
+            issue("b2", format!("has {} entries for a model width of {}", self.b2.len(), d_model));
+        }
+    }
+
+    fn parameters_mut(&mut self, prefix: &str) -> Vec<(String, ArrayViewMut2<'_, f32>)> {
+        vec![
+            (format!("{}.ln1_gamma", prefix), row(&mut self.ln1.gamma)),
+            (format!("{}.ln1_beta", prefix), row(&mut self.ln1.beta)),
+            (format!("{}.wq", prefix), self.wq.view_mut()),
+            (format!("{}.wk", prefix), self.wk.view_mut()),
+            (format!("{}.wv", prefix), self.wv.view_mut()),
+            (format!("{}.wo", prefix), self.wo.view_mut()),
+            (format!("{}.ln2_gamma", prefix), row(&mut self.ln2.gamma)),
+            (format!("{}.ln2_beta", prefix), row(&mut self.ln2.beta)),
+            (format!("{}.w1", prefix), self.w1.view_mut()),
+            (format!("{}.b1", prefix), row(&mut self.b1)),
+            (format!("{}.w2", prefix), self.w2.view_mut()),
+            (format!("{}.b2", prefix), row(&mut self.b2)),
+        ]
+    }
+
+    fn parameter_shapes(&self, prefix: &str) -> Vec<(String, (usize, usize))> {
+        vec![
+            (format!("{}.ln1_gamma", prefix), (1, self.ln1.gamma.len())),
+            (format!("{}.ln1_beta", prefix), (1, self.ln1.beta.len())),
+            (format!("{}.wq", prefix), self.wq.dim()),
+            (format!("{}.wk", prefix), self.wk.dim()),
+            (format!("{}.wv", prefix), self.wv.dim()),
+            (format!("{}.wo", prefix), self.wo.dim()),
+            (format!("{}.ln2_gamma", prefix), (1, self.ln2.gamma.len())),
+            (format!("{}.ln2_beta", prefix), (1, self.ln2.beta.len())),
+            (format!("{}.w1", prefix), self.w1.dim()),
+            (format!("{}.b1", prefix), (1, self.b1.len())),
+            (format!("{}.w2", prefix), self.w2.dim()),
+            (format!("{}.b2", prefix), (1, self.b2.len())),
+        ]
+    }
+}
+
+// A decoder-only language model: token and learned position embeddings, `TransformerBlock`s,
+// a final layer norm and an untied output head. Forward only, for evaluation and losses;
+// `parameters_mut` exposes the weights to load or update them.
+pub struct TransformerModel {
+    embedding: Array2<f32>,
+    positions: Array2<f32>,
+    blocks: Vec<TransformerBlock>,
+    ln_f: LayerNorm,
+    head: Array2<f32>,
+}
+
+impl TransformerModel {
+    pub fn new(vocab: usize, d_model: usize, heads: usize, d_ff: usize, layers: usize, max_len: usize) -> Self {
+        let mut rng = thread_rng();
+        let embedding = Array2::random_using((vocab, d_model), Uniform::new(-0.08, 0.08), &mut rng);
+        let positions = Array2::random_using((max_len, d_model), Uniform::new(-0.08, 0.08), &mut rng);
+        let head = Array2::random_using((vocab, d_model), Uniform::new(-0.08, 0.08), &mut rng);
+        let blocks = (0..layers).map(|_| TransformerBlock::new(d_model, heads, d_ff)).collect();
+        TransformerModel { embedding, positions, blocks, ln_f: LayerNorm::new(d_model, 1e-5), head }
+    }
+
+    // Blocks built individually are not checked against the embedding width; see `validate`.
+    pub fn from_blocks(vocab: usize, d_model: usize, max_len: usize, blocks: Vec<TransformerBlock>) -> Self {
+        TransformerModel { blocks, ..Self::new(vocab, d_model, 1, 1, 0, max_len) }
+    }
+
+    pub fn d_model(&self) -> usize {
+        self.embedding.ncols()
+    }
+
+    pub fn vocab(&self) -> usize {
+        self.embedding.nrows()
+    }
+
+    // Causal logits (seq_len x vocab) for one sequence.
+    pub fn forward(&self, tokens: &[usize]) -> Array2<f32> {
+        let len = tokens.len();
+        let causal = Array2::from_shape_fn((len, len), |(i, j)| if j <= i { 0.0 } else { f32::NEG_INFINITY });
+        let positions: Vec<usize> = (0..len).collect();
+        self.forward_masked(tokens, &positions, &causal.view())
+    }
+
+    // Logits for `tokens` at the given position ids, with `mask` (seq_len x seq_len, 0 or
+    // -inf) added to the attention scores of every head in every block.
+    pub fn forward_masked(&self, tokens: &[usize], positions: &[usize], mask: &ArrayView2<f32>) -> Array2<f32> {
+        assert_eq!(tokens.len(), positions.len(), "one position per token");
+        assert_eq!(mask.dim(), (tokens.len(), tokens.len()), "one mask entry per query and key");
+        let mut x = Array2::from_shape_fn((tokens.len(), self.d_model()), |(t, c)| {
+            self.embedding[[tokens[t], c]] + self.positions[[positions[t], c]]
+        });
+        for block in &self.blocks {
+            block.forward(&mut x, mask);
+        }
+        self.ln_f.forward_batch(&mut x);
+        x.dot(&self.head.t())
+    }
+
+    // Logits for every row of a packed batch, stacked into (rows * seq_len) x vocab to
+    // match `PackedTargets`. Each row attends causally within its documents only
+    // (`PackedBatch::attention_mask`) and uses their restarting positions, so every
+    // document gets the logits it would have alone.
+    pub fn forward_packed(&self, batch: &PackedBatch) -> Array2<f32> {
+        let seq_len = batch.seq_len();
+        let mut logits = Array2::zeros((batch.rows() * seq_len, self.vocab()));
+        for r in 0..batch.rows() {
+            let tokens = batch.tokens.row(r).to_vec();
+            let positions = batch.positions.row(r).to_vec();
+            let mask = batch.attention_mask(r, true);
+            logits.slice_mut(s![r * seq_len..(r + 1) * seq_len, ..]).assign(&self.forward_masked(&tokens, &positions, &mask.view()));
+        }
+        logits
+    }
+
+    // Checks every block against the embedding width, the output head and position table
+    // against the vocabulary and width, and, if given, that the GaLore configuration is
+    // feasible for the parameters (indexed in `parameters_mut` order). Reports every issue
+    // found under the parameter's name; run it before training.
+    pub fn validate(&self, galore: Option<&GaLoreProjection>) -> Result<(), Vec<ValidationIssue>> {
+        let mut issues = Vec::new();
+        let d_model = self.d_model();
+        let mut issue = |name: &str, message: String| issues.push(ValidationIssue { name: name.to_string(), message });
+        if self.positions.ncols() != d_model {
+            issue("positions", format!("has width {} but the embedding has {}", self.positions.ncols(), d_model));
+        }
+        if self.ln_f.gamma.len() != d_model || self.ln_f.beta.len() != d_model {
+            issue("ln_f", format!("covers {}/{} features for a model width of {}", self.ln_f.gamma.len(), self.ln_f.beta.len(), d_model));
+        }
+        if self.head.dim() != (self.vocab(), d_model) {
+            issue("head", format!("is {}x{} but maps width {} to a vocabulary of {}", self.head.nrows(), self.head.ncols(), d_model, self.vocab()));
+        }
+        for (i, block) in self.blocks.iter().enumerate() {
+            block.validate(&format!("block{}", i), d_model, &mut issues);
+        }
+
+        if let Some(galore) = galore {
+            let named = self.parameter_shapes();
+            let shapes: Vec<(usize, usize)> = named.iter().map(|(_, shape)| *shape).collect();
+            for (index, message) in galore.check_shapes(&shapes) {
+                let name = named.get(index).map_or_else(|| format!("parameter {}", index), |(name, _)| name.clone());
+                issues.push(ValidationIssue { name, message });
+            }
+        }
+
+        if issues.is_empty() {
+            Ok(())
+        } else {
+            Err(issues)
+        }
+    }
+
+    // The parameters under their `parameters_mut` names.
+    pub fn registry(&self) -> TensorRegistry {
+        TensorRegistry::from_shapes(self.parameter_shapes()).expect("parameter names are unique")
+    }
+
+    pub fn parameter_shapes(&self) -> Vec<(String, (usize, usize))> {
+        let mut shapes = vec![("embedding".to_string(), self.embedding.dim()), ("positions".to_string(), self.positions.dim())];
+        for (i, block) in self.blocks.iter().enumerate() {
+            shapes.extend(block.parameter_shapes(&format!("block{}", i)));
+        }
+        shapes.push(("ln_f_gamma".to_string(), (1, self.ln_f.gamma.len())));
+        shapes.push(("ln_f_beta".to_string(), (1, self.ln_f.beta.len())));
+        shapes.push(("head".to_string(), self.head.dim()));
+        shapes
+    }
+
+    // Every trainable tensor: "embedding", "positions", "block{i}.<name>" (ln1_gamma,
+    // ln1_beta, wq, wk, wv, wo, ln2_gamma, ln2_beta, w1, b1, w2, b2), "ln_f_gamma",
+    // "ln_f_beta" and "head". Vectors are 1 x n matrices.
+    pub fn parameters_mut(&mut self) -> Vec<(String, ArrayViewMut2<'_, f32>)> {
+        let mut params = vec![("embedding".to_string(), self.embedding.view_mut()), ("positions".to_string(), self.positions.view_mut())];
+        for (i, block) in self.blocks.iter_mut().enumerate() {
+            params.extend(block.parameters_mut(&format!("block{}", i)));
+        }
+        params.push(("ln_f_gamma".to_string(), row(&mut self.ln_f.gamma)));
+        params.push(("ln_f_beta".to_string(), row(&mut self.ln_f.beta)));
+        params.push(("head".to_string(), self.head.view_mut()));
+        params
+    }
+}
+
+// A vector parameter as the 1 x n matrix the optimizer steps.
+fn row(v: &mut Array1<f32>) -> ArrayViewMut2<'_, f32> {
+    v.view_mut().insert_axis(Axis(0))
+}
+
+fn softmax_rows(scores: &mut Array2<f32>) {
+    for mut row in scores.rows_mut() {
+        let max = row.fold(f32::NEG_INFINITY, |m, &x| m.max(x));
+        row.mapv_inplace(|x| (x - max).exp());
+        let sum = row.sum();
+        row /= sum;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::galore::matrix_ops::{LayerRank, RankConfig};
+
+    #[test]
+    fn validate_accepts_a_consistent_model() {
+        let model = TransformerModel::new(11, 8, 2, 16, 2, 6);
+        assert_eq!(model.validate(None), Ok(()));
+        assert_eq!(model.forward(&[1, 4, 2]).dim(), (3, 11));
+        assert_eq!(model.registry().len(), model.parameter_shapes().len());
+    }
+
+    #[test]
+    fn validate_names_every_mismatched_parameter() {
+        // Block 1 was built for a narrower model and with heads that do not divide it.
+        let blocks = vec![TransformerBlock::new(8, 2, 16), TransformerBlock::new(6, 3, 16)];
+        let model = TransformerModel::from_blocks(11, 8, 6, blocks);
+        // A rank of 12 does not fit the 8x8 attention weights of block 0.
+        let mut ranks = vec![LayerRank::Full; model.parameter_shapes().len()];
+        ranks[4] = LayerRank::LowRank { rank: 12, one_sided: false };
+        let galore = GaLoreProjection::new(4, 200, 0.0).with_rank_config(RankConfig::new(ranks));
+
+        let issues = model.validate(Some(&galore)).unwrap_err();
+        let names: Vec<&str> = issues.iter().map(|issue| issue.name.as_str()).collect();
+        assert_eq!(
+            names,
+            ["block1.heads", "block1.ln1", "block1.ln2", "block1.wq", "block1.wk", "block1.wv", "block1.wo", "block1.w1", "block1.w2", "block1.b2", "block0.wq"]
+        );
+        assert_eq!(issues[3].to_string(), "block1.wq: is 6x6 but attention needs 8x8");
+        assert_eq!(issues[0].to_string(), "block1.heads: 3 heads do not divide the model width 8");
+        assert_eq!(issues[10].to_string(), "block0.wq: rank 12 is not feasible for a 8x8 weight");
+    }
+}
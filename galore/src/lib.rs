@@ -0,0 +1,29 @@
+//! GaLore: memory-efficient training by projecting gradients onto a low-rank subspace.
+//!
+//! The modules below are the full API. `prelude` re-exports the types most training code
+//! needs; those names and signatures only change in a major release, while the rest of the
+//! module tree may still change in minor ones.
+//!
+//! Optional features:
+//!
+//! - `blas` (default): LAPACK/BLAS through OpenBLAS
+//! - `trainer` (default): `trainer::Trainer` and `shutdown::Shutdown`
+//! - `half`: f16 projection kernels and bf16 master weights
+//! - `experimental-fp8`: fp8 codecs and accumulation buffers; no stability guarantee
+//! - `object-store`: `checkpoint::object_store::ObjectStorage`, checkpoints on S3-compatible stores
+mod galore;
+
+pub use crate::galore::{
+    checkpoint, compat, decompose, distributed, ensemble, eval, fallback, golden, heads, loss, matrix_ops, metrics,
+    neural_network, offload, optimizer, packing, pipeline, planner, quantize, registry, restart, rollback, shared, sparse,
+    subspace, sweep, timings, transformer,
+};
+
+#[cfg(feature = "experimental-fp8")]
+pub use crate::galore::fp8;
+#[cfg(feature = "half")]
+pub use crate::galore::{half_gemm, master};
+#[cfg(feature = "trainer")]
+pub use crate::galore::{shutdown, trainer};
+
+pub mod prelude;
@@ -1,11 +1,113 @@
-use ndarray::{Array2, ArrayView2, s};
+use ndarray::{Array1, Array2, ArrayView2, s};
 use ndarray_linalg::SVD;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::env;
+use std::path::Path;
+use std::process;
+
+use galore::checkpoint::{Checkpoint, CheckpointFormat};
+use galore::ensemble::{self, SoupWeighting};
+use galore::eval::{evaluate_all, EvalTask};
+use galore::golden::{self, Tolerance};
+use galore::neural_network::{Activation, NeuralNetwork};
+#[cfg(feature = "trainer")]
+use galore::pipeline::Scale;
+#[cfg(feature = "trainer")]
+use galore::prelude::*;
+use galore::sweep::{comparison_table, run_command, run_program, run_sweep, SweepSpec};
+
+const USAGE: &str = "usage:
+    galore                                   run the projection example
+    galore convert <input> <output> [--from <format>] [--to <format>]
+    galore sweep <spec> [--parallel <n>] [--dry-run]
+    galore train <checkpoint> [--steps <n>] [--seed <n>] [--sizes <n1,n2,...>] [--dropout <p>]
+                 [--rank <r>] [--update-freq <n>] [--lr <x>] [--scale <x>]
+                 [--checkpoint-every <n>] [--metrics <csv>]
+    galore ensemble <checkpoint>... --output <path> [--metrics <m1,m2,...>] [--higher-is-better]
+                    [--sizes <n1,n2,...>] [--eval <command with {checkpoint}>]
+    galore golden write <path>
+    galore golden check <path> [--atol <x>] [--rtol <x>]
+
+formats: galore (native), safetensors, npz; inferred from the file extension by default
+
+train fits an MLP to a fixed random teacher with GaLore-projected Adam, refreshing the
+projection every --update-freq steps and multiplying the update by --scale; ensemble scores the soup and its members
+in-process on the same task when given the network --sizes; --eval runs a program instead, split on whitespace and run without a shell";
+
+// Validation batches of the teacher task, drawn from their own fixed seed.
+const EVAL_BATCHES: usize = 8;
+const EVAL_BATCH_SIZE: usize = 32;
+const EVAL_SEED: u64 = 0x5eed_e7a1;
+
+fn parse_sizes(value: &str) -> Result<Vec<usize>, String> {
+    let sizes = value.split(',').map(|n| n.trim().parse().map_err(|_| format!("bad sizes {}", value))).collect::<Result<Vec<usize>, _>>()?;
+    if sizes.len() < 2 || sizes.contains(&0) {
+        return Err(format!("sizes {} need at least an input and an output width", value));
+    }
+    Ok(sizes)
+}
+
+// An MLP of the given widths regressing tanh(T x) for a fixed T, with inputs uniform in
+// [-1, 1). Hidden layers get `dropout`, the output layer none.
+struct TeacherTask {
+    sizes: Vec<usize>,
+    teacher: Array2<f32>,
+}
+
+impl TeacherTask {
+    fn new(sizes: Vec<usize>) -> Self {
+        let (inputs, outputs) = (sizes[0], sizes[sizes.len() - 1]);
+        let teacher = Array2::from_shape_fn((outputs, inputs), |(i, j)| ((i * inputs + j) as f32 * 0.37).sin() / (inputs as f32).sqrt());
+        TeacherTask { sizes, teacher }
+    }
+
+    fn network(&self, dropout: f32) -> NeuralNetwork {
+        let last = self.sizes.len() - 1;
+        let rate = |i| if i == 0 || i == last { 0.0 } else { dropout };
+        NeuralNetwork::new(self.sizes.iter().enumerate().map(|(i, &n)| (n, Activation::Tanh, false, rate(i))).collect())
+    }
+
+    fn sample<R: Rng>(&self, rng: &mut R) -> (Array1<f32>, Array1<f32>) {
+        let input = Array1::from_shape_fn(self.sizes[0], |_| rng.gen_range(-1.0..1.0f32));
+        let target = self.teacher.dot(&input).mapv(f32::tanh);
+        (input, target)
+    }
+
+    // Checkpoint names of the network's weights, in `parameters_mut` order.
+    fn weight_names(&self) -> Vec<(String, (usize, usize))> {
+        self.network(0.0).parameter_shapes().into_iter().map(|(name, shape)| (format!("model.{}", name), shape)).collect()
+    }
+}
+
+impl EvalTask for TeacherTask {
+    fn num_batches(&self) -> usize {
+        EVAL_BATCHES
+    }
+
+    fn evaluate(&self, weights: &[Array2<f32>], batch: usize) -> Vec<(String, f32)> {
+        let mut network = self.network(0.0);
+        network.eval();
+        for ((_, mut param), weight) in network.parameters_mut().into_iter().zip(weights) {
+            param.assign(weight);
+        }
+        let mut rng = StdRng::seed_from_u64(EVAL_SEED ^ batch as u64);
+        let loss: f32 = (0..EVAL_BATCH_SIZE)
+            .map(|_| {
+                let (input, target) = self.sample(&mut rng);
+                let diff = network.forward(&input.view()).0 - target;
+                0.5 * diff.dot(&diff)
+            })
+            .sum();
+        vec![("loss".to_string(), loss / EVAL_BATCH_SIZE as f32)]
+    }
+}
 
 fn svd_lowrank(matrix: &ArrayView2<f32>, rank: usize) -> (Array2<f32>, Array2<f32>, Array2<f32>) {
-    let (u_opt, s_opt, vt_opt) = matrix.svd(true, true).expect("SVD failed");
+    let (u_opt, s, vt_opt) = matrix.svd(true, true).expect("SVD failed");
 
     let u = u_opt.unwrap().slice(s![.., ..rank]).to_owned();
-    let s = Array2::from_diag(&s_opt.unwrap().slice(s![..rank]));
+    let s = Array2::from_diag(&s.slice(s![..rank]));
     let vt = vt_opt.unwrap().slice(s![..rank, ..]).to_owned();
 
     (u, s, vt)
@@ -15,8 +117,7 @@ fn project_gradient(gradient: &ArrayView2<f32>, p: &ArrayView2<f32>, q: &ArrayVi
     p.t().dot(&gradient.dot(q))
 }
 
-fn main() {
-    // Example usage
+fn example() {
     let matrix = Array2::eye(5);
     let (u, s, vt) = svd_lowrank(&matrix.view(), 3);
     println!("U: {:?}", u);
@@ -29,3 +130,369 @@ fn main() {
     let projected_gradient = project_gradient(&gradient.view(), &p.view(), &q.view());
     println!("Projected gradient: {:?}", projected_gradient);
 }
+
+fn parse_format(value: Option<&String>) -> Result<CheckpointFormat, String> {
+    let value = value.ok_or("missing format name")?;
+    CheckpointFormat::parse(value).ok_or_else(|| format!("unknown format {}", value))
+}
+
+fn convert(args: &[String]) -> Result<(), String> {
+    let mut paths = Vec::new();
+    let mut from = None;
+    let mut to = None;
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        match arg.as_str() {
+            "--from" => from = Some(parse_format(iter.next())?),
+            "--to" => to = Some(parse_format(iter.next())?),
+            _ => paths.push(arg.as_str()),
+        }
+    }
+    let (input, output) = match paths[..] {
+        [input, output] => (input, output),
+        _ => return Err(USAGE.to_string()),
+    };
+
+    let from = from
+        .or_else(|| CheckpointFormat::from_path(Path::new(input)))
+        .ok_or_else(|| format!("cannot infer format of {}; pass --from", input))?;
+    let to = to
+        .or_else(|| CheckpointFormat::from_path(Path::new(output)))
+        .ok_or_else(|| format!("cannot infer format of {}; pass --to", output))?;
+
+    let mut checkpoint = Checkpoint::load(input, from).map_err(|e| format!("{}: {}", input, e))?;
+    let metadata_entries = checkpoint.metadata_entries().count();
+    if !to.supports_metadata() && metadata_entries > 0 {
+        eprintln!(
+            "warning: {:?} cannot store metadata; dropping {} entries (optimizer and projection scalars will not be restorable)",
+            to, metadata_entries
+        );
+        checkpoint.clear_metadata();
+    }
+    checkpoint.save(output, to).map_err(|e| format!("{}: {}", output, e))?;
+
+    println!("{} -> {}: {} tensors", input, output, checkpoint.tensors().count());
+    Ok(())
+}
+
+fn sweep(args: &[String]) -> Result<(), String> {
+    let mut spec_path = None;
+    let mut parallel = None;
+    let mut dry_run = false;
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        match arg.as_str() {
+            "--dry-run" => dry_run = true,
+            "--parallel" => {
+                let value = iter.next().ok_or("missing value for --parallel")?;
+                parallel = Some(value.parse::<usize>().map_err(|_| format!("bad --parallel {}", value))?);
+            }
+            path if spec_path.is_none() => spec_path = Some(path),
+            _ => return Err(USAGE.to_string()),
+        }
+    }
+    let spec_path = spec_path.ok_or_else(|| USAGE.to_string())?;
+
+    let spec = SweepSpec::load(spec_path).map_err(|e| e.to_string())?;
+    let configs = spec.expand().map_err(|e| e.to_string())?;
+    let command = spec.command.clone().ok_or("sweep spec has no `command`")?;
+    if dry_run {
+        for config in &configs {
+            println!("{}", config.render(&command));
+        }
+        return Ok(());
+    }
+
+    let parallel = parallel.unwrap_or(spec.parallel);
+    eprintln!("running {} configurations, {} at a time", configs.len(), parallel);
+    let results = run_sweep(configs, parallel, |config| run_command(&command, config));
+    println!("{}", comparison_table(&results));
+    Ok(())
+}
+
+#[cfg(feature = "trainer")]
+struct TrainArgs {
+    path: String,
+    steps: usize,
+    seed: u64,
+    rank: usize,
+    update_freq: usize,
+    lr: f32,
+    scale: f32,
+    dropout: f32,
+    sizes: Vec<usize>,
+    checkpoint_every: Option<usize>,
+    metrics: Option<String>,
+}
+
+#[cfg(feature = "trainer")]
+fn parse_train_args(args: &[String]) -> Result<TrainArgs, String> {
+    let mut path = None;
+    let (mut steps, mut seed, mut rank, mut update_freq) = (1000usize, 0u64, 4usize, 200usize);
+    let (mut lr, mut scale, mut dropout) = (1e-3f32, 1.0f32, 0.1f32);
+    let mut sizes = vec![16, 64, 16];
+    let mut checkpoint_every = None;
+    let mut metrics = None;
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        let mut value = || iter.next().ok_or_else(|| format!("missing value for {}", arg));
+        let bad = |value: &String| format!("bad {} {}", arg, value);
+        match arg.as_str() {
+            "--steps" => steps = value().and_then(|v| v.parse().map_err(|_| bad(v)))?,
+            "--seed" => seed = value().and_then(|v| v.parse().map_err(|_| bad(v)))?,
+            "--rank" => rank = value().and_then(|v| v.parse().map_err(|_| bad(v)))?,
+            "--update-freq" => update_freq = value().and_then(|v| v.parse().map_err(|_| bad(v)))?,
+            "--lr" => lr = value().and_then(|v| v.parse().map_err(|_| bad(v)))?,
+            "--scale" => scale = value().and_then(|v| v.parse().map_err(|_| bad(v)))?,
+            "--dropout" => dropout = value().and_then(|v| v.parse().map_err(|_| bad(v)))?,
+            "--checkpoint-every" => checkpoint_every = Some(value().and_then(|v| v.parse::<usize>().map_err(|_| bad(v)))?),
+            "--metrics" => metrics = Some(value()?.clone()),
+            "--sizes" => sizes = parse_sizes(value()?)?,
+            other if path.is_none() => path = Some(other.to_string()),
+            _ => return Err(USAGE.to_string()),
+        }
+    }
+    let path = path.ok_or_else(|| USAGE.to_string())?;
+    if checkpoint_every == Some(0) || update_freq == 0 {
+        return Err(USAGE.to_string());
+    }
+    if !(lr > 0.0 && lr.is_finite()) {
+        return Err(format!("bad --lr {}", lr));
+    }
+    Ok(TrainArgs { path, steps, seed, rank, update_freq, lr, scale, dropout, sizes, checkpoint_every, metrics })
+}
+
+// Trains an MLP on `TeacherTask`, resuming from <checkpoint> if it exists.
+// SIGINT / SIGTERM stop it between steps after saving; running it again continues where it
+// stopped and ends with the weights an uninterrupted run would have.
+#[cfg(feature = "trainer")]
+fn train(args: &[String]) -> Result<(), String> {
+    let TrainArgs { path, steps, seed, rank, update_freq, lr, scale, dropout, sizes, checkpoint_every, metrics } = parse_train_args(args)?;
+    let path = path.as_str();
+    let format = CheckpointFormat::from_path(Path::new(path)).ok_or_else(|| format!("cannot infer format of {}", path))?;
+    if !format.supports_metadata() {
+        return Err("training checkpoints need a format with metadata (galore or safetensors)".to_string());
+    }
+
+    let task = TeacherTask::new(sizes);
+    let network = task.network(dropout);
+    let mut galore = GaLoreOptimizer::new(Adam::new(lr, 0.9, 0.999, 1e-8), rank, update_freq, 0.0);
+    if scale != 1.0 {
+        galore = galore.with_transform_after("project_back", Box::new(Scale::new(scale))).map_err(|e| e.to_string())?;
+    }
+    network.validate(task.sizes[0], Some(galore.projection())).map_err(|issues| {
+        issues.iter().map(ToString::to_string).collect::<Vec<_>>().join("\n")
+    })?;
+
+    let shutdown = Shutdown::install().map_err(|e| format!("installing signal handlers: {}", e))?;
+    let mut trainer = Trainer::new(network, galore, seed).with_checkpoint(path, format).with_shutdown(shutdown);
+    if let Some(every) = checkpoint_every {
+        trainer = trainer.with_checkpoint_every(every);
+    }
+    if let Some(metrics) = metrics {
+        trainer = trainer.with_metrics_file(metrics);
+    }
+    // The initial weights are not seeded; a resumed run replaces them with the checkpoint's.
+    if trainer.resume().map_err(|e| format!("{}: {}", path, e))? {
+        eprintln!("resumed {} at step {}", path, trainer.step());
+    }
+
+    let reason = trainer
+        .run(steps, |network, rng, _| {
+            let (input, target) = task.sample(rng);
+            let (output, cache) = network.forward(&input.view());
+            let diff = output - target;
+            let loss = 0.5 * diff.dot(&diff);
+            StepOutput { gradients: network.backward(diff, &cache), metrics: vec![("loss".to_string(), loss)] }
+        })
+        .map_err(|e| format!("{}: {}", path, e))?;
+
+    let loss = trainer.metrics().latest("loss").map_or_else(|| "-".to_string(), |l| format!("{:.6}", l));
+    match reason {
+        StopReason::Finished => println!("finished {} steps, loss {}; saved {}", trainer.step(), loss, path),
+        StopReason::Interrupted(signal) => println!("stopped by {} after step {}, loss {}; saved {}", signal, trainer.step(), loss, path),
+    }
+    Ok(())
+}
+
+fn ensemble(args: &[String]) -> Result<(), String> {
+    let mut inputs = Vec::new();
+    let mut output = None;
+    let mut metrics = None;
+    let mut higher_is_better = false;
+    let mut eval = None;
+    let mut sizes = None;
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        match arg.as_str() {
+            "--output" => output = Some(iter.next().ok_or("missing value for --output")?.as_str()),
+            "--higher-is-better" => higher_is_better = true,
+            "--eval" => eval = Some(iter.next().ok_or("missing value for --eval")?.as_str()),
+            "--sizes" => sizes = Some(parse_sizes(iter.next().ok_or("missing value for --sizes")?)?),
+            "--metrics" => {
+                let value = iter.next().ok_or("missing value for --metrics")?;
+                let parsed = value
+                    .split(',')
+                    .map(|m| m.trim().parse::<f64>().map_err(|_| format!("bad metric {}", m)))
+                    .collect::<Result<Vec<_>, _>>()?;
+                metrics = Some(parsed);
+            }
+            _ => inputs.push(arg.as_str()),
+        }
+    }
+    let output = output.ok_or_else(|| USAGE.to_string())?;
+    if inputs.is_empty() {
+        return Err(USAGE.to_string());
+    }
+
+    let format_of = |path: &str| {
+        CheckpointFormat::from_path(Path::new(path)).ok_or_else(|| format!("cannot infer format of {}", path))
+    };
+    let checkpoints = inputs
+        .iter()
+        .map(|path| Checkpoint::load(path, format_of(path)?).map_err(|e| format!("{}: {}", path, e)))
+        .collect::<Result<Vec<_>, _>>()?;
+    let weighting = match metrics {
+        Some(metrics) => SoupWeighting::Metric { metrics, higher_is_better },
+        None => SoupWeighting::Uniform,
+    };
+    let soup = ensemble::average(&checkpoints, &weighting).map_err(|e| e.to_string())?;
+    soup.save(output, format_of(output)?).map_err(|e| format!("{}: {}", output, e))?;
+    println!("{} checkpoints -> {} (weights {})", checkpoints.len(), output, soup.metadata("ensemble.weights").unwrap_or(""));
+
+    let mut candidates = vec![(output, &soup)];
+    candidates.extend(inputs.iter().copied().zip(&checkpoints));
+    if let Some(sizes) = sizes {
+        let task = TeacherTask::new(sizes);
+        let weights = task.weight_names();
+        let names: Vec<String> = weights.iter().map(|(name, _)| name.clone()).collect();
+        for (path, checkpoint) in &candidates {
+            for (name, shape) in &weights {
+                let found = checkpoint.matrix(name).map_err(|e| format!("{}: {}", path, e))?.dim();
+                if found != *shape {
+                    return Err(format!("{}: {} is {:?} but --sizes needs {:?}", path, name, found, shape));
+                }
+            }
+        }
+        let score = |weights: &[Array2<f32>]| {
+            evaluate_all(&task, weights).get("loss").map(|&loss| loss as f64).ok_or_else(|| "no loss reported".to_string())
+        };
+        println!("in-process loss:");
+        for (path, result) in ensemble::compare(&candidates, |checkpoint| ensemble::evaluate(checkpoint, &names, score)) {
+            print_score(&path, result);
+        }
+    }
+    if let Some(command) = eval {
+        let argv: Vec<&str> = command.split_whitespace().collect();
+        println!("{}:", command);
+        for &(path, _) in &candidates {
+            let args: Vec<String> = argv.iter().map(|arg| arg.replace("{checkpoint}", path)).collect();
+            print_score(path, run_program(&args));
+        }
+    }
+    Ok(())
+}
+
+fn print_score(path: &str, score: Result<f64, String>) {
+    match score {
+        Ok(metric) => println!("{:.6}  {}", metric, path),
+        Err(e) => println!("failed: {}  {}", e, path),
+    }
+}
+
+fn golden(args: &[String]) -> Result<(), String> {
+    let (command, path) = match args {
+        [command, path, ..] => (command.as_str(), path.as_str()),
+        _ => return Err(USAGE.to_string()),
+    };
+    let format = CheckpointFormat::from_path(Path::new(path)).ok_or_else(|| format!("cannot infer format of {}", path))?;
+    if !format.supports_metadata() {
+        return Err("golden files need a format with metadata (galore or safetensors)".to_string());
+    }
+    match command {
+        "write" => {
+            let cases = golden::default_cases();
+            golden::generate(&cases).save(path, format).map_err(|e| format!("{}: {}", path, e))?;
+            println!("wrote {} golden cases to {}", cases.len(), path);
+            Ok(())
+        }
+        "check" => {
+            let mut tolerance = Tolerance::default();
+            let mut iter = args[2..].iter();
+            while let Some(arg) = iter.next() {
+                let target = match arg.as_str() {
+                    "--atol" => &mut tolerance.abs,
+                    "--rtol" => &mut tolerance.rel,
+                    _ => return Err(USAGE.to_string()),
+                };
+                let value = iter.next().ok_or_else(|| format!("missing value for {}", arg))?;
+                *target = value.parse().map_err(|_| format!("bad {} {}", arg, value))?;
+            }
+            let reference = Checkpoint::load(path, format).map_err(|e| format!("{}: {}", path, e))?;
+            let report = golden::compare(&reference, tolerance).map_err(|e| e.to_string())?;
+            println!("{}", report);
+            if report.passed() {
+                Ok(())
+            } else {
+                Err("golden comparison failed".to_string())
+            }
+        }
+        _ => Err(USAGE.to_string()),
+    }
+}
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let result = match args.first().map(String::as_str) {
+        None => {
+            example();
+            Ok(())
+        }
+        Some("convert") => convert(&args[1..]),
+        Some("sweep") => sweep(&args[1..]),
+        #[cfg(feature = "trainer")]
+        Some("train") => train(&args[1..]),
+        Some("ensemble") => ensemble(&args[1..]),
+        Some("golden") => golden(&args[1..]),
+        Some(_) => Err(USAGE.to_string()),
+    };
+    if let Err(message) = result {
+        eprintln!("{}", message);
+        process::exit(1);
+    }
+}
+
+#[cfg(all(test, feature = "trainer"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sweep_command_lines_are_accepted_by_train() {
+        let spec = SweepSpec::parse(
+            "command = galore train run-{run}.galore --rank {rank} --update-freq {update_freq} --lr {lr} --scale {scale}\n\
+             rank = 4, 8\nupdate_freq = 50\nlr = 0.001, 0.0003\nscale = 0.25",
+        )
+        .unwrap();
+        let command = spec.command.clone().unwrap();
+        let configs = spec.expand().unwrap();
+        assert_eq!(configs.len(), 4);
+        let mut paths = Vec::new();
+        for config in &configs {
+            let argv = config.render_args(&command);
+            assert_eq!(argv[..2], ["galore", "train"]);
+            let args = parse_train_args(&argv[2..]).unwrap();
+            assert_eq!(args.path, format!("run-{}.galore", config.run));
+            assert_eq!(args.rank as f64, config.get("rank").unwrap());
+            assert_eq!(args.update_freq, 50);
+            assert!((args.lr as f64 - config.get("lr").unwrap()).abs() < 1e-9);
+            assert_eq!(args.scale, 0.25);
+            paths.push(args.path);
+        }
+        // Every run writes its own checkpoint instead of resuming another run's.
+        paths.sort();
+        paths.dedup();
+        assert_eq!(paths.len(), configs.len());
+        assert!(parse_train_args(&["run.galore".to_string(), "--update-freq".to_string(), "0".to_string()]).is_err());
+        assert!(parse_train_args(&["run.galore".to_string(), "--lr".to_string(), "fast".to_string()]).is_err());
+    }
+}
@@ -0,0 +1,33 @@
+//! `use galore::prelude::*;` for the usual training setup: a network, a GaLore optimizer
+//! around a base optimizer, checkpoints and, with the `trainer` feature, the training loop.
+//!
+//! ```
+//! use galore::prelude::*;
+//! use ndarray::Array2;
+//!
+//! let mut optimizer = GaLoreOptimizer::new(Adam::new(1e-3, 0.9, 0.999, 1e-8), 4, 200, 0.0);
+//! let mut weights = vec![Array2::<f32>::zeros((32, 64))];
+//! let gradients = [Array2::from_shape_fn((32, 64), |(i, j)| ((i * 64 + j) as f32 * 0.1).sin())];
+//! optimizer.apply_updates(&mut weights, gradients.iter().map(|g| g.view()).collect()).unwrap();
+//! assert!(weights[0].iter().any(|&w| w != 0.0));
+//!
+//! let mut checkpoint = Checkpoint::new();
+//! optimizer.save_state(&mut checkpoint);
+//! ```
+
+pub use crate::checkpoint::{Checkpoint, CheckpointError, CheckpointFormat};
+pub use crate::loss::{CompositeLoss, CrossEntropy, Loss, Mse};
+pub use crate::heads::HeadAxis;
+pub use crate::matrix_ops::{GaLoreOptimizer, GaLoreProjection, GradStructure, LayerRank, RankConfig};
+pub use crate::metrics::Metrics;
+pub use crate::neural_network::{Activation, Layer, Mode, NeuralNetwork};
+pub use crate::optimizer::{Adam, Optimizer};
+pub use crate::pipeline::{ClipSpace, Grad, GradTransform, ParamGroup, WeightDecayMode};
+pub use crate::registry::{RegistryError, TensorRegistry};
+
+#[cfg(feature = "half")]
+pub use crate::master::MasterWeights;
+#[cfg(feature = "trainer")]
+pub use crate::shutdown::Shutdown;
+#[cfg(feature = "trainer")]
+pub use crate::trainer::{StepOutput, StopReason, TrainError, Trainer};